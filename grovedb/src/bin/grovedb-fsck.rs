@@ -0,0 +1,43 @@
+//! Verifies integrity of a GroveDB database, for instance after an unclean
+//! shutdown.
+//!
+//! Usage: `grovedb-fsck <path>`; exits with 1 if inconsistencies are found
+//! and with 2 if the database cannot be verified at all.
+
+use std::process::ExitCode;
+
+use grovedb::{GroveDb, GroveDbConfig};
+
+fn main() -> ExitCode {
+    let path = match std::env::args_os().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: grovedb-fsck <path>");
+            return ExitCode::from(2);
+        }
+    };
+    let db = match GroveDb::open_with_config(&path, GroveDbConfig::default().refuse_writes(true)) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("unable to open database: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    match db.verify_integrity() {
+        Ok(issues) if issues.is_empty() => {
+            println!("no inconsistencies found");
+            ExitCode::SUCCESS
+        }
+        Ok(issues) => {
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            println!("{} inconsistencies found", issues.len());
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("unable to verify database: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
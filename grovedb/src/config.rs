@@ -0,0 +1,59 @@
+//! Settings GroveDb is opened with
+
+use merk::RetentionPolicy;
+use storage::rocksdb_storage::RocksDbConfig;
+
+/// Number of open subtree Merks GroveDb keeps by default
+pub const DEFAULT_MERK_CACHE_SIZE: usize = 64;
+
+/// Settings to open GroveDb with, see [`crate::GroveDb::open_with_config`]
+#[derive(Debug, Clone)]
+pub struct GroveDbConfig {
+    pub(crate) storage: RocksDbConfig,
+    pub(crate) refuse_writes: bool,
+    pub(crate) merk_cache_size: usize,
+    pub(crate) retention_policy: RetentionPolicy,
+}
+
+impl Default for GroveDbConfig {
+    fn default() -> Self {
+        Self {
+            storage: RocksDbConfig::default(),
+            refuse_writes: false,
+            merk_cache_size: DEFAULT_MERK_CACHE_SIZE,
+            retention_policy: RetentionPolicy::default(),
+        }
+    }
+}
+
+impl GroveDbConfig {
+    /// RocksDB tuning: block cache, compression and write buffers per column
+    /// family, open files limit, WAL and prefix extractors
+    pub fn storage(mut self, storage: RocksDbConfig) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Refuses all writes, including transactional ones. It's not a read-only
+    /// mode: RocksDB is still opened for writes as optimistic transaction DB
+    /// can't be opened otherwise, so the DB can't be shared with another
+    /// process this way.
+    pub fn refuse_writes(mut self, refuse_writes: bool) -> Self {
+        self.refuse_writes = refuse_writes;
+        self
+    }
+
+    /// Number of recently used subtree Merks kept open with their loaded
+    /// top levels between non-transactional operations, zero disables it
+    pub fn merk_cache_size(mut self, merk_cache_size: usize) -> Self {
+        self.merk_cache_size = merk_cache_size;
+        self
+    }
+
+    /// Decides which nodes of each open subtree Merk stay loaded in memory,
+    /// a memory budget applies to every Merk on its own
+    pub fn retention_policy(mut self, retention_policy: RetentionPolicy) -> Self {
+        self.retention_policy = retention_policy;
+        self
+    }
+}
@@ -1,4 +1,6 @@
+mod config;
 mod operations;
+mod proof;
 mod subtree;
 mod subtrees;
 #[cfg(test)]
@@ -9,15 +11,34 @@ use std::{
     cell::RefCell,
     collections::{BTreeMap, HashMap, HashSet},
     path::Path,
-    rc::Rc,
 };
 
-pub use merk::proofs::{query::QueryItem, Query};
-use merk::{self, Merk};
+pub use config::{GroveDbConfig, DEFAULT_MERK_CACHE_SIZE};
+use lru::LruCache;
+use merk::{self, MergeFns, Merk};
+pub use merk::{
+    proofs::{query::QueryItem, Query},
+    MergeFn, MergeFnId, RetentionPolicy, ADD_U64_MERGE_FN, APPEND_MERGE_FN, FIRST_CUSTOM_MERGE_FN,
+};
+use operations::subtree_index::CHILDREN_INDEXED_KEY;
+pub use operations::{
+    gc::GarbageReport,
+    integrity::IntegrityIssue,
+    iter::ElementIter,
+    prefix_scheme::PREFIX_SCHEME_VERSION,
+    proof::{PathProofResult, PathProofResults},
+    stats::SubtreeStats,
+};
+pub use proof::{LayerProof, Proof, ProofDecoder, ProofLayer, RootLayerProof, PROOF_VERSION};
 use rs_merkle::{algorithms::Sha256, MerkleTree};
-use serde::{Deserialize, Serialize};
-use storage::rocksdb_storage::{OptimisticTransactionDBTransaction, PrefixedRocksDbStorageError};
-pub use storage::{rocksdb_storage::PrefixedRocksDbStorage, Storage, Transaction};
+pub use storage::{
+    memory::MemoryStorage,
+    rocksdb_storage::{
+        Column, DBCompressionType, DBRecoveryMode, PrefixedRocksDbStorage, RocksDbConfig,
+    },
+    PrefixedStorage, Storage, Transaction,
+};
+use storage::{rocksdb_storage::PrefixedRocksDbStorageError, Batch};
 pub use subtree::Element;
 use subtrees::Subtrees;
 #[cfg(feature = "visualize")]
@@ -27,6 +48,8 @@ pub use visualize::{visualize_stderr, visualize_stdout, Drawer, Visualize};
 /// structure
 /// A key to store serialized data about root tree leafs keys and order
 const ROOT_LEAFS_SERIALIZED_KEY: &[u8] = b"rootLeafsSerialized";
+/// A meta storage key to store the root hash written with the root leaves
+const ROOT_HASH_KEY: &[u8] = b"rootHash";
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -39,6 +62,8 @@ pub enum Error {
     InternalError(&'static str),
     #[error("invalid proof: {0}")]
     InvalidProof(&'static str),
+    #[error("invalid merge: {0}")]
+    InvalidMerge(String),
 
     // Path errors
 
@@ -62,7 +87,7 @@ pub enum Error {
     MissingParameter(&'static str),
     // Irrecoverable errors
     #[error("storage error: {0}")]
-    StorageError(#[from] PrefixedRocksDbStorageError),
+    StorageError(Box<dyn std::error::Error + Send + Sync>),
     #[error("data corruption error: {0}")]
     CorruptedData(String),
     #[error(
@@ -70,6 +95,25 @@ pub enum Error {
          commit it"
     )]
     DbIsInReadonlyMode,
+    #[error("db is opened refusing writes")]
+    WritesRefused,
+    #[error("unsupported subtree prefix scheme version: {0}")]
+    UnsupportedPrefixScheme(u32),
+    #[error("subtree prefixes must be migrated, db has to be opened for writes once")]
+    PrefixMigrationRequired,
+}
+
+impl Error {
+    /// Wraps an error of the underlying storage
+    fn storage<E: std::error::Error + Send + Sync + 'static>(error: E) -> Self {
+        Error::StorageError(Box::new(error))
+    }
+}
+
+impl From<PrefixedRocksDbStorageError> for Error {
+    fn from(error: PrefixedRocksDbStorageError) -> Self {
+        Error::storage(error)
+    }
 }
 
 #[derive(Debug)]
@@ -111,62 +155,104 @@ impl PathQuery {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Proof {
-    query_paths: Vec<Vec<Vec<u8>>>,
-    proofs: HashMap<Vec<u8>, Vec<u8>>,
-    root_proof: Vec<u8>,
-    root_leaf_keys: HashMap<Vec<u8>, usize>,
-}
-
-pub struct GroveDb {
+pub struct GroveDb<S: PrefixedStorage = PrefixedRocksDbStorage> {
     root_tree: MerkleTree<Sha256>,
     root_leaf_keys: BTreeMap<Vec<u8>, usize>,
-    meta_storage: PrefixedRocksDbStorage,
-    db: Rc<storage::rocksdb_storage::OptimisticTransactionDB>,
+    // Storage without a prefix, subtrees storages are opened from it
+    meta_storage: S,
     // Locks the database for writes during the transaction
     is_readonly: bool,
+    // Refuses all writes, set by the config the database is opened with
+    refuse_writes: bool,
     // Temp trees used for writes during transaction
     temp_root_tree: MerkleTree<Sha256>,
     temp_root_leaf_keys: BTreeMap<Vec<u8>, usize>,
-    temp_subtrees: RefCell<HashMap<Vec<u8>, Merk<PrefixedRocksDbStorage>>>,
+    temp_subtrees: RefCell<HashMap<Vec<u8>, Merk<S>>>,
     temp_deleted_subtrees: RefCell<HashSet<Vec<u8>>>,
+    // Recently used Merks opened without a transaction
+    merk_cache: RefCell<LruCache<Vec<u8>, Merk<S>>>,
+    // Decides which nodes of open Merks stay loaded in memory
+    retention_policy: RetentionPolicy,
+    // Child subtrees are found by the index instead of scanning subtrees
+    children_indexed: bool,
+    // Data of deleted subtrees is scheduled to be cleared
+    has_pending_clears: bool,
+    // Merge functions items are merged with, including the custom ones
+    merge_fns: MergeFns,
 }
 
 impl GroveDb {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::open_with_config(path, GroveDbConfig::default())
+    }
+
+    /// Opens GroveDb with RocksDB tuned by `config`
+    pub fn open_with_config<P: AsRef<Path>>(path: P, config: GroveDbConfig) -> Result<Self, Error> {
+        let db = config
+            .storage
+            .open(path)
+            .map_err(Into::<PrefixedRocksDbStorageError>::into)?;
+        Self::open_with_storage_and_config(
+            PrefixedRocksDbStorage::new(db.into(), Vec::new())?,
+            config,
+        )
+    }
+}
+
+impl<S: PrefixedStorage> GroveDb<S> {
     pub fn new(
         root_tree: MerkleTree<Sha256>,
         root_leaf_keys: BTreeMap<Vec<u8>, usize>,
-        meta_storage: PrefixedRocksDbStorage,
-        db: Rc<storage::rocksdb_storage::OptimisticTransactionDB>,
+        meta_storage: S,
     ) -> Self {
         Self {
             root_tree,
             root_leaf_keys,
             meta_storage,
-            db,
             temp_root_tree: MerkleTree::new(),
             temp_root_leaf_keys: BTreeMap::new(),
             temp_subtrees: RefCell::new(HashMap::new()),
             temp_deleted_subtrees: RefCell::new(HashSet::new()),
+            merk_cache: RefCell::new(LruCache::new(DEFAULT_MERK_CACHE_SIZE)),
+            retention_policy: RetentionPolicy::default(),
             is_readonly: false,
+            refuse_writes: false,
+            children_indexed: false,
+            has_pending_clears: false,
+            merge_fns: MergeFns::default(),
         }
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let db = Rc::new(
-            storage::rocksdb_storage::OptimisticTransactionDB::open_cf_descriptors(
-                &storage::rocksdb_storage::default_db_opts(),
-                path,
-                storage::rocksdb_storage::column_families(),
-            )
-            .map_err(Into::<PrefixedRocksDbStorageError>::into)?,
-        );
-        let meta_storage = PrefixedRocksDbStorage::new(db.clone(), Vec::new())?;
+    /// Opens GroveDb on top of a storage, subtrees are stored in storages
+    /// opened from it with their own prefixes
+    pub fn open_with_storage(meta_storage: S) -> Result<Self, Error> {
+        Self::open_with_storage_and_config(meta_storage, GroveDbConfig::default())
+    }
+
+    /// Opens GroveDb on top of a storage with settings of `config`, except
+    /// RocksDB tuning as the storage is opened already
+    pub fn open_with_storage_and_config(
+        meta_storage: S,
+        config: GroveDbConfig,
+    ) -> Result<Self, Error> {
+        let mut grove_db = Self::load(meta_storage)?;
+        grove_db.refuse_writes = config.refuse_writes;
+        grove_db.merk_cache.get_mut().resize(config.merk_cache_size);
+        grove_db.retention_policy = config.retention_policy;
+        if grove_db.refuse_writes {
+            grove_db.check_prefix_scheme()?;
+        } else {
+            grove_db.recover()?;
+        }
+        Ok(grove_db)
+    }
 
+    fn load(meta_storage: S) -> Result<Self, Error> {
         // TODO: owned `get` is not required for deserialization
         let root_leaf_keys: BTreeMap<Vec<u8>, usize> = if let Some(root_leaf_keys_serialized) =
-            meta_storage.get_meta(ROOT_LEAFS_SERIALIZED_KEY)?
+            meta_storage
+                .get_meta(ROOT_LEAFS_SERIALIZED_KEY)
+                .map_err(Error::storage)?
         {
             bincode::deserialize(&root_leaf_keys_serialized).map_err(|_| {
                 Error::CorruptedData(String::from("unable to deserialize root leafs"))
@@ -175,21 +261,38 @@ impl GroveDb {
             BTreeMap::new()
         };
 
-        let temp_subtrees: RefCell<HashMap<Vec<u8>, Merk<PrefixedRocksDbStorage>>> =
-            RefCell::new(HashMap::new());
+        let temp_subtrees: RefCell<HashMap<Vec<u8>, Merk<S>>> = RefCell::new(HashMap::new());
         let subtrees_view = Subtrees {
             root_leaf_keys: &root_leaf_keys,
             temp_subtrees: &temp_subtrees,
             deleted_subtrees: &RefCell::new(HashSet::new()),
-            storage: db.clone(),
+            merk_cache: None,
+            retention_policy: RetentionPolicy::default(),
+            storage: &meta_storage,
         };
+        let root_tree = Self::build_root_tree(&subtrees_view, &root_leaf_keys, None);
+        let children_indexed = meta_storage
+            .get_meta(CHILDREN_INDEXED_KEY)
+            .map_err(Error::storage)?
+            .is_some();
+
+        let mut grove_db = Self::new(root_tree, root_leaf_keys, meta_storage);
+        grove_db.children_indexed = children_indexed;
+        Ok(grove_db)
+    }
 
-        Ok(Self::new(
-            Self::build_root_tree(&subtrees_view, &root_leaf_keys, None),
-            root_leaf_keys,
-            meta_storage,
-            db,
-        ))
+    /// Migrates subtree prefixes derived with an older scheme, binds value
+    /// hashes of elements stored before they were bound, indexes child
+    /// subtrees if the database was created without the index and clears
+    /// data of deleted subtrees if it wasn't cleared because the process
+    /// stopped
+    fn recover(&mut self) -> Result<(), Error> {
+        self.migrate_prefix_scheme()?;
+        self.migrate_value_bindings()?;
+        if !self.children_indexed {
+            self.index_child_subtrees()?;
+        }
+        self.clear_scheduled()
     }
 
     // TODO: Checkpoints are currently not implemented for the transactional DB
@@ -204,10 +307,7 @@ impl GroveDb {
 
     /// Returns root hash of GroveDb.
     /// Will be `None` if GroveDb is empty.
-    pub fn root_hash(
-        &self,
-        db_transaction: Option<&OptimisticTransactionDBTransaction>,
-    ) -> Option<[u8; 32]> {
+    pub fn root_hash(&self, db_transaction: Option<&S::DBTransaction<'_>>) -> Option<[u8; 32]> {
         if db_transaction.is_some() {
             self.temp_root_tree.root()
         } else {
@@ -216,9 +316,9 @@ impl GroveDb {
     }
 
     fn build_root_tree(
-        subtrees: &Subtrees,
+        subtrees: &Subtrees<S>,
         root_leaf_keys: &BTreeMap<Vec<u8>, usize>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> MerkleTree<Sha256> {
         let mut leaf_hashes: Vec<[u8; 32]> = vec![[0; 32]; root_leaf_keys.len()];
         for (subtree_path, root_leaf_idx) in root_leaf_keys {
@@ -232,36 +332,40 @@ impl GroveDb {
 
     fn store_root_leafs_keys_data(
         &self,
-        db_transaction: Option<&OptimisticTransactionDBTransaction>,
+        db_transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error> {
-        match db_transaction {
-            None => {
-                self.meta_storage.put_meta(
-                    ROOT_LEAFS_SERIALIZED_KEY,
-                    &bincode::serialize(&self.root_leaf_keys).map_err(|_| {
-                        Error::CorruptedData(String::from("unable to serialize root leaves data"))
-                    })?,
-                )?;
-            }
-            Some(tx) => {
-                let transaction = self.meta_storage.transaction(tx);
-                transaction.put_meta(
-                    ROOT_LEAFS_SERIALIZED_KEY,
-                    &bincode::serialize(&self.temp_root_leaf_keys).map_err(|_| {
-                        Error::CorruptedData(String::from("unable to serialize root leaves data"))
-                    })?,
-                )?;
-            }
+        let (root_leaf_keys, root_tree) = if db_transaction.is_some() {
+            (&self.temp_root_leaf_keys, &self.temp_root_tree)
+        } else {
+            (&self.root_leaf_keys, &self.root_tree)
+        };
+        let mut batch = self
+            .meta_storage
+            .new_batch(db_transaction.map(S::shorten_db_transaction))
+            .map_err(Error::storage)?;
+        batch
+            .put_meta(
+                ROOT_LEAFS_SERIALIZED_KEY,
+                &bincode::serialize(root_leaf_keys).map_err(|_| {
+                    Error::CorruptedData(String::from("unable to serialize root leaves data"))
+                })?,
+            )
+            .map_err(Error::storage)?;
+        match root_tree.root() {
+            Some(root_hash) => batch.put_meta(ROOT_HASH_KEY, &root_hash),
+            None => batch.delete_meta(ROOT_HASH_KEY),
         }
-
-        Ok(())
+        .map_err(Error::storage)?;
+        self.meta_storage
+            .commit_batch(batch)
+            .map_err(Error::storage)
     }
 
     /// Method to propagate updated subtree root hashes up to GroveDB root
-    fn propagate_changes<'a: 'b, 'b, 'c, P>(
-        &'a mut self,
+    fn propagate_changes<'c, P>(
+        &mut self,
         path: P,
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error>
     where
         P: IntoIterator<Item = &'c [u8]>,
@@ -298,47 +402,83 @@ impl GroveDb {
         Ok(())
     }
 
-    fn get_subtrees(&self) -> Subtrees {
+    fn get_subtrees(&self) -> Subtrees<S> {
         Subtrees {
             root_leaf_keys: &self.root_leaf_keys,
             temp_subtrees: &self.temp_subtrees,
             deleted_subtrees: &self.temp_deleted_subtrees,
-            storage: self.storage(),
+            merk_cache: (!self.is_readonly).then_some(&self.merk_cache),
+            retention_policy: self.retention_policy,
+            storage: &self.meta_storage,
         }
     }
 
-    /// A helper method to build a prefix to rocksdb keys or identify a subtree
-    /// in `subtrees` map by tree path;
-    fn compress_subtree_key<'a, P>(path: P, key: Option<&'a [u8]>) -> Vec<u8>
-    where
-        P: IntoIterator<Item = &'a [u8]>,
-    {
-        let segments_iter = path.into_iter().chain(key.into_iter());
-        let mut segments_count: usize = 0;
-        let mut res = Vec::new();
-        let mut lengthes = Vec::new();
-
-        for s in segments_iter {
-            segments_count += 1;
-            res.extend_from_slice(s);
-            lengthes.extend(s.len().to_ne_bytes());
+    /// Checks if a write is allowed: nothing can be written if the DB is
+    /// opened refusing them, and writes without a transaction are locked while
+    /// one is started
+    fn check_writable<T>(&self, transaction: Option<&T>) -> Result<(), Error> {
+        if self.refuse_writes {
+            return Err(Error::WritesRefused);
+        }
+        if transaction.is_none() && self.is_readonly {
+            return Err(Error::DbIsInReadonlyMode);
         }
+        Ok(())
+    }
 
-        res.extend(segments_count.to_ne_bytes());
-        res.extend(lengthes);
-        res = blake3::hash(&res).as_bytes().to_vec();
-        res
+    /// Runs a non-transactional operation with changes of all subtrees and
+    /// metadata it touches gathered into one batch. The batch is written at
+    /// once if the operation succeeds and dropped otherwise, so a failure in
+    /// the middle doesn't leave the hierarchy inconsistent. Nested
+    /// operations become a part of the outer batch. An error to clear data of
+    /// deleted subtrees afterwards is returned too, though the operation is
+    /// written.
+    fn with_shared_batch<T>(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if self.meta_storage.has_shared_batch() {
+            return operation(self);
+        }
+        let batched_storage = self.meta_storage.with_shared_batch();
+        let meta_storage = std::mem::replace(&mut self.meta_storage, batched_storage);
+        let root_leaf_keys = self.root_leaf_keys.clone();
+        let has_pending_clears = self.has_pending_clears;
+
+        let result = operation(self);
+        let batched_storage = std::mem::replace(&mut self.meta_storage, meta_storage);
+        let result = result.and_then(|value| {
+            batched_storage
+                .commit_shared_batch()
+                .map_err(Error::storage)?;
+            Ok(value)
+        });
+        if result.is_err() {
+            // Nothing was written, so in-memory state must match the storage again
+            self.root_leaf_keys = root_leaf_keys;
+            self.merk_cache.get_mut().clear();
+            self.root_tree =
+                Self::build_root_tree(&self.get_subtrees(), &self.root_leaf_keys, None);
+            // Clears scheduled by the operation were dropped with the batch, earlier ones
+            // are still pending
+            self.has_pending_clears = has_pending_clears;
+        } else if self.has_pending_clears {
+            // The deletion is written already; if clearing fails, it stays scheduled and
+            // is retried after the next write or on the next open
+            self.clear_scheduled()?;
+        }
+        result
     }
 
     pub fn flush(&self) -> Result<(), Error> {
-        Ok(self.meta_storage.flush()?)
+        self.meta_storage.flush().map_err(Error::storage)
     }
 
-    /// Returns a clone of reference counter to the underlying db storage.
+    /// Returns a handle to the underlying db storage.
     /// Useful when working with transactions. For more details, please
     /// refer to the [`GroveDb::start_transaction`] examples section.
-    pub fn storage(&self) -> Rc<storage::rocksdb_storage::OptimisticTransactionDB> {
-        self.db.clone()
+    pub fn storage(&self) -> S::DB {
+        self.meta_storage.db()
     }
 
     /// Starts database transaction. Please note that you have to start
@@ -387,11 +527,15 @@ impl GroveDb {
     /// # }
     /// ```
     pub fn start_transaction(&mut self) -> Result<(), Error> {
+        if self.refuse_writes {
+            return Err(Error::WritesRefused);
+        }
         if self.is_readonly {
             return Err(Error::DbIsInReadonlyMode);
         }
         // Locking all writes outside of the transaction
         self.is_readonly = true;
+        self.merk_cache.get_mut().clear();
 
         // Cloning all the trees to maintain original state before the transaction
         self.temp_root_tree = self.root_tree.clone();
@@ -410,7 +554,7 @@ impl GroveDb {
     /// transaction usage, please check [`GroveDb::start_transaction`]
     pub fn commit_transaction(
         &mut self,
-        db_transaction: OptimisticTransactionDBTransaction,
+        db_transaction: S::DBTransaction<'_>,
     ) -> Result<(), Error> {
         // Copying all changes that were made during the transaction into the db
 
@@ -423,10 +567,16 @@ impl GroveDb {
         self.is_readonly = false;
 
         self.cleanup_transactional_data();
-
-        Ok(db_transaction
-            .commit()
-            .map_err(PrefixedRocksDbStorageError::RocksDbError)?)
+        // Cached Merks don't know about changes made by the transaction
+        self.merk_cache.get_mut().clear();
+
+        S::commit_db_transaction(db_transaction).map_err(Error::storage)?;
+        // Data of subtrees deleted by the transaction is cleared once the deletion is
+        // committed
+        if self.has_pending_clears {
+            self.clear_scheduled()?;
+        }
+        Ok(())
     }
 
     /// Rollbacks previously started db transaction to initial state.
@@ -434,14 +584,12 @@ impl GroveDb {
     /// [`GroveDb::start_transaction`]
     pub fn rollback_transaction(
         &mut self,
-        db_transaction: &OptimisticTransactionDBTransaction,
+        db_transaction: &S::DBTransaction<'_>,
     ) -> Result<(), Error> {
         // Cloning all the trees to maintain to rollback transactional changes
         self.cleanup_transactional_data();
 
-        Ok(db_transaction
-            .rollback()
-            .map_err(PrefixedRocksDbStorageError::RocksDbError)?)
+        S::rollback_db_transaction(db_transaction).map_err(Error::storage)
     }
 
     /// Rollbacks previously started db transaction to initial state.
@@ -449,7 +597,7 @@ impl GroveDb {
     /// [`GroveDb::start_transaction`]
     pub fn abort_transaction(
         &mut self,
-        _db_transaction: OptimisticTransactionDBTransaction,
+        _db_transaction: S::DBTransaction<'_>,
     ) -> Result<(), Error> {
         // Enabling writes again
         self.is_readonly = false;
@@ -468,3 +616,53 @@ impl GroveDb {
         self.temp_deleted_subtrees = RefCell::new(HashSet::new());
     }
 }
+
+/// A helper function to build a prefix to storage keys or identify a subtree
+/// in `subtrees` map by tree path;
+///
+/// Lengths are encoded as fixed-width little-endian integers, so prefixes
+/// don't depend on the platform. Any change here requires a new
+/// `PREFIX_SCHEME_VERSION` and a migration.
+fn compress_subtree_key<'a, P>(path: P, key: Option<&'a [u8]>) -> Vec<u8>
+where
+    P: IntoIterator<Item = &'a [u8]>,
+{
+    let segments_iter = path.into_iter().chain(key.into_iter());
+    let mut segments_count: u64 = 0;
+    let mut res = Vec::new();
+    let mut lengthes = Vec::new();
+
+    for s in segments_iter {
+        segments_count += 1;
+        res.extend_from_slice(s);
+        lengthes.extend((s.len() as u64).to_le_bytes());
+    }
+
+    res.extend(segments_count.to_le_bytes());
+    res.extend(lengthes);
+    res = blake3::hash(&res).as_bytes().to_vec();
+    res
+}
+
+/// Prefix derivation used before prefix scheme versions were introduced,
+/// lengths are encoded with pointer width and endianness of the platform
+fn compress_subtree_key_legacy<'a, P>(path: P, key: Option<&'a [u8]>) -> Vec<u8>
+where
+    P: IntoIterator<Item = &'a [u8]>,
+{
+    let segments_iter = path.into_iter().chain(key);
+    let mut segments_count: usize = 0;
+    let mut res = Vec::new();
+    let mut lengthes = Vec::new();
+
+    for s in segments_iter {
+        segments_count += 1;
+        res.extend_from_slice(s);
+        lengthes.extend(s.len().to_ne_bytes());
+    }
+
+    res.extend(segments_count.to_ne_bytes());
+    res.extend(lengthes);
+    res = blake3::hash(&res).as_bytes().to_vec();
+    res
+}
@@ -1,6 +1,15 @@
 pub mod aux;
+pub mod copy;
 pub mod delete;
+pub mod gc;
 pub mod get;
 pub mod insert;
+pub mod integrity;
 pub mod is_empty_tree;
+pub mod iter;
+pub mod prefix_scheme;
 pub mod proof;
+pub mod references;
+pub mod stats;
+pub mod subtree_index;
+pub mod value_bindings;
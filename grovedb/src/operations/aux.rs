@@ -1,53 +1,51 @@
-use storage::{Storage, Transaction};
+use storage::{PrefixedStorage, Transaction};
 
-use crate::{Error, GroveDb, PrefixedRocksDbStorage};
+use crate::{Error, GroveDb};
 
-impl GroveDb {
-    pub fn put_aux<'a: 'b, 'b, K: AsRef<[u8]>>(
-        &'a mut self,
+impl<S: PrefixedStorage> GroveDb<S> {
+    pub fn put_aux<K: AsRef<[u8]>>(
+        &mut self,
         key: K,
         value: &[u8],
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error> {
+        self.check_writable(transaction)?;
         if let Some(tx) = transaction {
-            let transaction = self.meta_storage.transaction(tx);
-            transaction.put_aux(key, value)?;
+            let transaction = self.meta_storage.transaction(S::shorten_db_transaction(tx));
+            transaction.put_aux(key, value).map_err(Error::storage)?;
             Ok(())
         } else {
-            if self.is_readonly {
-                return Err(Error::DbIsInReadonlyMode);
-            }
-            Ok(self.meta_storage.put_aux(key, value)?)
+            self.meta_storage
+                .put_aux(key, value)
+                .map_err(Error::storage)
         }
     }
 
-    pub fn delete_aux<'a: 'b, 'b, K: AsRef<[u8]>>(
-        &'a mut self,
+    pub fn delete_aux<K: AsRef<[u8]>>(
+        &mut self,
         key: K,
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error> {
+        self.check_writable(transaction)?;
         if let Some(tx) = transaction {
-            let transaction = self.meta_storage.transaction(tx);
-            transaction.delete_aux(key)?;
+            let transaction = self.meta_storage.transaction(S::shorten_db_transaction(tx));
+            transaction.delete_aux(key).map_err(Error::storage)?;
             Ok(())
         } else {
-            if self.is_readonly {
-                return Err(Error::DbIsInReadonlyMode);
-            }
-            Ok(self.meta_storage.delete_aux(key)?)
+            self.meta_storage.delete_aux(key).map_err(Error::storage)
         }
     }
 
-    pub fn get_aux<'a: 'b, 'b, K: AsRef<[u8]>>(
-        &'a mut self,
+    pub fn get_aux<K: AsRef<[u8]>>(
+        &mut self,
         key: K,
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Option<Vec<u8>>, Error> {
         if let Some(tx) = transaction {
-            let transaction = self.meta_storage.transaction(tx);
-            Ok(transaction.get_aux(key)?)
+            let transaction = self.meta_storage.transaction(S::shorten_db_transaction(tx));
+            transaction.get_aux(key).map_err(Error::storage)
         } else {
-            Ok(self.meta_storage.get_aux(key)?)
+            self.meta_storage.get_aux(key).map_err(Error::storage)
         }
     }
 }
@@ -0,0 +1,173 @@
+use storage::PrefixedStorage;
+
+use crate::{Element, Error, GroveDb};
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Copies a subtree under `from_path` with all nested subtrees to
+    /// `to_path`, which must not exist yet while its parent subtree must.
+    /// Subtrees are recreated under their new prefixes and hashes are
+    /// propagated up from the destination.
+    ///
+    /// References pointing inside the copied subtree are rebased to point
+    /// inside the copy, other references are copied as they are.
+    pub fn copy_subtree<'a, 'b, P, Q>(
+        &mut self,
+        from_path: P,
+        to_path: Q,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        Q: IntoIterator<Item = &'b [u8]>,
+    {
+        let from_path: Vec<Vec<u8>> = from_path.into_iter().map(|x| x.to_vec()).collect();
+        let to_path: Vec<Vec<u8>> = to_path.into_iter().map(|x| x.to_vec()).collect();
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self
+                .with_shared_batch(|db| db.copy_subtree_internal(&from_path, &to_path, None));
+        }
+        self.copy_subtree_internal(&from_path, &to_path, transaction)
+    }
+
+    /// Moves a subtree under `from_path` with all nested subtrees to
+    /// `to_path` the same way `copy_subtree` does and deletes the source,
+    /// propagating hashes on both sides. References pointing inside the
+    /// moved subtree from elsewhere keep pointing to the source path, they are
+    /// rebound as dangling ones as the source is deleted.
+    pub fn move_subtree<'a, 'b, P, Q>(
+        &mut self,
+        from_path: P,
+        to_path: Q,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        Q: IntoIterator<Item = &'b [u8]>,
+    {
+        let from_path: Vec<Vec<u8>> = from_path.into_iter().map(|x| x.to_vec()).collect();
+        let to_path: Vec<Vec<u8>> = to_path.into_iter().map(|x| x.to_vec()).collect();
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self
+                .with_shared_batch(|db| db.move_subtree_internal(&from_path, &to_path, None));
+        }
+        self.move_subtree_internal(&from_path, &to_path, transaction)
+    }
+
+    fn move_subtree_internal(
+        &mut self,
+        from_path: &[Vec<u8>],
+        to_path: &[Vec<u8>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        self.copy_subtree_internal(from_path, to_path, transaction)?;
+        let (key, path) = from_path
+            .split_last()
+            .expect("paths are checked to be non-empty by copying");
+        self.delete_internal(path.iter().map(|x| x.as_slice()), key, false, transaction)?;
+        Ok(())
+    }
+
+    fn copy_subtree_internal(
+        &mut self,
+        from_path: &[Vec<u8>],
+        to_path: &[Vec<u8>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        self.check_writable(transaction)?;
+        let (to_key, to_parent_path) = match to_path.split_last() {
+            Some(split) if !from_path.is_empty() => split,
+            _ => return Err(Error::InvalidPath("root tree cannot be copied or replaced")),
+        };
+        if to_path.starts_with(from_path) {
+            return Err(Error::InvalidPath("subtree cannot be copied into itself"));
+        }
+        let (from_key, from_parent_path) = from_path.split_last().expect("path is not empty");
+        if !matches!(
+            self.get_raw(
+                from_parent_path.iter().map(|x| x.as_slice()),
+                from_key,
+                transaction
+            )?,
+            Element::Tree(_)
+        ) {
+            return Err(Error::InvalidPath("source path refers to an element"));
+        }
+        match self.get_raw(
+            to_parent_path.iter().map(|x| x.as_slice()),
+            to_key,
+            transaction,
+        ) {
+            Ok(_) => return Err(Error::InvalidPath("destination path already exists")),
+            Err(Error::PathKeyNotFound(_)) | Err(Error::PathNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        let rebase = |path: &[Vec<u8>]| -> Vec<Vec<u8>> {
+            let mut rebased = to_path.to_vec();
+            rebased.extend_from_slice(&path[from_path.len()..]);
+            rebased
+        };
+        // Parents are found before their children, so each subtree is created under
+        // an existing one
+        let mut references = Vec::new();
+        for source_path in
+            self.find_subtrees(from_path.iter().map(|x| x.as_slice()), transaction)?
+        {
+            let path = rebase(&source_path);
+            let (key, parent_path) = path.split_last().expect("path is not empty");
+            self.insert_internal(
+                parent_path.iter().map(|x| x.as_slice()),
+                key,
+                Element::empty_tree(),
+                transaction,
+            )?;
+
+            let subtrees = self.get_subtrees();
+            let source =
+                subtrees.borrow_mut(source_path.iter().map(|x| x.as_slice()), transaction)?;
+            let mut elements = Vec::new();
+            let mut iter =
+                Element::iterator(source.raw_iter(transaction.map(S::shorten_db_transaction)));
+            while let Some((key, element)) = iter.next()? {
+                match element {
+                    Element::Item(_) => elements.push((key, element)),
+                    Element::Reference(reference_path) => {
+                        let reference_path = if reference_path.starts_with(from_path) {
+                            rebase(&reference_path)
+                        } else {
+                            reference_path
+                        };
+                        references.push((path.clone(), key, Element::Reference(reference_path)));
+                    }
+                    Element::Tree(_) => {}
+                }
+            }
+            drop(iter);
+            drop(source);
+
+            let mut merk = subtrees.borrow_mut(path.iter().map(|x| x.as_slice()), transaction)?;
+            for (key, element) in &elements {
+                element.insert(&mut merk, key, transaction)?;
+            }
+            drop(merk);
+            self.propagate_changes(path.iter().map(|x| x.as_slice()), transaction)?;
+            for (key, _) in &elements {
+                self.update_references(path.iter().map(|x| x.as_slice()), key, transaction)?;
+            }
+        }
+
+        // References are inserted once everything they could point to is copied to be
+        // bound to the copies
+        for (path, key, element) in references {
+            self.insert_internal(
+                path.iter().map(|x| x.as_slice()),
+                &key,
+                element,
+                transaction,
+            )?;
+        }
+        Ok(())
+    }
+}
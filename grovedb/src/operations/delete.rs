@@ -1,14 +1,34 @@
-use storage::rocksdb_storage::OptimisticTransactionDBTransaction;
+use storage::{PrefixedStorage, RawIterator};
 
-use crate::{Element, Error, GroveDb};
+use crate::{compress_subtree_key, subtree::raw_decode, Element, Error, GroveDb};
 
-impl GroveDb {
+impl<S: PrefixedStorage> GroveDb<S> {
     pub fn delete_up_tree_while_empty<'a, P>(
         &mut self,
         path: P,
         key: &'a [u8],
         stop_path_height: Option<u16>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<u16, Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
+    {
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self.with_shared_batch(|db| {
+                db.delete_up_tree_while_empty_internal(path, key, stop_path_height, None)
+            });
+        }
+        self.delete_up_tree_while_empty_internal(path, key, stop_path_height, transaction)
+    }
+
+    fn delete_up_tree_while_empty_internal<'a, P>(
+        &mut self,
+        path: P,
+        key: &'a [u8],
+        stop_path_height: Option<u16>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<u16, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
@@ -25,8 +45,12 @@ impl GroveDb {
         }
         let mut delete_count: u16 = 1;
         if let Some(last) = path_iter.next_back() {
-            let deleted_parent =
-                self.delete_up_tree_while_empty(path_iter, last, stop_path_height, transaction)?;
+            let deleted_parent = self.delete_up_tree_while_empty_internal(
+                path_iter,
+                last,
+                stop_path_height,
+                transaction,
+            )?;
             delete_count += deleted_parent;
         }
         Ok(delete_count)
@@ -36,12 +60,17 @@ impl GroveDb {
         &mut self,
         path: P,
         key: &'a [u8],
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
         <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
     {
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self
+                .with_shared_batch(|db| db.delete_internal(path, key, false, None).map(|_| ()));
+        }
         self.delete_internal(path, key, false, transaction)?;
         Ok(())
     }
@@ -50,29 +79,115 @@ impl GroveDb {
         &mut self,
         path: P,
         key: &'a [u8],
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<bool, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
         <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
     {
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self.with_shared_batch(|db| db.delete_internal(path, key, true, None));
+        }
         self.delete_internal(path, key, true, transaction)
     }
 
-    fn delete_internal<'a, P>(
+    /// Deletes all elements of the subtree under `path` with keys from `from`
+    /// (inclusive) to `to` (exclusive). Subtrees within the range are deleted
+    /// with their contents, other elements are deleted from the Merk in one
+    /// pass.
+    pub fn delete_range<'a, P>(
+        &mut self,
+        path: P,
+        from: &[u8],
+        to: &[u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
+    {
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self.with_shared_batch(|db| db.delete_range_internal(path, from, to, None));
+        }
+        self.delete_range_internal(path, from, to, transaction)
+    }
+
+    fn delete_range_internal<'a, P>(
+        &mut self,
+        path: P,
+        from: &[u8],
+        to: &[u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
+    {
+        self.check_writable(transaction)?;
+        let transaction = transaction.map(S::shorten_db_transaction);
+        let path_iter = path.into_iter();
+        if path_iter.len() == 0 {
+            return Err(Error::InvalidPath(
+                "root tree leafs currently cannot be deleted",
+            ));
+        }
+        if from >= to {
+            return Ok(());
+        }
+
+        let mut tree_keys = Vec::new();
+        let mut keys = Vec::new();
+        {
+            let subtrees = self.get_subtrees();
+            let merk = subtrees.borrow_mut(path_iter.clone(), transaction)?;
+            let mut raw_iter = merk.raw_iter(transaction.map(S::shorten_db_transaction));
+            raw_iter.seek(from);
+            while let Some((key, value)) = raw_iter.key().zip(raw_iter.value()) {
+                if key >= to {
+                    break;
+                }
+                match raw_decode(value)? {
+                    Element::Tree(_) => tree_keys.push(key.to_vec()),
+                    _ => keys.push(key.to_vec()),
+                }
+                raw_iter.next();
+            }
+        }
+
+        // Subtrees' contents have to be deleted as well
+        let path: Vec<Vec<u8>> = path_iter.clone().map(|x| x.to_vec()).collect();
+        let path_iter = path.iter().map(|x| x.as_slice());
+        for key in &tree_keys {
+            self.delete_internal(path_iter.clone(), key, false, transaction)?;
+        }
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.get_subtrees()
+            .borrow_mut(path_iter.clone(), transaction)?
+            .apply(|s| Element::delete_range(s, from, to, transaction))?;
+        self.propagate_changes(path_iter.clone(), transaction)?;
+        for key in &keys {
+            self.update_references(path_iter.clone(), key, transaction)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn delete_internal<'a, P>(
         &mut self,
         path: P,
         key: &'a [u8],
         only_delete_tree_if_empty: bool,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<bool, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
         <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
     {
-        if transaction.is_none() && self.is_readonly {
-            return Err(Error::DbIsInReadonlyMode);
-        }
+        self.check_writable(transaction)?;
+        let transaction = transaction.map(S::shorten_db_transaction);
         let path_iter = path.into_iter();
         if path_iter.len() == 0 {
             // Attempt to delete a root tree leaf
@@ -94,44 +209,77 @@ impl GroveDb {
                 let subtrees_paths = self.find_subtrees(subtree_merk_path.clone(), transaction)?;
                 let is_empty = subtrees
                     .borrow_mut(subtree_merk_path, transaction)?
-                    .apply(|s| s.is_empty_tree(transaction));
+                    .apply(|s| s.is_empty_tree(transaction.map(S::shorten_db_transaction)));
 
                 if only_delete_tree_if_empty && !is_empty {
                     return Ok(false);
                 } else {
-                    // TODO: dumb traversal should not be tolerated
-                    for subtree_path in subtrees_paths {
-                        let mut subtree = subtrees
-                            .borrow_mut(subtree_path.iter().map(|x| x.as_slice()), transaction)?;
-                        subtree.clear(transaction).map_err(|e| {
-                            Error::CorruptedData(format!(
-                                "unable to cleanup tree from storage: {}",
-                                e
-                            ))
-                        })?;
-                        if let Some(prefix) = subtree.get_prefix() {
-                            self.get_subtrees()
-                                .delete_temp_tree_with_prefix(prefix.to_vec(), transaction);
-                        }
+                    // Subtrees' data is cleared by prefixes right after the deletion is
+                    // written, files holding nothing else are dropped at once and the rest
+                    // is deleted by keys; inside of a transaction it's after the commit
+                    let prefixes: Vec<Vec<u8>> = subtrees_paths
+                        .iter()
+                        .map(|path| compress_subtree_key(path.iter().map(|x| x.as_slice()), None))
+                        .collect();
+                    subtrees.invalidate_cached(&prefixes);
+                    for prefix in &prefixes {
+                        subtrees.delete_temp_tree_with_prefix(prefix.clone(), transaction);
                     }
                     delete_element()?;
+                    self.remove_child_subtrees(
+                        path_iter.clone(),
+                        key,
+                        &subtrees_paths,
+                        transaction,
+                    )?;
+                    self.schedule_clears(prefixes, transaction)?;
                 }
+                self.propagate_changes(path_iter, transaction)?;
+                self.update_references_to_deleted(&subtrees_paths, transaction)?;
             } else {
                 delete_element()?;
+                self.propagate_changes(path_iter.clone(), transaction)?;
+                self.update_references(path_iter, key, transaction)?;
             }
-            self.propagate_changes(path_iter, transaction)?;
             Ok(true)
         }
     }
 
-    // TODO: dumb traversal should not be tolerated
     /// Finds keys which are trees for a given subtree recursively.
     /// One element means a key of a `merk`, n > 1 elements mean relative path
     /// for a deeply nested subtree.
     pub(crate) fn find_subtrees<'a, P>(
         &self,
         path: P,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Vec<Vec<Vec<u8>>>, Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+    {
+        if !self.children_indexed {
+            return self.find_subtrees_by_scan(path, transaction);
+        }
+        let mut queue: Vec<Vec<Vec<u8>>> =
+            vec![path.into_iter().map(|x| x.as_ref().to_vec()).collect()];
+        let mut result: Vec<Vec<Vec<u8>>> = queue.clone();
+
+        while let Some(q) = queue.pop() {
+            for key in self.get_child_keys(&q, transaction)? {
+                let mut sub_path = q.clone();
+                sub_path.push(key);
+                queue.push(sub_path.clone());
+                result.push(sub_path);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Same as `find_subtrees`, but reads every element of every subtree
+    /// instead of using the index of child subtrees
+    pub(crate) fn find_subtrees_by_scan<'a, P>(
+        &self,
+        path: P,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Vec<Vec<Vec<u8>>>, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
@@ -153,7 +301,8 @@ impl GroveDb {
             // Get the correct subtree with q_ref as path
             let path_iter = q.iter().map(|x| x.as_slice());
             let merk = subtrees.borrow_mut(path_iter.clone(), transaction)?;
-            let mut raw_iter = Element::iterator(merk.raw_iter(transaction));
+            let mut raw_iter =
+                Element::iterator(merk.raw_iter(transaction.map(S::shorten_db_transaction)));
             while let Some((key, value)) = raw_iter.next()? {
                 if let Element::Tree(_) = value {
                     let mut sub_path = q.clone();
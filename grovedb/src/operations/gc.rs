@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+
+use storage::PrefixedStorage;
+
+use crate::{compress_subtree_key, Error, GroveDb};
+
+/// Result of a garbage collection pass
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GarbageReport {
+    /// Prefixes of subtrees data which is not reachable from root leaves
+    pub orphaned_prefixes: Vec<Vec<u8>>,
+    /// Number of bytes of keys and values under orphaned prefixes, before
+    /// compression
+    pub storage_bytes: u64,
+}
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Finds data left under prefixes of subtrees which are not reachable
+    /// from root leaves anymore, for instance because a subtree deletion was
+    /// interrupted, and deletes it from data, auxiliary and roots storages.
+    /// With `dry_run` nothing is deleted and only the report is made.
+    ///
+    /// Orphaned prefixes are found by the data and roots storages because
+    /// GroveDB auxiliary data is stored without prefixes.
+    pub fn collect_garbage(&mut self, dry_run: bool) -> Result<GarbageReport, Error> {
+        if !dry_run {
+            // Subtrees of a pending transaction are not reachable yet
+            self.check_writable(None::<&S::DBTransaction<'_>>)?;
+        }
+
+        // Subtrees are found by their elements, the index of child subtrees is not
+        // trusted as data reachable by a stale index would never be collected
+        let mut reachable_prefixes = HashSet::new();
+        for root_leaf_key in self.root_leaf_keys.keys() {
+            for path in self.find_subtrees_by_scan([root_leaf_key.as_slice()], None)? {
+                reachable_prefixes.insert(compress_subtree_key(
+                    path.iter().map(|x| x.as_slice()),
+                    None,
+                ));
+            }
+        }
+
+        let mut report = GarbageReport::default();
+        for prefix in self
+            .meta_storage
+            .stored_prefixes(blake3::OUT_LEN)
+            .map_err(Error::storage)?
+        {
+            if reachable_prefixes.contains(&prefix) {
+                continue;
+            }
+            let storage = self
+                .meta_storage
+                .with_prefix(prefix.clone())
+                .map_err(Error::storage)?;
+            report.storage_bytes += storage.logical_size().map_err(Error::storage)?;
+            if !dry_run {
+                storage.clear().map_err(Error::storage)?;
+                self.get_subtrees().invalidate_cached([&prefix]);
+            }
+            report.orphaned_prefixes.push(prefix);
+        }
+        Ok(report)
+    }
+}
@@ -1,18 +1,18 @@
 use std::collections::HashSet;
 
-use storage::rocksdb_storage::OptimisticTransactionDBTransaction;
+use storage::PrefixedStorage;
 
 use crate::{Element, Error, GroveDb, PathQuery, Subtrees};
 
 /// Limit of possible indirections
 pub const MAX_REFERENCE_HOPS: usize = 10;
 
-impl GroveDb {
+impl<S: PrefixedStorage> GroveDb<S> {
     pub fn get<'a, P>(
         &self,
         path: P,
         key: &'a [u8],
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Element, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
@@ -26,10 +26,35 @@ impl GroveDb {
         }
     }
 
-    fn follow_reference(
+    /// Gets elements under many keys of one subtree, which is opened only
+    /// once. `None` is returned for keys which are not found; references are
+    /// followed the same way as by `get`.
+    pub fn get_many<'a, P, K>(
+        &self,
+        path: P,
+        keys: &[K],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Vec<Option<Element>>, Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
+        K: AsRef<[u8]>,
+    {
+        self.get_many_raw(path, keys, transaction)?
+            .into_iter()
+            .map(|maybe_element| match maybe_element {
+                Some(Element::Reference(reference_path)) => {
+                    self.follow_reference(reference_path, transaction).map(Some)
+                }
+                other => Ok(other),
+            })
+            .collect()
+    }
+
+    pub(super) fn follow_reference(
         &self,
         mut path: Vec<Vec<u8>>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Element, Error> {
         let mut hops_left = MAX_REFERENCE_HOPS;
         let mut current_element;
@@ -60,7 +85,7 @@ impl GroveDb {
         &self,
         path: P,
         key: &'a [u8],
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Element, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
@@ -81,10 +106,41 @@ impl GroveDb {
         }
     }
 
+    /// Get many tree items of one subtree without following references
+    fn get_many_raw<'a, P, K>(
+        &self,
+        path: P,
+        keys: &[K],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Vec<Option<Element>>, Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+        K: AsRef<[u8]>,
+    {
+        let path_iter = path.into_iter();
+        if path_iter.len() == 0 {
+            // Root leafs are subtrees on their own, there is nothing to share
+            return keys
+                .iter()
+                .map(
+                    |key| match self.get_raw(std::iter::empty(), key.as_ref(), transaction) {
+                        Ok(element) => Ok(Some(element)),
+                        Err(Error::PathNotFound(_)) => Ok(None),
+                        Err(e) => Err(e),
+                    },
+                )
+                .collect();
+        }
+        self.get_subtrees()
+            .borrow_mut(path_iter, transaction)?
+            .apply(|s| Element::get_many(s, keys))
+    }
+
     pub fn get_path_queries(
         &mut self,
         path_queries: &[&PathQuery],
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Vec<Vec<u8>>, Error> {
         let elements = self.get_path_queries_raw(path_queries, transaction)?;
         let results = elements
@@ -109,7 +165,7 @@ impl GroveDb {
     pub fn get_path_queries_raw(
         &mut self,
         path_queries: &[&PathQuery],
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Vec<Element>, Error> {
         let mut result = Vec::new();
         for query in path_queries {
@@ -122,7 +178,7 @@ impl GroveDb {
     pub fn get_path_query(
         &mut self,
         path_query: &PathQuery,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(Vec<Vec<u8>>, u16), Error> {
         let (elements, skipped) = self.get_path_query_raw(path_query, transaction)?;
         let results = elements
@@ -148,7 +204,7 @@ impl GroveDb {
     pub fn get_path_query_raw(
         &mut self,
         path_query: &PathQuery,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(Vec<Element>, u16), Error> {
         let subtrees = self.get_subtrees();
         self.get_path_query_on_trees_raw(path_query, subtrees, transaction)
@@ -157,14 +213,19 @@ impl GroveDb {
     fn get_path_query_on_trees_raw(
         &self,
         path_query: &PathQuery,
-        subtrees: Subtrees,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        subtrees: Subtrees<S>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(Vec<Element>, u16), Error> {
         let path_slices = path_query
             .path
             .iter()
             .map(|x| x.as_slice())
             .collect::<Vec<_>>();
-        Element::get_path_query(&path_slices, path_query, transaction, &subtrees)
+        Element::get_path_query(
+            &path_slices,
+            path_query,
+            transaction.map(S::shorten_db_transaction),
+            &subtrees,
+        )
     }
 }
@@ -1,42 +1,63 @@
-use std::rc::Rc;
+use storage::PrefixedStorage;
 
-use storage::{rocksdb_storage, Storage};
-
-use crate::{Element, Error, GroveDb, Merk, PrefixedRocksDbStorage};
+use crate::{
+    compress_subtree_key, Element, Error, GroveDb, MergeFn, MergeFnId, Merk, RetentionPolicy,
+};
 
 /// A helper function that builds a prefix for a key under a path and opens a
 /// Merk instance.
-fn create_merk_with_prefix<'a, P>(
-    db: Rc<rocksdb_storage::OptimisticTransactionDB>,
+fn create_merk_with_prefix<'a, S, P>(
+    storage: &S,
     path: P,
     key: &'a [u8],
-) -> Result<(Vec<u8>, Merk<PrefixedRocksDbStorage>), Error>
+    retention_policy: RetentionPolicy,
+) -> Result<(Vec<u8>, Merk<S>), Error>
 where
+    S: PrefixedStorage,
     P: IntoIterator<Item = &'a [u8]>,
 {
-    let subtree_prefix = GroveDb::compress_subtree_key(path, Some(key));
-    Ok((
-        subtree_prefix.clone(),
-        Merk::open(PrefixedRocksDbStorage::new(db, subtree_prefix)?)
-            .map_err(|e| Error::CorruptedData(e.to_string()))?,
-    ))
+    let subtree_prefix = compress_subtree_key(path, Some(key));
+    let mut merk = Merk::open(
+        storage
+            .with_prefix(subtree_prefix.clone())
+            .map_err(Error::storage)?,
+    )
+    .map_err(|e| Error::CorruptedData(e.to_string()))?;
+    merk.set_retention_policy(retention_policy);
+    Ok((subtree_prefix, merk))
 }
 
-impl GroveDb {
-    pub fn insert<'a: 'b, 'b, 'c, P>(
-        &'a mut self,
+impl<S: PrefixedStorage> GroveDb<S> {
+    pub fn insert<'c, P>(
+        &mut self,
         path: P,
         key: &'c [u8],
         element: Element,
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error>
     where
         P: IntoIterator<Item = &'c [u8]>,
         <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
     {
-        if transaction.is_none() && self.is_readonly {
-            return Err(Error::DbIsInReadonlyMode);
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self.with_shared_batch(|db| db.insert_internal(path, key, element, None));
         }
+        self.insert_internal(path, key, element, transaction)
+    }
+
+    pub(super) fn insert_internal<'c, P>(
+        &mut self,
+        path: P,
+        key: &'c [u8],
+        element: Element,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'c [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+    {
+        self.check_writable(transaction)?;
         let path_iter = path.into_iter();
         match element {
             Element::Tree(_) => {
@@ -54,28 +75,171 @@ impl GroveDb {
                         "only subtrees are allowed as root tree's leafs",
                     ));
                 }
-                self.get_subtrees()
-                    .borrow_mut(path_iter.clone(), transaction)?
-                    .apply(|s| element.insert(s, key, transaction))?;
-                self.propagate_changes(path_iter, transaction)?;
+                if let Element::Reference(reference_path) = &element {
+                    let referenced_value_hash =
+                        self.referenced_value_hash(reference_path, transaction)?;
+                    self.get_subtrees()
+                        .borrow_mut(path_iter.clone(), transaction)?
+                        .apply(|s| {
+                            element.insert_reference(s, key, referenced_value_hash, transaction)
+                        })?;
+                    self.add_referencing_path(reference_path, path_iter.clone(), key, transaction)?;
+                } else {
+                    self.get_subtrees()
+                        .borrow_mut(path_iter.clone(), transaction)?
+                        .apply(|s| element.insert(s, key, transaction))?;
+                }
+                self.propagate_changes(path_iter.clone(), transaction)?;
+                // References to the element are bound to its value hash
+                self.update_references(path_iter, key, transaction)?;
             }
         }
         Ok(())
     }
 
+    /// Registers a custom merge function items can be merged with under the
+    /// given id, which has to be at least `FIRST_CUSTOM_MERGE_FN` and not
+    /// registered yet. Merge functions aren't stored, so they have to be
+    /// registered each time the database is opened.
+    pub fn register_merge_fn(&mut self, id: MergeFnId, merge_fn: MergeFn) -> Result<(), Error> {
+        self.merge_fns
+            .register(id, merge_fn)
+            .map_err(|e| Error::InvalidMerge(e.to_string()))
+    }
+
+    /// Merges `operand` into the item under `key` with the merge function of
+    /// the given id. The item is merged by Merk while the change is applied,
+    /// which saves getting it first. The item is created if there is nothing
+    /// under `key` yet.
+    pub fn merge<'c, P>(
+        &mut self,
+        path: P,
+        key: &'c [u8],
+        operand: &[u8],
+        merge_fn_id: MergeFnId,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'c [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+    {
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self
+                .with_shared_batch(|db| db.merge_internal(path, key, operand, merge_fn_id, None));
+        }
+        self.merge_internal(path, key, operand, merge_fn_id, transaction)
+    }
+
+    fn merge_internal<'c, P>(
+        &mut self,
+        path: P,
+        key: &'c [u8],
+        operand: &[u8],
+        merge_fn_id: MergeFnId,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'c [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+    {
+        self.check_writable(transaction)?;
+        let path_iter = path.into_iter();
+        if path_iter.len() == 0 {
+            return Err(Error::InvalidPath(
+                "only subtrees are allowed as root tree's leafs",
+            ));
+        }
+        self.get_subtrees()
+            .borrow_mut(path_iter.clone(), transaction)?
+            .apply(|s| {
+                Element::merge(s, key, operand, merge_fn_id, &self.merge_fns, transaction)
+            })?;
+        self.propagate_changes(path_iter.clone(), transaction)?;
+        // References to the item are bound to its value hash
+        self.update_references(path_iter, key, transaction)
+    }
+
+    /// Imports `len` items into the empty subtree under `path` at once, which
+    /// is much cheaper than inserting them one by one, see `Merk::bulk_load`.
+    /// Items are given as their keys and contents, sorted by keys, and are
+    /// consumed as they are yielded; the import fails if there are not exactly
+    /// `len` of them.
+    pub fn import_subtree<'c, P, I>(
+        &mut self,
+        path: P,
+        items: I,
+        len: usize,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'c [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        if transaction.is_none() {
+            // Changes of all touched subtrees are written at once
+            return self.with_shared_batch(|db| db.import_subtree_internal(path, items, len, None));
+        }
+        self.import_subtree_internal(path, items, len, transaction)
+    }
+
+    fn import_subtree_internal<'c, P, I>(
+        &mut self,
+        path: P,
+        items: I,
+        len: usize,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'c [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        self.check_writable(transaction)?;
+        let path_iter = path.into_iter();
+        if path_iter.len() == 0 {
+            return Err(Error::InvalidPath(
+                "only subtrees are allowed as root tree's leafs",
+            ));
+        }
+        let elements = items.into_iter().map(|(key, item)| {
+            let element =
+                bincode::serialize(&Element::Item(item)).expect("items are always serializable");
+            (key, element)
+        });
+        self.get_subtrees()
+            .borrow_mut(path_iter.clone(), transaction)?
+            .apply(|s| {
+                if s.root_key().is_some() {
+                    return Err(Error::InvalidPath(
+                        "items can only be imported into empty subtrees",
+                    ));
+                }
+                s.bulk_load(elements, len, transaction.map(S::shorten_db_transaction))
+                    .map_err(|e| Error::CorruptedData(e.to_string()))
+            })?;
+        self.propagate_changes(path_iter.clone(), transaction)?;
+        // References inserted before the import are bound to the imported items
+        let subtree_path: Vec<Vec<u8>> = path_iter.map(|x| x.to_vec()).collect();
+        for key in self.get_referenced_keys(&subtree_path, transaction)? {
+            self.update_references(subtree_path.iter().map(|x| x.as_slice()), &key, transaction)?;
+        }
+        Ok(())
+    }
+
     /// Add subtree to the root tree
-    fn add_root_leaf<'a: 'b, 'b>(
-        &'a mut self,
+    fn add_root_leaf(
+        &mut self,
         key: &[u8],
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error> {
-        if transaction.is_none() && self.is_readonly {
-            return Err(Error::DbIsInReadonlyMode);
-        }
+        self.check_writable(transaction)?;
 
         // Open Merk and put handle into `subtrees` dictionary accessible by its
         // compressed path
-        let (subtree_prefix, subtree_merk) = create_merk_with_prefix(self.db.clone(), [], key)?;
+        let (subtree_prefix, subtree_merk) =
+            create_merk_with_prefix(&self.meta_storage, [], key, self.retention_policy)?;
         self.get_subtrees()
             .insert_temp_tree_with_prefix(subtree_prefix, subtree_merk, transaction);
 
@@ -102,19 +266,17 @@ impl GroveDb {
     // if it exists, then create merk to be inserted, and get root hash
     // we only care about root hash of merk to be inserted
     //
-    fn add_non_root_subtree<'a: 'b, 'b, 'c, P>(
-        &'a mut self,
+    fn add_non_root_subtree<'c, P>(
+        &mut self,
         path: P,
         key: &'c [u8],
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error>
     where
         P: IntoIterator<Item = &'c [u8]>,
         <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
     {
-        if transaction.is_none() && self.is_readonly {
-            return Err(Error::DbIsInReadonlyMode);
-        }
+        self.check_writable(transaction)?;
         let subtrees = self.get_subtrees();
         let path_iter = path.into_iter();
         // First, check if a subtree exists to create a new subtree under it
@@ -129,11 +291,19 @@ impl GroveDb {
                 }
             })?;
 
-        let (subtree_prefix, mut subtree_merk) =
-            create_merk_with_prefix(self.db.clone(), path_iter.clone(), key)?;
+        // Data of a subtree deleted under the same path may be not cleared yet
+        if self.has_pending_clears {
+            self.clear_if_scheduled(&compress_subtree_key(path_iter.clone(), Some(key)))?;
+        }
+        let (subtree_prefix, mut subtree_merk) = create_merk_with_prefix(
+            &self.meta_storage,
+            path_iter.clone(),
+            key,
+            self.retention_policy,
+        )?;
 
         // If the subtree was deleted previously inside a transaction then we should
-        // insert it as empty
+        // insert it as empty, and its data must not be cleared after the commit
         // TODO: open Merk on transactional data
         if transaction.is_some()
             && self
@@ -141,7 +311,10 @@ impl GroveDb {
                 .borrow()
                 .contains(&subtree_prefix)
         {
-            subtree_merk.clear(transaction).unwrap();
+            subtree_merk
+                .clear(transaction.map(S::shorten_db_transaction))
+                .unwrap();
+            self.unschedule_clear(&subtree_prefix, transaction)?;
         }
 
         // Set tree value as a a subtree root hash
@@ -153,17 +326,18 @@ impl GroveDb {
             .borrow_mut(path_iter.clone(), transaction)
             .expect("must exist at this point")
             .apply(|s| element.insert(s, key, transaction))?;
+        self.add_child_subtree(path_iter.clone(), key, transaction)?;
         self.propagate_changes(path_iter, transaction)?;
 
         Ok(())
     }
 
-    pub fn insert_if_not_exists<'a: 'b, 'b, 'c, P>(
+    pub fn insert_if_not_exists<'c, P>(
         &mut self,
         path: P,
         key: &'c [u8],
         element: Element,
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<bool, Error>
     where
         P: IntoIterator<Item = &'c [u8]>,
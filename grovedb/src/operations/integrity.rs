@@ -0,0 +1,345 @@
+use std::{collections::HashSet, fmt};
+
+use merk::{
+    tree::{Blake3Hasher, Hash, Hasher, Sha256Hasher, Tree, ValueBinding},
+    Merk, HASHER_KEY,
+};
+use rs_merkle::{algorithms::Sha256, MerkleTree};
+use storage::{PrefixedStorage, RawIterator, Store};
+
+use crate::{compress_subtree_key, Element, Error, GroveDb, ROOT_HASH_KEY};
+
+/// An inconsistency found by `GroveDb::verify_integrity`; `path` is the path
+/// of a subtree the inconsistency is found in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityIssue {
+    /// Stored Merk node or the element in it cannot be decoded
+    CorruptedNode { path: Vec<Vec<u8>>, key: Vec<u8> },
+    /// Hash of node's key and value doesn't match the stored one
+    InvalidNodeHash { path: Vec<Vec<u8>>, key: Vec<u8> },
+    /// Node's value hash is not bound to its element, the subtree or the
+    /// referenced element the way it was inserted
+    InvalidValueHash { path: Vec<Vec<u8>>, key: Vec<u8> },
+    /// Node links to a child which is missing or has a different hash
+    InvalidLink {
+        path: Vec<Vec<u8>>,
+        key: Vec<u8>,
+        child_key: Vec<u8>,
+    },
+    /// Node is stored, but cannot be reached from the root of the Merk
+    UnreachableNode { path: Vec<Vec<u8>>, key: Vec<u8> },
+    /// Subtree element's hash differs from the root hash of the subtree
+    SubtreeHashMismatch { path: Vec<Vec<u8>>, key: Vec<u8> },
+    /// Reference points to no element
+    DanglingReference {
+        path: Vec<Vec<u8>>,
+        key: Vec<u8>,
+        reference_path: Vec<Vec<u8>>,
+    },
+    /// Root leaf has a position out of the root tree leaves range or taken
+    /// by another root leaf
+    InvalidRootLeaf { key: Vec<u8> },
+    /// Root hash of root leaves subtrees doesn't match the committed root hash
+    RootHashMismatch,
+    /// Subtree is recorded to be hashed with an unknown hash function, its
+    /// nodes are not verified
+    UnknownHasher { path: Vec<Vec<u8>> },
+}
+
+/// Merk of a subtree opened with the hash function recorded for it
+enum SubtreeMerk<S: PrefixedStorage> {
+    Blake3(Merk<S, Blake3Hasher>),
+    Sha256(Merk<S, Sha256Hasher>),
+}
+
+impl<S: PrefixedStorage> SubtreeMerk<S> {
+    fn root_hash(&self) -> Hash {
+        match self {
+            SubtreeMerk::Blake3(merk) => merk.root_hash(),
+            SubtreeMerk::Sha256(merk) => merk.root_hash(),
+        }
+    }
+}
+
+fn fmt_path(path: &[Vec<u8>]) -> String {
+    let segments: Vec<String> = path.iter().map(hex::encode).collect();
+    format!("[{}]", segments.join(", "))
+}
+
+impl fmt::Display for IntegrityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityIssue::CorruptedNode { path, key } => write!(
+                f,
+                "corrupted node {} in {}",
+                hex::encode(key),
+                fmt_path(path)
+            ),
+            IntegrityIssue::InvalidNodeHash { path, key } => write!(
+                f,
+                "invalid hash of node {} in {}",
+                hex::encode(key),
+                fmt_path(path)
+            ),
+            IntegrityIssue::InvalidValueHash { path, key } => write!(
+                f,
+                "invalid value hash of node {} in {}",
+                hex::encode(key),
+                fmt_path(path)
+            ),
+            IntegrityIssue::InvalidLink {
+                path,
+                key,
+                child_key,
+            } => write!(
+                f,
+                "invalid link from node {} to {} in {}",
+                hex::encode(key),
+                hex::encode(child_key),
+                fmt_path(path)
+            ),
+            IntegrityIssue::UnreachableNode { path, key } => write!(
+                f,
+                "unreachable node {} in {}",
+                hex::encode(key),
+                fmt_path(path)
+            ),
+            IntegrityIssue::SubtreeHashMismatch { path, key } => write!(
+                f,
+                "subtree {} in {} doesn't match its root hash",
+                hex::encode(key),
+                fmt_path(path)
+            ),
+            IntegrityIssue::DanglingReference {
+                path,
+                key,
+                reference_path,
+            } => write!(
+                f,
+                "reference {} in {} points to missing {}",
+                hex::encode(key),
+                fmt_path(path),
+                fmt_path(reference_path)
+            ),
+            IntegrityIssue::InvalidRootLeaf { key } => {
+                write!(f, "invalid position of root leaf {}", hex::encode(key))
+            }
+            IntegrityIssue::RootHashMismatch => {
+                write!(f, "root leaves don't match the committed root hash")
+            }
+            IntegrityIssue::UnknownHasher { path } => {
+                write!(f, "unknown hash function of {}", fmt_path(path))
+            }
+        }
+    }
+}
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Walks every subtree and verifies that Merk nodes' hashes, computed with
+    /// the hash function recorded for the subtree, match the stored data and
+    /// links, subtree elements match subtrees' root hashes,
+    /// root leaves match the committed root hash and references point to
+    /// existing elements. Only committed data is verified.
+    ///
+    /// Inconsistencies are returned instead of stopping on the first one, an
+    /// error is returned only if the storage itself fails.
+    pub fn verify_integrity(&self) -> Result<Vec<IntegrityIssue>, Error> {
+        let mut issues = Vec::new();
+
+        let mut leaf_hashes: Vec<Option<Hash>> = vec![None; self.root_leaf_keys.len()];
+        // Subtrees to verify with hashes their parents' elements hold
+        let mut queue: Vec<(Vec<Vec<u8>>, Option<Hash>)> = Vec::new();
+        for (key, idx) in &self.root_leaf_keys {
+            let path = vec![key.clone()];
+            let hash = self
+                .open_subtree_with_recorded_hasher(&path)?
+                .map(|merk| merk.root_hash());
+            match leaf_hashes.get_mut(*idx) {
+                Some(leaf_hash @ None) => *leaf_hash = hash,
+                _ => issues.push(IntegrityIssue::InvalidRootLeaf { key: key.clone() }),
+            }
+            queue.push((path, None));
+        }
+        // Databases written before the root hash was stored have nothing to compare
+        // with
+        let committed_root_hash = self
+            .meta_storage
+            .get_meta(ROOT_HASH_KEY)
+            .map_err(Error::storage)?;
+        if let Some(committed_root_hash) = committed_root_hash {
+            if leaf_hashes.iter().all(Option::is_some) {
+                let leaf_hashes: Vec<Hash> = leaf_hashes.into_iter().flatten().collect();
+                let root_hash = MerkleTree::<Sha256>::from_leaves(&leaf_hashes).root();
+                if root_hash.map(|hash| hash.to_vec()) != Some(committed_root_hash) {
+                    issues.push(IntegrityIssue::RootHashMismatch);
+                }
+            }
+        }
+
+        while let Some((path, expected_hash)) = queue.pop() {
+            let (parent_path, parent_key) = path.split_at(path.len() - 1);
+            // Merks are opened from storage as cached ones could miss changes made to it
+            let merk = match self.open_subtree_with_recorded_hasher(&path)? {
+                Some(merk) => merk,
+                None => {
+                    issues.push(IntegrityIssue::UnknownHasher { path });
+                    continue;
+                }
+            };
+            if matches!(expected_hash, Some(hash) if hash != merk.root_hash()) {
+                issues.push(IntegrityIssue::SubtreeHashMismatch {
+                    path: parent_path.to_vec(),
+                    key: parent_key[0].clone(),
+                });
+            }
+            match &merk {
+                SubtreeMerk::Blake3(merk) => {
+                    self.verify_subtree_nodes(merk, &path, &mut queue, &mut issues)?
+                }
+                SubtreeMerk::Sha256(merk) => {
+                    self.verify_subtree_nodes(merk, &path, &mut queue, &mut issues)?
+                }
+            }
+        }
+        Ok(issues)
+    }
+
+    /// Opens the Merk of the subtree under `path` with the hash function
+    /// recorded in its metadata, returns `None` if the hash function is unknown
+    fn open_subtree_with_recorded_hasher(
+        &self,
+        path: &[Vec<u8>],
+    ) -> Result<Option<SubtreeMerk<S>>, Error> {
+        let prefix = compress_subtree_key(path.iter().map(|x| x.as_slice()), None);
+        let storage = self
+            .meta_storage
+            .with_prefix(prefix)
+            .map_err(Error::storage)?;
+        let not_found = |_| Error::PathNotFound("no subtree found under that path");
+        let merk = match storage.get_root(HASHER_KEY).map_err(Error::storage)? {
+            None => SubtreeMerk::Blake3(Merk::open(storage).map_err(not_found)?),
+            Some(name) if name == Blake3Hasher::NAME => {
+                SubtreeMerk::Blake3(Merk::open(storage).map_err(not_found)?)
+            }
+            Some(name) if name == Sha256Hasher::NAME => SubtreeMerk::Sha256(
+                Merk::open_with_hasher::<Sha256Hasher>(storage).map_err(not_found)?,
+            ),
+            Some(_) => return Ok(None),
+        };
+        Ok(Some(merk))
+    }
+
+    /// Verifies nodes of a single subtree hashed with `H`, queueing subtrees
+    /// its elements hold
+    fn verify_subtree_nodes<H: Hasher>(
+        &self,
+        merk: &Merk<S, H>,
+        path: &[Vec<u8>],
+        queue: &mut Vec<(Vec<Vec<u8>>, Option<Hash>)>,
+        issues: &mut Vec<IntegrityIssue>,
+    ) -> Result<(), Error> {
+        let mut stored_keys = Vec::new();
+        let mut linked_keys = HashSet::new();
+        let mut iter = merk.raw_iter(None);
+        iter.seek_to_first();
+        while iter.valid() {
+            let (key, value) = match iter.key().zip(iter.value()) {
+                Some((key, value)) => (key.to_vec(), value),
+                None => break,
+            };
+            let corrupted = || IntegrityIssue::CorruptedNode {
+                path: path.to_vec(),
+                key: key.clone(),
+            };
+            let tree = match <Tree as Store>::decode(value) {
+                Ok(tree) => tree,
+                Err(_) => {
+                    issues.push(corrupted());
+                    stored_keys.push(key);
+                    iter.next();
+                    continue;
+                }
+            };
+
+            if H::kv_digest_to_kv_hash(&key, tree.value_hash()) != *tree.kv_hash() {
+                issues.push(IntegrityIssue::InvalidNodeHash {
+                    path: path.to_vec(),
+                    key: key.clone(),
+                });
+            }
+            for left in [true, false] {
+                if let Some(link) = tree.link(left) {
+                    linked_keys.insert(link.key().to_vec());
+                    let child = merk.storage.get(link.key()).map_err(Error::storage)?;
+                    let child_hash = child
+                        .and_then(|bytes| <Tree as Store>::decode(&bytes).ok())
+                        .map(|child| child.hash_by::<H>());
+                    if child_hash.as_ref() != Some(link.hash()) {
+                        issues.push(IntegrityIssue::InvalidLink {
+                            path: path.to_vec(),
+                            key: key.clone(),
+                            child_key: link.key().to_vec(),
+                        });
+                    }
+                }
+            }
+
+            match bincode::deserialize(tree.value()) {
+                Ok(element) => {
+                    let binding = match &element {
+                        Element::Item(_) => ValueBinding::Plain,
+                        Element::Tree(root_hash) => {
+                            let mut subtree_path = path.to_vec();
+                            subtree_path.push(key.clone());
+                            queue.push((subtree_path, Some(*root_hash)));
+                            ValueBinding::Layered(*root_hash)
+                        }
+                        Element::Reference(reference_path) => {
+                            match self.follow_reference(reference_path.to_vec(), None) {
+                                Ok(_) => {}
+                                Err(Error::PathKeyNotFound(_))
+                                | Err(Error::PathNotFound(_))
+                                | Err(Error::InvalidPath(_))
+                                | Err(Error::CorruptedPath(_))
+                                | Err(Error::CyclicReference)
+                                | Err(Error::ReferenceLimit) => {
+                                    issues.push(IntegrityIssue::DanglingReference {
+                                        path: path.to_vec(),
+                                        key: key.clone(),
+                                        reference_path: reference_path.to_vec(),
+                                    })
+                                }
+                                Err(e) => return Err(e),
+                            }
+                            ValueBinding::Reference(
+                                self.referenced_value_hash(reference_path, None)?,
+                            )
+                        }
+                    };
+                    if *tree.value_binding() != binding
+                        || binding.value_hash::<H>(tree.value()) != *tree.value_hash()
+                    {
+                        issues.push(IntegrityIssue::InvalidValueHash {
+                            path: path.to_vec(),
+                            key: key.clone(),
+                        });
+                    }
+                }
+                Err(_) => issues.push(corrupted()),
+            }
+            stored_keys.push(key);
+            iter.next();
+        }
+
+        let root_key = merk.root_key();
+        for key in stored_keys {
+            if !linked_keys.contains(&key) && root_key.as_ref() != Some(&key) {
+                issues.push(IntegrityIssue::UnreachableNode {
+                    path: path.to_vec(),
+                    key,
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,12 @@
-use storage::rocksdb_storage::OptimisticTransactionDBTransaction;
+use storage::PrefixedStorage;
 
 use crate::{Error, GroveDb};
 
-impl GroveDb {
+impl<S: PrefixedStorage> GroveDb<S> {
     pub fn is_empty_tree<'a, P>(
         &self,
         path: P,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<bool, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
@@ -15,6 +15,6 @@ impl GroveDb {
         Ok(self
             .get_subtrees()
             .borrow_mut(path, transaction)?
-            .apply(|s| s.is_empty_tree(transaction)))
+            .apply(|s| s.is_empty_tree(transaction.map(S::shorten_db_transaction))))
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+
+use merk::{proofs::query::QueryItem, Iter};
+use storage::PrefixedStorage;
+
+use crate::{Element, Error, GroveDb};
+
+/// Number of elements read from storage at once by `ElementIter`
+const ITER_PAGE_SIZE: usize = 256;
+
+/// Iterator over elements of a subtree within a range of keys, in either
+/// direction. Created with `GroveDb::iter`.
+///
+/// Elements are read from storage in pages, each page continues right after
+/// the last yielded key. Without a transaction, changes made between pages may
+/// be visible to the iteration.
+pub struct ElementIter<'db, S: PrefixedStorage> {
+    storage: S,
+    transaction: Option<&'db S::DBTransaction<'db>>,
+    range: QueryItem,
+    left_to_right: bool,
+    page: VecDeque<(Vec<u8>, Element)>,
+    /// Key of the last yielded element
+    last_key: Option<Vec<u8>>,
+    /// Key to read the next page from instead of the one after `last_key`
+    seek_key: Option<Vec<u8>>,
+    exhausted: bool,
+}
+
+impl<'db, S: PrefixedStorage> ElementIter<'db, S> {
+    /// Positions the iterator at `key`, or at the next key of the range in the
+    /// iteration direction if there is no such key.
+    pub fn seek(&mut self, key: &[u8]) {
+        self.page.clear();
+        self.seek_key = Some(key.to_vec());
+        self.exhausted = false;
+    }
+
+    /// Positions the iterator at the key following `key` in the iteration
+    /// direction, to resume an iteration which yielded `key` last, possibly
+    /// by another `ElementIter`.
+    pub fn resume_after(&mut self, key: &[u8]) {
+        self.page.clear();
+        self.seek_key = None;
+        self.last_key = Some(key.to_vec());
+        self.exhausted = false;
+    }
+
+    fn read_page(&mut self) -> Result<(), Error> {
+        let mut iter = Iter::new(
+            self.storage
+                .raw_iter(self.transaction.map(S::shorten_db_transaction)),
+            self.range.clone(),
+            self.left_to_right,
+        );
+        if let Some(key) = self.seek_key.take() {
+            iter.seek(&key);
+        } else if let Some(key) = &self.last_key {
+            iter.resume_after(key);
+        }
+        for entry in iter.by_ref().take(ITER_PAGE_SIZE) {
+            let (key, value) = entry.map_err(|e| Error::CorruptedData(e.to_string()))?;
+            let element = bincode::deserialize(&value)
+                .map_err(|_| Error::CorruptedData(String::from("unable to deserialize element")))?;
+            self.page.push_back((key, element));
+        }
+        self.exhausted = self.page.len() < ITER_PAGE_SIZE;
+        Ok(())
+    }
+}
+
+impl<'db, S: PrefixedStorage> Iterator for ElementIter<'db, S> {
+    type Item = Result<(Vec<u8>, Element), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.exhausted {
+            if let Err(e) = self.read_page() {
+                self.exhausted = true;
+                return Some(Err(e));
+            }
+        }
+        let (key, element) = self.page.pop_front()?;
+        self.last_key = Some(key.clone());
+        Some(Ok((key, element)))
+    }
+}
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Iterates over elements of the subtree under `path` within `range` in
+    /// the given direction. References are not followed. Works inside of
+    /// `transaction` if one is given.
+    pub fn iter<'a, 'db, 'tx: 'db, P>(
+        &self,
+        path: P,
+        range: QueryItem,
+        left_to_right: bool,
+        transaction: Option<&'db S::DBTransaction<'tx>>,
+    ) -> Result<ElementIter<'db, S>, Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        <P as IntoIterator>::IntoIter: ExactSizeIterator + DoubleEndedIterator + Clone,
+    {
+        let path_iter = path.into_iter();
+        if path_iter.len() == 0 {
+            return Err(Error::InvalidPath("the root tree can not be iterated"));
+        }
+        // TODO: no better way until storage refactoring
+        let storage = self
+            .get_subtrees()
+            .borrow_mut(path_iter, transaction)?
+            .apply(|s| s.storage.clone());
+        Ok(ElementIter {
+            storage,
+            transaction: transaction.map(S::shorten_db_transaction),
+            range,
+            left_to_right,
+            page: VecDeque::new(),
+            last_key: None,
+            seek_key: None,
+            exhausted: false,
+        })
+    }
+}
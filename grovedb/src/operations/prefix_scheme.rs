@@ -0,0 +1,211 @@
+use merk::{HASHER_KEY, ROOT_KEY_KEY};
+use storage::{PrefixedStorage, RawIterator};
+
+use super::{references::REFERENCES_KEY_PREFIX, subtree_index::CHILDREN_KEY_PREFIX};
+use crate::{compress_subtree_key, compress_subtree_key_legacy, Element, Error, GroveDb, Merk};
+
+/// Version of the scheme subtree prefixes are derived with by
+/// `compress_subtree_key`; databases created before versions were introduced
+/// have version 0
+pub const PREFIX_SCHEME_VERSION: u32 = 1;
+/// A meta storage key to store the prefix scheme version under
+pub(crate) const PREFIX_SCHEME_VERSION_KEY: &[u8] = b"prefixSchemeVersion";
+
+/// Derives a subtree prefix from its path
+pub(crate) type PrefixScheme = fn(&[Vec<u8>]) -> Vec<u8>;
+
+pub(crate) fn current_prefix(path: &[Vec<u8>]) -> Vec<u8> {
+    compress_subtree_key(path.iter().map(|x| x.as_slice()), None)
+}
+
+fn legacy_prefix(path: &[Vec<u8>]) -> Vec<u8> {
+    compress_subtree_key_legacy(path.iter().map(|x| x.as_slice()), None)
+}
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Returns version of the scheme prefixes of stored subtrees are derived
+    /// with, an empty database has no subtrees and is up to date
+    pub(crate) fn stored_prefix_scheme_version(&self) -> Result<u32, Error> {
+        let version = match self
+            .meta_storage
+            .get_meta(PREFIX_SCHEME_VERSION_KEY)
+            .map_err(Error::storage)?
+        {
+            Some(bytes) => u32::from_le_bytes(bytes.as_slice().try_into().map_err(|_| {
+                Error::CorruptedData(String::from("unable to deserialize prefix scheme version"))
+            })?),
+            None if self.root_leaf_keys.is_empty() => PREFIX_SCHEME_VERSION,
+            None => 0,
+        };
+        if version > PREFIX_SCHEME_VERSION {
+            return Err(Error::UnsupportedPrefixScheme(version));
+        }
+        Ok(version)
+    }
+
+    /// Checks that subtrees can be read without migrating their prefixes,
+    /// which is the case on platforms legacy prefixes match current ones on
+    pub(crate) fn check_prefix_scheme(&self) -> Result<(), Error> {
+        let path = [b"key".to_vec()];
+        let legacy_prefixes_match = legacy_prefix(&path) == current_prefix(&path);
+        if self.stored_prefix_scheme_version()? < PREFIX_SCHEME_VERSION && !legacy_prefixes_match {
+            return Err(Error::PrefixMigrationRequired);
+        }
+        Ok(())
+    }
+
+    /// Moves data of subtrees stored under prefixes of an older scheme to
+    /// their current prefixes and records the scheme version.
+    ///
+    /// Each subtree is moved together with its index entries at once, so an
+    /// interrupted migration continues from where it stopped on the next
+    /// open: subtrees which have no data under the legacy prefix are already
+    /// moved and read from the current one.
+    pub(crate) fn migrate_prefix_scheme(&mut self) -> Result<(), Error> {
+        if self.stored_prefix_scheme_version()? == PREFIX_SCHEME_VERSION {
+            if self
+                .meta_storage
+                .get_meta(PREFIX_SCHEME_VERSION_KEY)
+                .map_err(Error::storage)?
+                .is_none()
+            {
+                self.meta_storage
+                    .put_meta(
+                        PREFIX_SCHEME_VERSION_KEY,
+                        &PREFIX_SCHEME_VERSION.to_le_bytes(),
+                    )
+                    .map_err(Error::storage)?;
+            }
+            return Ok(());
+        }
+
+        self.move_prefixes(legacy_prefix, current_prefix)?;
+        self.meta_storage
+            .put_meta(
+                PREFIX_SCHEME_VERSION_KEY,
+                &PREFIX_SCHEME_VERSION.to_le_bytes(),
+            )
+            .map_err(Error::storage)?;
+        self.root_tree = Self::build_root_tree(&self.get_subtrees(), &self.root_leaf_keys, None);
+        Ok(())
+    }
+
+    /// Moves data of all subtrees and their index entries from prefixes
+    /// derived by scheme `from` to ones derived by scheme `to`
+    pub(crate) fn move_prefixes(
+        &mut self,
+        from: PrefixScheme,
+        to: PrefixScheme,
+    ) -> Result<(), Error> {
+        let mut queue: Vec<Vec<Vec<u8>>> = self
+            .root_leaf_keys
+            .keys()
+            .map(|key| vec![key.clone()])
+            .collect();
+        while let Some(path) = queue.pop() {
+            let child_paths =
+                self.with_shared_batch(|db| db.move_subtree_prefix(&path, from, to))?;
+            queue.extend(child_paths);
+        }
+        self.merk_cache.get_mut().clear();
+        Ok(())
+    }
+
+    /// Moves a subtree under `path` to the prefix derived by scheme `to` and
+    /// returns paths of its child subtrees
+    fn move_subtree_prefix(
+        &self,
+        path: &[Vec<u8>],
+        from: PrefixScheme,
+        to: PrefixScheme,
+    ) -> Result<Vec<Vec<Vec<u8>>>, Error> {
+        let old_prefix = from(path);
+        let new_prefix = to(path);
+        let old_storage = self
+            .meta_storage
+            .with_prefix(old_prefix.clone())
+            .map_err(Error::storage)?;
+        let new_storage = self
+            .meta_storage
+            .with_prefix(new_prefix.clone())
+            .map_err(Error::storage)?;
+        let old_root_key = old_storage.get_root(ROOT_KEY_KEY).map_err(Error::storage)?;
+        let moving = old_prefix != new_prefix && old_root_key.is_some();
+
+        let source = if moving { old_prefix } else { new_prefix };
+        let merk = Merk::open(
+            self.meta_storage
+                .with_prefix(source)
+                .map_err(Error::storage)?,
+        )
+        .map_err(|e| Error::CorruptedData(e.to_string()))?;
+        let mut child_paths = Vec::new();
+        let mut elements = Element::iterator(merk.raw_iter(None));
+        while let Some((key, element)) = elements.next()? {
+            match element {
+                Element::Tree(_) => {
+                    let mut child_path = path.to_vec();
+                    child_path.push(key);
+                    child_paths.push(child_path);
+                }
+                Element::Reference(reference_path) if moving => {
+                    self.move_meta_entry(REFERENCES_KEY_PREFIX, &reference_path, from, to)?
+                }
+                _ => {}
+            }
+        }
+        if !moving {
+            return Ok(child_paths);
+        }
+
+        // GroveDB stores no auxiliary data under subtree prefixes, so Merk nodes and
+        // the root metadata are all there is to move
+        let mut iter = merk.raw_iter(None);
+        iter.seek_to_first();
+        while let Some((key, value)) = iter.key().zip(iter.value()) {
+            new_storage.put(key, value).map_err(Error::storage)?;
+            iter.next();
+        }
+        if let Some(root_key) = old_root_key {
+            new_storage
+                .put_root(ROOT_KEY_KEY, &root_key)
+                .map_err(Error::storage)?;
+        }
+        if let Some(hasher) = old_storage.get_root(HASHER_KEY).map_err(Error::storage)? {
+            new_storage
+                .put_root(HASHER_KEY, &hasher)
+                .map_err(Error::storage)?;
+        }
+        old_storage.clear().map_err(Error::storage)?;
+        self.move_meta_entry(CHILDREN_KEY_PREFIX, path, from, to)?;
+        Ok(child_paths)
+    }
+
+    /// Moves a meta storage entry keyed by `key_prefix` and a compressed
+    /// `path` from the key derived by scheme `from` to one derived by `to`
+    fn move_meta_entry(
+        &self,
+        key_prefix: &[u8],
+        path: &[Vec<u8>],
+        from: PrefixScheme,
+        to: PrefixScheme,
+    ) -> Result<(), Error> {
+        let mut old_key = key_prefix.to_vec();
+        old_key.extend(from(path));
+        if let Some(value) = self
+            .meta_storage
+            .get_meta(&old_key)
+            .map_err(Error::storage)?
+        {
+            let mut new_key = key_prefix.to_vec();
+            new_key.extend(to(path));
+            self.meta_storage
+                .put_meta(new_key, &value)
+                .map_err(Error::storage)?;
+            self.meta_storage
+                .delete_meta(old_key)
+                .map_err(Error::storage)?;
+        }
+        Ok(())
+    }
+}
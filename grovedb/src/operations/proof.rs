@@ -1,261 +1,541 @@
-// use std::collections::HashMap;
-//
-// use merk::proofs::query::Map;
-// use rs_merkle::{algorithms::Sha256, MerkleProof};
-//
-// use crate::{Element, Error, GroveDb, PathQuery, Proof, Query};
-//
-// impl GroveDb {
-//     pub fn proof(&mut self, proof_queries: Vec<PathQuery>) -> Result<Vec<u8>,
-// Error> {         // To prove a path we need to return a proof for each node
-// on the path including         // the root. With multiple paths, nodes can
-// overlap i.e two or more paths can         // share the same nodes. We should
-// only have one proof for each node,         // if a node forks into multiple
-// relevant paths then we should create a         // combined proof for that
-// node with all the relevant keys         let mut query_paths = Vec::new();
-//         let mut intermediate_proof_spec: HashMap<Vec<u8>, Query> =
-// HashMap::new();         let mut root_keys: Vec<Vec<u8>> = Vec::new();
-//         let mut proofs: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
-//
-//         // For each unique node including the root
-//         // determine what keys would need to be included in the proof
-//         for proof_query in proof_queries.iter() {
-//             query_paths.push(
-//                 proof_query
-//                     .path
-//                     .iter()
-//                     .map(|x| x.to_vec())
-//                     .collect::<Vec<_>>(),
-//             );
-//
-//             let mut split_path = proof_query.path.split_last();
-//             while let Some((key, path_slice)) = split_path {
-//                 if path_slice.is_empty() {
-//                     // We have gotten to the root node
-//                     let compressed_path = GroveDb::compress_subtree_key(&[],
-// Some(key));                     root_keys.push(compressed_path);
-//                 } else {
-//                     let compressed_path =
-// GroveDb::compress_subtree_key(path_slice, None);                     if let
-// Some(path_query) = intermediate_proof_spec.get_mut(&compressed_path) {
-//                         path_query.insert_key(key.to_vec());
-//                     } else {
-//                         let mut path_query = Query::new();
-//                         path_query.insert_key(key.to_vec());
-//                         intermediate_proof_spec.insert(compressed_path,
-// path_query);                     }
-//                 }
-//                 split_path = path_slice.split_last();
-//             }
-//         }
-//
-//         // Construct the path proofs
-//         for (path, query) in intermediate_proof_spec {
-//             let proof = self.prove_item(&path, query)?;
-//             proofs.insert(path, proof);
-//         }
-//
-//         // Construct the leaf proofs
-//         for proof_query in proof_queries {
-//             let path = proof_query.path;
-//
-//             // If there is a subquery with a limit it's possible that we only
-// need a reduced             // proof for this leaf.
-//             let reduced_proof_query = proof_query;
-//
-//             // First we must get elements
-//
-//             if let Some(subquery_key) =
-// reduced_proof_query.query.query.subquery_key.clone() {
-// self.get_path_queries_raw(&[&reduced_proof_query], None)?;
-//
-//                 let mut path_vec = path.to_vec();
-//                 path_vec.push(subquery_key.as_slice());
-//                 let compressed_path =
-// GroveDb::compress_subtree_key(path_vec.as_slice(), None);             }
-//
-//             // Now we must insert the final proof for the sub leaves
-//             let compressed_path = GroveDb::compress_subtree_key(path, None);
-//             let proof = self.prove_path_item(&compressed_path,
-// reduced_proof_query)?;             proofs.insert(compressed_path, proof);
-//         }
-//
-//         // Construct the root proof
-//         let mut root_index: Vec<usize> = Vec::new();
-//         for key in root_keys {
-//             let index = self
-//                 .root_leaf_keys
-//                 .get(&key)
-//                 .ok_or(Error::InvalidPath("root key not found"))?;
-//             root_index.push(*index);
-//         }
-//         let root_proof = self.root_tree.proof(&root_index).to_bytes();
-//
-//         let proof = Proof {
-//             query_paths,
-//             proofs,
-//             root_proof,
-//             root_leaf_keys: self.root_leaf_keys.clone(),
-//         };
-//
-//         let serialized_proof = bincode::serialize(&proof)
-//             .map_err(|_| Error::CorruptedData(String::from("unable to
-// serialize proof")))?;
-//
-//         Ok(serialized_proof)
-//     }
-//
-//     fn prove_path_item(
-//         &self,
-//         compressed_path: &[u8],
-//         path_query: PathQuery,
-//     ) -> Result<Vec<u8>, Error> {
-//         let merk = self
-//             .subtrees
-//             .get(compressed_path)
-//             .ok_or(Error::InvalidPath("no subtree found under that path"))?;
-//
-//         let sized_query = path_query.query;
-//
-//         if sized_query.query.subquery.is_none() {
-//             // then limit should be applied directly to the proof here
-//             let proof_result = merk
-//                 .prove(
-//                     sized_query.query,
-//                     sized_query.limit,
-//                     sized_query.offset,
-//                 )
-//                 .expect("should prove both inclusion and absence");
-//             Ok(proof_result)
-//         } else {
-//             let proof_result = merk
-//                 .prove(sized_query.query, None, None)
-//                 .expect("should prove both inclusion and absence");
-//             Ok(proof_result)
-//         }
-//     }
-//
-//     fn prove_item(&self, path: &[u8], query: Query) -> Result<Vec<u8>,
-// Error> {         let merk = self
-//             .subtrees
-//             .get(path)
-//             .ok_or(Error::InvalidPath("no subtree found under that path"))?;
-//
-//         // then limit should be applied directly to the proof here
-//         let proof_result = merk
-//             .prove(query, None, None)
-//             .expect("should prove both inclusion and absence");
-//         Ok(proof_result)
-//     }
-//
-//     pub fn execute_proof(proof: Vec<u8>) -> Result<([u8; 32],
-// HashMap<Vec<u8>, Map>), Error> {         // Deserialize the proof
-//         let proof: Proof = bincode::deserialize(&proof)
-//             .map_err(|_| Error::CorruptedData(String::from("unable to
-// deserialize proof")))?;
-//
-//         // Required to execute the root proof
-//         let mut root_keys_index: Vec<usize> = Vec::new();
-//         let mut root_hashes: Vec<[u8; 32]> = Vec::new();
-//
-//         // Collects the result map for each query
-//         let mut result_map: HashMap<Vec<u8>, Map> = HashMap::new();
-//
-//         for path in proof.query_paths {
-//             let path = path.iter().map(|x| x.as_slice()).collect::<Vec<_>>();
-//             // For each query path, get the result map after execution
-//             // and store hash + index for later root proof execution
-//             let root_key = &path[0];
-//             let (hash, proof_result_map) = GroveDb::execute_path(&path,
-// &proof.proofs)?;             let compressed_root_key_path =
-// GroveDb::compress_subtree_key(&[], Some(root_key));             let
-// compressed_query_path = GroveDb::compress_subtree_key(&path, None);
-//
-//             let index = proof
-//                 .root_leaf_keys
-//                 .get(&compressed_root_key_path)
-//                 .ok_or(Error::InvalidPath("Bad path"))?;
-//             if !root_keys_index.contains(index) {
-//                 root_keys_index.push(*index);
-//                 root_hashes.push(hash);
-//             }
-//
-//             result_map.insert(compressed_query_path, proof_result_map);
-//         }
-//
-//         let root_proof = match
-// MerkleProof::<Sha256>::try_from(proof.root_proof) {             Ok(proof) =>
-// Ok(proof),             Err(_) => Err(Error::InvalidProof("Invalid proof
-// element")),         }?;
-//
-//         let root_hash =
-//             match root_proof.root(&root_keys_index, &root_hashes,
-// proof.root_leaf_keys.len()) {                 Ok(hash) => Ok(hash),
-//                 Err(_) => Err(Error::InvalidProof("Invalid proof element")),
-//             }?;
-//
-//         Ok((root_hash, result_map))
-//     }
-//
-//     // Given a query path and a set of proofs
-//     // execute_path validates that the nodes represented by the paths
-//     // are connected to one another i.e root hash of child node is in parent
-// node     // at the correct key.
-//     // If path is valid, it returns the root hash of topmost merk and result
-// map of     // leaf merk.
-//     fn execute_path(
-//         path: &[&[u8]],
-//         proofs: &HashMap<Vec<u8>, Vec<u8>>,
-//     ) -> Result<([u8; 32], Map), Error> {
-//         let compressed_path = GroveDb::compress_subtree_key(path, None);
-//         let proof = proofs
-//             .get(&compressed_path)
-//             .ok_or(Error::InvalidPath("Bad path"))?;
-//
-//         // Execute the leaf merk proof
-//         let (mut last_root_hash, result_map) = match
-// merk::execute_proof(&proof[..]) {             Ok(result) => Ok(result),
-//             Err(_) => Err(Error::InvalidPath("Invalid proof element")),
-//         }?;
-//
-//         // Validate the path
-//         let mut split_path = path.split_last();
-//         while let Some((key, path_slice)) = split_path {
-//             if !path_slice.is_empty() {
-//                 let compressed_path =
-// GroveDb::compress_subtree_key(path_slice, None);                 let proof =
-// proofs                     .get(&compressed_path)
-//                     .ok_or(Error::InvalidPath("Bad path"))?;
-//
-//                 let proof_result = match merk::execute_proof(&proof[..]) {
-//                     Ok(result) => Ok(result),
-//                     Err(_) => Err(Error::InvalidPath("Invalid proof
-// element")),                 }?;
-//
-//                 let result_map = proof_result.1;
-//                 // TODO: Handle the error better here
-//                 let elem: Element =
-//
-// bincode::deserialize(result_map.get(key).unwrap().unwrap()).unwrap();
-//                 let merk_root_hash = match elem {
-//                     Element::Tree(hash) => Ok(hash),
-//                     _ => Err(Error::InvalidProof(
-//                         "Intermediate proofs should be for trees",
-//                     )),
-//                 }?;
-//
-//                 if merk_root_hash != last_root_hash {
-//                     return Err(Error::InvalidProof("Bad path"));
-//                 }
-//
-//                 last_root_hash = proof_result.0;
-//             } else {
-//                 break;
-//             }
-//
-//             split_path = path_slice.split_last();
-//         }
-//
-//         Ok((last_root_hash, result_map))
-//     }
-// }
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use merk::{
+    proofs::query::{Map, MapBuilder, QueryItem},
+    tree::{Blake3Hasher, Hash, Hasher, Sha256Hasher, ValueBinding, NULL_HASH},
+    Merk,
+};
+use rs_merkle::{algorithms::Sha256, MerkleProof};
+use storage::PrefixedStorage;
+
+use super::get::MAX_REFERENCE_HOPS;
+use crate::{
+    Element, Error, GroveDb, LayerProof, PathQuery, Proof, ProofDecoder, Query, RootLayerProof,
+    Subtrees,
+};
+
+/// Verified result of a single query path of a proof
+#[derive(Debug)]
+pub enum PathProofResult {
+    /// The subtree exists, `results` holds verified results of its query and
+    /// `references` holds the elements that references among them resolve to.
+    /// References to proven absent elements are not in `references`.
+    Found {
+        results: Map,
+        references: BTreeMap<Vec<u8>, Element>,
+    },
+    /// The subtree doesn't exist: `path[..existing_depth]` is the deepest
+    /// existing subtree on the path and it was proven to have no subtree under
+    /// `path[existing_depth]`
+    PathNotFound { existing_depth: usize },
+}
+
+/// Verified results of a proof by query path
+pub type PathProofResults = BTreeMap<Vec<Vec<u8>>, PathProofResult>;
+
+type LayerQueries = BTreeMap<Vec<Vec<u8>>, Query>;
+
+/// Proves the query merged from all path queries that go through or end at
+/// the subtree, an empty subtree gets an empty proof
+fn prove_layer<S: PrefixedStorage, H: Hasher>(
+    merk: &Merk<S, H>,
+    query: Query,
+) -> Result<LayerProof, Error> {
+    let proof = if merk.is_empty_tree(None) {
+        Vec::new()
+    } else {
+        merk.prove(query, None, None)
+            .map_err(|e| Error::CorruptedData(e.to_string()))?
+    };
+    Ok(LayerProof {
+        hasher: H::NAME.to_vec(),
+        proof,
+    })
+}
+
+fn deserialize_element(bytes: &[u8]) -> Result<Element, Error> {
+    bincode::deserialize(bytes)
+        .map_err(|_| Error::CorruptedData(String::from("unable to deserialize element")))
+}
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Generates a proof for the path queries over the committed state. See
+    /// [`Proof`](crate::Proof) for the encoding.
+    ///
+    /// Every subtree on the way from the root to a queried subtree gets a
+    /// single Merk proof for all the keys the path queries need from it.
+    /// Subqueries, limits and offsets are not supported yet.
+    ///
+    /// If a queried subtree doesn't exist the proof goes down to the deepest
+    /// existing subtree on its path, which proves the next path segment to be
+    /// absent or to not be a subtree. Root leafs are not keyed in the root
+    /// tree so a missing root leaf cannot be proven and is an error.
+    ///
+    /// References among the proven elements of queried subtrees are followed
+    /// up to [`MAX_REFERENCE_HOPS`] and every hop target is proven as well.
+    pub fn proof(&self, path_queries: &[&PathQuery]) -> Result<Vec<u8>, Error> {
+        if path_queries.is_empty() {
+            return Err(Error::InvalidQuery("no path queries to prove"));
+        }
+
+        let mut query_paths = Vec::with_capacity(path_queries.len());
+        let mut root_keys = BTreeSet::new();
+        let mut layer_queries = LayerQueries::new();
+        let subtrees = self.get_subtrees();
+
+        for path_query in path_queries {
+            let query = &path_query.query.query;
+            if query.subquery.is_some() || query.subquery_key.is_some() {
+                return Err(Error::InvalidQuery(
+                    "subqueries are not supported by proofs",
+                ));
+            }
+            if path_query.query.limit.is_some() || path_query.query.offset.is_some() {
+                return Err(Error::InvalidQuery(
+                    "limit and offset are not supported by proofs",
+                ));
+            }
+            let path = &path_query.path;
+            if path.is_empty() {
+                return Err(Error::InvalidPath("path query for a proof cannot be empty"));
+            }
+            self.insert_layer_queries(
+                &subtrees,
+                &mut layer_queries,
+                &mut root_keys,
+                path,
+                query.iter().cloned(),
+            )?;
+            query_paths.push(path.clone());
+        }
+
+        // Proving reference targets extends layer queries, which may bring more
+        // references into the proven elements of queried subtrees
+        let mut followed_references = BTreeSet::new();
+        let layers = loop {
+            let mut layers = BTreeMap::new();
+            for (path, layer_query) in layer_queries.iter() {
+                let layer_proof = subtrees
+                    .borrow_mut(path.iter().map(|x| x.as_slice()), None)?
+                    .apply(|merk| prove_layer(merk, layer_query.clone()))?;
+                layers.insert(path.clone(), layer_proof);
+            }
+
+            let mut new_references = Vec::new();
+            for layer_proof in query_paths.iter().filter_map(|path| layers.get(path)) {
+                let (_, map) = GroveDb::execute_layer_proof(layer_proof)?;
+                for (_, (_, value)) in map.all() {
+                    if let Element::Reference(reference_path) = deserialize_element(value)? {
+                        if followed_references.insert(reference_path.clone()) {
+                            new_references.push(reference_path);
+                        }
+                    }
+                }
+            }
+            if new_references.is_empty() {
+                break layers;
+            }
+            for reference_path in new_references {
+                self.insert_reference_layer_queries(
+                    &subtrees,
+                    &mut layer_queries,
+                    &mut root_keys,
+                    reference_path,
+                )?;
+            }
+        };
+
+        let mut root_indices = root_keys
+            .iter()
+            .map(|key| {
+                self.root_leaf_keys
+                    .get(key)
+                    .copied()
+                    .ok_or(Error::PathNotFound("root key not found"))
+            })
+            .collect::<Result<Vec<usize>, Error>>()?;
+        root_indices.sort_unstable();
+
+        Proof {
+            query_paths,
+            root_layer: RootLayerProof {
+                root_leaf_keys: self.root_leaf_keys.clone(),
+                proof: self.root_tree.proof(&root_indices).to_bytes(),
+            },
+            layers,
+        }
+        .encode()
+    }
+
+    /// Adds queries for all layers needed to prove the items in the subtree
+    /// at the path, or to prove the subtree absence. Returns whether the
+    /// subtree exists.
+    fn insert_layer_queries(
+        &self,
+        subtrees: &Subtrees<S>,
+        layer_queries: &mut LayerQueries,
+        root_keys: &mut BTreeSet<Vec<u8>>,
+        path: &[Vec<u8>],
+        items: impl Iterator<Item = QueryItem>,
+    ) -> Result<bool, Error> {
+        if !self.root_leaf_keys.contains_key(&path[0]) {
+            return Err(Error::PathNotFound("root key not found"));
+        }
+        root_keys.insert(path[0].clone());
+
+        let existing_depth = Self::existing_path_depth(subtrees, path)?;
+        for depth in 1..=existing_depth.min(path.len() - 1) {
+            layer_queries
+                .entry(path[..depth].to_vec())
+                .or_default()
+                .insert_item(QueryItem::Key(path[depth].clone()));
+        }
+        if existing_depth < path.len() {
+            return Ok(false);
+        }
+        let layer_query = layer_queries.entry(path.to_vec()).or_default();
+        for item in items {
+            layer_query.insert_item(item);
+        }
+        Ok(true)
+    }
+
+    /// Adds layer queries for every hop of the reference, hops are followed the
+    /// same way `get` does
+    fn insert_reference_layer_queries(
+        &self,
+        subtrees: &Subtrees<S>,
+        layer_queries: &mut LayerQueries,
+        root_keys: &mut BTreeSet<Vec<u8>>,
+        mut path: Vec<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let mut hops_left = MAX_REFERENCE_HOPS;
+        let mut visited = HashSet::new();
+
+        while hops_left > 0 {
+            if visited.contains(&path) {
+                return Err(Error::CyclicReference);
+            }
+            let (key, subtree_path) = path
+                .split_last()
+                .ok_or(Error::CorruptedPath("empty path"))?;
+            if subtree_path.is_empty() {
+                return Err(Error::InvalidQuery(
+                    "references to root leafs are not supported by proofs",
+                ));
+            }
+            let subtree_exists = self.insert_layer_queries(
+                subtrees,
+                layer_queries,
+                root_keys,
+                subtree_path,
+                std::iter::once(QueryItem::Key(key.clone())),
+            )?;
+            if !subtree_exists {
+                return Ok(());
+            }
+            let element = subtrees
+                .borrow_mut(subtree_path.iter().map(|x| x.as_slice()), None)?
+                .apply(|merk| Element::get(merk, key));
+            visited.insert(path);
+            match element {
+                Ok(Element::Reference(reference_path)) => path = reference_path,
+                Ok(_) | Err(Error::PathKeyNotFound(_)) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            hops_left -= 1;
+        }
+        Err(Error::ReferenceLimit)
+    }
+
+    /// Returns how many segments of the path lead to existing subtrees, the
+    /// root key is expected to exist
+    fn existing_path_depth(subtrees: &Subtrees<S>, path: &[Vec<u8>]) -> Result<usize, Error> {
+        for depth in 1..path.len() {
+            let element = subtrees
+                .borrow_mut(path[..depth].iter().map(|x| x.as_slice()), None)?
+                .apply(|merk| Element::get(merk, &path[depth]));
+            match element {
+                Ok(Element::Tree(_)) => {}
+                Ok(_) | Err(Error::PathKeyNotFound(_)) => return Ok(depth),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(path.len())
+    }
+}
+
+// Verification doesn't touch the storage, it's defined for the default
+// backend only so it can be called as `GroveDb::execute_proof`
+impl GroveDb {
+    /// Executes an encoded proof, returning the GroveDb root hash it commits
+    /// to and the verified result of every query path.
+    ///
+    /// Layers are checked as they are decoded: each subtree root hash must
+    /// match the tree element stored under its key in the parent layer and be
+    /// bound to that node's layered value hash, and top level subtrees are
+    /// chained to the root hash through the root layer. References in query
+    /// results are resolved the way `get_path_query` does, failing on
+    /// cycles and on too many hops. The caller is expected to compare the
+    /// returned root hash with the one it trusts.
+    ///
+    /// Every layer is verified with the hash function its subtree is hashed
+    /// with, as named by the layer.
+    pub fn execute_proof(proof: &[u8]) -> Result<([u8; 32], PathProofResults), Error> {
+        let mut decoder = ProofDecoder::new(proof)?;
+        if decoder.query_paths().is_empty() {
+            return Err(Error::InvalidProof("proof has no query paths"));
+        }
+        let query_paths: BTreeSet<Vec<Vec<u8>>> = decoder.query_paths().iter().cloned().collect();
+
+        // Results of every layer executed so far, parents are always decoded before
+        // their children
+        let mut layers: BTreeMap<Vec<Vec<u8>>, ([u8; 32], Map)> = BTreeMap::new();
+        let mut root_leafs: BTreeMap<usize, [u8; 32]> = BTreeMap::new();
+
+        while let Some((path, layer_proof)) = decoder.next_layer()? {
+            let (hash, map) = GroveDb::execute_layer_proof(&layer_proof)?;
+
+            let (key, parent_path) = path
+                .split_last()
+                .ok_or(Error::InvalidProof("proof layer path is empty"))?;
+            if parent_path.is_empty() {
+                let index = decoder
+                    .root_layer()
+                    .root_leaf_keys
+                    .get(key)
+                    .ok_or(Error::InvalidProof("root leaf key is missing"))?;
+                root_leafs.insert(*index, hash);
+            } else {
+                let (_, parent_map) = layers
+                    .get(parent_path)
+                    .ok_or(Error::InvalidProof("proof layer has no parent layer"))?;
+                let element_bytes = parent_map
+                    .get(key)
+                    .map_err(|_| Error::InvalidProof("parent layer doesn't prove subtree key"))?
+                    .ok_or(Error::InvalidProof(
+                        "parent layer doesn't contain subtree key",
+                    ))?;
+                // The parent node commits to the subtree root through its layered value
+                // hash, which only subtree nodes have
+                match parent_map.get_binding(key) {
+                    Some(ValueBinding::Layered(subtree_hash)) if subtree_hash == hash => {}
+                    Some(ValueBinding::Layered(_)) => {
+                        return Err(Error::InvalidProof(
+                            "subtree root hash doesn't match its parent layer",
+                        ))
+                    }
+                    _ => {
+                        return Err(Error::InvalidProof(
+                            "intermediate proof layers should be for trees",
+                        ))
+                    }
+                }
+                let element = deserialize_element(element_bytes)?;
+                if !matches!(element, Element::Tree(subtree_hash) if subtree_hash == hash) {
+                    return Err(Error::InvalidProof(
+                        "subtree element doesn't match its layered value hash",
+                    ));
+                }
+            }
+
+            layers.insert(path, (hash, map));
+        }
+
+        // Subtrees holding reference targets need their layers as much as
+        // queried subtrees do
+        let mut target_subtree_paths = BTreeSet::new();
+        let mut references = BTreeMap::new();
+        let mut absent_paths = BTreeMap::new();
+        for path in query_paths.iter() {
+            if let Some((_, map)) = layers.get(path) {
+                references.insert(
+                    path.clone(),
+                    Self::resolve_references(&layers, map, &mut target_subtree_paths)?,
+                );
+            } else {
+                absent_paths.insert(path.clone(), Self::proven_absence_depth(&layers, path)?);
+            }
+        }
+
+        let is_used = |path: &Vec<Vec<u8>>| {
+            query_paths
+                .iter()
+                .chain(target_subtree_paths.iter())
+                .any(|used_path| used_path.starts_with(path))
+        };
+        if !layers.keys().all(is_used) {
+            return Err(Error::InvalidProof("proof layer is not used by any query"));
+        }
+
+        let root_layer = decoder.root_layer();
+        let root_proof = MerkleProof::<Sha256>::try_from(root_layer.proof.clone())
+            .map_err(|_| Error::InvalidProof("invalid root proof"))?;
+        let (root_indices, root_hashes): (Vec<usize>, Vec<[u8; 32]>) =
+            root_leafs.into_iter().unzip();
+        let root_hash = root_proof
+            .root(&root_indices, &root_hashes, root_layer.root_leaf_keys.len())
+            .map_err(|_| Error::InvalidProof("invalid root proof"))?;
+
+        let mut results: PathProofResults = layers
+            .into_iter()
+            .filter(|(path, _)| query_paths.contains(path))
+            .map(|(path, (_, map))| {
+                let references = references.remove(&path).unwrap_or_default();
+                (
+                    path,
+                    PathProofResult::Found {
+                        results: map,
+                        references,
+                    },
+                )
+            })
+            .collect();
+        results.extend(absent_paths.into_iter().map(|(path, existing_depth)| {
+            (path, PathProofResult::PathNotFound { existing_depth })
+        }));
+
+        Ok((root_hash, results))
+    }
+
+    /// Checks that the deepest layer on the path proves that the next path
+    /// segment is not a subtree, returns that layer depth
+    fn proven_absence_depth(
+        layers: &BTreeMap<Vec<Vec<u8>>, ([u8; 32], Map)>,
+        path: &[Vec<u8>],
+    ) -> Result<usize, Error> {
+        let depth = (1..path.len())
+            .rev()
+            .find(|depth| layers.contains_key(&path[..*depth]))
+            .ok_or(Error::InvalidProof("query path has no proof layer"))?;
+        let (_, map) = &layers[&path[..depth]];
+        map.get(&path[depth])
+            .map_err(|_| Error::InvalidProof("proof doesn't prove absence of subtree key"))?;
+        if let Some(ValueBinding::Layered(_)) = map.get_binding(&path[depth]) {
+            return Err(Error::InvalidProof("query path has no proof layer"));
+        }
+        Ok(depth)
+    }
+
+    /// Resolves every reference in the layer results, recording the paths of
+    /// subtrees the targets were looked up in
+    fn resolve_references(
+        layers: &BTreeMap<Vec<Vec<u8>>, ([u8; 32], Map)>,
+        map: &Map,
+        target_subtree_paths: &mut BTreeSet<Vec<Vec<u8>>>,
+    ) -> Result<BTreeMap<Vec<u8>, Element>, Error> {
+        let mut references = BTreeMap::new();
+        for (key, (_, value)) in map.all() {
+            if let Element::Reference(reference_path) = deserialize_element(value)? {
+                let binding = map.get_binding(key).expect("key is in the map");
+                if let Some(element) =
+                    Self::resolve_reference(layers, reference_path, binding, target_subtree_paths)?
+                {
+                    references.insert(key.clone(), element);
+                }
+            }
+        }
+        Ok(references)
+    }
+
+    /// Follows the reference through the proven layers the same way `get`
+    /// does, returns `None` if the target is proven to be absent.
+    ///
+    /// `binding` is what the proof binds the value hash of the reference to.
+    /// Each reference on the way must be bound to the value hash of the
+    /// element it points to, the bindings are checked once the chain is known
+    /// to have no cycles.
+    fn resolve_reference(
+        layers: &BTreeMap<Vec<Vec<u8>>, ([u8; 32], Map)>,
+        mut path: Vec<Vec<u8>>,
+        mut binding: ValueBinding,
+        target_subtree_paths: &mut BTreeSet<Vec<Vec<u8>>>,
+    ) -> Result<Option<Element>, Error> {
+        let mut hops_left = MAX_REFERENCE_HOPS;
+        let mut visited = HashSet::new();
+        let mut bindings = Vec::new();
+        let check_bindings = |bindings: Vec<(ValueBinding, Hash)>| {
+            for (binding, referenced_value_hash) in bindings {
+                if binding != ValueBinding::Reference(referenced_value_hash) {
+                    return Err(Error::InvalidProof("reference is not bound to its target"));
+                }
+            }
+            Ok(())
+        };
+
+        while hops_left > 0 {
+            if visited.contains(&path) {
+                return Err(Error::CyclicReference);
+            }
+            let (key, subtree_path) = path
+                .split_last()
+                .ok_or(Error::CorruptedPath("empty path"))?;
+            if subtree_path.is_empty() {
+                return Err(Error::InvalidProof(
+                    "references to root leafs cannot be proven",
+                ));
+            }
+            target_subtree_paths.insert(subtree_path.to_vec());
+            let (element, element_binding, element_value_hash) = match layers.get(subtree_path) {
+                Some((_, map)) => {
+                    match map
+                        .get(key)
+                        .map_err(|_| Error::InvalidProof("proof doesn't prove reference target"))?
+                    {
+                        Some(element_bytes) => (
+                            deserialize_element(element_bytes)?,
+                            map.get_binding(key).expect("key is in the map"),
+                            map.get_value_hash(key).expect("key is in the map"),
+                        ),
+                        None => return check_bindings(bindings).map(|_| None),
+                    }
+                }
+                None => {
+                    Self::proven_absence_depth(layers, subtree_path)?;
+                    return check_bindings(bindings).map(|_| None);
+                }
+            };
+            visited.insert(path);
+            match element {
+                Element::Reference(reference_path) => {
+                    bindings.push((binding, element_value_hash));
+                    binding = element_binding;
+                    path = reference_path;
+                }
+                other => {
+                    let referenced_value_hash = match other {
+                        Element::Tree(_) => NULL_HASH,
+                        _ => element_value_hash,
+                    };
+                    bindings.push((binding, referenced_value_hash));
+                    return check_bindings(bindings).map(|_| Some(other));
+                }
+            }
+            hops_left -= 1;
+        }
+        Err(Error::ReferenceLimit)
+    }
+
+    /// Executes a single Merk proof with the hash function the layer names
+    fn execute_layer_proof(layer: &LayerProof) -> Result<([u8; 32], Map), Error> {
+        match layer.hasher.as_slice() {
+            name if name == Blake3Hasher::NAME => {
+                Self::execute_layer_proof_by::<Blake3Hasher>(&layer.proof)
+            }
+            name if name == Sha256Hasher::NAME => {
+                Self::execute_layer_proof_by::<Sha256Hasher>(&layer.proof)
+            }
+            _ => Err(Error::InvalidProof("unknown subtree hasher")),
+        }
+    }
+
+    /// Executes a single Merk proof of a subtree hashed with `H`, an empty one
+    /// stands for an empty subtree
+    fn execute_layer_proof_by<H: Hasher>(layer_proof: &[u8]) -> Result<([u8; 32], Map), Error> {
+        if layer_proof.is_empty() {
+            return Ok((NULL_HASH, MapBuilder::new_by::<H>().build()));
+        }
+        merk::execute_proof_by::<H>(layer_proof)
+            .map_err(|_| Error::InvalidProof("invalid subtree proof"))
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+
+use merk::tree::{Hash, NULL_HASH};
+use storage::{PrefixedStorage, Transaction};
+
+use super::get::MAX_REFERENCE_HOPS;
+use crate::{compress_subtree_key, Element, Error, GroveDb};
+
+/// A prefix of meta storage keys under which paths of references pointing to
+/// an element are stored
+pub(super) const REFERENCES_KEY_PREFIX: &[u8] = b"references";
+
+/// A prefix of meta storage keys under which keys of a subtree's elements
+/// which references point to are stored, so references into a deleted
+/// subtree are found without reading it
+const REFERENCED_KEYS_KEY_PREFIX: &[u8] = b"referencedKeys";
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Returns the value hash a reference to `reference_path` is bound to:
+    /// value hash of an item or a reference under the path, `NULL_HASH` if
+    /// there is no element there yet or it's a subtree.
+    ///
+    /// Subtrees are not bound because a subtree could contain references to
+    /// itself, so its root hash would depend on its own value.
+    pub(crate) fn referenced_value_hash(
+        &self,
+        reference_path: &[Vec<u8>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Hash, Error> {
+        let (key, path) = match reference_path.split_last() {
+            Some((key, path)) if !path.is_empty() => (key, path),
+            _ => return Ok(NULL_HASH),
+        };
+        let subtrees = self.get_subtrees();
+        let merk = match subtrees.borrow_mut(path.iter().map(|x| x.as_slice()), transaction) {
+            Ok(merk) => merk,
+            Err(Error::PathNotFound(_)) | Err(Error::InvalidPath(_)) => return Ok(NULL_HASH),
+            Err(e) => return Err(e),
+        };
+        merk.apply(|s| match Element::get(s, key) {
+            Ok(Element::Tree(_)) | Err(Error::PathKeyNotFound(_)) => Ok(NULL_HASH),
+            Ok(_) => Ok(s
+                .get_value_hash(key)
+                .map_err(|e| Error::CorruptedData(e.to_string()))?
+                .unwrap_or(NULL_HASH)),
+            Err(e) => Err(e),
+        })
+    }
+
+    /// Remembers that there is a reference under `path` and `key` pointing to
+    /// `reference_path` to rebind it when the referenced element changes
+    pub(crate) fn add_referencing_path<'a, P>(
+        &self,
+        reference_path: &[Vec<u8>],
+        path: P,
+        key: &'a [u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+    {
+        let referencing_path: Vec<Vec<u8>> = path
+            .into_iter()
+            .chain(std::iter::once(key))
+            .map(|x| x.to_vec())
+            .collect();
+        let mut referencing_paths = self.get_referencing_paths(reference_path, transaction)?;
+        if !referencing_paths.contains(&referencing_path) {
+            referencing_paths.push(referencing_path);
+            self.store_referencing_paths(reference_path, &referencing_paths, transaction)?;
+        }
+        Ok(())
+    }
+
+    /// Rebinds references pointing to the element under `path` and `key` to
+    /// its current value hash, and so on for references pointing to them.
+    /// Stale entries of the references index are dropped on the way.
+    pub(crate) fn update_references<'c, P>(
+        &mut self,
+        path: P,
+        key: &'c [u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'c [u8]>,
+    {
+        let target_path: Vec<Vec<u8>> = path
+            .into_iter()
+            .chain(std::iter::once(key))
+            .map(|x| x.to_vec())
+            .collect();
+        self.rebind_references(target_path, None, &[], transaction)
+    }
+
+    /// Rebinds references pointing to elements of deleted subtrees under
+    /// `subtree_paths` as dangling ones, and so on for references pointing
+    /// to them. References inside of the deleted subtrees are dropped from
+    /// the references index.
+    ///
+    /// Data of subtrees deleted without a transaction may be still stored
+    /// until it's cleared, so it's never read here.
+    pub(crate) fn update_references_to_deleted(
+        &mut self,
+        subtree_paths: &[Vec<Vec<u8>>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        for subtree_path in subtree_paths {
+            for key in self.get_referenced_keys(subtree_path, transaction)? {
+                let mut target_path = subtree_path.clone();
+                target_path.push(key);
+                self.rebind_references(target_path, Some(NULL_HASH), subtree_paths, transaction)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebinds references pointing to `target_path` and the ones pointing to
+    /// them. `target_value_hash` replaces the value hash of the target if
+    /// given, references under `deleted_subtree_paths` are not rebound.
+    fn rebind_references(
+        &mut self,
+        target_path: Vec<Vec<u8>>,
+        target_value_hash: Option<Hash>,
+        deleted_subtree_paths: &[Vec<Vec<u8>>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        let mut visited = HashSet::new();
+        let mut queue = vec![(target_path, 0)];
+
+        while let Some((target_path, hops)) = queue.pop() {
+            // Cyclic references cannot be bound consistently, the loop just has to
+            // stop on them
+            if hops == MAX_REFERENCE_HOPS || !visited.insert(target_path.clone()) {
+                continue;
+            }
+            let referencing_paths = self.get_referencing_paths(&target_path, transaction)?;
+            if referencing_paths.is_empty() {
+                continue;
+            }
+            let referenced_value_hash = match target_value_hash {
+                Some(hash) if hops == 0 => hash,
+                _ => self.referenced_value_hash(&target_path, transaction)?,
+            };
+            let mut live_referencing_paths = Vec::new();
+            for referencing_path in referencing_paths {
+                let (key, path) = referencing_path
+                    .split_last()
+                    .expect("referencing paths are never empty");
+                if deleted_subtree_paths
+                    .iter()
+                    .any(|deleted| path.starts_with(deleted))
+                {
+                    continue;
+                }
+                let path_iter = path.iter().map(|x| x.as_slice());
+                let element = match self.get_raw(path_iter.clone(), key, transaction) {
+                    Ok(element) => element,
+                    Err(Error::PathKeyNotFound(_))
+                    | Err(Error::PathNotFound(_))
+                    | Err(Error::InvalidPath(_)) => continue,
+                    Err(e) => return Err(e),
+                };
+                if !matches!(&element, Element::Reference(p) if p == &target_path) {
+                    continue;
+                }
+                self.get_subtrees()
+                    .borrow_mut(path_iter.clone(), transaction)?
+                    .apply(|s| {
+                        element.insert_reference(s, key, referenced_value_hash, transaction)
+                    })?;
+                self.propagate_changes(path_iter, transaction)?;
+                live_referencing_paths.push(referencing_path.clone());
+                queue.push((referencing_path, hops + 1));
+            }
+            self.store_referencing_paths(&target_path, &live_referencing_paths, transaction)?;
+        }
+        Ok(())
+    }
+
+    fn referencing_paths_key(reference_path: &[Vec<u8>]) -> Vec<u8> {
+        let mut key = REFERENCES_KEY_PREFIX.to_vec();
+        key.extend(compress_subtree_key(
+            reference_path.iter().map(|x| x.as_slice()),
+            None,
+        ));
+        key
+    }
+
+    fn get_referencing_paths(
+        &self,
+        reference_path: &[Vec<u8>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Vec<Vec<Vec<u8>>>, Error> {
+        self.get_meta_list(Self::referencing_paths_key(reference_path), transaction)
+    }
+
+    fn store_referencing_paths(
+        &self,
+        reference_path: &[Vec<u8>],
+        referencing_paths: &[Vec<Vec<u8>>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        if let Some((key, subtree_path)) = reference_path.split_last() {
+            let mut referenced_keys = self.get_referenced_keys(subtree_path, transaction)?;
+            let is_referenced = referenced_keys.contains(key);
+            if referencing_paths.is_empty() && is_referenced {
+                referenced_keys.retain(|referenced_key| referenced_key != key);
+                self.store_meta_list(
+                    Self::referenced_keys_key(subtree_path),
+                    &referenced_keys,
+                    transaction,
+                )?;
+            } else if !referencing_paths.is_empty() && !is_referenced {
+                referenced_keys.push(key.clone());
+                self.store_meta_list(
+                    Self::referenced_keys_key(subtree_path),
+                    &referenced_keys,
+                    transaction,
+                )?;
+            }
+        }
+        self.store_meta_list(
+            Self::referencing_paths_key(reference_path),
+            referencing_paths,
+            transaction,
+        )
+    }
+
+    fn referenced_keys_key(subtree_path: &[Vec<u8>]) -> Vec<u8> {
+        let mut key = REFERENCED_KEYS_KEY_PREFIX.to_vec();
+        key.extend(compress_subtree_key(
+            subtree_path.iter().map(|x| x.as_slice()),
+            None,
+        ));
+        key
+    }
+
+    /// Returns keys of elements of the subtree under `subtree_path` which
+    /// references point to
+    pub(crate) fn get_referenced_keys(
+        &self,
+        subtree_path: &[Vec<u8>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.get_meta_list(Self::referenced_keys_key(subtree_path), transaction)
+    }
+
+    fn get_meta_list<T: serde::de::DeserializeOwned>(
+        &self,
+        key: Vec<u8>,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Vec<T>, Error> {
+        let value = match transaction {
+            None => self.meta_storage.get_meta(key).map_err(Error::storage),
+            Some(tx) => self
+                .meta_storage
+                .transaction(S::shorten_db_transaction(tx))
+                .get_meta(key)
+                .map_err(Error::storage),
+        }?;
+        value
+            .map(|bytes| {
+                bincode::deserialize(&bytes).map_err(|_| {
+                    Error::CorruptedData(String::from("unable to deserialize references index"))
+                })
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Stores `list` under `key`, an empty list is not stored at all
+    fn store_meta_list<T: serde::Serialize>(
+        &self,
+        key: Vec<u8>,
+        list: &[T],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        if list.is_empty() {
+            return match transaction {
+                None => self.meta_storage.delete_meta(key).map_err(Error::storage),
+                Some(tx) => self
+                    .meta_storage
+                    .transaction(S::shorten_db_transaction(tx))
+                    .delete_meta(key)
+                    .map_err(Error::storage),
+            };
+        }
+        let value = bincode::serialize(list).map_err(|_| {
+            Error::CorruptedData(String::from("unable to serialize references index"))
+        })?;
+        match transaction {
+            None => self
+                .meta_storage
+                .put_meta(key, &value)
+                .map_err(Error::storage),
+            Some(tx) => self
+                .meta_storage
+                .transaction(S::shorten_db_transaction(tx))
+                .put_meta(key, &value)
+                .map_err(Error::storage),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use merk::tree::Tree;
+use storage::{PrefixedStorage, RawIterator, Store};
+
+use crate::{Element, Error, GroveDb};
+
+/// Statistics of a single subtree, nested subtrees are not included
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubtreeStats {
+    /// Number of Merk nodes, which is the number of elements
+    pub node_count: u64,
+    /// Total length of element keys
+    pub key_bytes: u64,
+    /// Total length of serialized elements
+    pub value_bytes: u64,
+    /// Height of the Merk tree, zero for an empty subtree
+    pub height: u8,
+    /// Number of elements which are subtrees themselves
+    pub child_subtrees: u64,
+    /// Number of bytes of keys and values the subtree has in data, auxiliary
+    /// and roots storages, before compression
+    pub storage_bytes: u64,
+}
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Collects statistics of a subtree under `path` by iterating over its
+    /// Merk nodes. Storage size accounts for committed data only, so changes
+    /// made in a scope of a `transaction` are not reflected in it.
+    pub fn subtree_stats<'a, P>(
+        &self,
+        path: P,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<SubtreeStats, Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+        <P as IntoIterator>::IntoIter: DoubleEndedIterator + ExactSizeIterator + Clone,
+    {
+        let path_iter = path.into_iter();
+        if path_iter.len() == 0 {
+            return Err(Error::InvalidPath("root tree is not a subtree"));
+        }
+        let transaction = transaction.map(S::shorten_db_transaction);
+        let subtrees = self.get_subtrees();
+        let merk = subtrees.borrow_mut(path_iter, transaction)?;
+
+        let mut stats = SubtreeStats {
+            height: merk.height(),
+            storage_bytes: merk.storage.logical_size().map_err(Error::storage)?,
+            ..Default::default()
+        };
+        let mut iter = merk.raw_iter(transaction.map(S::shorten_db_transaction));
+        iter.seek_to_first();
+        while iter.valid() {
+            let (key, value) = match iter.key().zip(iter.value()) {
+                Some(entry) => entry,
+                None => break,
+            };
+            let tree =
+                <Tree as Store>::decode(value).map_err(|e| Error::CorruptedData(e.to_string()))?;
+            let element: Element = bincode::deserialize(tree.value())
+                .map_err(|_| Error::CorruptedData(String::from("unable to deserialize element")))?;
+            stats.node_count += 1;
+            stats.key_bytes += key.len() as u64;
+            stats.value_bytes += tree.value().len() as u64;
+            if let Element::Tree(_) = element {
+                stats.child_subtrees += 1;
+            }
+            iter.next();
+        }
+        Ok(stats)
+    }
+
+    /// Returns an approximate number of bytes taken by tree nodes loaded into
+    /// memory by subtree Merks kept open, which are cached ones and ones used
+    /// in a scope of a transaction
+    pub fn memory_usage(&self) -> usize {
+        let cached: usize = self
+            .merk_cache
+            .borrow()
+            .iter()
+            .map(|(_, merk)| merk.memory_usage())
+            .sum();
+        let transactional: usize = self
+            .temp_subtrees
+            .borrow()
+            .values()
+            .map(|merk| merk.memory_usage())
+            .sum();
+        cached + transactional
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+
+use storage::{PrefixedStorage, Transaction};
+
+use crate::{compress_subtree_key, Error, GroveDb};
+
+/// A prefix of meta storage keys under which keys of child subtrees of a
+/// subtree are stored
+pub(super) const CHILDREN_KEY_PREFIX: &[u8] = b"children";
+/// A meta storage key marking that child subtrees are indexed
+pub(crate) const CHILDREN_INDEXED_KEY: &[u8] = b"childrenIndexed";
+/// A meta storage key to store prefixes of deleted subtrees which data is not
+/// cleared yet
+const PENDING_CLEARS_KEY: &[u8] = b"pendingClears";
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    fn get_meta_value(
+        &self,
+        key: &[u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        match transaction {
+            None => self.meta_storage.get_meta(key).map_err(Error::storage),
+            Some(tx) => self
+                .meta_storage
+                .transaction(S::shorten_db_transaction(tx))
+                .get_meta(key)
+                .map_err(Error::storage),
+        }
+    }
+
+    fn put_meta_value(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        match transaction {
+            None => self
+                .meta_storage
+                .put_meta(key, value)
+                .map_err(Error::storage),
+            Some(tx) => self
+                .meta_storage
+                .transaction(S::shorten_db_transaction(tx))
+                .put_meta(key, value)
+                .map_err(Error::storage),
+        }
+    }
+
+    fn delete_meta_value(
+        &self,
+        key: &[u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        match transaction {
+            None => self.meta_storage.delete_meta(key).map_err(Error::storage),
+            Some(tx) => self
+                .meta_storage
+                .transaction(S::shorten_db_transaction(tx))
+                .delete_meta(key)
+                .map_err(Error::storage),
+        }
+    }
+
+    fn child_keys_key(path: &[Vec<u8>]) -> Vec<u8> {
+        let mut key = CHILDREN_KEY_PREFIX.to_vec();
+        key.extend(compress_subtree_key(
+            path.iter().map(|x| x.as_slice()),
+            None,
+        ));
+        key
+    }
+
+    /// Returns keys of child subtrees of a subtree under `path`
+    pub(crate) fn get_child_keys(
+        &self,
+        path: &[Vec<u8>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.get_meta_value(&Self::child_keys_key(path), transaction)?
+            .map(|bytes| {
+                bincode::deserialize(&bytes).map_err(|_| {
+                    Error::CorruptedData(String::from("unable to deserialize subtrees index"))
+                })
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn store_child_keys(
+        &self,
+        path: &[Vec<u8>],
+        child_keys: &[Vec<u8>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        let key = Self::child_keys_key(path);
+        if child_keys.is_empty() {
+            return self.delete_meta_value(&key, transaction);
+        }
+        let value = bincode::serialize(child_keys).map_err(|_| {
+            Error::CorruptedData(String::from("unable to serialize subtrees index"))
+        })?;
+        self.put_meta_value(&key, &value, transaction)
+    }
+
+    /// Remembers that there is a subtree under `path` and `key`
+    pub(crate) fn add_child_subtree<'a, P>(
+        &self,
+        path: P,
+        key: &'a [u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+    {
+        let path: Vec<Vec<u8>> = path.into_iter().map(|x| x.to_vec()).collect();
+        let mut child_keys = self.get_child_keys(&path, transaction)?;
+        if !child_keys.iter().any(|child_key| child_key == key) {
+            child_keys.push(key.to_vec());
+            self.store_child_keys(&path, &child_keys, transaction)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the subtree under `path` and `key` and subtrees under `paths`
+    /// which are the deleted subtree and all its descendants
+    pub(crate) fn remove_child_subtrees<'a, P>(
+        &self,
+        path: P,
+        key: &'a [u8],
+        paths: &[Vec<Vec<u8>>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error>
+    where
+        P: IntoIterator<Item = &'a [u8]>,
+    {
+        let path: Vec<Vec<u8>> = path.into_iter().map(|x| x.to_vec()).collect();
+        let mut child_keys = self.get_child_keys(&path, transaction)?;
+        child_keys.retain(|child_key| child_key != key);
+        self.store_child_keys(&path, &child_keys, transaction)?;
+        for path in paths {
+            self.store_child_keys(path, &[], transaction)?;
+        }
+        Ok(())
+    }
+
+    /// Builds the index of child subtrees for a database created before the
+    /// index was introduced
+    pub(crate) fn index_child_subtrees(&mut self) -> Result<(), Error> {
+        self.with_shared_batch(|db| {
+            let mut child_keys: BTreeMap<Vec<Vec<u8>>, Vec<Vec<u8>>> = BTreeMap::new();
+            for root_leaf_key in db.root_leaf_keys.keys() {
+                for mut path in db.find_subtrees_by_scan([root_leaf_key.as_slice()], None)? {
+                    // Subtrees without children get no entry and stale ones are deleted
+                    child_keys.entry(path.clone()).or_default();
+                    if path.len() > 1 {
+                        let key = path.pop().expect("path is not empty");
+                        child_keys.entry(path).or_default().push(key);
+                    }
+                }
+            }
+            for (path, keys) in child_keys {
+                db.store_child_keys(&path, &keys, None)?;
+            }
+            db.meta_storage
+                .put_meta(CHILDREN_INDEXED_KEY, &[])
+                .map_err(Error::storage)
+        })?;
+        self.children_indexed = true;
+        Ok(())
+    }
+
+    fn get_pending_clears(
+        &self,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.get_meta_value(PENDING_CLEARS_KEY, transaction)?
+            .map(|bytes| {
+                bincode::deserialize(&bytes).map_err(|_| {
+                    Error::CorruptedData(String::from("unable to deserialize pending clears"))
+                })
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn store_pending_clears(
+        &self,
+        prefixes: &[Vec<u8>],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        if prefixes.is_empty() {
+            return self.delete_meta_value(PENDING_CLEARS_KEY, transaction);
+        }
+        let value = bincode::serialize(prefixes).map_err(|_| {
+            Error::CorruptedData(String::from("unable to serialize pending clears"))
+        })?;
+        self.put_meta_value(PENDING_CLEARS_KEY, &value, transaction)
+    }
+
+    /// Schedules data under `prefixes` of deleted subtrees to be cleared
+    /// right after the deletion is written, that is after the operation or,
+    /// inside of `transaction`, after the transaction is committed. The
+    /// schedule is persisted with the deletion, so the data is cleared on the
+    /// next open if the process stops before.
+    pub(crate) fn schedule_clears(
+        &mut self,
+        prefixes: Vec<Vec<u8>>,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        let mut pending_clears = self.get_pending_clears(transaction)?;
+        pending_clears.extend(prefixes);
+        self.store_pending_clears(&pending_clears, transaction)?;
+        self.has_pending_clears = true;
+        Ok(())
+    }
+
+    /// Cancels a clear of `prefix` scheduled inside of `transaction` once a
+    /// subtree under the same path is created again and its data is cleared
+    /// by the transaction itself
+    pub(crate) fn unschedule_clear(
+        &self,
+        prefix: &[u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        let mut pending_clears = self.get_pending_clears(transaction)?;
+        let len = pending_clears.len();
+        pending_clears.retain(|pending| pending != prefix);
+        if pending_clears.len() == len {
+            return Ok(());
+        }
+        self.store_pending_clears(&pending_clears, transaction)
+    }
+
+    /// Clears data of deleted subtrees scheduled to be cleared
+    pub(crate) fn clear_scheduled(&mut self) -> Result<(), Error> {
+        let pending_clears = self.get_pending_clears(None)?;
+        self.get_subtrees().invalidate_cached(&pending_clears);
+        for prefix in pending_clears {
+            self.meta_storage
+                .with_prefix(prefix)
+                .map_err(Error::storage)?
+                .clear()
+                .map_err(Error::storage)?;
+        }
+        self.store_pending_clears(&[], None)?;
+        self.has_pending_clears = false;
+        Ok(())
+    }
+
+    /// Clears data left under `prefix` if a deleted subtree which data is not
+    /// cleared yet is created again
+    pub(crate) fn clear_if_scheduled(&self, prefix: &[u8]) -> Result<(), Error> {
+        let mut pending_clears = self.get_pending_clears(None)?;
+        if !pending_clears.iter().any(|pending| pending == prefix) {
+            return Ok(());
+        }
+        self.get_subtrees().invalidate_cached([prefix]);
+        self.meta_storage
+            .with_prefix(prefix.to_vec())
+            .map_err(Error::storage)?
+            .clear()
+            .map_err(Error::storage)?;
+        pending_clears.retain(|pending| pending != prefix);
+        self.store_pending_clears(&pending_clears, None)
+    }
+}
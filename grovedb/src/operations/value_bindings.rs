@@ -0,0 +1,101 @@
+use merk::tree::{Tree, ValueBinding};
+use storage::{PrefixedStorage, RawIterator, Store};
+
+use crate::{Element, Error, GroveDb};
+
+/// A meta storage key marking that value hashes of all stored elements are
+/// bound, databases created before bindings were introduced have no marker
+pub(crate) const VALUE_BINDINGS_MIGRATED_KEY: &[u8] = b"valueBindingsMigrated";
+
+/// An element stored with an unbound value hash: a path of its subtree, its
+/// key and the element itself
+type LegacyElement = (Vec<Vec<u8>>, Vec<u8>, Element);
+
+impl<S: PrefixedStorage> GroveDb<S> {
+    /// Binds value hashes of elements stored before bindings were introduced,
+    /// nodes of the legacy encoding are decoded with plain value hashes.
+    /// References are bound to value hashes of elements they point to and
+    /// added to the references index, subtrees are bound to root hashes of
+    /// their child Merks.
+    ///
+    /// The migration is written in one batch together with the marker, so an
+    /// interrupted one starts over on the next open.
+    pub(crate) fn migrate_value_bindings(&mut self) -> Result<(), Error> {
+        if self
+            .meta_storage
+            .get_meta(VALUE_BINDINGS_MIGRATED_KEY)
+            .map_err(Error::storage)?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let legacy_elements = self.find_legacy_elements()?;
+        self.with_shared_batch(|db| {
+            for (path, key, element) in legacy_elements {
+                if let Element::Reference(reference_path) = &element {
+                    let path_iter = path.iter().map(|x| x.as_slice());
+                    let referenced_value_hash = db.referenced_value_hash(reference_path, None)?;
+                    db.get_subtrees()
+                        .borrow_mut(path_iter.clone(), None)?
+                        .apply(|s| {
+                            element.insert_reference(s, &key, referenced_value_hash, None)
+                        })?;
+                    db.add_referencing_path(reference_path, path_iter.clone(), &key, None)?;
+                    db.propagate_changes(path_iter.clone(), None)?;
+                    // References migrated earlier could point to this one
+                    db.update_references(path_iter, &key, None)?;
+                } else {
+                    // Propagating changes of the child subtree puts it into its parent with
+                    // the root hash bound
+                    let mut child_path = path.clone();
+                    child_path.push(key);
+                    db.propagate_changes(child_path.iter().map(|x| x.as_slice()), None)?;
+                }
+            }
+            db.meta_storage
+                .put_meta(VALUE_BINDINGS_MIGRATED_KEY, &[])
+                .map_err(Error::storage)
+        })
+    }
+
+    /// Walks all subtrees and returns elements which value hashes are plain
+    /// though they have to be bound
+    fn find_legacy_elements(&self) -> Result<Vec<LegacyElement>, Error> {
+        let mut legacy_elements = Vec::new();
+        let mut queue: Vec<Vec<Vec<u8>>> = self
+            .root_leaf_keys
+            .keys()
+            .map(|key| vec![key.clone()])
+            .collect();
+        let subtrees = self.get_subtrees();
+        while let Some(path) = queue.pop() {
+            let merk = subtrees.borrow_mut(path.iter().map(|x| x.as_slice()), None)?;
+            let mut iter = merk.raw_iter(None);
+            iter.seek_to_first();
+            while let Some((key, value)) = iter.key().zip(iter.value()) {
+                let node = <Tree as Store>::decode(value)
+                    .map_err(|e| Error::CorruptedData(e.to_string()))?;
+                let element: Element = bincode::deserialize(node.value()).map_err(|_| {
+                    Error::CorruptedData(String::from("unable to deserialize element"))
+                })?;
+                let is_plain = *node.value_binding() == ValueBinding::Plain;
+                match element {
+                    Element::Tree(_) => {
+                        let mut child_path = path.clone();
+                        child_path.push(key.to_vec());
+                        queue.push(child_path);
+                        if is_plain {
+                            legacy_elements.push((path.clone(), key.to_vec(), element))
+                        }
+                    }
+                    Element::Reference(_) if is_plain => {
+                        legacy_elements.push((path.clone(), key.to_vec(), element))
+                    }
+                    _ => {}
+                }
+                iter.next();
+            }
+        }
+        Ok(legacy_elements)
+    }
+}
@@ -0,0 +1,447 @@
+//! Canonical encoding of GroveDB proofs.
+//!
+//! A proof is a sequence of sections, every integer being a little-endian
+//! `u32` unless stated otherwise:
+//!
+//! ```text
+//! proof       = version query_paths root_layer layers
+//! version     = u8                            ; PROOF_VERSION
+//! query_paths = count path*                   ; in the order of the queries
+//! root_layer  = count root_leaf* bytes        ; root leafs and rs_merkle proof
+//! root_leaf   = bytes index                   ; strictly ascending by key
+//! layers      = count layer*                  ; strictly ascending by path
+//! layer       = path hasher bytes             ; subtree path and its Merk proof
+//! hasher      = bytes                         ; name of the subtree hasher
+//! path        = count bytes*                  ; path segments
+//! bytes       = length u8*
+//! ```
+//!
+//! Paths are compared segment by segment and a path goes before any longer
+//! path it is a prefix of, so a parent layer always precedes its children.
+//! The root layer carries the whole root leafs map (indices are a permutation
+//! of `0..count`) and a proof for the root leafs the queries go through. An
+//! empty Merk proof stands for an empty subtree. Every subtree is hashed with
+//! its own hash function, so a layer names the one its Merk proof is verified
+//! with (`merk::tree::Hasher::NAME`).
+//!
+//! The decoder rejects unknown versions, truncated input, entries that are
+//! duplicated or out of order and trailing bytes.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Read, Write},
+};
+
+use crate::Error;
+
+/// Version of the proof encoding described in the module documentation
+pub const PROOF_VERSION: u8 = 3;
+
+/// Root tree part of a proof
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootLayerProof {
+    /// All root leafs keys with their indices in the root tree
+    pub root_leaf_keys: BTreeMap<Vec<u8>, usize>,
+    /// `rs_merkle` proof for the root leafs used by the queries
+    pub proof: Vec<u8>,
+}
+
+/// Merk proof of a single subtree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerProof {
+    /// Name of the hash function the subtree is hashed with
+    pub hasher: Vec<u8>,
+    pub proof: Vec<u8>,
+}
+
+/// Proof for a set of path queries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proof {
+    /// Paths of the proven queries, in the order the queries were given
+    pub query_paths: Vec<Vec<Vec<u8>>>,
+    pub root_layer: RootLayerProof,
+    /// Merk proofs of every subtree on the way to the queried ones, by path
+    pub layers: BTreeMap<Vec<Vec<u8>>, LayerProof>,
+}
+
+/// A single subtree proof as read from an encoded proof
+pub type ProofLayer = (Vec<Vec<u8>>, LayerProof);
+
+impl Proof {
+    /// Encodes the proof into its canonical representation
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        bytes.push(PROOF_VERSION);
+
+        write_len(&mut bytes, self.query_paths.len())?;
+        for path in &self.query_paths {
+            write_path(&mut bytes, path)?;
+        }
+
+        write_len(&mut bytes, self.root_layer.root_leaf_keys.len())?;
+        for (key, index) in &self.root_layer.root_leaf_keys {
+            write_bytes(&mut bytes, key)?;
+            write_len(&mut bytes, *index)?;
+        }
+        write_bytes(&mut bytes, &self.root_layer.proof)?;
+
+        write_len(&mut bytes, self.layers.len())?;
+        for (path, layer) in &self.layers {
+            write_path(&mut bytes, path)?;
+            write_bytes(&mut bytes, &layer.hasher)?;
+            write_bytes(&mut bytes, &layer.proof)?;
+        }
+
+        Ok(bytes)
+    }
+
+    /// Decodes a proof, see [`ProofDecoder`] to process layers one by one
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut decoder = ProofDecoder::new(bytes)?;
+        let mut layers = BTreeMap::new();
+        while let Some((path, layer)) = decoder.next_layer()? {
+            layers.insert(path, layer);
+        }
+        let ProofDecoder {
+            query_paths,
+            root_layer,
+            ..
+        } = decoder;
+
+        Ok(Proof {
+            query_paths,
+            root_layer,
+            layers,
+        })
+    }
+}
+
+/// Streaming proof decoder.
+///
+/// Query paths and the root layer are read on creation, then layers are read
+/// one at a time, parents before children. Once the last layer is read the
+/// decoder makes sure there is nothing left in the input.
+pub struct ProofDecoder<R> {
+    input: R,
+    query_paths: Vec<Vec<Vec<u8>>>,
+    root_layer: RootLayerProof,
+    layers_left: u32,
+    last_path: Option<Vec<Vec<u8>>>,
+    finished: bool,
+}
+
+impl<R: Read> ProofDecoder<R> {
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        let mut version = [0];
+        read_exact(&mut input, &mut version)?;
+        if version[0] != PROOF_VERSION {
+            return Err(Error::InvalidProof("unsupported proof version"));
+        }
+
+        let query_paths_count = read_u32(&mut input)?;
+        let mut query_paths = Vec::new();
+        for _ in 0..query_paths_count {
+            query_paths.push(read_path(&mut input)?);
+        }
+
+        let root_leafs_count = read_u32(&mut input)?;
+        let mut root_leaf_keys = BTreeMap::new();
+        let mut seen_indices = BTreeSet::new();
+        let mut last_key: Option<Vec<u8>> = None;
+        for _ in 0..root_leafs_count {
+            let key = read_bytes(&mut input)?;
+            let index = read_u32(&mut input)?;
+            if last_key.as_ref().map(|k| k >= &key).unwrap_or(false) {
+                return Err(Error::InvalidProof("root leafs keys are not in order"));
+            }
+            if index >= root_leafs_count {
+                return Err(Error::InvalidProof("root leaf index is out of bounds"));
+            }
+            if !seen_indices.insert(index) {
+                return Err(Error::InvalidProof("duplicated root leaf index"));
+            }
+            root_leaf_keys.insert(key.clone(), index as usize);
+            last_key = Some(key);
+        }
+        let proof = read_bytes(&mut input)?;
+
+        let layers_left = read_u32(&mut input)?;
+
+        Ok(ProofDecoder {
+            input,
+            query_paths,
+            root_layer: RootLayerProof {
+                root_leaf_keys,
+                proof,
+            },
+            layers_left,
+            last_path: None,
+            finished: false,
+        })
+    }
+
+    pub fn query_paths(&self) -> &[Vec<Vec<u8>>] {
+        &self.query_paths
+    }
+
+    pub fn root_layer(&self) -> &RootLayerProof {
+        &self.root_layer
+    }
+
+    /// Reads the next layer, returns `None` once all layers were read and the
+    /// input is exhausted
+    pub fn next_layer(&mut self) -> Result<Option<ProofLayer>, Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        let result = self.read_layer();
+        if !matches!(result, Ok(Some(_))) {
+            self.finished = true;
+        }
+        result
+    }
+
+    fn read_layer(&mut self) -> Result<Option<ProofLayer>, Error> {
+        if self.layers_left == 0 {
+            let mut trailing = [0];
+            return match self.input.read(&mut trailing) {
+                Ok(0) => Ok(None),
+                Ok(_) => Err(Error::InvalidProof("trailing bytes after the last layer")),
+                Err(e) => Err(read_error(e)),
+            };
+        }
+        self.layers_left -= 1;
+
+        let path = read_path(&mut self.input)?;
+        if let Some(last_path) = &self.last_path {
+            if last_path == &path {
+                return Err(Error::InvalidProof("duplicated proof layer"));
+            }
+            if last_path > &path {
+                return Err(Error::InvalidProof("proof layers are not in order"));
+            }
+        }
+        let hasher = read_bytes(&mut self.input)?;
+        let proof = read_bytes(&mut self.input)?;
+        self.last_path = Some(path.clone());
+
+        Ok(Some((path, LayerProof { hasher, proof })))
+    }
+}
+
+impl<R: Read> Iterator for ProofDecoder<R> {
+    type Item = Result<ProofLayer, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_layer().transpose()
+    }
+}
+
+fn write_len<W: Write>(out: &mut W, len: usize) -> Result<(), Error> {
+    let len = u32::try_from(len).map_err(|_| Error::InvalidProof("proof is too large"))?;
+    out.write_all(&len.to_le_bytes())
+        .map_err(|e| Error::CorruptedData(e.to_string()))
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> Result<(), Error> {
+    write_len(out, bytes.len())?;
+    out.write_all(bytes)
+        .map_err(|e| Error::CorruptedData(e.to_string()))
+}
+
+fn write_path<W: Write>(out: &mut W, path: &[Vec<u8>]) -> Result<(), Error> {
+    write_len(out, path.len())?;
+    for segment in path {
+        write_bytes(out, segment)?;
+    }
+    Ok(())
+}
+
+fn read_error(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        Error::InvalidProof("unexpected end of proof")
+    } else {
+        Error::CorruptedData(format!("unable to read proof: {}", e))
+    }
+}
+
+fn read_exact<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    input.read_exact(buf).map_err(read_error)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> Result<u32, Error> {
+    let mut bytes = [0; 4];
+    read_exact(input, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> Result<Vec<u8>, Error> {
+    let len = read_u32(input)? as u64;
+    // Reading through `take` to not allocate whatever length the input claims
+    let mut bytes = Vec::new();
+    input
+        .take(len)
+        .read_to_end(&mut bytes)
+        .map_err(read_error)?;
+    if bytes.len() as u64 != len {
+        return Err(Error::InvalidProof("unexpected end of proof"));
+    }
+    Ok(bytes)
+}
+
+fn read_path<R: Read>(input: &mut R) -> Result<Vec<Vec<u8>>, Error> {
+    let segments_count = read_u32(input)?;
+    let mut path = Vec::new();
+    for _ in 0..segments_count {
+        path.push(read_bytes(input)?);
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_proof() -> Proof {
+        let mut root_leaf_keys = BTreeMap::new();
+        root_leaf_keys.insert(b"a".to_vec(), 1);
+        root_leaf_keys.insert(b"b".to_vec(), 0);
+
+        let layer = |hasher: &[u8], proof: Vec<u8>| LayerProof {
+            hasher: hasher.to_vec(),
+            proof,
+        };
+        let mut layers = BTreeMap::new();
+        layers.insert(vec![b"a".to_vec()], layer(b"blake3", vec![1, 2, 3]));
+        layers.insert(vec![b"a".to_vec(), b"c".to_vec()], layer(b"sha256", vec![]));
+        layers.insert(vec![b"b".to_vec()], layer(b"blake3", vec![4]));
+
+        Proof {
+            query_paths: vec![vec![b"a".to_vec(), b"c".to_vec()], vec![b"b".to_vec()]],
+            root_layer: RootLayerProof {
+                root_leaf_keys,
+                proof: vec![7; 32],
+            },
+            layers,
+        }
+    }
+
+    #[test]
+    fn test_encoding_roundtrip() {
+        let proof = sample_proof();
+        let bytes = proof.encode().expect("proof should be encoded");
+        assert_eq!(bytes[0], PROOF_VERSION);
+        assert_eq!(
+            Proof::decode(&bytes).expect("proof should be decoded"),
+            proof
+        );
+    }
+
+    #[test]
+    fn test_encoding_is_canonical() {
+        let bytes = sample_proof().encode().unwrap();
+
+        // Building the same proof in a different order gives the same bytes
+        let mut proof = sample_proof();
+        let layers = std::mem::take(&mut proof.layers);
+        for (path, layer) in layers.into_iter().rev() {
+            proof.layers.insert(path, layer);
+        }
+        assert_eq!(proof.encode().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_decoder_streams_layers_in_order() {
+        let bytes = sample_proof().encode().unwrap();
+        let decoder = ProofDecoder::new(bytes.as_slice()).unwrap();
+        assert_eq!(decoder.query_paths().len(), 2);
+        assert_eq!(decoder.root_layer().root_leaf_keys.len(), 2);
+
+        let paths: Vec<Vec<Vec<u8>>> = decoder.map(|layer| layer.unwrap().0).collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec![b"a".to_vec()],
+                vec![b"a".to_vec(), b"c".to_vec()],
+                vec![b"b".to_vec()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decoder_rejects_unknown_version() {
+        let mut bytes = sample_proof().encode().unwrap();
+        bytes[0] = PROOF_VERSION + 1;
+        assert!(matches!(
+            Proof::decode(&bytes),
+            Err(Error::InvalidProof("unsupported proof version"))
+        ));
+    }
+
+    #[test]
+    fn test_decoder_rejects_trailing_bytes() {
+        let mut bytes = sample_proof().encode().unwrap();
+        bytes.push(0);
+        assert!(matches!(
+            Proof::decode(&bytes),
+            Err(Error::InvalidProof("trailing bytes after the last layer"))
+        ));
+    }
+
+    #[test]
+    fn test_decoder_rejects_truncated_input() {
+        let bytes = sample_proof().encode().unwrap();
+        for len in 0..bytes.len() {
+            assert!(Proof::decode(&bytes[..len]).is_err());
+        }
+    }
+
+    /// Builds an encoded proof without query paths and root leafs out of
+    /// the given layers, bypassing `Proof` to keep them as is
+    fn encode_layers(layers: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec![PROOF_VERSION];
+        write_len(&mut bytes, 0).unwrap();
+        write_len(&mut bytes, 0).unwrap();
+        write_bytes(&mut bytes, &[]).unwrap();
+        write_len(&mut bytes, layers.len()).unwrap();
+        for key in layers {
+            write_path(&mut bytes, &[key.to_vec()]).unwrap();
+            write_bytes(&mut bytes, b"blake3").unwrap();
+            write_bytes(&mut bytes, &[]).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_decoder_rejects_duplicated_layers() {
+        assert!(Proof::decode(&encode_layers(&[b"a", b"b"])).is_ok());
+        assert!(matches!(
+            Proof::decode(&encode_layers(&[b"a", b"a"])),
+            Err(Error::InvalidProof("duplicated proof layer"))
+        ));
+    }
+
+    #[test]
+    fn test_decoder_rejects_unordered_layers() {
+        assert!(matches!(
+            Proof::decode(&encode_layers(&[b"b", b"a"])),
+            Err(Error::InvalidProof("proof layers are not in order"))
+        ));
+    }
+
+    #[test]
+    fn test_decoder_rejects_bad_root_leafs() {
+        let mut bytes = vec![PROOF_VERSION];
+        write_len(&mut bytes, 0).unwrap();
+        write_len(&mut bytes, 2).unwrap();
+        write_bytes(&mut bytes, b"a").unwrap();
+        write_len(&mut bytes, 1).unwrap();
+        write_bytes(&mut bytes, b"b").unwrap();
+        write_len(&mut bytes, 1).unwrap();
+        write_bytes(&mut bytes, &[]).unwrap();
+        write_len(&mut bytes, 0).unwrap();
+        assert!(matches!(
+            Proof::decode(&bytes),
+            Err(Error::InvalidProof("duplicated root leaf index"))
+        ));
+    }
+}
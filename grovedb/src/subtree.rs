@@ -4,17 +4,11 @@
 
 use merk::{
     proofs::{query::QueryItem, Query},
-    tree::Tree,
-    Op,
+    tree::{Hash, Tree},
+    MergeFnId, MergeFns, MergeResolver, Op,
 };
 use serde::{Deserialize, Serialize};
-use storage::{
-    rocksdb_storage::{
-        OptimisticTransactionDBTransaction, PrefixedRocksDbStorage,
-        RawPrefixedTransactionalIterator,
-    },
-    RawIterator, Storage, Store,
-};
+use storage::{PrefixedStorage, RawIterator, Store};
 
 use crate::{Error, Merk, PathQuery, SizedQuery, Subtrees};
 
@@ -31,9 +25,52 @@ pub enum Element {
     Tree([u8; 32]),
 }
 
-pub struct PathQueryPushArgs<'a> {
-    pub transaction: Option<&'a OptimisticTransactionDBTransaction<'a>>,
-    pub subtrees: &'a Subtrees<'a>,
+/// Merges `operand` into the contents of the `existing` serialized item with
+/// the merge function of the given id, the item is serialized back
+fn merge_item(
+    merge_fns: &MergeFns,
+    existing: Option<&[u8]>,
+    merge_fn_id: MergeFnId,
+    operand: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let existing = existing
+        .map(bincode::deserialize)
+        .transpose()
+        .map_err(|_| Error::CorruptedData(String::from("unable to deserialize element")))?;
+    let item = match existing {
+        None => None,
+        Some(Element::Item(item)) => Some(item),
+        Some(_) => {
+            return Err(Error::InvalidMerge(String::from(
+                "only items can be merged into",
+            )))
+        }
+    };
+    let merged = merge_fns
+        .resolve(merge_fn_id, item.as_deref(), operand)
+        .map_err(|e| Error::InvalidMerge(e.to_string()))?;
+    bincode::serialize(&Element::Item(merged))
+        .map_err(|_| Error::CorruptedData(String::from("unable to serialize element")))
+}
+
+/// Resolves Merk merges of serialized items, see `merge_item`
+struct ItemMergeFns<'a>(&'a MergeFns);
+
+impl MergeResolver for ItemMergeFns<'_> {
+    fn merge(
+        &self,
+        merge_fn_id: MergeFnId,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operand: &[u8],
+    ) -> merk::Result<Vec<u8>> {
+        Ok(merge_item(self.0, existing, merge_fn_id, operand)?)
+    }
+}
+
+pub struct PathQueryPushArgs<'a, 'db, S: PrefixedStorage> {
+    pub transaction: Option<&'db S::DBTransaction<'db>>,
+    pub subtrees: &'a Subtrees<'a, S>,
     pub key: Option<&'a [u8]>,
     pub element: Element,
     pub path: Option<&'a [&'a [u8]]>,
@@ -52,20 +89,58 @@ impl Element {
     }
 
     /// Delete an element from Merk under a key
-    pub fn delete<K: AsRef<[u8]>>(
-        merk: &mut Merk<PrefixedRocksDbStorage>,
+    pub fn delete<S: PrefixedStorage, K: AsRef<[u8]>>(
+        merk: &mut Merk<S>,
         key: K,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error> {
         // TODO: delete references on this element
         let batch = [(key, Op::Delete)];
-        merk.apply::<_, Vec<u8>>(&batch, &[], transaction)
+        merk.apply::<_, Vec<u8>>(&batch, &[], transaction.map(S::shorten_db_transaction))
             .map_err(|e| Error::CorruptedData(e.to_string()))
     }
 
+    /// Delete all elements from Merk with keys from `from` (inclusive) to `to`
+    /// (exclusive) in one pass
+    pub fn delete_range<S: PrefixedStorage>(
+        merk: &mut Merk<S>,
+        from: &[u8],
+        to: &[u8],
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        let batch = [(from, Op::DeleteRange(to.to_vec()))];
+        merk.apply::<_, Vec<u8>>(&batch, &[], transaction.map(S::shorten_db_transaction))
+            .map_err(|e| Error::CorruptedData(e.to_string()))
+    }
+
+    /// Merge `operand` into an item in Merk under a key with the merge function
+    /// of the given id, the item is merged while Merk applies the change so
+    /// there is no need to get it first. An item is created if there is
+    /// nothing under the key, other elements can't be merged into.
+    pub fn merge<S: PrefixedStorage, K: AsRef<[u8]>>(
+        merk: &mut Merk<S>,
+        key: K,
+        operand: &[u8],
+        merge_fn_id: MergeFnId,
+        merge_fns: &MergeFns,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        let batch = [(key, Op::Merge(operand.to_vec(), merge_fn_id))];
+        merk.apply_with_merge_fns::<_, Vec<u8>>(
+            &batch,
+            &[],
+            &ItemMergeFns(merge_fns),
+            transaction.map(S::shorten_db_transaction),
+        )
+        .map_err(|e| {
+            e.downcast()
+                .unwrap_or_else(|e| Error::CorruptedData(e.to_string()))
+        })
+    }
+
     /// Get an element from Merk under a key; path should be resolved and proper
     /// Merk should be loaded by this moment
-    pub fn get<K: AsRef<[u8]>>(merk: &Merk<PrefixedRocksDbStorage>, key: K) -> Result<Self, Error> {
+    pub fn get<S: PrefixedStorage, K: AsRef<[u8]>>(merk: &Merk<S>, key: K) -> Result<Self, Error> {
         let element = bincode::deserialize(
             merk.get(key.as_ref())
                 .map_err(|e| Error::CorruptedData(e.to_string()))?
@@ -78,18 +153,39 @@ impl Element {
         Ok(element)
     }
 
-    pub fn get_query(
+    /// Get elements from Merk under many keys at once, `None` is returned for
+    /// keys which are not found
+    pub fn get_many<S: PrefixedStorage, K: AsRef<[u8]>>(
+        merk: &Merk<S>,
+        keys: &[K],
+    ) -> Result<Vec<Option<Self>>, Error> {
+        merk.get_many(keys)
+            .map_err(|e| Error::CorruptedData(e.to_string()))?
+            .into_iter()
+            .map(|maybe_element| {
+                maybe_element
+                    .map(|element| {
+                        bincode::deserialize(element.as_slice()).map_err(|_| {
+                            Error::CorruptedData(String::from("unable to deserialize element"))
+                        })
+                    })
+                    .transpose()
+            })
+            .collect()
+    }
+
+    pub fn get_query<'db, S: PrefixedStorage>(
         merk_path: &[&[u8]],
         query: &Query,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
-        subtrees: &Subtrees,
+        transaction: Option<&'db S::DBTransaction<'db>>,
+        subtrees: &Subtrees<S>,
     ) -> Result<Vec<Self>, Error> {
         let sized_query = SizedQuery::new(query.clone(), None, None);
         let (elements, _) = Self::get_sized_query(merk_path, &sized_query, transaction, subtrees)?;
         Ok(elements)
     }
 
-    fn basic_push(args: PathQueryPushArgs) -> Result<(), Error> {
+    fn basic_push<S: PrefixedStorage>(args: PathQueryPushArgs<S>) -> Result<(), Error> {
         let PathQueryPushArgs {
             element,
             results,
@@ -108,7 +204,7 @@ impl Element {
         Ok(())
     }
 
-    fn path_query_push(args: PathQueryPushArgs) -> Result<(), Error> {
+    fn path_query_push<S: PrefixedStorage>(args: PathQueryPushArgs<S>) -> Result<(), Error> {
         let PathQueryPushArgs {
             transaction,
             subtrees,
@@ -197,17 +293,17 @@ impl Element {
         Ok(())
     }
 
-    fn query_item(
+    fn query_item<'db, S: PrefixedStorage>(
         item: &QueryItem,
         results: &mut Vec<Self>,
         merk_path: &[&[u8]],
         sized_query: &SizedQuery,
         path: Option<&[&[u8]]>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
-        subtrees: &Subtrees,
+        transaction: Option<&'db S::DBTransaction<'db>>,
+        subtrees: &Subtrees<S>,
         limit: &mut Option<u16>,
         offset: &mut Option<u16>,
-        add_element_function: fn(PathQueryPushArgs) -> Result<(), Error>,
+        add_element_function: fn(PathQueryPushArgs<'_, 'db, S>) -> Result<(), Error>,
     ) -> Result<(), Error> {
         if !item.is_range() {
             // this is a query on a key
@@ -251,7 +347,7 @@ impl Element {
                 .borrow_mut(merk_path.iter().copied(), transaction)?
                 .apply(|s| s.storage.clone());
 
-            let mut iter = storage.raw_iter(transaction);
+            let mut iter = storage.raw_iter(transaction.map(S::shorten_db_transaction));
 
             item.seek_for_iter(&mut iter, sized_query.query.left_to_right);
 
@@ -286,13 +382,13 @@ impl Element {
         }
     }
 
-    pub fn get_query_apply_function(
+    pub fn get_query_apply_function<'db, S: PrefixedStorage>(
         merk_path: &[&[u8]],
         sized_query: &SizedQuery,
         path: Option<&[&[u8]]>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
-        subtrees: &Subtrees,
-        add_element_function: fn(PathQueryPushArgs) -> Result<(), Error>,
+        transaction: Option<&'db S::DBTransaction<'db>>,
+        subtrees: &Subtrees<S>,
+        add_element_function: fn(PathQueryPushArgs<'_, 'db, S>) -> Result<(), Error>,
     ) -> Result<(Vec<Self>, u16), Error> {
         let mut results = Vec::new();
 
@@ -347,11 +443,11 @@ impl Element {
     }
 
     // Returns a vector of elements, and the number of skipped elements
-    pub fn get_path_query(
+    pub fn get_path_query<'db, S: PrefixedStorage>(
         merk_path: &[&[u8]],
         path_query: &PathQuery,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
-        subtrees: &Subtrees,
+        transaction: Option<&'db S::DBTransaction<'db>>,
+        subtrees: &Subtrees<S>,
     ) -> Result<(Vec<Self>, u16), Error> {
         let path_slices = path_query
             .path
@@ -369,11 +465,11 @@ impl Element {
     }
 
     // Returns a vector of elements, and the number of skipped elements
-    pub fn get_sized_query(
+    pub fn get_sized_query<'db, S: PrefixedStorage>(
         merk_path: &[&[u8]],
         sized_query: &SizedQuery,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
-        subtrees: &Subtrees,
+        transaction: Option<&'db S::DBTransaction<'db>>,
+        subtrees: &Subtrees<S>,
     ) -> Result<(Vec<Self>, u16), Error> {
         Self::get_query_apply_function(
             merk_path,
@@ -390,31 +486,64 @@ impl Element {
     /// If transaction is not passed, the batch will be written immediately.
     /// If transaction is passed, the operation will be committed on the
     /// transaction commit.
-    pub fn insert<'a: 'b, 'b, K: AsRef<[u8]>>(
-        &'a self,
-        merk: &mut Merk<PrefixedRocksDbStorage>,
+    /// Subtrees are put as layered values, so their nodes' value hashes are
+    /// bound to the subtrees' root hashes.
+    pub fn insert<S: PrefixedStorage, K: AsRef<[u8]>>(
+        &self,
+        merk: &mut Merk<S>,
+        key: K,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<(), Error> {
+        let serialized = bincode::serialize(self)
+            .map_err(|_| Error::CorruptedData(String::from("unable to serialize element")))?;
+        let op = match self {
+            Element::Tree(root_hash) => Op::PutLayered(serialized, *root_hash),
+            _ => Op::Put(serialized),
+        };
+        let batch_operations = [(key, op)];
+        merk.apply::<_, Vec<u8>>(
+            &batch_operations,
+            &[],
+            transaction.map(S::shorten_db_transaction),
+        )
+        .map_err(|e| Error::CorruptedData(e.to_string()))
+    }
+
+    /// Insert a reference in Merk under a key, its node's value hash combines
+    /// the hash of the reference itself and `referenced_value_hash` which is
+    /// the value hash of the element it points to
+    pub fn insert_reference<S: PrefixedStorage, K: AsRef<[u8]>>(
+        &self,
+        merk: &mut Merk<S>,
         key: K,
-        transaction: Option<&'b <PrefixedRocksDbStorage as Storage>::DBTransaction<'b>>,
+        referenced_value_hash: Hash,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<(), Error> {
-        let batch_operations =
-            [(
-                key,
-                Op::Put(bincode::serialize(self).map_err(|_| {
+        let batch_operations = [(
+            key,
+            Op::PutReference(
+                bincode::serialize(self).map_err(|_| {
                     Error::CorruptedData(String::from("unable to serialize element"))
-                })?),
-            )];
-        merk.apply::<_, Vec<u8>>(&batch_operations, &[], transaction)
-            .map_err(|e| Error::CorruptedData(e.to_string()))
+                })?,
+                referenced_value_hash,
+            ),
+        )];
+        merk.apply::<_, Vec<u8>>(
+            &batch_operations,
+            &[],
+            transaction.map(S::shorten_db_transaction),
+        )
+        .map_err(|e| Error::CorruptedData(e.to_string()))
     }
 
-    pub fn iterator(mut raw_iter: RawPrefixedTransactionalIterator) -> ElementsIterator {
+    pub fn iterator<I: RawIterator>(mut raw_iter: I) -> ElementsIterator<I> {
         raw_iter.seek_to_first();
         ElementsIterator::new(raw_iter)
     }
 }
 
-pub struct ElementsIterator<'a> {
-    raw_iter: RawPrefixedTransactionalIterator<'a>,
+pub struct ElementsIterator<I: RawIterator> {
+    raw_iter: I,
 }
 
 pub fn raw_decode(bytes: &[u8]) -> Result<Element, Error> {
@@ -424,8 +553,8 @@ pub fn raw_decode(bytes: &[u8]) -> Result<Element, Error> {
     Ok(element)
 }
 
-impl<'a> ElementsIterator<'a> {
-    pub const fn new(raw_iter: RawPrefixedTransactionalIterator<'a>) -> Self {
+impl<I: RawIterator> ElementsIterator<I> {
+    pub const fn new(raw_iter: I) -> Self {
         ElementsIterator { raw_iter }
     }
 
@@ -3,31 +3,55 @@ use std::{
     cell::{RefCell, RefMut},
     collections::{BTreeMap, HashMap, HashSet},
     ops::{Deref, DerefMut},
-    rc::Rc,
 };
 
-use merk::Merk;
-use storage::rocksdb_storage::{OptimisticTransactionDBTransaction, PrefixedRocksDbStorage};
+use lru::LruCache;
+use merk::{Merk, RetentionPolicy};
+use storage::PrefixedStorage;
 
-use crate::{Element, Error, GroveDb};
+use crate::{compress_subtree_key, Element, Error};
 
 // TODO: should take temp_root_leaf_keys also
-pub struct Subtrees<'a> {
+pub struct Subtrees<'a, S: PrefixedStorage> {
     pub root_leaf_keys: &'a BTreeMap<Vec<u8>, usize>,
-    pub temp_subtrees: &'a RefCell<HashMap<Vec<u8>, Merk<PrefixedRocksDbStorage>>>,
+    pub temp_subtrees: &'a RefCell<HashMap<Vec<u8>, Merk<S>>>,
     pub deleted_subtrees: &'a RefCell<HashSet<Vec<u8>>>,
-    pub storage: Rc<storage::rocksdb_storage::OptimisticTransactionDB>,
+    // Not used while a transaction is started
+    pub merk_cache: Option<&'a RefCell<LruCache<Vec<u8>, Merk<S>>>>,
+    pub retention_policy: RetentionPolicy,
+    pub storage: &'a S,
 }
 
-/// Can hold an owned Merk or a referenced to temporary transactional Merks
-/// storage
-pub enum TempMerk<'a> {
-    Owned(Merk<PrefixedRocksDbStorage>),
-    Borrowed(RefMut<'a, Merk<PrefixedRocksDbStorage>>, Vec<u8>),
+/// Can hold an owned Merk, a referenced to temporary transactional Merks
+/// storage or a Merk taken from the cache of open Merks
+pub enum TempMerk<'a, S: PrefixedStorage> {
+    Owned(Merk<S>),
+    Borrowed(RefMut<'a, Merk<S>>, Vec<u8>),
+    Cached(CachedMerk<'a, S>),
 }
 
-impl TempMerk<'_> {
-    pub fn apply<U>(mut self, f: impl FnOnce(&mut Merk<PrefixedRocksDbStorage>) -> U) -> U {
+/// A Merk taken from the cache of open Merks which is put back once dropped
+pub struct CachedMerk<'a, S: PrefixedStorage> {
+    merk: Option<Merk<S>>,
+    prefix: Vec<u8>,
+    cache: &'a RefCell<LruCache<Vec<u8>, Merk<S>>>,
+}
+
+impl<S: PrefixedStorage> Drop for CachedMerk<'_, S> {
+    fn drop(&mut self) {
+        if let Some(merk) = self.merk.take() {
+            let mut cache = self.cache.borrow_mut();
+            // The same subtree was opened twice at once, so it's unknown which one is
+            // up to date
+            if cache.pop(&self.prefix).is_none() {
+                cache.put(std::mem::take(&mut self.prefix), merk);
+            }
+        }
+    }
+}
+
+impl<S: PrefixedStorage> TempMerk<'_, S> {
+    pub fn apply<U>(mut self, f: impl FnOnce(&mut Merk<S>) -> U) -> U {
         f(&mut self)
     }
 
@@ -40,40 +64,42 @@ impl TempMerk<'_> {
     }
 }
 
-impl Deref for TempMerk<'_> {
-    type Target = Merk<PrefixedRocksDbStorage>;
+impl<S: PrefixedStorage> Deref for TempMerk<'_, S> {
+    type Target = Merk<S>;
 
     fn deref(&self) -> &Self::Target {
         match self {
             TempMerk::Owned(m) => m,
             TempMerk::Borrowed(m, _) => m,
+            TempMerk::Cached(m) => m.merk.as_ref().expect("taken on drop only"),
         }
     }
 }
 
-impl DerefMut for TempMerk<'_> {
+impl<S: PrefixedStorage> DerefMut for TempMerk<'_, S> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         match self {
             TempMerk::Owned(m) => m,
             TempMerk::Borrowed(m, _) => m,
+            TempMerk::Cached(m) => m.merk.as_mut().expect("taken on drop only"),
         }
     }
 }
 
-impl Subtrees<'_> {
+impl<S: PrefixedStorage> Subtrees<'_, S> {
     pub fn insert_temp_tree<'a, P>(
         &self,
         path: P,
-        merk: Merk<PrefixedRocksDbStorage>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
-    ) -> Option<Merk<PrefixedRocksDbStorage>>
+        merk: Merk<S>,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Option<Merk<S>>
     where
         P: IntoIterator<Item = &'a [u8]>,
     {
         match transaction {
             None => None,
             Some(_) => {
-                let prefix = GroveDb::compress_subtree_key(path, None);
+                let prefix = compress_subtree_key(path, None);
                 self.insert_temp_tree_with_prefix(prefix, merk, transaction)
             }
         }
@@ -82,9 +108,9 @@ impl Subtrees<'_> {
     pub fn insert_temp_tree_with_prefix(
         &self,
         prefix: Vec<u8>,
-        merk: Merk<PrefixedRocksDbStorage>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
-    ) -> Option<Merk<PrefixedRocksDbStorage>> {
+        merk: Merk<S>,
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Option<Merk<S>> {
         match transaction {
             None => None,
             Some(_) => {
@@ -96,6 +122,16 @@ impl Subtrees<'_> {
         }
     }
 
+    /// Drops open Merks of subtrees under `prefixes` from the cache
+    pub fn invalidate_cached(&self, prefixes: impl IntoIterator<Item = impl AsRef<[u8]>>) {
+        if let Some(merk_cache) = self.merk_cache {
+            let mut cache = merk_cache.borrow_mut();
+            for prefix in prefixes {
+                cache.pop(prefix.as_ref());
+            }
+        }
+    }
+
     pub fn delete_temp_tree_with_prefix<T>(&self, prefix: Vec<u8>, transaction: Option<T>) {
         if transaction.is_some() {
             self.deleted_subtrees.borrow_mut().insert(prefix);
@@ -105,20 +141,44 @@ impl Subtrees<'_> {
     pub fn borrow_mut<'a, P>(
         &self,
         path: P,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
-    ) -> Result<TempMerk, Error>
+        transaction: Option<&S::DBTransaction<'_>>,
+    ) -> Result<TempMerk<S>, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
         <P as IntoIterator>::IntoIter: Clone + DoubleEndedIterator,
     {
         let merk;
         match transaction {
-            None => {
-                merk = TempMerk::Owned(self.get_subtree_without_transaction(path)?);
-            }
+            None => match self.merk_cache {
+                Some(merk_cache) => {
+                    let path_iter = path.into_iter();
+                    let prefix = compress_subtree_key(path_iter.clone(), None);
+                    let cached = merk_cache.borrow_mut().pop(&prefix);
+                    let open_merk = match cached {
+                        Some(mut merk) => {
+                            // Writes have to go to the shared batch of the current operation
+                            // if there is one
+                            merk.storage = self
+                                .storage
+                                .with_prefix(prefix.clone())
+                                .map_err(Error::storage)?;
+                            merk
+                        }
+                        None => self.get_subtree_without_transaction(path_iter)?,
+                    };
+                    merk = TempMerk::Cached(CachedMerk {
+                        merk: Some(open_merk),
+                        prefix,
+                        cache: merk_cache,
+                    });
+                }
+                None => {
+                    merk = TempMerk::Owned(self.get_subtree_without_transaction(path)?);
+                }
+            },
             Some(_) => {
                 let path_iter = path.into_iter();
-                let tree_prefix = GroveDb::compress_subtree_key(path_iter.clone(), None);
+                let tree_prefix = compress_subtree_key(path_iter.clone(), None);
                 if self.deleted_subtrees.borrow().contains(&tree_prefix) {
                     return Err(Error::PathNotFound("no subtree found under that path"));
                 }
@@ -149,10 +209,7 @@ impl Subtrees<'_> {
         Ok(merk)
     }
 
-    pub fn get_subtree_without_transaction<'a, P>(
-        &self,
-        path: P,
-    ) -> Result<Merk<PrefixedRocksDbStorage>, Error>
+    pub fn get_subtree_without_transaction<'a, P>(&self, path: P) -> Result<Merk<S>, Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
         <P as IntoIterator>::IntoIter: DoubleEndedIterator + Clone,
@@ -206,16 +263,18 @@ impl Subtrees<'_> {
         &self,
         path: P,
         key: Option<&'a [u8]>,
-    ) -> Result<(Merk<PrefixedRocksDbStorage>, bool), Error>
+    ) -> Result<(Merk<S>, bool), Error>
     where
         P: IntoIterator<Item = &'a [u8]>,
     {
-        let subtree_prefix = GroveDb::compress_subtree_key(path, key);
-        let merk = Merk::open(PrefixedRocksDbStorage::new(
-            self.storage.clone(),
-            subtree_prefix,
-        )?)
+        let subtree_prefix = compress_subtree_key(path, key);
+        let mut merk = Merk::open(
+            self.storage
+                .with_prefix(subtree_prefix)
+                .map_err(Error::storage)?,
+        )
         .map_err(|_| Error::PathNotFound("no subtree found under that path"))?;
+        merk.set_retention_policy(self.retention_policy);
         let has_keys = !merk.is_empty_tree(None);
         Ok((merk, has_keys))
     }
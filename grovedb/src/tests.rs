@@ -3,7 +3,15 @@ use std::{
     option::Option::None,
 };
 
+use merk::{
+    tree::{
+        combine_hash, layered_value_hash, value_hash, Blake3Hasher, Hasher, Sha256Hasher, Tree,
+        ValueBinding, HASH_LENGTH, NULL_HASH,
+    },
+    Op,
+};
 use rand::Rng;
+use storage::{RawIterator, Storage, Store};
 use tempdir::TempDir;
 
 // use test::RunIgnored::No;
@@ -56,6 +64,56 @@ fn test_init() {
     GroveDb::open(tmp_dir).expect("empty tree is ok");
 }
 
+#[test]
+fn test_open_with_config() {
+    let tmp_dir = TempDir::new("db").unwrap();
+    let config = GroveDbConfig::default().storage(
+        RocksDbConfig::default()
+            .block_cache_size(1 << 20)
+            .compression(Column::Default, DBCompressionType::Lz4)
+            .max_open_files(128),
+    );
+    let mut db = GroveDb::open_with_config(tmp_dir.path(), config).unwrap();
+    add_test_leafs(&mut db);
+    db.insert([TEST_LEAF], b"key", Element::Item(b"ayy".to_vec()), None)
+        .expect("successful insert");
+    let root_hash = db.root_hash(None);
+    drop(db);
+
+    let mut db =
+        GroveDb::open_with_config(tmp_dir.path(), GroveDbConfig::default().refuse_writes(true))
+            .unwrap();
+    assert_eq!(db.root_hash(None), root_hash);
+    assert_eq!(
+        db.get([TEST_LEAF], b"key", None).expect("successful get"),
+        Element::Item(b"ayy".to_vec())
+    );
+    assert!(matches!(
+        db.insert([TEST_LEAF], b"key2", Element::Item(b"ayy".to_vec()), None),
+        Err(Error::WritesRefused)
+    ));
+    assert!(matches!(
+        db.delete([TEST_LEAF], b"key", None),
+        Err(Error::WritesRefused)
+    ));
+    assert!(matches!(
+        db.put_aux(b"aux", b"value", None),
+        Err(Error::WritesRefused)
+    ));
+    assert!(matches!(db.start_transaction(), Err(Error::WritesRefused)));
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    assert!(matches!(
+        db.insert(
+            [TEST_LEAF],
+            b"key2",
+            Element::Item(b"ayy".to_vec()),
+            Some(&db_transaction)
+        ),
+        Err(Error::WritesRefused)
+    ));
+}
+
 #[test]
 fn test_insert_value_to_merk() {
     let mut db = make_grovedb();
@@ -113,6 +171,386 @@ fn test_changes_propagated() {
     assert_ne!(old_hash, db.root_tree.root());
 }
 
+#[test]
+fn test_get_many() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    for i in 0u8..20 {
+        db.insert(
+            [TEST_LEAF, b"key1"],
+            &[b'a', i],
+            Element::Item(vec![i]),
+            None,
+        )
+        .expect("successful value insert");
+    }
+    db.insert(
+        [TEST_LEAF, b"key1"],
+        b"reference",
+        Element::Reference(vec![TEST_LEAF.to_vec(), b"key1".to_vec(), vec![b'a', 5]]),
+        None,
+    )
+    .expect("successful reference insert");
+
+    let keys: [&[u8]; 4] = [&[b'a', 3], b"missing", b"reference", &[b'a', 19]];
+    assert_eq!(
+        db.get_many([TEST_LEAF, b"key1"], &keys, None)
+            .expect("successful get many"),
+        vec![
+            Some(Element::Item(vec![3])),
+            None,
+            Some(Element::Item(vec![5])),
+            Some(Element::Item(vec![19])),
+        ]
+    );
+
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    db.insert(
+        [TEST_LEAF, b"key1"],
+        b"missing",
+        Element::Item(b"found".to_vec()),
+        Some(&db_transaction),
+    )
+    .expect("successful value insert");
+    assert_eq!(
+        db.get_many([TEST_LEAF, b"key1"], &keys[..2], Some(&db_transaction))
+            .expect("successful get many"),
+        vec![
+            Some(Element::Item(vec![3])),
+            Some(Element::Item(b"found".to_vec())),
+        ]
+    );
+    db.abort_transaction(db_transaction)
+        .expect("successful abort");
+
+    // Root leafs are subtrees
+    assert!(matches!(
+        db.get_many([], &[TEST_LEAF, b"missing"], None)
+            .expect("successful get many")
+            .as_slice(),
+        [Some(Element::Tree(_)), None]
+    ));
+    assert!(matches!(
+        db.get_many([TEST_LEAF, b"missing"], &keys, None),
+        Err(Error::PathNotFound(_))
+    ));
+}
+
+#[test]
+fn test_subtree_stats() {
+    let mut db = make_grovedb();
+    let empty = db
+        .subtree_stats([TEST_LEAF], None)
+        .expect("successful stats");
+    assert_eq!(empty, SubtreeStats::default());
+
+    db.insert([TEST_LEAF], b"subtree", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    db.insert(
+        [TEST_LEAF, b"subtree"],
+        b"key",
+        Element::Item(b"value".to_vec()),
+        None,
+    )
+    .expect("successful value insert");
+    db.insert([TEST_LEAF], b"item", Element::Item(b"value".to_vec()), None)
+        .expect("successful value insert");
+
+    let stats = db
+        .subtree_stats([TEST_LEAF], None)
+        .expect("successful stats");
+    assert_eq!(stats.node_count, 2);
+    assert_eq!(stats.key_bytes, (b"subtree".len() + b"item".len()) as u64);
+    assert_eq!(
+        stats.value_bytes,
+        (bincode::serialized_size(&Element::Item(b"value".to_vec())).unwrap()
+            + bincode::serialized_size(&Element::empty_tree()).unwrap())
+    );
+    assert_eq!(stats.height, 2);
+    assert_eq!(stats.child_subtrees, 1);
+    // Stored nodes also keep hashes and links, so they take more space
+    assert!(stats.storage_bytes > stats.key_bytes + stats.value_bytes);
+
+    // Nested subtrees are accounted separately
+    let nested = db
+        .subtree_stats([TEST_LEAF, b"subtree"], None)
+        .expect("successful stats");
+    assert_eq!(nested.node_count, 1);
+    assert_eq!(nested.child_subtrees, 0);
+
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    db.insert(
+        [TEST_LEAF],
+        b"another",
+        Element::Item(b"value".to_vec()),
+        Some(&db_transaction),
+    )
+    .expect("successful value insert");
+    let transactional = db
+        .subtree_stats([TEST_LEAF], Some(&db_transaction))
+        .expect("successful stats");
+    assert_eq!(transactional.node_count, 3);
+    assert_eq!(transactional.storage_bytes, stats.storage_bytes);
+    db.abort_transaction(db_transaction)
+        .expect("successful abort");
+
+    assert!(matches!(
+        db.subtree_stats([TEST_LEAF, b"missing"], None),
+        Err(Error::PathNotFound(_))
+    ));
+    assert!(matches!(
+        db.subtree_stats([], None),
+        Err(Error::InvalidPath(_))
+    ));
+}
+
+#[test]
+fn test_verify_integrity() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"subtree", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    for i in 0u8..10 {
+        db.insert([TEST_LEAF, b"subtree"], &[i], Element::Item(vec![i]), None)
+            .expect("successful value insert");
+    }
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"reference",
+        Element::Reference(vec![TEST_LEAF.to_vec(), b"subtree".to_vec(), vec![5]]),
+        None,
+    )
+    .expect("successful reference insert");
+    assert_eq!(
+        db.verify_integrity().expect("successful verification"),
+        vec![]
+    );
+
+    // Reference which points nowhere
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"dangling",
+        Element::Reference(vec![TEST_LEAF.to_vec(), b"missing".to_vec()]),
+        None,
+    )
+    .expect("successful reference insert");
+    assert_eq!(
+        db.verify_integrity().expect("successful verification"),
+        vec![IntegrityIssue::DanglingReference {
+            path: vec![ANOTHER_TEST_LEAF.to_vec()],
+            key: b"dangling".to_vec(),
+            reference_path: vec![TEST_LEAF.to_vec(), b"missing".to_vec()],
+        }]
+    );
+    db.delete([ANOTHER_TEST_LEAF], b"dangling", None)
+        .expect("successful delete");
+
+    // Node value changed without updating its hashes
+    let merk = db
+        .get_subtrees()
+        .get_subtree_without_transaction([TEST_LEAF, b"subtree"])
+        .expect("subtree exists");
+    let stored = merk
+        .storage
+        .get([3])
+        .expect("successful get")
+        .expect("node is stored");
+    let tree = <Tree as Store>::decode(&stored).expect("node is decoded");
+    let tampered = Tree::from_fields(
+        vec![3],
+        bincode::serialize(&Element::Item(b"tampered".to_vec())).unwrap(),
+        *tree.kv_hash(),
+        *tree.value_hash(),
+        tree.link(true).cloned(),
+        tree.link(false).cloned(),
+    );
+    merk.storage
+        .put([3], &tampered.encode())
+        .expect("successful put");
+    drop(merk);
+    assert_eq!(
+        db.verify_integrity().expect("successful verification"),
+        vec![IntegrityIssue::InvalidValueHash {
+            path: vec![TEST_LEAF.to_vec(), b"subtree".to_vec()],
+            key: vec![3],
+        }]
+    );
+    db.insert([TEST_LEAF, b"subtree"], &[3], Element::Item(vec![3]), None)
+        .expect("successful value insert");
+
+    // Subtree element out of sync with the subtree, root hash is not propagated
+    let mut merk = db
+        .get_subtrees()
+        .get_subtree_without_transaction([TEST_LEAF])
+        .expect("subtree exists");
+    Element::Tree([1; 32])
+        .insert(&mut merk, b"subtree", None)
+        .expect("successful subtree insert");
+    assert_eq!(
+        db.verify_integrity().expect("successful verification"),
+        vec![
+            IntegrityIssue::RootHashMismatch,
+            IntegrityIssue::SubtreeHashMismatch {
+                path: vec![TEST_LEAF.to_vec()],
+                key: b"subtree".to_vec(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_verify_integrity_against_committed_root_hash() {
+    let tmp_dir = TempDir::new("db").unwrap();
+    {
+        let mut db = GroveDb::open(tmp_dir.path()).unwrap();
+        add_test_leafs(&mut db);
+        db.insert([TEST_LEAF], b"key", Element::Item(b"ayy".to_vec()), None)
+            .expect("successful value insert");
+        assert!(db.verify_integrity().expect("successful verify").is_empty());
+
+        // Root leaf changed without updating the root hash
+        let mut merk = db
+            .get_subtrees()
+            .get_subtree_without_transaction([TEST_LEAF])
+            .expect("subtree exists");
+        Element::Item(b"lmao".to_vec())
+            .insert(&mut merk, b"key", None)
+            .expect("successful value insert");
+    }
+
+    // The root tree is built from root leaves on open, so the mismatch is found
+    // only against the committed root hash
+    let db = GroveDb::open(tmp_dir.path()).unwrap();
+    assert_eq!(
+        db.verify_integrity().expect("successful verify"),
+        vec![IntegrityIssue::RootHashMismatch]
+    );
+}
+
+#[test]
+fn test_verify_integrity_uses_recorded_hasher() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"subtree", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    for i in 0u8..10 {
+        db.insert([TEST_LEAF, b"subtree"], &[i], Element::Item(vec![i]), None)
+            .expect("successful value insert");
+    }
+    let path = vec![TEST_LEAF.to_vec(), b"subtree".to_vec()];
+    let record_hasher = |name: &[u8]| {
+        db.meta_storage
+            .with_prefix(compress_subtree_key([TEST_LEAF, b"subtree"], None))
+            .expect("successful prefix")
+            .put_root(merk::HASHER_KEY, name)
+            .expect("successful put");
+    };
+
+    // BLAKE3 nodes don't match SHA-256 hashes
+    record_hasher(Sha256Hasher::NAME);
+    let issues = db.verify_integrity().expect("successful verification");
+    assert!(issues.contains(&IntegrityIssue::SubtreeHashMismatch {
+        path: vec![TEST_LEAF.to_vec()],
+        key: b"subtree".to_vec(),
+    }));
+    assert!(issues.contains(&IntegrityIssue::InvalidNodeHash {
+        path: path.clone(),
+        key: vec![0],
+    }));
+
+    record_hasher(b"unknown");
+    assert_eq!(
+        db.verify_integrity().expect("successful verification"),
+        vec![IntegrityIssue::UnknownHasher { path }]
+    );
+
+    record_hasher(Blake3Hasher::NAME);
+    assert!(db
+        .verify_integrity()
+        .expect("successful verification")
+        .is_empty());
+}
+
+#[test]
+fn test_collect_garbage() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"kept", Element::Item(b"value".to_vec()), None)
+        .expect("successful value insert");
+    db.insert([TEST_LEAF], b"subtree", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    db.insert(
+        [TEST_LEAF, b"subtree"],
+        b"nested",
+        Element::empty_tree(),
+        None,
+    )
+    .expect("successful subtree insert");
+    db.insert(
+        [TEST_LEAF, b"subtree", b"nested"],
+        b"key",
+        Element::Item(b"value".to_vec()),
+        None,
+    )
+    .expect("successful value insert");
+    assert_eq!(
+        db.collect_garbage(false).expect("successful gc"),
+        GarbageReport::default()
+    );
+
+    // Subtree deletion interrupted before its data is cleared
+    let mut merk = db
+        .get_subtrees()
+        .get_subtree_without_transaction([TEST_LEAF])
+        .expect("subtree exists");
+    Element::delete(&mut merk, b"subtree", None).expect("successful delete");
+    let mut orphaned_prefixes = vec![
+        compress_subtree_key([TEST_LEAF, b"subtree"], None),
+        compress_subtree_key([TEST_LEAF, b"subtree", b"nested"], None),
+    ];
+    orphaned_prefixes.sort();
+    let orphaned_size = |db: &GroveDb| -> u64 {
+        orphaned_prefixes
+            .iter()
+            .map(|prefix| {
+                db.meta_storage
+                    .with_prefix(prefix.clone())
+                    .unwrap()
+                    .logical_size()
+                    .unwrap()
+            })
+            .sum()
+    };
+
+    let report = db.collect_garbage(true).expect("successful gc");
+    assert_eq!(report.orphaned_prefixes, orphaned_prefixes);
+    assert!(report.storage_bytes > 0);
+    assert_eq!(orphaned_size(&db), report.storage_bytes);
+
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    assert!(matches!(
+        db.collect_garbage(false),
+        Err(Error::DbIsInReadonlyMode)
+    ));
+    db.abort_transaction(db_transaction)
+        .expect("successful abort");
+
+    assert_eq!(db.collect_garbage(false).expect("successful gc"), report);
+    assert_eq!(orphaned_size(&db), 0);
+    assert_eq!(
+        db.collect_garbage(true).expect("successful gc"),
+        GarbageReport::default()
+    );
+    assert_eq!(
+        db.get([TEST_LEAF], b"kept", None).expect("successful get"),
+        Element::Item(b"value".to_vec())
+    );
+}
+
 #[test]
 fn test_follow_references() {
     let mut db = make_grovedb();
@@ -244,391 +682,871 @@ fn test_root_tree_leafs_are_noted() {
     assert_eq!(db.root_tree.leaves_len(), 2);
 }
 
-// #[test]
-// fn test_proof_construction() {
-//     // Tree Structure
-//     // root
-//     //     test_leaf
-//     //         innertree
-//     //             k1,v1
-//     //             k2,v2
-//     //     another_test_leaf
-//     //         innertree2
-//     //             k3,v3
-//     //         innertree3
-//     //             k4,v4
-//
-//     // Insert elements into grovedb instance
-//     let mut temp_db = make_grovedb();
-//     // Insert level 1 nodes
-//     temp_db
-//         .insert(
-//             [TEST_LEAF],
-//             b"innertree",
-//             Element::empty_tree(),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[ANOTHER_TEST_LEAF],
-//             b"innertree2",
-//             Element::empty_tree(),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[ANOTHER_TEST_LEAF],
-//             b"innertree3",
-//             Element::empty_tree(),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     // Insert level 2 nodes
-//     temp_db
-//         .insert(
-//             &[TEST_LEAF, b"innertree"],
-//             b"key1",
-//             Element::Item(b"value1"),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[TEST_LEAF, b"innertree"],
-//             b"key2",
-//             Element::Item(b"value2".to_vec()),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[ANOTHER_TEST_LEAF, b"innertree2"],
-//             b"key3",
-//             Element::Item(b"value3".to_vec()),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[ANOTHER_TEST_LEAF, b"innertree3"],
-//             b"key4",
-//             Element::Item(b"value4".to_vec()),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//
-//     // Manually construct HADS bottom up
-//     // Insert level 2 nodes
-//     let mut inner_tree = TempMerk::new();
-//     let value_one = Element::Item(b"value1".to_vec());
-//     value_one
-//         .insert(&mut inner_tree, b"key1", None)
-//         .unwrap();
-//     let value_two = Element::Item(b"value2".to_vec());
-//     value_two
-//         .insert(&mut inner_tree, b"key2", None)
-//         .unwrap();
-//
-//     let mut inner_tree_2 = TempMerk::new();
-//     let value_three = Element::Item(b"value3".to_vec());
-//     value_three
-//         .insert(&mut inner_tree_2, b"key3", None)
-//         .unwrap();
-//
-//     let mut inner_tree_3 = TempMerk::new();
-//     let value_four = Element::Item(b"value4".to_vec());
-//     value_four
-//         .insert(&mut inner_tree_3, b"key4", None)
-//         .unwrap();
-//     // Insert level 1 nodes
-//     let mut test_leaf = TempMerk::new();
-//     let inner_tree_root = Element::Tree(inner_tree.root_hash());
-//     inner_tree_root
-//         .insert(&mut test_leaf, b"innertree", None)
-//         .unwrap();
-//     let mut another_test_leaf = TempMerk::new();
-//     let inner_tree_2_root = Element::Tree(inner_tree_2.root_hash());
-//     inner_tree_2_root
-//         .insert(&mut another_test_leaf, b"innertree2", None)
-//         .unwrap();
-//     let inner_tree_3_root = Element::Tree(inner_tree_3.root_hash());
-//     inner_tree_3_root
-//         .insert(&mut another_test_leaf, b"innertree3", None)
-//         .unwrap();
-//     // Insert root nodes
-//     let leaves = [test_leaf.root_hash(), another_test_leaf.root_hash()];
-//     let root_tree = MerkleTree::<Sha256>::from_leaves(&leaves);
-//
-//     // Proof construction
-//     // Generating a proof for two paths
-//     // root -> test_leaf -> innertree (prove both k1 and k2)
-//     // root -> another_test_leaf -> innertree3 (prove k4)
-//     // root -> another_test_leaf -> innertree2 (prove k3)
-//
-//     // Build reusable query objects
-//     let mut path_one_query = Query::new();
-//     path_one_query.insert_key(b"key1");
-//     path_one_query.insert_key(b"key2");
-//
-//     let mut path_two_query = Query::new();
-//     path_two_query.insert_key(b"key4".to_vec());
-//
-//     let mut path_three_query = Query::new();
-//     path_three_query.insert_key(b"key3");
-//
-//     // Get grovedb proof
-//     let proof = temp_db
-//         .proof(vec![
-//             PathQuery::new_unsized(&[TEST_LEAF, b"innertree"],
-// path_one_query),             PathQuery::new_unsized(&[ANOTHER_TEST_LEAF,
-// b"innertree3"], path_two_query),
-// PathQuery::new_unsized(&[ANOTHER_TEST_LEAF, b"innertree2"],
-// path_three_query),         ])
-//         .unwrap();
-//
-//     // Deserialize the proof
-//     let proof: Proof = bincode::deserialize(&proof).unwrap();
-//
-//     // Perform assertions
-//     assert_eq!(proof.query_paths.len(), 3);
-//     assert_eq!(proof.query_paths[0], &[TEST_LEAF, b"innertree"]);
-//     assert_eq!(proof.query_paths[1], &[ANOTHER_TEST_LEAF, b"innertree3"]);
-//     assert_eq!(proof.query_paths[2], &[ANOTHER_TEST_LEAF, b"innertree2"]);
-//
-//     // For path 1 to path 3, there are 9 nodes
-//     // root is repeated three times and another_test_leaf is repeated twice
-//     // Accounting for duplication, there are 6 unique nodes
-//     // root, test_leaf, another_test_leaf, innertree, innertree2, innertree3
-//     // proof.proofs contains all nodes except the root so we expect 5 sub
-// proofs     assert_eq!(proof.proofs.len(), 5);
-//
-//     // Check that all the subproofs were constructed correctly for each path
-// and     // subpath
-//     let path_one_as_vec = GroveDb::compress_subtree_key(&[TEST_LEAF,
-// b"innertree"], None);     let path_two_as_vec =
-// GroveDb::compress_subtree_key(&[ANOTHER_TEST_LEAF, b"innertree3"], None);
-//     let path_three_as_vec =
-//         GroveDb::compress_subtree_key(&[ANOTHER_TEST_LEAF, b"innertree2"],
-// None);     let test_leaf_path_as_vec =
-// GroveDb::compress_subtree_key([TEST_LEAF], None);
-//     let another_test_leaf_path_as_vec =
-// GroveDb::compress_subtree_key(&[ANOTHER_TEST_LEAF], None);
-//
-//     let proof_for_path_one = proof.proofs.get(&path_one_as_vec).unwrap();
-//     let proof_for_path_two = proof.proofs.get(&path_two_as_vec).unwrap();
-//     let proof_for_path_three = proof.proofs.get(&path_three_as_vec).unwrap();
-//     let proof_for_test_leaf =
-// proof.proofs.get(&test_leaf_path_as_vec).unwrap();
-//     let proof_for_another_test_leaf =
-// proof.proofs.get(&another_test_leaf_path_as_vec).unwrap();
-//
-//     // Assert path 1 proof
-//     let mut proof_query = Query::new();
-//     proof_query.insert_key(b"key1");
-//     proof_query.insert_key(b"key2");
-//     assert_eq!(
-//         *proof_for_path_one,
-//         inner_tree.prove(proof_query, None, None).unwrap()
-//     );
-//
-//     // Assert path 2 proof
-//     let mut proof_query = Query::new();
-//     proof_query.insert_key(b"key4".to_vec());
-//     assert_eq!(
-//         *proof_for_path_two,
-//         inner_tree_3.prove(proof_query, None, None).unwrap()
-//     );
-//
-//     // Assert path 3 proof
-//     let mut proof_query = Query::new();
-//     proof_query.insert_key(b"key3");
-//     assert_eq!(
-//         *proof_for_path_three,
-//         inner_tree_2.prove(proof_query, None, None).unwrap()
-//     );
-//
-//     // Assert test leaf proof
-//     let mut proof_query = Query::new();
-//     proof_query.insert_key(b"innertree".to_vec());
-//     assert_eq!(
-//         *proof_for_test_leaf,
-//         test_leaf.prove(proof_query, None, None).unwrap()
-//     );
-//
-//     // Assert another test leaf proof
-//     // another test leaf appeared in two path,
-//     // hence it should contain proofs for both keys
-//     let mut proof_query = Query::new();
-//     proof_query.insert_key(b"innertree2".to_vec());
-//     proof_query.insert_key(b"innertree3".to_vec());
-//     assert_eq!(
-//         *proof_for_another_test_leaf,
-//         another_test_leaf
-//             .prove(proof_query, None, None)
-//             .unwrap()
-//     );
-//
-//     // Check that the root proof is valid
-//     // Root proof should contain proof for both test_leaf and
-// another_test_leaf     let test_leaf_root_key =
-// GroveDb::compress_subtree_key(&[], Some(TEST_LEAF));
-//     let another_test_leaf_root_key = GroveDb::compress_subtree_key(&[],
-// Some(ANOTHER_TEST_LEAF));     assert_eq!(
-//         proof.root_proof,
-//         root_tree
-//             .proof(&[
-//                 temp_db.root_leaf_keys[&test_leaf_root_key],
-//                 temp_db.root_leaf_keys[&another_test_leaf_root_key],
-//             ])
-//             .to_bytes()
-//     );
-//
-//     // Assert that we got the correct root leaf keys
-//     assert_eq!(proof.root_leaf_keys.len(), 2);
-//     assert_eq!(proof.root_leaf_keys[&test_leaf_root_key], 0);
-//     assert_eq!(proof.root_leaf_keys[&another_test_leaf_root_key], 1);
-// }
+/// Populates GroveDB with the following structure for proof tests:
+///
+/// root
+///     test_leaf
+///         innertree
+///             key1,value1
+///             key2,value2
+///     another_test_leaf
+///         innertree2
+///             key3,value3
+///         innertree3
+///             key4,value4
+fn make_grovedb_for_proofs() -> TempGroveDb {
+    let mut temp_db = make_grovedb();
+    // Insert level 1 nodes
+    temp_db
+        .insert([TEST_LEAF], b"innertree", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    temp_db
+        .insert(
+            [ANOTHER_TEST_LEAF],
+            b"innertree2",
+            Element::empty_tree(),
+            None,
+        )
+        .expect("successful subtree insert");
+    temp_db
+        .insert(
+            [ANOTHER_TEST_LEAF],
+            b"innertree3",
+            Element::empty_tree(),
+            None,
+        )
+        .expect("successful subtree insert");
+    // Insert level 2 nodes
+    temp_db
+        .insert(
+            [TEST_LEAF, b"innertree"],
+            b"key1",
+            Element::Item(b"value1".to_vec()),
+            None,
+        )
+        .expect("successful subtree insert");
+    temp_db
+        .insert(
+            [TEST_LEAF, b"innertree"],
+            b"key2",
+            Element::Item(b"value2".to_vec()),
+            None,
+        )
+        .expect("successful subtree insert");
+    temp_db
+        .insert(
+            [ANOTHER_TEST_LEAF, b"innertree2"],
+            b"key3",
+            Element::Item(b"value3".to_vec()),
+            None,
+        )
+        .expect("successful subtree insert");
+    temp_db
+        .insert(
+            [ANOTHER_TEST_LEAF, b"innertree3"],
+            b"key4",
+            Element::Item(b"value4".to_vec()),
+            None,
+        )
+        .expect("successful subtree insert");
+    temp_db
+}
 
-// #[test]
-// fn test_successful_proof_verification() {
-//     // Build a grovedb database
-//     // Tree Structure
-//     // root
-//     //     test_leaf
-//     //         innertree
-//     //             k1,v1
-//     //             k2,v2
-//     //     another_test_leaf
-//     //         innertree2
-//     //             k3,v3
-//     //         innertree3
-//     //             k4,v4
-//
-//     // Insert elements into grovedb instance
-//     let mut temp_db = make_grovedb();
-//     // Insert level 1 nodes
-//     temp_db
-//         .insert(
-//             [TEST_LEAF],
-//             b"innertree",
-//             Element::empty_tree(),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[ANOTHER_TEST_LEAF],
-//             b"innertree2",
-//             Element::empty_tree(),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[ANOTHER_TEST_LEAF],
-//             b"innertree3",
-//             Element::empty_tree(),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     // Insert level 2 nodes
-//     temp_db
-//         .insert(
-//             &[TEST_LEAF, b"innertree"],
-//             b"key1",
-//             Element::Item(b"value1".to_vec()),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[TEST_LEAF, b"innertree"],
-//             b"key2",
-//             Element::Item(b"value2".to_vec()),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[ANOTHER_TEST_LEAF, b"innertree2"],
-//             b"key3",
-//             Element::Item(b"value3".to_vec()),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//     temp_db
-//         .insert(
-//             &[ANOTHER_TEST_LEAF, b"innertree3"],
-//             b"key4",
-//             Element::Item(b"value4".to_vec()),
-//             None,
-//         )
-//         .expect("successful subtree insert");
-//
-//     // Single query proof verification
-//     let mut path_one_query = Query::new();
-//     path_one_query.insert_key(b"key1");
-//     path_one_query.insert_key(b"key2");
-//
-//     let proof = temp_db
-//         .proof(vec![PathQuery::new_unsized(
-//             &[TEST_LEAF, b"innertree"],
-//             path_one_query,
-//         )])
-//         .unwrap();
-//
-//     // Assert correct root hash
-//     let (root_hash, result_maps) = GroveDb::execute_proof(proof).unwrap();
-//     assert_eq!(temp_db.root_tree.root().unwrap(), root_hash);
-//
-//     // Assert correct result object
-//     // Proof query was for two keys key1 and key2
-//     let path_as_vec = GroveDb::compress_subtree_key(&[TEST_LEAF,
-// b"innertree"], None);     let result_map =
-// result_maps.get(&path_as_vec).unwrap();     let elem_1: Element =
-// bincode::deserialize(result_map.get(b"key1").unwrap().unwrap()).unwrap();
-//     let elem_2: Element =
-// bincode::deserialize(result_map.get(b"key2").unwrap().unwrap()).unwrap();
-//     assert_eq!(elem_1, Element::Item(b"value1".to_vec()));
-//     assert_eq!(elem_2, Element::Item(b"value2".to_vec()));
-//
-//     // Multi query proof verification
-//     let mut path_two_query = Query::new();
-//     path_two_query.insert_key(b"key4".to_vec());
-//
-//     let mut path_three_query = Query::new();
-//     path_three_query.insert_key(b"key3");
-//
-//     // Get grovedb proof
-//     let proof = temp_db
-//         .proof(vec![
-//             PathQuery::new_unsized(&[ANOTHER_TEST_LEAF, b"innertree3"],
-// path_two_query),             PathQuery::new_unsized(&[ANOTHER_TEST_LEAF,
-// b"innertree2"], path_three_query),         ])
-//         .unwrap();
-//
-//     // Assert correct root hash
-//     let (root_hash, result_maps) = GroveDb::execute_proof(proof).unwrap();
-//     assert_eq!(temp_db.root_tree.root().unwrap(), root_hash);
-//
-//     // Assert correct result object
-//     let path_one_as_vec = GroveDb::compress_subtree_key(&[ANOTHER_TEST_LEAF,
-// b"innertree3"], None);     let result_map =
-// result_maps.get(&path_one_as_vec).unwrap();     let elem: Element =
-// bincode::deserialize(result_map.get(b"key4").unwrap().unwrap()).unwrap();
-//     assert_eq!(elem, Element::Item(b"value4".to_vec()));
-//
-//     let path_two_as_vec = GroveDb::compress_subtree_key(&[ANOTHER_TEST_LEAF,
-// b"innertree2"], None);     let result_map =
-// result_maps.get(&path_two_as_vec).unwrap();     let elem: Element =
-// bincode::deserialize(result_map.get(b"key3").unwrap().unwrap()).unwrap();
-//     assert_eq!(elem, Element::Item(b"value3".to_vec()));
-// }
+#[test]
+fn test_proof_construction() {
+    let temp_db = make_grovedb_for_proofs();
+
+    // Generating a proof for three paths
+    // root -> test_leaf -> innertree (prove both key1 and key2)
+    // root -> another_test_leaf -> innertree3 (prove key4)
+    // root -> another_test_leaf -> innertree2 (prove key3)
+    let mut path_one_query = Query::new();
+    path_one_query.insert_key(b"key1".to_vec());
+    path_one_query.insert_key(b"key2".to_vec());
+
+    let mut path_two_query = Query::new();
+    path_two_query.insert_key(b"key4".to_vec());
+
+    let mut path_three_query = Query::new();
+    path_three_query.insert_key(b"key3".to_vec());
+
+    let path_one = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    let path_two = vec![ANOTHER_TEST_LEAF.to_vec(), b"innertree3".to_vec()];
+    let path_three = vec![ANOTHER_TEST_LEAF.to_vec(), b"innertree2".to_vec()];
+
+    let proof = temp_db
+        .proof(&[
+            &PathQuery::new_unsized(path_one.clone(), path_one_query.clone()),
+            &PathQuery::new_unsized(path_two.clone(), path_two_query.clone()),
+            &PathQuery::new_unsized(path_three.clone(), path_three_query.clone()),
+        ])
+        .expect("successful proof generation");
+    let proof = Proof::decode(&proof).expect("successful proof decoding");
 
-// #[test]
-// fn test_checkpoint() {
+    assert_eq!(
+        proof.query_paths,
+        vec![path_one.clone(), path_two.clone(), path_three.clone()]
+    );
+
+    // For path 1 to path 3, there are 9 nodes
+    // root is repeated three times and another_test_leaf is repeated twice
+    // Accounting for duplication, there are 6 unique nodes
+    // root, test_leaf, another_test_leaf, innertree, innertree2, innertree3
+    // layers contain all nodes except the root so we expect 5 of them
+    assert_eq!(proof.layers.len(), 5);
+    assert!(proof
+        .layers
+        .values()
+        .all(|layer| layer.hasher == Blake3Hasher::NAME));
+
+    let subtrees = temp_db.get_subtrees();
+    let prove = |path: &[&[u8]], query: Query| {
+        subtrees
+            .borrow_mut(path.iter().copied(), None)
+            .expect("subtree exists")
+            .apply(|s| s.prove(query, None, None))
+            .expect("successful merk proof")
+    };
+
+    assert_eq!(
+        proof.layers[&path_one].proof,
+        prove(&[TEST_LEAF, b"innertree"], path_one_query)
+    );
+    assert_eq!(
+        proof.layers[&path_two].proof,
+        prove(&[ANOTHER_TEST_LEAF, b"innertree3"], path_two_query)
+    );
+    assert_eq!(
+        proof.layers[&path_three].proof,
+        prove(&[ANOTHER_TEST_LEAF, b"innertree2"], path_three_query)
+    );
+
+    let mut proof_query = Query::new();
+    proof_query.insert_key(b"innertree".to_vec());
+    assert_eq!(
+        proof.layers[&vec![TEST_LEAF.to_vec()]].proof,
+        prove(&[TEST_LEAF], proof_query)
+    );
+
+    // another test leaf appeared in two paths,
+    // hence it should contain proofs for both keys
+    let mut proof_query = Query::new();
+    proof_query.insert_key(b"innertree2".to_vec());
+    proof_query.insert_key(b"innertree3".to_vec());
+    assert_eq!(
+        proof.layers[&vec![ANOTHER_TEST_LEAF.to_vec()]].proof,
+        prove(&[ANOTHER_TEST_LEAF], proof_query)
+    );
+
+    // Root proof should contain proof for both test_leaf and another_test_leaf
+    assert_eq!(proof.root_layer.root_leaf_keys, temp_db.root_leaf_keys);
+    assert_eq!(proof.root_layer.root_leaf_keys[TEST_LEAF], 0);
+    assert_eq!(proof.root_layer.root_leaf_keys[ANOTHER_TEST_LEAF], 1);
+    assert_eq!(
+        proof.root_layer.proof,
+        temp_db.root_tree.proof(&[0, 1]).to_bytes()
+    );
+}
+
+fn proven_map<'a>(results: &'a PathProofResults, path: &[Vec<u8>]) -> &'a merk::proofs::query::Map {
+    match &results[path] {
+        PathProofResult::Found { results, .. } => results,
+        result => panic!("expected the path to be found, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_successful_proof_verification() {
+    let temp_db = make_grovedb_for_proofs();
+
+    // Single query proof verification
+    let mut path_one_query = Query::new();
+    path_one_query.insert_key(b"key1".to_vec());
+    path_one_query.insert_key(b"key2".to_vec());
+    let path_one = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+
+    let proof = temp_db
+        .proof(&[&PathQuery::new_unsized(path_one.clone(), path_one_query)])
+        .unwrap();
+
+    // Assert correct root hash
+    let (root_hash, result_maps) = GroveDb::execute_proof(&proof).unwrap();
+    assert_eq!(temp_db.root_tree.root().unwrap(), root_hash);
+
+    // Assert correct result object
+    // Proof query was for two keys key1 and key2
+    let result_map = proven_map(&result_maps, &path_one);
+    let elem_1: Element = bincode::deserialize(result_map.get(b"key1").unwrap().unwrap()).unwrap();
+    let elem_2: Element = bincode::deserialize(result_map.get(b"key2").unwrap().unwrap()).unwrap();
+    assert_eq!(elem_1, Element::Item(b"value1".to_vec()));
+    assert_eq!(elem_2, Element::Item(b"value2".to_vec()));
+
+    // Multi query proof verification
+    let mut path_two_query = Query::new();
+    path_two_query.insert_key(b"key4".to_vec());
+    let path_two = vec![ANOTHER_TEST_LEAF.to_vec(), b"innertree3".to_vec()];
+
+    let mut path_three_query = Query::new();
+    path_three_query.insert_key(b"key3".to_vec());
+    let path_three = vec![ANOTHER_TEST_LEAF.to_vec(), b"innertree2".to_vec()];
+
+    let proof = temp_db
+        .proof(&[
+            &PathQuery::new_unsized(path_two.clone(), path_two_query),
+            &PathQuery::new_unsized(path_three.clone(), path_three_query),
+        ])
+        .unwrap();
+
+    // Assert correct root hash
+    let (root_hash, result_maps) = GroveDb::execute_proof(&proof).unwrap();
+    assert_eq!(temp_db.root_tree.root().unwrap(), root_hash);
+
+    // Assert correct result object
+    let result_map = proven_map(&result_maps, &path_two);
+    let elem: Element = bincode::deserialize(result_map.get(b"key4").unwrap().unwrap()).unwrap();
+    assert_eq!(elem, Element::Item(b"value4".to_vec()));
+
+    let result_map = proven_map(&result_maps, &path_three);
+    let elem: Element = bincode::deserialize(result_map.get(b"key3").unwrap().unwrap()).unwrap();
+    assert_eq!(elem, Element::Item(b"value3".to_vec()));
+}
+
+#[test]
+fn test_proof_rejects_limit_and_offset() {
+    let temp_db = make_grovedb_for_proofs();
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+
+    // Merk proofs don't apply limits and offsets, so such results couldn't be
+    // verified
+    let mut query = Query::new();
+    query.insert_all();
+    let path_query = PathQuery::new(path, SizedQuery::new(query, Some(1), Some(1)));
+    assert!(matches!(
+        temp_db.proof(&[&path_query]),
+        Err(Error::InvalidQuery(_))
+    ));
+}
+
+#[test]
+fn test_tampered_proof_is_rejected() {
+    let temp_db = make_grovedb_for_proofs();
+    let mut query = Query::new();
+    query.insert_key(b"key1".to_vec());
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    let proof = temp_db
+        .proof(&[&PathQuery::new_unsized(path.clone(), query)])
+        .unwrap();
+
+    // Replace the proven value in the leaf layer
+    let mut decoded = Proof::decode(&proof).unwrap();
+    let layer = &mut decoded.layers.get_mut(&path).unwrap().proof;
+    let value = bincode::serialize(&Element::Item(b"value1".to_vec())).unwrap();
+    let position = layer
+        .windows(value.len())
+        .position(|w| w == value.as_slice())
+        .expect("value is in the proof");
+    layer[position + value.len() - 1] ^= 1;
+    let tampered = decoded.encode().unwrap();
+
+    match GroveDb::execute_proof(&tampered) {
+        Ok((root_hash, _)) => assert_ne!(root_hash, temp_db.root_tree.root().unwrap()),
+        Err(e) => assert!(matches!(e, Error::InvalidProof(_))),
+    }
+
+    // Layers that no query needs are rejected
+    let mut decoded = Proof::decode(&proof).unwrap();
+    decoded.layers.insert(
+        vec![ANOTHER_TEST_LEAF.to_vec()],
+        LayerProof {
+            hasher: Blake3Hasher::NAME.to_vec(),
+            proof: Vec::new(),
+        },
+    );
+    assert!(matches!(
+        GroveDb::execute_proof(&decoded.encode().unwrap()),
+        Err(Error::InvalidProof(_))
+    ));
+}
+
+#[test]
+fn test_absence_proof() {
+    let temp_db = make_grovedb_for_proofs();
+    let mut query = Query::new();
+    query.insert_key(b"key1".to_vec());
+
+    let missing_leaf = vec![
+        TEST_LEAF.to_vec(),
+        b"innertree".to_vec(),
+        b"missing".to_vec(),
+    ];
+    let through_item = vec![
+        TEST_LEAF.to_vec(),
+        b"innertree".to_vec(),
+        b"key1".to_vec(),
+        b"deeper".to_vec(),
+    ];
+    let missing_intermediate = vec![
+        ANOTHER_TEST_LEAF.to_vec(),
+        b"missing".to_vec(),
+        b"deeper".to_vec(),
+    ];
+    let existing = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+
+    let proof = temp_db
+        .proof(&[
+            &PathQuery::new_unsized(missing_leaf.clone(), query.clone()),
+            &PathQuery::new_unsized(through_item.clone(), query.clone()),
+            &PathQuery::new_unsized(missing_intermediate.clone(), query.clone()),
+            &PathQuery::new_unsized(existing.clone(), query.clone()),
+        ])
+        .unwrap();
+
+    let (root_hash, results) = GroveDb::execute_proof(&proof).unwrap();
+    assert_eq!(temp_db.root_tree.root().unwrap(), root_hash);
+    assert!(matches!(
+        results[&missing_leaf],
+        PathProofResult::PathNotFound { existing_depth: 2 }
+    ));
+    assert!(matches!(
+        results[&through_item],
+        PathProofResult::PathNotFound { existing_depth: 2 }
+    ));
+    assert!(matches!(
+        results[&missing_intermediate],
+        PathProofResult::PathNotFound { existing_depth: 1 }
+    ));
+    let elem: Element = bincode::deserialize(
+        proven_map(&results, &existing)
+            .get(b"key1")
+            .unwrap()
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(elem, Element::Item(b"value1".to_vec()));
+
+    // Absence in an empty subtree is proven as well
+    let mut temp_db = temp_db;
+    temp_db
+        .insert([TEST_LEAF], b"empty", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    let empty_subtree = vec![TEST_LEAF.to_vec(), b"empty".to_vec(), b"missing".to_vec()];
+    let proof = temp_db
+        .proof(&[&PathQuery::new_unsized(
+            empty_subtree.clone(),
+            query.clone(),
+        )])
+        .unwrap();
+    let (root_hash, results) = GroveDb::execute_proof(&proof).unwrap();
+    assert_eq!(temp_db.root_tree.root().unwrap(), root_hash);
+    assert!(matches!(
+        results[&empty_subtree],
+        PathProofResult::PathNotFound { existing_depth: 2 }
+    ));
+
+    // Root leafs are not keyed in the root tree so their absence cannot be proven
+    assert!(matches!(
+        temp_db.proof(&[&PathQuery::new_unsized(vec![b"missing".to_vec()], query)]),
+        Err(Error::PathNotFound(_))
+    ));
+}
+
+#[test]
+fn test_absence_proof_of_existing_subtree_is_rejected() {
+    let temp_db = make_grovedb_for_proofs();
+    let mut query = Query::new();
+    query.insert_key(b"key1".to_vec());
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    let proof = temp_db
+        .proof(&[&PathQuery::new_unsized(path.clone(), query)])
+        .unwrap();
+
+    // Dropping the layer of an existing subtree must not turn into an absence
+    // proof as the parent layer shows the subtree
+    let mut decoded = Proof::decode(&proof).unwrap();
+    decoded.layers.remove(&path);
+    assert!(matches!(
+        GroveDb::execute_proof(&decoded.encode().unwrap()),
+        Err(Error::InvalidProof(_))
+    ));
+}
+
+#[test]
+fn test_proof_resolves_references() {
+    let mut temp_db = make_grovedb_for_proofs();
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    // `ref2` goes through `ref1` to an item in another subtree
+    temp_db
+        .insert(
+            [TEST_LEAF, b"innertree"],
+            b"ref1",
+            Element::Reference(vec![
+                ANOTHER_TEST_LEAF.to_vec(),
+                b"innertree2".to_vec(),
+                b"key3".to_vec(),
+            ]),
+            None,
+        )
+        .expect("successful reference insert");
+    temp_db
+        .insert(
+            [TEST_LEAF, b"innertree"],
+            b"ref2",
+            Element::Reference(vec![
+                TEST_LEAF.to_vec(),
+                b"innertree".to_vec(),
+                b"ref1".to_vec(),
+            ]),
+            None,
+        )
+        .expect("successful reference insert");
+    temp_db
+        .insert(
+            [TEST_LEAF, b"innertree"],
+            b"ref3",
+            Element::Reference(vec![
+                ANOTHER_TEST_LEAF.to_vec(),
+                b"innertree2".to_vec(),
+                b"missing".to_vec(),
+            ]),
+            None,
+        )
+        .expect("successful reference insert");
+
+    let mut query = Query::new();
+    query.insert_key(b"ref2".to_vec());
+    query.insert_key(b"ref3".to_vec());
+    let proof = temp_db
+        .proof(&[&PathQuery::new_unsized(path.clone(), query)])
+        .unwrap();
+
+    let (root_hash, results) = GroveDb::execute_proof(&proof).unwrap();
+    assert_eq!(temp_db.root_tree.root().unwrap(), root_hash);
+    let references = match &results[&path] {
+        PathProofResult::Found { references, .. } => references,
+        result => panic!("expected the path to be found, got {:?}", result),
+    };
+    assert_eq!(
+        references.get(b"ref2".as_ref()),
+        Some(&Element::Item(b"value3".to_vec()))
+    );
+    // Target of `ref3` is proven to be absent
+    assert!(!references.contains_key(b"ref3".as_ref()));
+
+    // Reference targets cannot be stripped from the proof
+    let mut decoded = Proof::decode(&proof).unwrap();
+    decoded
+        .layers
+        .remove(&vec![ANOTHER_TEST_LEAF.to_vec(), b"innertree2".to_vec()]);
+    assert!(matches!(
+        GroveDb::execute_proof(&decoded.encode().unwrap()),
+        Err(Error::InvalidProof(_))
+    ));
+}
+
+#[test]
+fn test_proof_of_cyclic_references() {
+    let mut temp_db = make_grovedb_for_proofs();
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    temp_db
+        .insert(
+            [TEST_LEAF, b"innertree"],
+            b"ref1",
+            Element::Reference(vec![
+                TEST_LEAF.to_vec(),
+                b"innertree".to_vec(),
+                b"ref2".to_vec(),
+            ]),
+            None,
+        )
+        .expect("successful reference insert");
+    temp_db
+        .insert(
+            [TEST_LEAF, b"innertree"],
+            b"ref2",
+            Element::Reference(vec![
+                TEST_LEAF.to_vec(),
+                b"innertree".to_vec(),
+                b"ref1".to_vec(),
+            ]),
+            None,
+        )
+        .expect("successful reference insert");
+
+    let mut query = Query::new();
+    query.insert_key(b"ref1".to_vec());
+    assert!(matches!(
+        temp_db.proof(&[&PathQuery::new_unsized(path.clone(), query)]),
+        Err(Error::CyclicReference)
+    ));
+
+    // A proof crafted without following the references fails to verify
+    let mut query = Query::new();
+    query.insert_key(b"key1".to_vec());
+    let mut decoded = Proof::decode(
+        &temp_db
+            .proof(&[&PathQuery::new_unsized(path.clone(), query)])
+            .unwrap(),
+    )
+    .unwrap();
+    let mut query = Query::new();
+    query.insert_key(b"ref1".to_vec());
+    query.insert_key(b"ref2".to_vec());
+    let layer_proof = temp_db
+        .get_subtrees()
+        .borrow_mut([TEST_LEAF, b"innertree"], None)
+        .unwrap()
+        .apply(|merk| merk.prove(query, None, None).unwrap());
+    decoded.layers.get_mut(&path).unwrap().proof = layer_proof;
+    assert!(matches!(
+        GroveDb::execute_proof(&decoded.encode().unwrap()),
+        Err(Error::CyclicReference)
+    ));
+}
+
+#[test]
+fn test_proof_with_forged_item_value_fails() {
+    let temp_db = make_grovedb_for_proofs();
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    let mut query = Query::new();
+    query.insert_key(b"key1".to_vec());
+    let mut decoded = Proof::decode(
+        &temp_db
+            .proof(&[&PathQuery::new_unsized(path.clone(), query)])
+            .unwrap(),
+    )
+    .unwrap();
+
+    // Disguise the item as a reference bound to the item's real value hash
+    let forged_value = bincode::serialize(&Element::Item(b"forged".to_vec())).unwrap();
+    let ops: Vec<_> = merk::proofs::Decoder::new(&decoded.layers[&path].proof)
+        .map(|op| match op.unwrap() {
+            merk::proofs::Op::Push(merk::proofs::Node::KV(key, value)) if key == b"key1" => {
+                merk::proofs::Op::Push(merk::proofs::Node::KVRefValueHash(
+                    key,
+                    forged_value.clone(),
+                    value_hash(&value),
+                ))
+            }
+            op => op,
+        })
+        .collect();
+    let mut layer_proof = Vec::new();
+    merk::proofs::encode_into(ops.iter(), &mut layer_proof);
+    decoded.layers.get_mut(&path).unwrap().proof = layer_proof;
+
+    match GroveDb::execute_proof(&decoded.encode().unwrap()) {
+        Ok((root_hash, _)) => assert_ne!(root_hash, temp_db.root_tree.root().unwrap()),
+        Err(e) => assert!(matches!(e, Error::InvalidProof(_))),
+    }
+}
+
+#[test]
+fn test_proof_is_verified_with_layer_hasher() {
+    let temp_db = make_grovedb_for_proofs();
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    let mut query = Query::new();
+    query.insert_key(b"key1".to_vec());
+    let proof = temp_db
+        .proof(&[&PathQuery::new_unsized(path.clone(), query)])
+        .unwrap();
+
+    let (root_hash, _) = GroveDb::execute_proof(&proof).unwrap();
+    assert_eq!(root_hash, temp_db.root_tree.root().unwrap());
+    // A layer verified with another function doesn't match its parent
+    let mut decoded = Proof::decode(&proof).unwrap();
+    decoded.layers.get_mut(&path).unwrap().hasher = Sha256Hasher::NAME.to_vec();
+    assert!(matches!(
+        GroveDb::execute_proof(&decoded.encode().unwrap()),
+        Err(Error::InvalidProof(
+            "subtree root hash doesn't match its parent layer"
+        ))
+    ));
+    decoded.layers.get_mut(&path).unwrap().hasher = b"unknown".to_vec();
+    assert!(matches!(
+        GroveDb::execute_proof(&decoded.encode().unwrap()),
+        Err(Error::InvalidProof("unknown subtree hasher"))
+    ));
+}
+
+#[test]
+fn test_references_are_bound_to_targets() {
+    let mut temp_db = make_grovedb_for_proofs();
+    let target = vec![
+        ANOTHER_TEST_LEAF.to_vec(),
+        b"innertree2".to_vec(),
+        b"key3".to_vec(),
+    ];
+    let ref1 = Element::Reference(target.clone());
+    let ref2 = Element::Reference(vec![
+        TEST_LEAF.to_vec(),
+        b"innertree".to_vec(),
+        b"ref1".to_vec(),
+    ]);
+    temp_db
+        .insert([TEST_LEAF, b"innertree"], b"ref1", ref1.clone(), None)
+        .expect("successful reference insert");
+    temp_db
+        .insert([TEST_LEAF, b"innertree"], b"ref2", ref2.clone(), None)
+        .expect("successful reference insert");
+
+    let node_value_hash = |db: &GroveDb, path: &[&[u8]], key: &[u8]| {
+        db.get_subtrees()
+            .borrow_mut(path.iter().copied(), None)
+            .unwrap()
+            .apply(|merk| merk.get_value_hash(key).unwrap().unwrap())
+    };
+    let bound_hash = |reference: &Element, referenced_value_hash: &[u8; 32]| {
+        combine_hash(
+            &value_hash(&bincode::serialize(reference).unwrap()),
+            referenced_value_hash,
+        )
+    };
+    let assert_bound = |db: &GroveDb, referenced_value_hash: [u8; 32]| {
+        let ref1_hash = node_value_hash(db, &[TEST_LEAF, b"innertree"], b"ref1");
+        assert_eq!(ref1_hash, bound_hash(&ref1, &referenced_value_hash));
+        assert_eq!(
+            node_value_hash(db, &[TEST_LEAF, b"innertree"], b"ref2"),
+            bound_hash(&ref2, &ref1_hash)
+        );
+    };
+    assert_bound(
+        &temp_db,
+        node_value_hash(&temp_db, &[ANOTHER_TEST_LEAF, b"innertree2"], b"key3"),
+    );
+
+    // Changing the target changes the hash of the subtree holding references
+    let root_hash = temp_db.root_tree.root().unwrap();
+    let references_root_hash = temp_db.get([TEST_LEAF], b"innertree", None).unwrap();
+    temp_db
+        .insert(
+            [ANOTHER_TEST_LEAF, b"innertree2"],
+            b"key3",
+            Element::Item(b"value33".to_vec()),
+            None,
+        )
+        .expect("successful item insert");
+    assert_ne!(temp_db.root_tree.root().unwrap(), root_hash);
+    assert_ne!(
+        temp_db.get([TEST_LEAF], b"innertree", None).unwrap(),
+        references_root_hash
+    );
+    assert_bound(
+        &temp_db,
+        node_value_hash(&temp_db, &[ANOTHER_TEST_LEAF, b"innertree2"], b"key3"),
+    );
+
+    // Deleted target unbinds references until it's inserted again
+    temp_db
+        .delete([ANOTHER_TEST_LEAF, b"innertree2"], b"key3", None)
+        .expect("successful delete");
+    assert_bound(&temp_db, NULL_HASH);
+    temp_db
+        .insert(
+            [ANOTHER_TEST_LEAF, b"innertree2"],
+            b"key3",
+            Element::Item(b"value3".to_vec()),
+            None,
+        )
+        .expect("successful item insert");
+    assert_bound(
+        &temp_db,
+        node_value_hash(&temp_db, &[ANOTHER_TEST_LEAF, b"innertree2"], b"key3"),
+    );
+
+    // Overwritten reference is no longer updated with its former target
+    let ref1 = Element::Item(b"value1".to_vec());
+    temp_db
+        .insert([TEST_LEAF, b"innertree"], b"ref1", ref1, None)
+        .expect("successful item insert");
+    let ref1_hash = node_value_hash(&temp_db, &[TEST_LEAF, b"innertree"], b"ref1");
+    temp_db
+        .insert(
+            [ANOTHER_TEST_LEAF, b"innertree2"],
+            b"key3",
+            Element::Item(b"value33".to_vec()),
+            None,
+        )
+        .expect("successful item insert");
+    assert_eq!(
+        node_value_hash(&temp_db, &[TEST_LEAF, b"innertree"], b"ref1"),
+        ref1_hash
+    );
+    assert_eq!(
+        node_value_hash(&temp_db, &[TEST_LEAF, b"innertree"], b"ref2"),
+        bound_hash(&ref2, &ref1_hash)
+    );
+}
+
+#[test]
+fn test_proof_of_unbound_reference_is_rejected() {
+    let mut temp_db = make_grovedb_for_proofs();
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    // Put a reference bound to some other value bypassing GroveDb
+    temp_db
+        .get_subtrees()
+        .borrow_mut([TEST_LEAF, b"innertree"], None)
+        .unwrap()
+        .apply(|merk| {
+            Element::Reference(vec![
+                ANOTHER_TEST_LEAF.to_vec(),
+                b"innertree2".to_vec(),
+                b"key3".to_vec(),
+            ])
+            .insert_reference(merk, b"ref1", value_hash(b"value"), None)
+        })
+        .expect("successful reference insert");
+    temp_db
+        .propagate_changes([TEST_LEAF, b"innertree"], None)
+        .unwrap();
+
+    let mut query = Query::new();
+    query.insert_key(b"ref1".to_vec());
+    let proof = temp_db
+        .proof(&[&PathQuery::new_unsized(path, query)])
+        .unwrap();
+    assert!(matches!(
+        GroveDb::execute_proof(&proof),
+        Err(Error::InvalidProof("reference is not bound to its target"))
+    ));
+}
+
+#[test]
+fn test_references_into_deleted_subtrees_are_unbound() {
+    let mut db = make_grovedb();
+    let create_subtree = |db: &mut GroveDb| {
+        db.insert([TEST_LEAF], b"subtree", Element::empty_tree(), None)
+            .expect("successful subtree insert");
+        db.insert(
+            [TEST_LEAF, b"subtree"],
+            b"nested",
+            Element::empty_tree(),
+            None,
+        )
+        .expect("successful subtree insert");
+        db.insert(
+            [TEST_LEAF, b"subtree", b"nested"],
+            b"item",
+            Element::Item(b"value".to_vec()),
+            None,
+        )
+        .expect("successful item insert");
+    };
+    create_subtree(&mut db);
+    let reference_path = vec![
+        TEST_LEAF.to_vec(),
+        b"subtree".to_vec(),
+        b"nested".to_vec(),
+        b"item".to_vec(),
+    ];
+    let reference = Element::Reference(reference_path.clone());
+    db.insert([ANOTHER_TEST_LEAF], b"ref", reference.clone(), None)
+        .expect("successful reference insert");
+
+    let ref_value_hash = |db: &GroveDb| {
+        db.get_subtrees()
+            .borrow_mut([ANOTHER_TEST_LEAF], None)
+            .unwrap()
+            .apply(|merk| merk.get_value_hash(b"ref").unwrap().unwrap())
+    };
+    let unbound = combine_hash(
+        &value_hash(&bincode::serialize(&reference).unwrap()),
+        &NULL_HASH,
+    );
+    let dangling = vec![IntegrityIssue::DanglingReference {
+        path: vec![ANOTHER_TEST_LEAF.to_vec()],
+        key: b"ref".to_vec(),
+        reference_path,
+    }];
+    assert_ne!(ref_value_hash(&db), unbound);
+
+    // Deletion in a transaction
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    db.delete([TEST_LEAF], b"subtree", Some(&db_transaction))
+        .expect("successful delete");
+    assert_eq!(
+        db.get_subtrees()
+            .borrow_mut([ANOTHER_TEST_LEAF], Some(&db_transaction))
+            .unwrap()
+            .apply(|merk| merk.get_value_hash(b"ref").unwrap().unwrap()),
+        unbound
+    );
+    db.commit_transaction(db_transaction)
+        .expect("successful commit");
+    assert_eq!(ref_value_hash(&db), unbound);
+    assert_eq!(db.verify_integrity().expect("successful verify"), dangling);
+
+    // Deletion without a transaction, the target is bound again once created
+    create_subtree(&mut db);
+    assert_ne!(ref_value_hash(&db), unbound);
+    db.delete([TEST_LEAF], b"subtree", None)
+        .expect("successful delete");
+    assert_eq!(ref_value_hash(&db), unbound);
+    assert_eq!(db.verify_integrity().expect("successful verify"), dangling);
+
+    // Moved subtree leaves references pointing to the source
+    create_subtree(&mut db);
+    db.move_subtree([TEST_LEAF, b"subtree"], [TEST_LEAF, b"moved"], None)
+        .expect("successful move");
+    assert_eq!(ref_value_hash(&db), unbound);
+    assert_eq!(db.verify_integrity().expect("successful verify"), dangling);
+}
+
+#[test]
+fn test_subtrees_are_bound_to_parent_layers() {
+    let mut temp_db = make_grovedb_for_proofs();
+    let path = vec![TEST_LEAF.to_vec(), b"innertree".to_vec()];
+    let parent_value_hash = |db: &GroveDb| {
+        db.get_subtrees()
+            .borrow_mut([TEST_LEAF], None)
+            .unwrap()
+            .apply(|merk| merk.get_value_hash(b"innertree").unwrap().unwrap())
+    };
+    let subtree = temp_db.get([TEST_LEAF], b"innertree", None).unwrap();
+    let subtree_root_hash = match subtree {
+        Element::Tree(root_hash) => root_hash,
+        _ => panic!("expected a subtree"),
+    };
+    assert_eq!(
+        parent_value_hash(&temp_db),
+        layered_value_hash(
+            &value_hash(&bincode::serialize(&subtree).unwrap()),
+            &subtree_root_hash
+        )
+    );
+
+    // Neither a plain value nor a reference with the bytes of a tree element
+    // commits to a subtree
+    let element_bytes = bincode::serialize(&subtree).unwrap();
+    for op in [
+        Op::Put(element_bytes.clone()),
+        Op::PutReference(element_bytes.clone(), subtree_root_hash),
+    ] {
+        temp_db
+            .get_subtrees()
+            .borrow_mut([TEST_LEAF], None)
+            .unwrap()
+            .apply(|merk| merk.apply::<_, Vec<u8>>(&[(b"innertree".to_vec(), op)], &[], None))
+            .unwrap();
+        temp_db.propagate_changes([TEST_LEAF], None).unwrap();
+
+        let mut query = Query::new();
+        query.insert_key(b"key1".to_vec());
+        let proof = temp_db
+            .proof(&[&PathQuery::new_unsized(path.clone(), query)])
+            .unwrap();
+        assert!(matches!(
+            GroveDb::execute_proof(&proof),
+            Err(Error::InvalidProof(
+                "intermediate proof layers should be for trees"
+            ))
+        ));
+    }
+}
+
+// #[test]
+// fn test_checkpoint() {
 //     let mut db = make_grovedb();
 //     let element1 = Element::Item(b"ayy".to_vec());
 //
@@ -921,126 +1839,1289 @@ fn transaction_should_be_aborted() {
 }
 
 #[test]
-fn test_subtree_pairs_iterator() {
-    let mut db = make_grovedb();
-    let element = Element::Item(b"ayy".to_vec());
-    let element2 = Element::Item(b"lmao".to_vec());
+fn test_subtree_pairs_iterator() {
+    let mut db = make_grovedb();
+    let element = Element::Item(b"ayy".to_vec());
+    let element2 = Element::Item(b"lmao".to_vec());
+
+    // Insert some nested subtrees
+    db.insert([TEST_LEAF], b"subtree1", Element::empty_tree(), None)
+        .expect("successful subtree 1 insert");
+    db.insert(
+        [TEST_LEAF, b"subtree1"],
+        b"subtree11",
+        Element::empty_tree(),
+        None,
+    )
+    .expect("successful subtree 2 insert");
+    // Insert an element into subtree
+    db.insert(
+        [TEST_LEAF, b"subtree1", b"subtree11"],
+        b"key1",
+        element.clone(),
+        None,
+    )
+    .expect("successful value insert");
+    assert_eq!(
+        db.get([TEST_LEAF, b"subtree1", b"subtree11"], b"key1", None)
+            .expect("successful get 1"),
+        element
+    );
+    db.insert(
+        [TEST_LEAF, b"subtree1", b"subtree11"],
+        b"key0",
+        element.clone(),
+        None,
+    )
+    .expect("successful value insert");
+    db.insert(
+        [TEST_LEAF, b"subtree1"],
+        b"subtree12",
+        Element::empty_tree(),
+        None,
+    )
+    .expect("successful subtree 3 insert");
+    db.insert([TEST_LEAF, b"subtree1"], b"key1", element.clone(), None)
+        .expect("successful value insert");
+    db.insert([TEST_LEAF, b"subtree1"], b"key2", element2.clone(), None)
+        .expect("successful value insert");
+
+    // Iterate over subtree1 to see if keys of other subtrees messed up
+    // let mut iter = db
+    //     .elements_iterator(&[TEST_LEAF, b"subtree1"], None)
+    //     .expect("cannot create iterator");
+    let subtrees = db.get_subtrees();
+    let merk = subtrees.borrow_mut([TEST_LEAF, b"subtree1"], None).unwrap();
+    let mut iter = Element::iterator(merk.raw_iter(None));
+    assert_eq!(iter.next().unwrap(), Some((b"key1".to_vec(), element)));
+    assert_eq!(iter.next().unwrap(), Some((b"key2".to_vec(), element2)));
+    let subtree_element = iter.next().unwrap().unwrap();
+    assert_eq!(subtree_element.0, b"subtree11".to_vec());
+    assert!(matches!(subtree_element.1, Element::Tree(_)));
+    let subtree_element = iter.next().unwrap().unwrap();
+    assert_eq!(subtree_element.0, b"subtree12".to_vec());
+    assert!(matches!(subtree_element.1, Element::Tree(_)));
+    assert!(matches!(iter.next(), Ok(None)));
+}
+
+#[test]
+fn test_compress_path_not_possible_collision() {
+    let path_a = [b"aa".as_ref(), b"b"];
+    let path_b = [b"a".as_ref(), b"ab"];
+    assert_ne!(
+        compress_subtree_key(path_a, None),
+        compress_subtree_key(path_b, None)
+    );
+    assert_eq!(
+        compress_subtree_key(path_a, None),
+        compress_subtree_key(path_a, None),
+    );
+}
+
+#[test]
+fn test_compress_path_is_platform_independent() {
+    let mut expected = b"abc".to_vec();
+    expected.extend(2u64.to_le_bytes());
+    expected.extend(2u64.to_le_bytes());
+    expected.extend(1u64.to_le_bytes());
+    assert_eq!(
+        compress_subtree_key([b"ab".as_ref(), b"c"], None),
+        blake3::hash(&expected).as_bytes().to_vec()
+    );
+}
+
+/// Prefix derivation of a big-endian 32-bit platform to simulate databases
+/// created there
+fn big_endian_32_prefix(path: &[Vec<u8>]) -> Vec<u8> {
+    let mut res = Vec::new();
+    let mut lengthes = Vec::new();
+    for segment in path {
+        res.extend_from_slice(segment);
+        lengthes.extend((segment.len() as u32).to_be_bytes());
+    }
+    res.extend((path.len() as u32).to_be_bytes());
+    res.extend(lengthes);
+    blake3::hash(&res).as_bytes().to_vec()
+}
+
+#[test]
+fn test_prefix_scheme_migration() {
+    use operations::prefix_scheme::current_prefix;
+
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    db.insert([TEST_LEAF, b"key1"], b"key2", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    db.insert(
+        [TEST_LEAF, b"key1", b"key2"],
+        b"item",
+        Element::Item(b"ayy".to_vec()),
+        None,
+    )
+    .expect("successful item insert");
+    let reference_path = vec![
+        TEST_LEAF.to_vec(),
+        b"key1".to_vec(),
+        b"key2".to_vec(),
+        b"item".to_vec(),
+    ];
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"reference",
+        Element::Reference(reference_path),
+        None,
+    )
+    .expect("successful reference insert");
+    let root_hash = db.root_hash(None);
+    let subtrees = db
+        .find_subtrees([TEST_LEAF], None)
+        .expect("successful find");
+    let stored_prefixes = db
+        .meta_storage
+        .stored_prefixes(blake3::OUT_LEN)
+        .expect("successful prefixes listing");
+
+    // Data is moved to prefixes of another platform and back, moving it twice
+    // changes nothing as it happens when an interrupted migration is resumed
+    db.move_prefixes(current_prefix, big_endian_32_prefix)
+        .expect("successful move");
+    assert!(db
+        .get([TEST_LEAF, b"key1", b"key2"], b"item", None)
+        .is_err());
+    db.move_prefixes(big_endian_32_prefix, current_prefix)
+        .expect("successful migration");
+    db.move_prefixes(big_endian_32_prefix, current_prefix)
+        .expect("successful migration");
+
+    assert_eq!(
+        db.meta_storage
+            .stored_prefixes(blake3::OUT_LEN)
+            .expect("successful prefixes listing"),
+        stored_prefixes
+    );
+    assert_eq!(
+        db.find_subtrees([TEST_LEAF], None)
+            .expect("successful find"),
+        subtrees
+    );
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"reference", None)
+            .expect("successful get"),
+        Element::Item(b"ayy".to_vec())
+    );
+    // References index is moved too, so the reference is rebound on updates
+    db.insert(
+        [TEST_LEAF, b"key1", b"key2"],
+        b"item",
+        Element::Item(b"lmao".to_vec()),
+        None,
+    )
+    .expect("successful item update");
+    assert!(db.verify_integrity().expect("successful verify").is_empty());
+    assert_ne!(db.root_hash(None), root_hash);
+}
+
+#[test]
+fn test_prefix_scheme_version() {
+    use operations::prefix_scheme::PREFIX_SCHEME_VERSION_KEY;
+
+    let tmp_dir = TempDir::new("db").unwrap();
+    {
+        let mut db = GroveDb::open(tmp_dir.path()).unwrap();
+        assert_eq!(
+            db.meta_storage
+                .get_meta(PREFIX_SCHEME_VERSION_KEY)
+                .expect("successful meta get"),
+            Some(PREFIX_SCHEME_VERSION.to_le_bytes().to_vec())
+        );
+        add_test_leafs(&mut db);
+        db.insert([TEST_LEAF], b"key", Element::Item(b"ayy".to_vec()), None)
+            .expect("successful item insert");
+        // Database created before prefix scheme versions
+        db.meta_storage
+            .delete_meta(PREFIX_SCHEME_VERSION_KEY)
+            .expect("successful meta delete");
+        assert_eq!(db.stored_prefix_scheme_version().unwrap(), 0);
+    }
+    {
+        let db = GroveDb::open(tmp_dir.path()).unwrap();
+        assert_eq!(
+            db.stored_prefix_scheme_version().unwrap(),
+            PREFIX_SCHEME_VERSION
+        );
+        assert_eq!(
+            db.get([TEST_LEAF], b"key", None).expect("successful get"),
+            Element::Item(b"ayy".to_vec())
+        );
+        db.meta_storage
+            .put_meta(
+                PREFIX_SCHEME_VERSION_KEY,
+                &(PREFIX_SCHEME_VERSION + 1).to_le_bytes(),
+            )
+            .expect("successful meta put");
+    }
+    assert!(matches!(
+        GroveDb::open(tmp_dir.path()),
+        Err(Error::UnsupportedPrefixScheme(v)) if v == PREFIX_SCHEME_VERSION + 1
+    ));
+}
+
+#[test]
+fn test_value_bindings_migration() {
+    use operations::value_bindings::VALUE_BINDINGS_MIGRATED_KEY;
+
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"item", Element::Item(b"ayy".to_vec()), None)
+        .expect("successful item insert");
+    // References are written the way they were stored before value hashes were
+    // bound, with plain value hashes and no references index entries
+    let reference = Element::Reference(vec![TEST_LEAF.to_vec(), b"item".to_vec()]);
+    let chained_reference =
+        Element::Reference(vec![ANOTHER_TEST_LEAF.to_vec(), b"reference".to_vec()]);
+    let batch = [
+        (
+            b"chained".to_vec(),
+            Op::Put(bincode::serialize(&chained_reference).unwrap()),
+        ),
+        (
+            b"reference".to_vec(),
+            Op::Put(bincode::serialize(&reference).unwrap()),
+        ),
+    ];
+    db.get_subtrees()
+        .borrow_mut([ANOTHER_TEST_LEAF], None)
+        .expect("subtree exists")
+        .apply(|s| s.apply::<_, Vec<u8>>(&batch, &[], None))
+        .expect("successful legacy references insert");
+    db.propagate_changes([ANOTHER_TEST_LEAF], None)
+        .expect("successful propagation");
+    db.meta_storage
+        .delete_meta(VALUE_BINDINGS_MIGRATED_KEY)
+        .expect("successful meta delete");
+    assert!(!db
+        .verify_integrity()
+        .expect("successful integrity check")
+        .is_empty());
+
+    db.migrate_value_bindings().expect("successful migration");
+    assert!(db
+        .verify_integrity()
+        .expect("successful integrity check")
+        .is_empty());
+    // References are indexed, so they are rebound on updates
+    db.insert([TEST_LEAF], b"item", Element::Item(b"lmao".to_vec()), None)
+        .expect("successful item update");
+    assert!(db
+        .verify_integrity()
+        .expect("successful integrity check")
+        .is_empty());
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"chained", None)
+            .expect("successful get"),
+        Element::Item(b"lmao".to_vec())
+    );
+}
+
+/// Rewrites nodes of the subtree under `path` in the encoding used before
+/// value hashes were bound, all of them have to be plain
+fn rewrite_in_legacy_encoding(db: &GroveDb, path: &[&[u8]]) {
+    let storage = db
+        .meta_storage
+        .with_prefix(compress_subtree_key(path.iter().copied(), None))
+        .expect("successful storage open");
+    let mut nodes = Vec::new();
+    let mut iter = storage.raw_iter(None);
+    iter.seek_to_first();
+    while let Some((key, value)) = iter.key().zip(iter.value()) {
+        nodes.push((key.to_vec(), value.to_vec()));
+        iter.next();
+    }
+    drop(iter);
+    for (key, bytes) in nodes {
+        let node = <Tree as Store>::decode(&bytes).expect("successful node decode");
+        assert_eq!(*node.value_binding(), ValueBinding::Plain);
+        // Legacy nodes have no version byte and nothing between the kv hash and the
+        // value, which is the value hash and the binding tag now
+        let kv_hash_end = bytes.len() - node.value().len() - HASH_LENGTH - 1;
+        let mut legacy_bytes = bytes[1..kv_hash_end].to_vec();
+        legacy_bytes.extend(node.value());
+        storage
+            .put(key, &legacy_bytes)
+            .expect("successful node rewrite");
+    }
+}
+
+#[test]
+fn test_open_legacy_database() {
+    use operations::value_bindings::VALUE_BINDINGS_MIGRATED_KEY;
+
+    let tmp_dir = TempDir::new("db").unwrap();
+    {
+        let mut db = GroveDb::open(tmp_dir.path()).unwrap();
+        add_test_leafs(&mut db);
+        db.insert([TEST_LEAF], b"subtree", Element::empty_tree(), None)
+            .expect("successful subtree insert");
+        db.insert(
+            [TEST_LEAF, b"subtree"],
+            b"item",
+            Element::Item(b"ayy".to_vec()),
+            None,
+        )
+        .expect("successful item insert");
+
+        // The subtree and the reference are put the way they were stored before
+        // value hashes were bound
+        let subtree_root_hash = db
+            .get_subtrees()
+            .borrow_mut([TEST_LEAF, b"subtree"], None)
+            .expect("subtree exists")
+            .apply(|s| s.root_hash());
+        let subtree = Element::Tree(subtree_root_hash);
+        db.get_subtrees()
+            .borrow_mut([TEST_LEAF], None)
+            .expect("subtree exists")
+            .apply(|s| {
+                s.apply::<_, Vec<u8>>(
+                    &[(b"subtree", Op::Put(bincode::serialize(&subtree).unwrap()))],
+                    &[],
+                    None,
+                )
+            })
+            .expect("successful legacy subtree insert");
+        db.propagate_changes([TEST_LEAF], None)
+            .expect("successful propagation");
+        let reference = Element::Reference(vec![
+            TEST_LEAF.to_vec(),
+            b"subtree".to_vec(),
+            b"item".to_vec(),
+        ]);
+        db.get_subtrees()
+            .borrow_mut([ANOTHER_TEST_LEAF], None)
+            .expect("subtree exists")
+            .apply(|s| {
+                s.apply::<_, Vec<u8>>(
+                    &[(
+                        b"reference",
+                        Op::Put(bincode::serialize(&reference).unwrap()),
+                    )],
+                    &[],
+                    None,
+                )
+            })
+            .expect("successful legacy reference insert");
+        db.propagate_changes([ANOTHER_TEST_LEAF], None)
+            .expect("successful propagation");
+
+        db.meta_storage
+            .delete_meta(VALUE_BINDINGS_MIGRATED_KEY)
+            .expect("successful meta delete");
+        rewrite_in_legacy_encoding(&db, &[TEST_LEAF]);
+        rewrite_in_legacy_encoding(&db, &[TEST_LEAF, b"subtree"]);
+        rewrite_in_legacy_encoding(&db, &[ANOTHER_TEST_LEAF]);
+    }
+    {
+        let db =
+            GroveDb::open_with_config(tmp_dir.path(), GroveDbConfig::default().refuse_writes(true))
+                .unwrap();
+        assert!(!db
+            .verify_integrity()
+            .expect("successful integrity check")
+            .is_empty());
+    }
+
+    let mut db = GroveDb::open(tmp_dir.path()).expect("legacy database is opened");
+    assert!(db
+        .verify_integrity()
+        .expect("successful integrity check")
+        .is_empty());
+    let path = vec![TEST_LEAF.to_vec(), b"subtree".to_vec()];
+    let mut query = Query::new();
+    query.insert_key(b"item".to_vec());
+    let proof = db
+        .proof(&[&PathQuery::new_unsized(path.clone(), query)])
+        .expect("successful proof");
+    let (root_hash, result_maps) = GroveDb::execute_proof(&proof).expect("valid proof");
+    assert_eq!(db.root_hash(None), Some(root_hash));
+    let element: Element = bincode::deserialize(
+        proven_map(&result_maps, &path)
+            .get(b"item")
+            .unwrap()
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(element, Element::Item(b"ayy".to_vec()));
+
+    // The reference is indexed, so it's rebound on updates
+    db.insert(
+        [TEST_LEAF, b"subtree"],
+        b"item",
+        Element::Item(b"lmao".to_vec()),
+        None,
+    )
+    .expect("successful item update");
+    assert!(db
+        .verify_integrity()
+        .expect("successful integrity check")
+        .is_empty());
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"reference", None)
+            .expect("successful get"),
+        Element::Item(b"lmao".to_vec())
+    );
+}
+
+#[test]
+fn test_element_deletion() {
+    let mut db = make_grovedb();
+    let element = Element::Item(b"ayy".to_vec());
+    db.insert([TEST_LEAF], b"key", element, None)
+        .expect("successful insert");
+    let root_hash = db.root_tree.root().unwrap();
+    assert!(db.delete([TEST_LEAF], b"key", None).is_ok());
+    assert!(matches!(
+        db.get([TEST_LEAF], b"key", None),
+        Err(Error::PathKeyNotFound(_))
+    ));
+    assert_ne!(root_hash, db.root_tree.root().unwrap());
+}
+
+#[test]
+fn test_delete_range() {
+    let mut db = make_grovedb();
+    for key in [b"a", b"b", b"c", b"e"] {
+        db.insert([TEST_LEAF], key, Element::Item(key.to_vec()), None)
+            .expect("successful insert");
+    }
+    db.insert([TEST_LEAF], b"d", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    db.insert(
+        [TEST_LEAF, b"d"],
+        b"key",
+        Element::Item(b"ayy".to_vec()),
+        None,
+    )
+    .expect("successful insert");
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"ref",
+        Element::Reference(vec![TEST_LEAF.to_vec(), b"e".to_vec()]),
+        None,
+    )
+    .expect("successful reference insert");
+
+    let root_hash = db.root_tree.root().unwrap();
+    db.delete_range([TEST_LEAF], b"b", b"e", None)
+        .expect("successful range deletion");
+    assert_ne!(root_hash, db.root_tree.root().unwrap());
+    for key in [b"b", b"c", b"d"] {
+        assert!(matches!(
+            db.get([TEST_LEAF], key, None),
+            Err(Error::PathKeyNotFound(_))
+        ));
+    }
+    assert!(matches!(
+        db.get([TEST_LEAF, b"d"], b"key", None),
+        Err(Error::PathNotFound(_))
+    ));
+    assert_eq!(
+        db.get([TEST_LEAF], b"a", None).expect("item is kept"),
+        Element::Item(b"a".to_vec())
+    );
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"ref", None)
+            .expect("referenced item is kept"),
+        Element::Item(b"e".to_vec())
+    );
+
+    // Empty ranges delete nothing
+    let root_hash = db.root_tree.root().unwrap();
+    db.delete_range([TEST_LEAF], b"z", b"a", None)
+        .expect("successful range deletion");
+    assert_eq!(root_hash, db.root_tree.root().unwrap());
+}
+
+#[test]
+fn test_merge() {
+    let mut db = make_grovedb();
+    db.merge([TEST_LEAF], b"log", b"ab", APPEND_MERGE_FN, None)
+        .expect("successful merge");
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"ref",
+        Element::Reference(vec![TEST_LEAF.to_vec(), b"log".to_vec()]),
+        None,
+    )
+    .expect("successful reference insert");
+    let references_root_hash = db.get([], ANOTHER_TEST_LEAF, None).unwrap();
+    db.merge([TEST_LEAF], b"log", b"cd", APPEND_MERGE_FN, None)
+        .expect("successful merge");
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"ref", None).unwrap(),
+        Element::Item(b"abcd".to_vec())
+    );
+    // The reference is bound to the merged item
+    assert_ne!(
+        db.get([], ANOTHER_TEST_LEAF, None).unwrap(),
+        references_root_hash
+    );
+
+    db.start_transaction().unwrap();
+    let storage = db.storage();
+    let transaction = storage.transaction();
+    for _ in 0..2 {
+        db.merge(
+            [TEST_LEAF],
+            b"counter",
+            &1u64.to_be_bytes(),
+            ADD_U64_MERGE_FN,
+            Some(&transaction),
+        )
+        .expect("successful merge");
+    }
+    db.commit_transaction(transaction).unwrap();
+    assert_eq!(
+        db.get([TEST_LEAF], b"counter", None).unwrap(),
+        Element::Item(2u64.to_be_bytes().to_vec())
+    );
+
+    // Failed merges change nothing
+    let root_hash = db.root_tree.root().unwrap();
+    assert!(matches!(
+        db.merge([TEST_LEAF], b"counter", b"1", ADD_U64_MERGE_FN, None),
+        Err(Error::InvalidMerge(_))
+    ));
+    assert!(matches!(
+        db.merge([], ANOTHER_TEST_LEAF, b"1", APPEND_MERGE_FN, None),
+        Err(Error::InvalidPath(_))
+    ));
+    assert!(matches!(
+        db.merge([ANOTHER_TEST_LEAF], b"ref", b"1", APPEND_MERGE_FN, None),
+        Err(Error::InvalidMerge(_))
+    ));
+    assert_eq!(root_hash, db.root_tree.root().unwrap());
+    assert_eq!(
+        db.get([TEST_LEAF], b"log", None).unwrap(),
+        Element::Item(b"abcd".to_vec())
+    );
+
+    // Custom merge functions are registered on the database
+    fn replace(_existing: Option<&[u8]>, operand: &[u8]) -> merk::Result<Vec<u8>> {
+        Ok(operand.to_vec())
+    }
+    assert!(matches!(
+        db.merge([TEST_LEAF], b"log", b"ef", FIRST_CUSTOM_MERGE_FN, None),
+        Err(Error::InvalidMerge(_))
+    ));
+    assert!(matches!(
+        db.register_merge_fn(APPEND_MERGE_FN, replace),
+        Err(Error::InvalidMerge(_))
+    ));
+    db.register_merge_fn(FIRST_CUSTOM_MERGE_FN, replace)
+        .expect("successful merge function registration");
+    db.merge([TEST_LEAF], b"log", b"ef", FIRST_CUSTOM_MERGE_FN, None)
+        .expect("successful merge");
+    assert_eq!(
+        db.get([TEST_LEAF], b"log", None).unwrap(),
+        Element::Item(b"ef".to_vec())
+    );
+}
+
+#[test]
+fn test_import_subtree() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"imported", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    let items: Vec<(Vec<u8>, Vec<u8>)> = (0u32..100)
+        .map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+        .collect();
+
+    let root_hash = db.root_tree.root().unwrap();
+    db.import_subtree([TEST_LEAF, b"imported"], items.clone(), items.len(), None)
+        .expect("successful import");
+    assert_ne!(root_hash, db.root_tree.root().unwrap());
+    for (key, item) in items.iter() {
+        assert_eq!(
+            db.get([TEST_LEAF, b"imported"], key, None).unwrap(),
+            Element::Item(item.clone())
+        );
+    }
+
+    assert!(matches!(
+        db.import_subtree([TEST_LEAF, b"imported"], items, 100, None),
+        Err(Error::InvalidPath(_))
+    ));
+}
+
+#[test]
+fn test_import_subtree_binds_references() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"imported", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    let key = 7u32.to_be_bytes().to_vec();
+    db.insert(
+        [ANOTHER_TEST_LEAF],
+        b"ref",
+        Element::Reference(vec![TEST_LEAF.to_vec(), b"imported".to_vec(), key.clone()]),
+        None,
+    )
+    .expect("successful reference insert");
+    let references_root_hash = db.get([], ANOTHER_TEST_LEAF, None).unwrap();
+
+    let items: Vec<(Vec<u8>, Vec<u8>)> = (0u32..10)
+        .map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+        .collect();
+    db.import_subtree([TEST_LEAF, b"imported"], items, 10, None)
+        .expect("successful import");
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF], b"ref", None).unwrap(),
+        Element::Item(7u32.to_le_bytes().to_vec())
+    );
+    // The reference is bound to the imported item
+    assert_ne!(
+        db.get([], ANOTHER_TEST_LEAF, None).unwrap(),
+        references_root_hash
+    );
+    assert_eq!(db.verify_integrity().unwrap(), vec![]);
+}
+
+#[test]
+fn test_iter() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"iterated", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    // More items than are read from storage at once
+    let items: Vec<(Vec<u8>, Vec<u8>)> = (0u32..600)
+        .map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+        .collect();
+    db.import_subtree([TEST_LEAF, b"iterated"], items.clone(), items.len(), None)
+        .expect("successful import");
+    let path = [TEST_LEAF, b"iterated"];
+    let entry = |i: u32| {
+        (
+            i.to_be_bytes().to_vec(),
+            Element::Item(i.to_le_bytes().to_vec()),
+        )
+    };
+
+    let all: Vec<_> = db
+        .iter(path, QueryItem::RangeFull(..), true, None)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(all, (0..600).map(entry).collect::<Vec<_>>());
+
+    let range = QueryItem::Range(100u32.to_be_bytes().to_vec()..500u32.to_be_bytes().to_vec());
+    let mut iter = db.iter(path, range.clone(), false, None).unwrap();
+    let first: Vec<_> = iter.by_ref().take(300).map(Result::unwrap).collect();
+    assert_eq!(first, (200..500).rev().map(entry).collect::<Vec<_>>());
+    let last_key = first.last().unwrap().0.clone();
+
+    // Resuming with a new iterator continues where the previous one stopped
+    let mut resumed = db.iter(path, range.clone(), false, None).unwrap();
+    resumed.resume_after(&last_key);
+    let rest: Vec<_> = resumed.map(Result::unwrap).collect();
+    assert_eq!(rest, (100..200).rev().map(entry).collect::<Vec<_>>());
+    assert_eq!(iter.map(Result::unwrap).collect::<Vec<_>>(), rest);
+
+    let mut iter = db.iter(path, range, true, None).unwrap();
+    iter.seek(&450u32.to_be_bytes());
+    assert_eq!(iter.count(), 50);
+
+    assert!(matches!(
+        db.iter(
+            [TEST_LEAF, b"missing"],
+            QueryItem::RangeFull(..),
+            true,
+            None
+        ),
+        Err(Error::PathNotFound(_))
+    ));
+
+    db.start_transaction().unwrap();
+    let storage = db.storage();
+    let transaction = storage.transaction();
+    db.insert(
+        path,
+        &1000u32.to_be_bytes(),
+        Element::Item(b"new".to_vec()),
+        Some(&transaction),
+    )
+    .expect("successful insert");
+    let last_in = |transaction| {
+        db.iter(path, QueryItem::RangeFull(..), false, transaction)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+    };
+    assert_eq!(last_in(Some(&transaction)).0, 1000u32.to_be_bytes());
+    assert_eq!(last_in(None), entry(599));
+}
+
+#[test]
+fn test_find_subtrees() {
+    let element = Element::Item(b"ayy".to_vec());
+    let mut db = make_grovedb();
+    // Insert some nested subtrees
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree 1 insert");
+    db.insert([TEST_LEAF, b"key1"], b"key2", Element::empty_tree(), None)
+        .expect("successful subtree 2 insert");
+    // Insert an element into subtree
+    db.insert([TEST_LEAF, b"key1", b"key2"], b"key3", element, None)
+        .expect("successful value insert");
+    db.insert([TEST_LEAF], b"key4", Element::empty_tree(), None)
+        .expect("successful subtree 3 insert");
+    let subtrees = db
+        .find_subtrees(vec![TEST_LEAF], None)
+        .expect("cannot get subtrees");
+    assert_eq!(
+        vec![
+            vec![TEST_LEAF],
+            vec![TEST_LEAF, b"key1"],
+            vec![TEST_LEAF, b"key4"],
+            vec![TEST_LEAF, b"key1", b"key2"],
+        ],
+        subtrees
+    );
+}
+
+#[test]
+fn test_find_subtrees_by_index() {
+    let mut db = make_grovedb();
+    assert!(db.children_indexed);
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree 1 insert");
+    db.insert([TEST_LEAF, b"key1"], b"key2", Element::empty_tree(), None)
+        .expect("successful subtree 2 insert");
+    db.insert([TEST_LEAF], b"key3", Element::empty_tree(), None)
+        .expect("successful subtree 3 insert");
+    // Overwriting a subtree doesn't index it twice
+    db.insert([TEST_LEAF], b"key3", Element::empty_tree(), None)
+        .expect("successful subtree 3 insert");
+
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    db.insert(
+        [TEST_LEAF, b"key1", b"key2"],
+        b"key4",
+        Element::empty_tree(),
+        Some(&db_transaction),
+    )
+    .expect("successful subtree 4 insert");
+    db.delete([TEST_LEAF], b"key3", Some(&db_transaction))
+        .expect("successful subtree 3 delete");
+    let in_transaction = vec![
+        vec![TEST_LEAF.to_vec()],
+        vec![TEST_LEAF.to_vec(), b"key1".to_vec()],
+        vec![TEST_LEAF.to_vec(), b"key1".to_vec(), b"key2".to_vec()],
+        vec![
+            TEST_LEAF.to_vec(),
+            b"key1".to_vec(),
+            b"key2".to_vec(),
+            b"key4".to_vec(),
+        ],
+    ];
+    assert_eq!(
+        db.find_subtrees([TEST_LEAF], Some(&db_transaction))
+            .expect("successful find"),
+        in_transaction
+    );
+    assert_eq!(
+        db.find_subtrees([TEST_LEAF], None)
+            .expect("successful find"),
+        db.find_subtrees_by_scan([TEST_LEAF], None)
+            .expect("successful scan")
+    );
+    db.commit_transaction(db_transaction)
+        .expect("successful commit");
+    assert_eq!(
+        db.find_subtrees([TEST_LEAF], None)
+            .expect("successful find"),
+        in_transaction
+    );
+
+    db.delete([TEST_LEAF], b"key1", None)
+        .expect("successful subtree 1 delete");
+    assert_eq!(
+        db.find_subtrees([TEST_LEAF], None)
+            .expect("successful find"),
+        vec![vec![TEST_LEAF.to_vec()]]
+    );
+}
+
+#[test]
+fn test_subtree_deletion_clears_data() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree 1 insert");
+    db.insert([TEST_LEAF, b"key1"], b"key2", Element::empty_tree(), None)
+        .expect("successful subtree 2 insert");
+    db.insert(
+        [TEST_LEAF, b"key1", b"key2"],
+        b"key3",
+        Element::Item(b"ayy".to_vec()),
+        None,
+    )
+    .expect("successful value insert");
+    db.put_aux(b"aux", b"value", None)
+        .expect("successful aux insert");
+    let prefixes = [
+        compress_subtree_key([TEST_LEAF, b"key1"], None),
+        compress_subtree_key([TEST_LEAF, b"key1", b"key2"], None),
+    ];
+
+    db.delete([TEST_LEAF], b"key1", None)
+        .expect("successful subtree delete");
+    let stored_prefixes = db
+        .meta_storage
+        .stored_prefixes(blake3::OUT_LEN)
+        .expect("successful prefixes listing");
+    for prefix in &prefixes {
+        assert!(!stored_prefixes.contains(prefix));
+    }
+    assert!(!db.has_pending_clears);
+    assert_eq!(
+        db.get_aux(b"aux", None).expect("successful aux get"),
+        Some(b"value".to_vec())
+    );
+
+    // Subtree inserted again under the same path is empty
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree 1 insert");
+    assert!(db
+        .is_empty_tree([TEST_LEAF, b"key1"], None)
+        .expect("successful emptiness check"));
+    assert!(db.verify_integrity().expect("successful verify").is_empty());
+}
+
+#[test]
+fn test_transactional_subtree_deletion_clears_data_on_commit() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree 1 insert");
+    db.insert([TEST_LEAF, b"key1"], b"key2", Element::empty_tree(), None)
+        .expect("successful subtree 2 insert");
+    db.insert(
+        [TEST_LEAF, b"key1", b"key2"],
+        b"key3",
+        Element::Item(b"ayy".to_vec()),
+        None,
+    )
+    .expect("successful value insert");
+    let prefixes = [
+        compress_subtree_key([TEST_LEAF, b"key1"], None),
+        compress_subtree_key([TEST_LEAF, b"key1", b"key2"], None),
+    ];
+    let stored_prefixes = |db: &GroveDb| {
+        db.meta_storage
+            .stored_prefixes(blake3::OUT_LEN)
+            .expect("successful prefixes listing")
+    };
+
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    db.delete([TEST_LEAF], b"key1", Some(&db_transaction))
+        .expect("successful subtree delete");
+    // Data stays until the deletion is committed
+    for prefix in &prefixes {
+        assert!(stored_prefixes(&db).contains(prefix));
+    }
+    db.commit_transaction(db_transaction)
+        .expect("successful commit");
+    for prefix in &prefixes {
+        assert!(!stored_prefixes(&db).contains(prefix));
+    }
+    assert!(!db.has_pending_clears);
+
+    // A subtree deleted and created again by the same transaction keeps its new
+    // data
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree 1 insert");
+    db.insert(
+        [TEST_LEAF, b"key1"],
+        b"key2",
+        Element::Item(b"ayy".to_vec()),
+        None,
+    )
+    .expect("successful value insert");
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    db.delete([TEST_LEAF], b"key1", Some(&db_transaction))
+        .expect("successful subtree delete");
+    db.insert(
+        [TEST_LEAF],
+        b"key1",
+        Element::empty_tree(),
+        Some(&db_transaction),
+    )
+    .expect("successful subtree 1 insert");
+    db.insert(
+        [TEST_LEAF, b"key1"],
+        b"key3",
+        Element::Item(b"lmao".to_vec()),
+        Some(&db_transaction),
+    )
+    .expect("successful value insert");
+    db.commit_transaction(db_transaction)
+        .expect("successful commit");
+    assert!(matches!(
+        db.get([TEST_LEAF, b"key1"], b"key2", None),
+        Err(Error::PathKeyNotFound(_))
+    ));
+    assert_eq!(
+        db.get([TEST_LEAF, b"key1"], b"key3", None)
+            .expect("successful get"),
+        Element::Item(b"lmao".to_vec())
+    );
+    assert!(db.verify_integrity().expect("successful verify").is_empty());
+}
+
+#[test]
+fn test_pending_clears_survive_failed_operations() {
+    let mut db = make_grovedb();
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    let prefix = compress_subtree_key([TEST_LEAF, b"key1"], None);
+    let mut merk = db
+        .get_subtrees()
+        .get_subtree_without_transaction([TEST_LEAF])
+        .expect("subtree exists");
+    Element::delete(&mut merk, b"key1", None).expect("successful delete");
+    db.schedule_clears(vec![prefix.clone()], None)
+        .expect("successful schedule");
+
+    assert!(db
+        .insert(
+            [b"nonexistent".as_ref()],
+            b"key",
+            Element::empty_tree(),
+            None
+        )
+        .is_err());
+    assert!(db.has_pending_clears);
+
+    db.insert([TEST_LEAF], b"key2", Element::Item(b"ayy".to_vec()), None)
+        .expect("successful value insert");
+    assert!(!db.has_pending_clears);
+    assert!(!db
+        .meta_storage
+        .stored_prefixes(blake3::OUT_LEN)
+        .expect("successful prefixes listing")
+        .contains(&prefix));
+}
+
+#[test]
+fn test_subtree_index_and_pending_clears_are_recovered() {
+    let tmp_dir = TempDir::new("db").unwrap();
+    let prefix = compress_subtree_key([TEST_LEAF, b"key1"], None);
+    {
+        let mut db = GroveDb::open(tmp_dir.path()).unwrap();
+        add_test_leafs(&mut db);
+        db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+            .expect("successful subtree insert");
+        db.insert(
+            [TEST_LEAF, b"key1"],
+            b"key2",
+            Element::Item(b"ayy".to_vec()),
+            None,
+        )
+        .expect("successful value insert");
 
-    // Insert some nested subtrees
-    db.insert([TEST_LEAF], b"subtree1", Element::empty_tree(), None)
-        .expect("successful subtree 1 insert");
+        // Subtree deletion is written, but the process stops before its data is
+        // cleared; the database is also made look as if it was never indexed
+        let mut merk = db
+            .get_subtrees()
+            .get_subtree_without_transaction([TEST_LEAF])
+            .expect("subtree exists");
+        Element::delete(&mut merk, b"key1", None).expect("successful delete");
+        db.schedule_clears(vec![prefix.clone()], None)
+            .expect("successful schedule");
+        db.meta_storage
+            .delete_meta(CHILDREN_INDEXED_KEY)
+            .expect("successful meta delete");
+    }
+
+    let db = GroveDb::open(tmp_dir.path()).unwrap();
+    assert!(db.children_indexed);
+    assert!(!db.has_pending_clears);
+    assert!(!db
+        .meta_storage
+        .stored_prefixes(blake3::OUT_LEN)
+        .expect("successful prefixes listing")
+        .contains(&prefix));
+    assert_eq!(
+        db.find_subtrees([TEST_LEAF], None)
+            .expect("successful find"),
+        vec![vec![TEST_LEAF.to_vec()]]
+    );
+}
+
+fn make_copy_source(db: &mut GroveDb) {
+    db.insert([TEST_LEAF], b"src", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    db.insert([TEST_LEAF, b"src"], b"nested", Element::empty_tree(), None)
+        .expect("successful subtree insert");
     db.insert(
-        [TEST_LEAF, b"subtree1"],
-        b"subtree11",
-        Element::empty_tree(),
+        [TEST_LEAF, b"src", b"nested"],
+        b"item",
+        Element::Item(b"ayy".to_vec()),
         None,
     )
-    .expect("successful subtree 2 insert");
-    // Insert an element into subtree
+    .expect("successful item insert");
     db.insert(
-        [TEST_LEAF, b"subtree1", b"subtree11"],
-        b"key1",
-        element.clone(),
+        [TEST_LEAF],
+        b"outside",
+        Element::Item(b"lmao".to_vec()),
         None,
     )
-    .expect("successful value insert");
-    assert_eq!(
-        db.get([TEST_LEAF, b"subtree1", b"subtree11"], b"key1", None)
-            .expect("successful get 1"),
-        element
-    );
+    .expect("successful item insert");
     db.insert(
-        [TEST_LEAF, b"subtree1", b"subtree11"],
-        b"key0",
-        element.clone(),
+        [TEST_LEAF, b"src"],
+        b"inner_ref",
+        Element::Reference(vec![
+            TEST_LEAF.to_vec(),
+            b"src".to_vec(),
+            b"nested".to_vec(),
+            b"item".to_vec(),
+        ]),
         None,
     )
-    .expect("successful value insert");
+    .expect("successful reference insert");
     db.insert(
-        [TEST_LEAF, b"subtree1"],
-        b"subtree12",
-        Element::empty_tree(),
+        [TEST_LEAF, b"src"],
+        b"outer_ref",
+        Element::Reference(vec![TEST_LEAF.to_vec(), b"outside".to_vec()]),
         None,
     )
-    .expect("successful subtree 3 insert");
-    db.insert([TEST_LEAF, b"subtree1"], b"key1", element.clone(), None)
-        .expect("successful value insert");
-    db.insert([TEST_LEAF, b"subtree1"], b"key2", element2.clone(), None)
-        .expect("successful value insert");
-
-    // Iterate over subtree1 to see if keys of other subtrees messed up
-    // let mut iter = db
-    //     .elements_iterator(&[TEST_LEAF, b"subtree1"], None)
-    //     .expect("cannot create iterator");
-    let subtrees = db.get_subtrees();
-    let merk = subtrees.borrow_mut([TEST_LEAF, b"subtree1"], None).unwrap();
-    let mut iter = Element::iterator(merk.raw_iter(None));
-    assert_eq!(iter.next().unwrap(), Some((b"key1".to_vec(), element)));
-    assert_eq!(iter.next().unwrap(), Some((b"key2".to_vec(), element2)));
-    let subtree_element = iter.next().unwrap().unwrap();
-    assert_eq!(subtree_element.0, b"subtree11".to_vec());
-    assert!(matches!(subtree_element.1, Element::Tree(_)));
-    let subtree_element = iter.next().unwrap().unwrap();
-    assert_eq!(subtree_element.0, b"subtree12".to_vec());
-    assert!(matches!(subtree_element.1, Element::Tree(_)));
-    assert!(matches!(iter.next(), Ok(None)));
+    .expect("successful reference insert");
 }
 
 #[test]
-fn test_compress_path_not_possible_collision() {
-    let path_a = [b"aa".as_ref(), b"b"];
-    let path_b = [b"a".as_ref(), b"ab"];
-    assert_ne!(
-        GroveDb::compress_subtree_key(path_a, None),
-        GroveDb::compress_subtree_key(path_b, None)
+fn test_copy_subtree() {
+    let mut db = make_grovedb();
+    make_copy_source(&mut db);
+    let root_hash = db.root_hash(None);
+
+    db.copy_subtree([TEST_LEAF, b"src"], [ANOTHER_TEST_LEAF, b"dst"], None)
+        .expect("successful copy");
+    assert_ne!(db.root_hash(None), root_hash);
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF, b"dst", b"nested"], b"item", None)
+            .expect("successful get"),
+        Element::Item(b"ayy".to_vec())
+    );
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF, b"dst"], b"outer_ref", None)
+            .expect("successful get"),
+        Element::Item(b"lmao".to_vec())
+    );
+    // Reference inside the copy points inside the copy
+    let merk = db
+        .get_subtrees()
+        .get_subtree_without_transaction([ANOTHER_TEST_LEAF, b"dst"])
+        .expect("subtree exists");
+    assert_eq!(
+        Element::get(&merk, b"inner_ref").expect("successful get"),
+        Element::Reference(vec![
+            ANOTHER_TEST_LEAF.to_vec(),
+            b"dst".to_vec(),
+            b"nested".to_vec(),
+            b"item".to_vec(),
+        ])
+    );
+    db.insert(
+        [ANOTHER_TEST_LEAF, b"dst", b"nested"],
+        b"item",
+        Element::Item(b"changed".to_vec()),
+        None,
+    )
+    .expect("successful item update");
+    assert_eq!(
+        db.get([TEST_LEAF, b"src"], b"inner_ref", None)
+            .expect("successful get"),
+        Element::Item(b"ayy".to_vec())
     );
     assert_eq!(
-        GroveDb::compress_subtree_key(path_a, None),
-        GroveDb::compress_subtree_key(path_a, None),
+        db.get([ANOTHER_TEST_LEAF, b"dst"], b"inner_ref", None)
+            .expect("successful get"),
+        Element::Item(b"changed".to_vec())
+    );
+    assert_eq!(
+        db.find_subtrees([ANOTHER_TEST_LEAF], None)
+            .expect("successful find"),
+        vec![
+            vec![ANOTHER_TEST_LEAF.to_vec()],
+            vec![ANOTHER_TEST_LEAF.to_vec(), b"dst".to_vec()],
+            vec![
+                ANOTHER_TEST_LEAF.to_vec(),
+                b"dst".to_vec(),
+                b"nested".to_vec()
+            ],
+        ]
     );
+    assert!(db.verify_integrity().expect("successful verify").is_empty());
+
+    // Nothing is written if the copy is not possible
+    let root_hash = db.root_hash(None);
+    assert!(matches!(
+        db.copy_subtree([TEST_LEAF, b"src"], [TEST_LEAF, b"src", b"inner"], None),
+        Err(Error::InvalidPath(_))
+    ));
+    assert!(matches!(
+        db.copy_subtree([TEST_LEAF, b"src"], [ANOTHER_TEST_LEAF, b"dst"], None),
+        Err(Error::InvalidPath(_))
+    ));
+    assert!(matches!(
+        db.copy_subtree([TEST_LEAF, b"outside"], [ANOTHER_TEST_LEAF, b"other"], None),
+        Err(Error::InvalidPath(_))
+    ));
+    assert_eq!(db.root_hash(None), root_hash);
 }
 
 #[test]
-fn test_element_deletion() {
+fn test_move_subtree_in_transaction() {
     let mut db = make_grovedb();
-    let element = Element::Item(b"ayy".to_vec());
-    db.insert([TEST_LEAF], b"key", element, None)
-        .expect("successful insert");
-    let root_hash = db.root_tree.root().unwrap();
-    assert!(db.delete([TEST_LEAF], b"key", None).is_ok());
+    make_copy_source(&mut db);
+    let root_hash = db.root_hash(None);
+
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    db.move_subtree(
+        [TEST_LEAF, b"src"],
+        [ANOTHER_TEST_LEAF, b"dst"],
+        Some(&db_transaction),
+    )
+    .expect("successful move");
+    assert!(db
+        .get([ANOTHER_TEST_LEAF, b"dst"], b"inner_ref", None)
+        .is_err());
+    assert_eq!(
+        db.get(
+            [ANOTHER_TEST_LEAF, b"dst"],
+            b"inner_ref",
+            Some(&db_transaction)
+        )
+        .expect("successful get"),
+        Element::Item(b"ayy".to_vec())
+    );
+    assert!(db.get([TEST_LEAF], b"src", Some(&db_transaction)).is_err());
+    assert_eq!(db.root_hash(None), root_hash);
+    db.commit_transaction(db_transaction)
+        .expect("successful commit");
+
+    assert_ne!(db.root_hash(None), root_hash);
     assert!(matches!(
-        db.get([TEST_LEAF], b"key", None),
+        db.get([TEST_LEAF], b"src", None),
         Err(Error::PathKeyNotFound(_))
     ));
-    assert_ne!(root_hash, db.root_tree.root().unwrap());
+    assert_eq!(
+        db.get([ANOTHER_TEST_LEAF, b"dst", b"nested"], b"item", None)
+            .expect("successful get"),
+        Element::Item(b"ayy".to_vec())
+    );
+    assert!(db.verify_integrity().expect("successful verify").is_empty());
 }
 
 #[test]
-fn test_find_subtrees() {
-    let element = Element::Item(b"ayy".to_vec());
-    let mut db = make_grovedb();
-    // Insert some nested subtrees
+fn test_merk_cache() {
+    let tmp_dir = TempDir::new("db").unwrap();
+    let mut db = GroveDb::open(tmp_dir.path()).unwrap();
+    add_test_leafs(&mut db);
+    let prefix = compress_subtree_key([TEST_LEAF, b"key1"], None);
     db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
-        .expect("successful subtree 1 insert");
-    db.insert([TEST_LEAF, b"key1"], b"key2", Element::empty_tree(), None)
-        .expect("successful subtree 2 insert");
-    // Insert an element into subtree
-    db.insert([TEST_LEAF, b"key1", b"key2"], b"key3", element, None)
-        .expect("successful value insert");
-    db.insert([TEST_LEAF], b"key4", Element::empty_tree(), None)
-        .expect("successful subtree 3 insert");
-    let subtrees = db
-        .find_subtrees(vec![TEST_LEAF], None)
-        .expect("cannot get subtrees");
+        .expect("successful subtree insert");
+    for i in 0u8..10 {
+        db.insert([TEST_LEAF, b"key1"], &[i], Element::Item(vec![i]), None)
+            .expect("successful value insert");
+    }
+    assert!(db.merk_cache.borrow().contains(&prefix));
     assert_eq!(
-        vec![
-            vec![TEST_LEAF],
-            vec![TEST_LEAF, b"key1"],
-            vec![TEST_LEAF, b"key4"],
-            vec![TEST_LEAF, b"key1", b"key2"],
-        ],
-        subtrees
+        db.get([TEST_LEAF, b"key1"], &[5], None)
+            .expect("successful get"),
+        Element::Item(vec![5])
+    );
+
+    // Failed operation drops cached Merks which could have its changes
+    assert!(db
+        .insert(
+            [TEST_LEAF, b"key1", b"missing"],
+            b"key",
+            Element::empty_tree(),
+            None
+        )
+        .is_err());
+    assert!(!db.merk_cache.borrow().contains(&prefix));
+
+    // Transaction bypasses the cache and invalidates it when committed
+    db.get([TEST_LEAF, b"key1"], &[5], None)
+        .expect("successful get");
+    let storage = db.storage();
+    let db_transaction = storage.transaction();
+    db.start_transaction().unwrap();
+    db.insert(
+        [TEST_LEAF, b"key1"],
+        &[5],
+        Element::Item(b"changed".to_vec()),
+        Some(&db_transaction),
+    )
+    .expect("successful value insert");
+    assert!(db.merk_cache.borrow().is_empty());
+    db.commit_transaction(db_transaction)
+        .expect("successful commit");
+    assert_eq!(
+        db.get([TEST_LEAF, b"key1"], &[5], None)
+            .expect("successful get"),
+        Element::Item(b"changed".to_vec())
     );
+
+    db.delete([TEST_LEAF], b"key1", None)
+        .expect("successful subtree delete");
+    assert!(!db.merk_cache.borrow().contains(&prefix));
+    assert!(db.get([TEST_LEAF, b"key1"], &[5], None).is_err());
+    let root_hash = db.root_hash(None);
+    drop(storage);
+    drop(db);
+
+    let db = GroveDb::open_with_config(tmp_dir.path(), GroveDbConfig::default().merk_cache_size(0))
+        .unwrap();
+    assert_eq!(db.root_hash(None), root_hash);
+    db.get([TEST_LEAF], b"key1", None).unwrap_err();
+    assert!(db.merk_cache.borrow().is_empty());
+}
+
+#[test]
+fn test_retention_policy() {
+    let fill = |config: GroveDbConfig| {
+        let tmp_dir = TempDir::new("db").unwrap();
+        let mut db = GroveDb::open_with_config(tmp_dir.path(), config).unwrap();
+        add_test_leafs(&mut db);
+        for i in 0u8..200 {
+            db.insert([TEST_LEAF], &[i], Element::Item(vec![i; 100]), None)
+                .expect("successful value insert");
+        }
+        assert_eq!(
+            db.get([TEST_LEAF], &[42], None).expect("successful get"),
+            Element::Item(vec![42; 100])
+        );
+        (db.memory_usage(), tmp_dir)
+    };
+    let (default_usage, _tmp_dir) = fill(GroveDbConfig::default());
+    let (levels_usage, _tmp_dir) =
+        fill(GroveDbConfig::default().retention_policy(RetentionPolicy::KeepLevels(3)));
+    let (budget_usage, _tmp_dir) =
+        fill(GroveDbConfig::default().retention_policy(RetentionPolicy::MemoryBudget(2048)));
+    let (nothing_usage, _tmp_dir) =
+        fill(GroveDbConfig::default().retention_policy(RetentionPolicy::KeepNothing));
+    assert!(levels_usage < default_usage);
+    // Budget applies to each of the test leafs
+    assert!(budget_usage <= 2 * 2048);
+    assert!(nothing_usage < levels_usage);
+    assert!(nothing_usage <= budget_usage);
 }
 
 #[test]
@@ -2504,3 +4585,125 @@ fn test_subtree_deletion_with_transaction() {
     ));
     assert!(matches!(db.get([TEST_LEAF], b"key4", None), Ok(_)));
 }
+
+/// Exercises GroveDB only through the storage traits, so it runs the same for
+/// any backend
+fn check_generic_storage_operations<S: PrefixedStorage>(db: &mut GroveDb<S>) {
+    db.insert([], TEST_LEAF, Element::empty_tree(), None)
+        .expect("successful root tree leaf insert");
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    db.insert(
+        [TEST_LEAF, b"key1"],
+        b"key2",
+        Element::Item(b"ayy".to_vec()),
+        None,
+    )
+    .expect("successful item insert");
+    let root_hash = db.root_hash(None).expect("root hash is set");
+
+    let storage = db.storage();
+    let transaction = S::start_db_transaction(&storage);
+    db.start_transaction().expect("cannot start transaction");
+    db.insert(
+        [TEST_LEAF, b"key1"],
+        b"key3",
+        Element::Item(b"yay".to_vec()),
+        Some(&transaction),
+    )
+    .expect("successful item insert in transaction");
+    assert!(matches!(
+        db.get([TEST_LEAF, b"key1"], b"key3", None),
+        Err(Error::PathKeyNotFound(_))
+    ));
+    db.commit_transaction(transaction)
+        .expect("cannot commit transaction");
+
+    assert_eq!(
+        db.get([TEST_LEAF, b"key1"], b"key3", None)
+            .expect("successful get"),
+        Element::Item(b"yay".to_vec())
+    );
+    assert_ne!(db.root_hash(None), Some(root_hash));
+    db.delete([TEST_LEAF, b"key1"], b"key2", None)
+        .expect("successful delete");
+    assert!(matches!(
+        db.get([TEST_LEAF, b"key1"], b"key2", None),
+        Err(Error::PathKeyNotFound(_))
+    ));
+}
+
+#[test]
+fn test_generic_storage_operations_on_rocksdb() {
+    let tmp_dir = TempDir::new("db").unwrap();
+    let db = storage::rocksdb_storage::OptimisticTransactionDB::open_cf_descriptors(
+        &storage::rocksdb_storage::default_db_opts(),
+        tmp_dir.path(),
+        storage::rocksdb_storage::column_families(),
+    )
+    .expect("cannot open rocksdb");
+    let meta_storage =
+        PrefixedRocksDbStorage::new(db.into(), Vec::new()).expect("cannot create storage");
+    let mut db = GroveDb::open_with_storage(meta_storage).expect("cannot open grovedb");
+    check_generic_storage_operations(&mut db);
+}
+
+#[test]
+fn test_generic_storage_operations_in_memory() {
+    let mut db = GroveDb::open_with_storage(MemoryStorage::default()).expect("cannot open grovedb");
+    check_generic_storage_operations(&mut db);
+}
+
+#[test]
+fn test_open_with_storage_applies_config() {
+    let mut db = GroveDb::open_with_storage_and_config(
+        MemoryStorage::default(),
+        GroveDbConfig::default().refuse_writes(true),
+    )
+    .expect("cannot open grovedb");
+    assert!(matches!(
+        db.insert([], TEST_LEAF, Element::empty_tree(), None),
+        Err(Error::WritesRefused)
+    ));
+}
+
+#[test]
+fn test_failed_operation_without_transaction_writes_nothing() {
+    let tmp_dir = TempDir::new("db").unwrap();
+    let mut db = GroveDb::open(tmp_dir.path()).unwrap();
+    add_test_leafs(&mut db);
+    db.insert([TEST_LEAF], b"key1", Element::empty_tree(), None)
+        .expect("successful subtree insert");
+    let root_hash = db.root_hash(None);
+
+    // An operation failing after it changed a few subtrees
+    let result: Result<(), Error> = db.with_shared_batch(|db| {
+        db.insert([TEST_LEAF, b"key1"], b"key2", Element::empty_tree(), None)?;
+        db.insert(
+            [TEST_LEAF, b"key1", b"key2"],
+            b"key3",
+            Element::Item(b"ayy".to_vec()),
+            None,
+        )?;
+        db.insert([], b"new_leaf", Element::empty_tree(), None)?;
+        Err(Error::InvalidPath("failure in the middle"))
+    });
+    assert!(matches!(result, Err(Error::InvalidPath(_))));
+    assert_eq!(db.root_hash(None), root_hash);
+    assert!(matches!(
+        db.get([TEST_LEAF, b"key1"], b"key2", None),
+        Err(Error::PathKeyNotFound(_))
+    ));
+    assert!(matches!(
+        db.get([], b"new_leaf", None),
+        Err(Error::PathNotFound(_))
+    ));
+
+    drop(db);
+    let db = GroveDb::open(tmp_dir.path()).unwrap();
+    assert_eq!(db.root_hash(None), root_hash);
+    assert!(matches!(
+        db.get([TEST_LEAF, b"key1"], b"key2", None),
+        Err(Error::PathKeyNotFound(_))
+    ));
+}
@@ -1,7 +1,7 @@
 use std::io::{Result, Write};
 
 use itertools::Itertools;
-use storage::rocksdb_storage::OptimisticTransactionDBTransaction;
+use storage::PrefixedStorage;
 
 use crate::{subtree::Element, GroveDb};
 
@@ -109,19 +109,19 @@ impl Visualize for Element {
     }
 }
 
-impl GroveDb {
+impl<S: PrefixedStorage> GroveDb<S> {
     fn draw_subtree<'a, W: Write>(
         &self,
         mut drawer: Drawer<'a, W>,
         path: Vec<Vec<u8>>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Drawer<'a, W>> {
         let subtrees = self.get_subtrees();
         drawer.down();
         let merk = subtrees
             .borrow_mut(path.iter().map(|x| x.as_slice()), transaction)
             .expect("cannot find Merk");
-        let mut iter = Element::iterator(merk.raw_iter(transaction));
+        let mut iter = Element::iterator(merk.raw_iter(transaction.map(S::shorten_db_transaction)));
         while let Some((key, element)) = iter.next().expect("cannot get next element") {
             drawer.write(b"\n[key: ")?;
             drawer = key.visualize(drawer)?;
@@ -148,7 +148,7 @@ impl GroveDb {
     fn draw_root_tree<'a, W: Write>(
         &self,
         mut drawer: Drawer<'a, W>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Drawer<'a, W>> {
         drawer.down();
         let keys = self.root_leaf_keys.iter().fold(
@@ -171,7 +171,7 @@ impl GroveDb {
     fn visualize_start<'a, W: Write>(
         &self,
         mut drawer: Drawer<'a, W>,
-        transaction: Option<&OptimisticTransactionDBTransaction>,
+        transaction: Option<&S::DBTransaction<'_>>,
     ) -> Result<Drawer<'a, W>> {
         drawer.write(b"root")?;
         drawer = self.draw_root_tree(drawer, transaction)?;
@@ -180,13 +180,13 @@ impl GroveDb {
     }
 }
 
-impl Visualize for GroveDb {
+impl<S: PrefixedStorage> Visualize for GroveDb<S> {
     fn visualize<'a, W: Write>(&self, drawer: Drawer<'a, W>) -> Result<Drawer<'a, W>> {
         self.visualize_start(drawer, None)
     }
 }
 
-impl Visualize for (&GroveDb, &OptimisticTransactionDBTransaction<'_>) {
+impl<S: PrefixedStorage> Visualize for (&GroveDb<S>, &S::DBTransaction<'_>) {
     fn visualize<'a, W: Write>(&self, drawer: Drawer<'a, W>) -> Result<Drawer<'a, W>> {
         let (grovedb, transaction) = self;
         grovedb.visualize_start(drawer, Some(transaction))
@@ -16,11 +16,17 @@ pub mod test_utils;
 /// The core tree data structure.
 pub mod tree;
 
+/// The result type of Merk operations, also returned by merge functions.
+pub use anyhow::Result;
 #[allow(deprecated)]
 pub use proofs::query::verify_query;
-pub use proofs::query::{execute_proof, verify};
-pub use tree::{BatchEntry, Hash, MerkBatch, Op, PanicSource, HASH_LENGTH};
+pub use proofs::query::{execute_proof, execute_proof_by, verify, verify_by};
+pub use tree::{
+    BatchEntry, Blake3Hasher, Hash, Hasher, MergeFn, MergeFnId, MergeFns, MergeResolver, MerkBatch,
+    Op, PanicSource, Sha256Hasher, ADD_U64_MERGE_FN, APPEND_MERGE_FN, FIRST_CUSTOM_MERGE_FN,
+    HASH_LENGTH,
+};
 
 // #[cfg(feature = "full")]
 // // pub use crate::merk::{chunks, restore, Merk};
-pub use crate::merk::Merk;
+pub use crate::merk::{Iter, Merk, RetentionPolicy, HASHER_KEY, ROOT_KEY_KEY};
@@ -0,0 +1,174 @@
+//! Loading sorted key/value pairs into an empty Merk at once.
+
+use anyhow::{anyhow, bail, Result};
+use storage::{Batch, Storage};
+
+use super::{Merk, HASHER_KEY, ROOT_KEY_KEY};
+use crate::tree::{Hasher, Link, Tree};
+
+impl<S: Storage, H: Hasher> Merk<S, H>
+where
+    <S as Storage>::Error: std::error::Error,
+{
+    /// Loads `len` key/value pairs sorted by keys into an empty Merk. The
+    /// tree is built bottom-up perfectly balanced, every node is hashed
+    /// exactly once and written straight to a storage batch, so no rotations
+    /// or rehashing of `apply` are paid for. Only the root node is kept in
+    /// memory.
+    ///
+    /// The shape of the tree depends on the number of pairs, so it's given
+    /// upfront for the pairs to be consumed as they are yielded, e.g. read
+    /// from a file with the count stored in its header.
+    ///
+    /// This will fail if the Merk is not empty, if the keys are not sorted
+    /// and unique or if the iterator yields another number of pairs than
+    /// `len`.
+    pub fn bulk_load<'a: 'b, 'b, I>(
+        &'a mut self,
+        entries: I,
+        len: usize,
+        transaction: Option<&'b S::DBTransaction<'b>>,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        if self.use_tree(|tree| tree.is_some()) {
+            bail!("Only empty Merks can be bulk loaded");
+        }
+        let mut entries = entries.into_iter();
+        if len == 0 {
+            if entries.next().is_some() {
+                bail!("Iterator yielded more pairs than the given length");
+            }
+            return Ok(());
+        }
+
+        let mut batch = self.storage.new_batch(transaction)?;
+        let mut loader = BulkLoader {
+            entries: &mut entries,
+            prev_key: None,
+            batch: &mut batch,
+        };
+        let root = loader
+            .build::<H>(len)?
+            .expect("a tree of a non-zero number of nodes");
+        if entries.next().is_some() {
+            bail!("Iterator yielded more pairs than the given length");
+        }
+
+        batch.put_root(ROOT_KEY_KEY, root.key())?;
+        if !self.hasher_recorded {
+            batch.put_root(HASHER_KEY, H::NAME)?;
+        }
+        self.storage.commit_batch(batch)?;
+        self.hasher_recorded = true;
+        self.tree.set(Some(root));
+
+        Ok(())
+    }
+}
+
+/// Builds a tree from sorted pairs in key order, writing each node as soon as
+/// both of its children are written.
+struct BulkLoader<'a, I, B> {
+    entries: &'a mut I,
+    prev_key: Option<Vec<u8>>,
+    batch: &'a mut B,
+}
+
+impl<'a, I, B> BulkLoader<'a, I, B>
+where
+    I: Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    B: Batch,
+{
+    /// Builds a perfectly balanced tree of the next `len` pairs, its children
+    /// are pruned as they are written already.
+    fn build<H: Hasher>(&mut self, len: usize) -> Result<Option<Tree>> {
+        if len == 0 {
+            return Ok(None);
+        }
+        let left_len = len / 2;
+        let left = self.build::<H>(left_len)?.map(Self::link::<H>);
+
+        let (key, value) = self
+            .entries
+            .next()
+            .ok_or_else(|| anyhow!("Iterator yielded fewer pairs than the given length"))?;
+        if let Some(prev_key) = &self.prev_key {
+            if prev_key >= &key {
+                bail!("Keys must be sorted and unique");
+            }
+        }
+        self.prev_key = Some(key.clone());
+
+        let right = self.build::<H>(len - left_len - 1)?.map(Self::link::<H>);
+        let value_hash = H::value_hash(&value);
+        let kv_hash = H::kv_digest_to_kv_hash(&key, &value_hash);
+        let tree = Tree::from_fields(key, value, kv_hash, value_hash, left, right);
+
+        let mut buf = Vec::with_capacity(tree.encoding_length());
+        tree.encode_into(&mut buf);
+        self.batch.put(tree.key(), &buf)?;
+
+        Ok(Some(tree))
+    }
+
+    fn link<H: Hasher>(tree: Tree) -> Link {
+        Link::Reference {
+            hash: tree.hash_by::<H>(),
+            child_heights: tree.child_heights(),
+            key: tree.key().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{test_utils::*, Op};
+
+    #[test]
+    fn bulk_load() {
+        let batch = make_batch_seq(0..1000);
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = batch
+            .iter()
+            .map(|(key, _)| (key.clone(), vec![123; 60]))
+            .collect();
+
+        let mut applied = TempMerk::new();
+        applied.apply::<_, Vec<_>>(&batch, &[], None).unwrap();
+        let mut loaded = TempMerk::new();
+        loaded
+            .bulk_load(entries.clone(), entries.len(), None)
+            .unwrap();
+
+        assert_eq!(loaded.root_hash(), applied.root_hash());
+        assert_eq!(loaded.use_tree(|tree| tree.unwrap().height()), 10);
+        assert_eq!(loaded.get(&seq_key(500)).unwrap(), Some(vec![123; 60]));
+        loaded
+            .apply::<_, Vec<_>>(&[(seq_key(1000).to_vec(), Op::Put(vec![1]))], &[], None)
+            .unwrap();
+        assert!(loaded.bulk_load(entries, 1000, None).is_err());
+    }
+
+    #[test]
+    fn bulk_load_checks_input() {
+        let mut merk = TempMerk::new();
+        merk.bulk_load(Vec::new(), 0, None).unwrap();
+        assert!(merk.root_key().is_none());
+
+        let unsorted = vec![(vec![2], vec![]), (vec![1], vec![])];
+        assert!(merk.bulk_load(unsorted, 2, None).is_err());
+        let duplicates = vec![(vec![1], vec![]), (vec![1], vec![])];
+        assert!(merk.bulk_load(duplicates, 2, None).is_err());
+        let entries = || (0u8..3).map(|i| (vec![i], vec![]));
+        assert!(merk.bulk_load(entries(), 2, None).is_err());
+        assert!(merk.bulk_load(entries(), 4, None).is_err());
+        assert!(merk.bulk_load(entries(), 0, None).is_err());
+        assert!(merk.root_key().is_none());
+
+        // Pairs are consumed as they are yielded, the iterator needs no length
+        merk.bulk_load(entries().filter(|(key, _)| key[0] != 1), 2, None)
+            .unwrap();
+        assert_eq!(merk.get(&[2]).unwrap(), Some(vec![]));
+    }
+}
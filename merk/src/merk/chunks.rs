@@ -40,7 +40,9 @@ where
             trunk
                 .iter()
                 .filter_map(|op| match op {
-                    Op::Push(Node::KV(key, _)) => Some(key.clone()),
+                    Op::Push(Node::KV(key, _))
+                    | Op::Push(Node::KVRefValueHash(key, ..))
+                    | Op::Push(Node::KVLayered(key, ..)) => Some(key.clone()),
                     _ => None,
                 })
                 .collect()
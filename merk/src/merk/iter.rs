@@ -0,0 +1,176 @@
+//! Iterating over key/value pairs of a Merk in a range of keys.
+
+use anyhow::Result;
+use storage::{RawIterator, Store};
+
+use crate::{proofs::query::QueryItem, tree::Tree};
+
+/// Iterator over key/value pairs of a Merk within a range of keys, in either
+/// direction. Unlike the raw storage iterator it yields values rather than
+/// encoded tree nodes. Created with `Merk::iter`.
+pub struct Iter<I: RawIterator> {
+    raw_iter: I,
+    range: QueryItem,
+    left_to_right: bool,
+}
+
+impl<I: RawIterator> Iter<I> {
+    /// Wraps a raw iterator over a Merk's storage, positioning it at the first
+    /// key of `range` in the iteration direction.
+    pub fn new(raw_iter: I, range: QueryItem, left_to_right: bool) -> Self {
+        let range = match range {
+            // A single key is iterated over as a range of this key only
+            QueryItem::Key(key) => QueryItem::RangeInclusive(key.clone()..=key),
+            range => range,
+        };
+        let mut iter = Iter {
+            raw_iter,
+            range,
+            left_to_right,
+        };
+        iter.range.seek_for_iter(&mut iter.raw_iter, left_to_right);
+        iter
+    }
+
+    /// Positions the iterator at `key`, or at the next key of the range in the
+    /// iteration direction if there is no such key.
+    pub fn seek(&mut self, key: &[u8]) {
+        if self.left_to_right {
+            let (start, start_non_inclusive) = self.range.lower_bound();
+            if !self.range.lower_unbounded()
+                && (key < start || (key == start && start_non_inclusive))
+            {
+                self.range.seek_for_iter(&mut self.raw_iter, true);
+            } else {
+                self.raw_iter.seek(key);
+            }
+        } else {
+            let (end, end_inclusive) = self.range.upper_bound();
+            if !self.range.upper_unbounded() && (key > end || (key == end && !end_inclusive)) {
+                self.range.seek_for_iter(&mut self.raw_iter, false);
+            } else {
+                self.raw_iter.seek_for_prev(key);
+            }
+        }
+    }
+
+    /// Positions the iterator at the key following `key` in the iteration
+    /// direction, to resume an iteration which yielded `key` last.
+    pub fn resume_after(&mut self, key: &[u8]) {
+        self.seek(key);
+        if self.raw_iter.key() == Some(key) {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        if self.left_to_right {
+            self.raw_iter.next();
+        } else {
+            self.raw_iter.prev();
+        }
+    }
+}
+
+impl<I: RawIterator> Iterator for Iter<I> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self
+            .range
+            .iter_is_valid_for_type(&self.raw_iter, None, self.left_to_right)
+        {
+            return None;
+        }
+        let key = self.raw_iter.key().expect("key should exist").to_vec();
+        let entry = <Tree as Store>::decode(
+            self.raw_iter
+                .value()
+                .expect("if key exists then value should too"),
+        )
+        .map(|tree| (key, tree.value().to_vec()));
+        self.step();
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{proofs::query::QueryItem, test_utils::*, Op};
+
+    fn make_merk() -> TempMerk {
+        let mut merk = TempMerk::new();
+        let batch: Vec<_> = (0u8..10).map(|i| (vec![i], Op::Put(vec![i; 2]))).collect();
+        merk.apply::<_, Vec<_>>(&batch, &[], None).unwrap();
+        merk
+    }
+
+    fn keys(iter: impl Iterator<Item = anyhow::Result<(Vec<u8>, Vec<u8>)>>) -> Vec<u8> {
+        iter.map(|entry| {
+            let (key, value) = entry.unwrap();
+            assert_eq!(value, vec![key[0]; 2]);
+            key[0]
+        })
+        .collect()
+    }
+
+    #[test]
+    fn iter_ranges() {
+        let merk = make_merk();
+
+        assert_eq!(
+            keys(merk.iter(QueryItem::RangeFull(..), true, None)),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(merk.iter(QueryItem::RangeFull(..), false, None)),
+            (0..10).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(merk.iter(QueryItem::Range(vec![2]..vec![5]), true, None)),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            keys(merk.iter(QueryItem::Range(vec![2]..vec![5]), false, None)),
+            vec![4, 3, 2]
+        );
+        assert_eq!(
+            keys(merk.iter(
+                QueryItem::RangeAfterToInclusive(vec![2]..=vec![5]),
+                true,
+                None
+            )),
+            vec![3, 4, 5]
+        );
+        assert_eq!(
+            keys(merk.iter(QueryItem::Key(vec![7]), false, None)),
+            vec![7]
+        );
+        assert_eq!(
+            keys(merk.iter(QueryItem::Key(vec![20]), true, None)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn iter_seek_and_resume() {
+        let merk = make_merk();
+
+        let mut iter = merk.iter(QueryItem::Range(vec![2]..vec![8]), true, None);
+        iter.seek(&[5]);
+        assert_eq!(keys(iter.by_ref().take(2)), vec![5, 6]);
+        iter.resume_after(&[3]);
+        assert_eq!(keys(iter.by_ref().take(1)), vec![4]);
+        // Seeking out of the range clamps to its bounds
+        iter.seek(&[0]);
+        assert_eq!(keys(iter.by_ref().take(1)), vec![2]);
+        iter.seek(&[9]);
+        assert_eq!(keys(iter), vec![]);
+
+        let mut iter = merk.iter(QueryItem::Range(vec![2]..vec![8]), false, None);
+        iter.resume_after(&[5]);
+        assert_eq!(keys(iter.by_ref().take(2)), vec![4, 3]);
+        iter.seek(&[9]);
+        assert_eq!(keys(iter), vec![7, 6, 5, 4, 3, 2]);
+    }
+}
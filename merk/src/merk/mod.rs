@@ -1,28 +1,74 @@
+mod bulk_load;
 pub mod chunks;
+mod iter;
 // TODO
 // pub mod restore;
-use std::{cell::Cell, cmp::Ordering, collections::LinkedList, fmt};
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    collections::{BTreeMap, LinkedList},
+    fmt,
+    marker::PhantomData,
+};
 
 use anyhow::{anyhow, bail, Result};
+pub use iter::Iter;
 use storage::{self, rocksdb_storage::PrefixedRocksDbStorage, Batch, RawIterator, Storage, Store};
 
 use crate::{
     proofs::{encode_into, query::QueryItem, Query},
-    tree::{Commit, Fetch, Hash, Link, MerkBatch, Op, RefWalker, Tree, Walker, NULL_HASH},
+    tree::{
+        Blake3Hasher, Commit, Fetch, Hash, Hasher, Link, MergeFnId, MergeFns, MergeResolver,
+        MerkBatch, Op, ParallelCommit, RefWalker, Tree, Walker, NULL_HASH,
+    },
 };
 
-const ROOT_KEY_KEY: &[u8] = b"root";
+/// A key of trees roots storage the key of the root node is stored under
+pub const ROOT_KEY_KEY: &[u8] = b"root";
+
+/// A key of trees roots storage the name of the hash function the tree is
+/// hashed with is stored under, Merks with nothing recorded are hashed with
+/// BLAKE3
+pub const HASHER_KEY: &[u8] = b"hasher";
+
+/// Minimal number of modified nodes in each of two sibling subtrees for them
+/// to be committed in parallel
+const PARALLEL_COMMIT_MIN_WRITES: usize = 1024;
+
+/// Decides which tree nodes a Merk keeps loaded in memory after a commit,
+/// others are fetched from storage once needed again. The root node is always
+/// kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    /// Keeps nodes of the given number of top levels of the tree
+    KeepLevels(u8),
+    /// Keeps as many top levels of the tree as fit into the given number of
+    /// bytes, see `Merk::memory_usage`
+    MemoryBudget(usize),
+    /// Keeps the root node only
+    KeepNothing,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy::KeepLevels(100)
+    }
+}
 
-/// A handle to a Merkle key/value store backed by RocksDB.
-pub struct Merk<S>
+/// A handle to a Merkle key/value store backed by RocksDB, hashed with `H`.
+pub struct Merk<S, H = Blake3Hasher>
 where
     S: Storage,
+    H: Hasher,
 {
     pub(crate) tree: Cell<Option<Tree>>,
     pub storage: S,
+    retention_policy: RetentionPolicy,
+    hasher_recorded: bool,
+    hasher: PhantomData<H>,
 }
 
-impl<S: Storage> fmt::Debug for Merk<S> {
+impl<S: Storage, H: Hasher> fmt::Debug for Merk<S, H> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Merk").finish()
     }
@@ -34,16 +80,45 @@ impl<S: Storage> Merk<S>
 where
     <S as Storage>::Error: std::error::Error,
 {
+    /// Opens a Merk hashed with BLAKE3.
     pub fn open(storage: S) -> Result<Self> {
-        let mut merk = Self {
+        Self::open_with_hasher(storage)
+    }
+
+    /// Opens a Merk hashed with `H`, fails if the Merk was hashed with another
+    /// hash function.
+    pub fn open_with_hasher<H: Hasher>(storage: S) -> Result<Merk<S, H>> {
+        let recorded_hasher = storage.get_root(HASHER_KEY)?;
+        let mut merk = Merk {
             tree: Cell::new(None),
             storage,
+            retention_policy: RetentionPolicy::default(),
+            hasher_recorded: recorded_hasher.is_some(),
+            hasher: PhantomData,
         };
         merk.load_root()?;
 
+        let hasher_name = match recorded_hasher {
+            Some(name) => name,
+            None if merk.use_tree(|tree| tree.is_some()) => Blake3Hasher::NAME.to_vec(),
+            None => return Ok(merk),
+        };
+        if hasher_name != H::NAME {
+            bail!(
+                "Merk is hashed with {}, not {}",
+                String::from_utf8_lossy(&hasher_name),
+                String::from_utf8_lossy(H::NAME)
+            );
+        }
+
         Ok(merk)
     }
+}
 
+impl<S: Storage, H: Hasher> Merk<S, H>
+where
+    <S as Storage>::Error: std::error::Error,
+{
     /// Deletes tree data
     pub fn clear<'a>(&'a mut self, transaction: Option<&'a S::DBTransaction<'a>>) -> Result<()> {
         let mut iter = self.raw_iter(transaction);
@@ -77,38 +152,82 @@ where
     /// Gets a hash of a node by a given key, `None` is returned in case
     /// when node not found by the key.
     pub fn get_hash(&self, key: &[u8]) -> Result<Option<[u8; 32]>> {
-        self.get_node_fn(key, |node| node.hash())
+        self.get_node_fn(key, |node| node.hash_by::<H>())
+    }
+
+    /// Gets a value hash of a node by a given key, `None` is returned in case
+    /// when node not found by the key.
+    pub fn get_value_hash(&self, key: &[u8]) -> Result<Option<[u8; 32]>> {
+        self.get_node_fn(key, |node| *node.value_hash())
+    }
+
+    /// Gets values of many keys at once, `None` is returned for keys which
+    /// are not found. Nodes which are not loaded into memory are fetched
+    /// from the storage with one multi-get instead of one lookup per key.
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        let value = |node: &Tree| node.value().to_vec();
+        let mut values = Vec::with_capacity(keys.len());
+        let mut unloaded = Vec::new();
+        for key in keys {
+            match self.get_loaded_node_fn(key.as_ref(), value) {
+                Some(maybe_value) => values.push(maybe_value),
+                None => {
+                    unloaded.push(values.len());
+                    values.push(None);
+                }
+            }
+        }
+
+        if !unloaded.is_empty() {
+            let unloaded_keys: Vec<&[u8]> = unloaded.iter().map(|i| keys[*i].as_ref()).collect();
+            let nodes = self.storage.get_many(&unloaded_keys)?;
+            for (i, maybe_node) in unloaded.into_iter().zip(nodes) {
+                values[i] = maybe_node
+                    .map(|bytes| <Tree as Store>::decode(&bytes))
+                    .transpose()?
+                    .map(|node| value(&node));
+            }
+        }
+        Ok(values)
     }
 
     /// Generic way to get a node's field
     fn get_node_fn<T, F>(&self, key: &[u8], f: F) -> Result<Option<T>>
     where
-        F: FnOnce(&Tree) -> T,
+        F: Fn(&Tree) -> T,
+    {
+        match self.get_loaded_node_fn(key, &f) {
+            Some(result) => Ok(result),
+            // fetch from RocksDB
+            None => Tree::get(&self.storage, key).map(|maybe_node| maybe_node.map(|node| f(&node))),
+        }
+    }
+
+    /// Looks for a node among the ones loaded into memory; `None` is returned
+    /// if the search reaches a link to a node which is not loaded
+    fn get_loaded_node_fn<T, F>(&self, key: &[u8], f: F) -> Option<Option<T>>
+    where
+        F: Fn(&Tree) -> T,
     {
         self.use_tree(move |maybe_tree| {
             let mut cursor = match maybe_tree {
-                None => return Ok(None), // empty tree
+                None => return Some(None), // empty tree
                 Some(tree) => tree,
             };
 
             loop {
                 if key == cursor.key() {
-                    return Ok(Some(f(cursor)));
+                    return Some(Some(f(cursor)));
                 }
 
                 let left = key < cursor.key();
                 let link = match cursor.link(left) {
-                    None => return Ok(None), // not found
+                    None => return Some(None), // not found
                     Some(link) => link,
                 };
 
-                let maybe_child = link.tree();
-                match maybe_child {
-                    None => {
-                        // fetch from RocksDB
-                        break Tree::get(&self.storage, key)
-                            .map(|maybe_node| maybe_node.map(|node| f(&node)));
-                    }
+                match link.tree() {
+                    None => return None,
                     Some(child) => cursor = child, // traverse to child
                 }
             }
@@ -119,12 +238,23 @@ where
     /// proofs can be checked against). If the tree is empty, returns the null
     /// hash (zero-filled).
     pub fn root_hash(&self) -> Hash {
-        self.use_tree(|tree| tree.map_or(NULL_HASH, |tree| tree.hash()))
+        self.use_tree(|tree| tree.map_or(NULL_HASH, |tree| tree.hash_by::<H>()))
+    }
+
+    /// Returns the height of the tree, which is zero if the tree is empty.
+    pub fn height(&self) -> u8 {
+        self.use_tree(|tree| tree.map_or(0, |tree| tree.height()))
     }
 
-    /// Applies a batch of operations (puts and deletes) to the tree.
+    /// Returns the key of the root node, if the tree is not empty.
+    pub fn root_key(&self) -> Option<Vec<u8>> {
+        self.use_tree(|tree| tree.map(|tree| tree.key().to_vec()))
+    }
+
+    /// Applies a batch of operations (puts, deletes and merges) to the tree.
     ///
-    /// This will fail if the keys in `batch` are not sorted and unique. This
+    /// This will fail if the keys in `batch` are not sorted and unique or if
+    /// some of them are within a deleted range. This
     /// check creates some overhead, so if you are sure your batch is sorted and
     /// unique you can use the unsafe `apply_unchecked` for a small performance
     /// gain.
@@ -148,13 +278,31 @@ where
         aux: &MerkBatch<KA>,
         transaction: Option<&'b S::DBTransaction<'b>>,
     ) -> Result<()>
+    where
+        KB: AsRef<[u8]>,
+        KA: AsRef<[u8]>,
+    {
+        self.apply_with_merge_fns(batch, aux, &MergeFns::default(), transaction)
+    }
+
+    /// Applies a batch of operations to the tree like `apply` does, merges
+    /// are resolved with the given merge functions rather than with the
+    /// built-in ones only.
+    pub fn apply_with_merge_fns<'a: 'b, 'b, KB, KA>(
+        &'a mut self,
+        batch: &MerkBatch<KB>,
+        aux: &MerkBatch<KA>,
+        merge_fns: &dyn MergeResolver,
+        transaction: Option<&'b S::DBTransaction<'b>>,
+    ) -> Result<()>
     where
         KB: AsRef<[u8]>,
         KA: AsRef<[u8]>,
     {
         // ensure keys in batch are sorted and unique
         let mut maybe_prev_key: Option<&KB> = None;
-        for (key, _) in batch.iter() {
+        let mut maybe_range_end: Option<&[u8]> = None;
+        for (key, op) in batch.iter() {
             if let Some(prev_key) = maybe_prev_key {
                 match prev_key.as_ref().cmp(key.as_ref()) {
                     Ordering::Greater => bail!("Keys in batch must be sorted"),
@@ -162,17 +310,28 @@ where
                     _ => (),
                 }
             }
+            if maybe_range_end.is_some_and(|end| key.as_ref() < end) {
+                bail!("Keys in batch must not be within a deleted range");
+            }
+            maybe_range_end = match op {
+                Op::DeleteRange(end) if end.as_slice() <= key.as_ref() => {
+                    bail!("Deleted ranges must not be empty")
+                }
+                Op::DeleteRange(end) => Some(end),
+                _ => None,
+            };
             maybe_prev_key = Some(key);
         }
 
-        unsafe { self.apply_unchecked(batch, aux, transaction) }
+        unsafe { self.apply_unchecked_with_merge_fns(batch, aux, merge_fns, transaction) }
     }
 
-    /// Applies a batch of operations (puts and deletes) to the tree.
+    /// Applies a batch of operations (puts, deletes and merges) to the tree.
     ///
     /// # Safety
-    /// This is unsafe because the keys in `batch` must be sorted and unique -
-    /// if they are not, there will be undefined behavior. For a safe version of
+    /// This is unsafe because the keys in `batch` must be sorted, unique and
+    /// not within deleted ranges - if they are not, there will be undefined
+    /// behavior. For a safe version of
     /// this method which checks to ensure the batch is sorted and unique, see
     /// `apply`.
     ///
@@ -203,19 +362,77 @@ where
         KB: AsRef<[u8]>,
         KA: AsRef<[u8]>,
     {
+        self.apply_unchecked_with_merge_fns(batch, aux, &MergeFns::default(), transaction)
+    }
+
+    /// Applies a batch of operations to the tree like `apply_unchecked` does,
+    /// merges are resolved with the given merge functions.
+    ///
+    /// # Safety
+    /// The keys in `batch` must be sorted, unique and not within deleted
+    /// ranges, see `apply_with_merge_fns` for a safe version.
+    pub unsafe fn apply_unchecked_with_merge_fns<'a: 'b, 'b, KB, KA>(
+        &'a mut self,
+        batch: &MerkBatch<KB>,
+        aux: &MerkBatch<KA>,
+        merge_fns: &dyn MergeResolver,
+        transaction: Option<&'b S::DBTransaction<'b>>,
+    ) -> Result<()>
+    where
+        KB: AsRef<[u8]>,
+        KA: AsRef<[u8]>,
+    {
+        if aux
+            .iter()
+            .any(|(_, op)| matches!(op, Op::DeleteRange(_) | Op::Merge(..)))
+        {
+            bail!("Aux batch only supports puts and deletes");
+        }
+
+        // merge functions may refuse to merge, so merges are resolved before
+        // the walk takes the tree apart, a failed merge leaves it as it was
+        let merged = self.resolve_merges(batch, merge_fns)?;
+
         let maybe_walker = self
             .tree
             .take()
             .take()
             .map(|tree| Walker::new(tree, self.source()));
 
-        let (maybe_tree, deleted_keys) = Walker::apply_to(maybe_walker, batch, self.source())?;
+        let (maybe_tree, deleted_keys) =
+            Walker::apply_to(maybe_walker, batch, self.source(), &merged)?;
         self.tree.set(maybe_tree);
 
         // commit changes to db
         self.commit(deleted_keys, aux, transaction)
     }
 
+    /// Resolves the merges of the batch with the current values of their keys.
+    /// Nodes on the way to the keys are loaded into memory, so the walk
+    /// applying the batch doesn't fetch them again.
+    fn resolve_merges<'k, K: AsRef<[u8]>>(
+        &self,
+        batch: &'k MerkBatch<K>,
+        merge_fns: &dyn MergeResolver,
+    ) -> Result<ResolvedMerges<'k>> {
+        let mut merged = BTreeMap::new();
+        for (key, op) in batch {
+            if let Op::Merge(operand, merge_fn_id) = op {
+                let key = key.as_ref();
+                let value = self.use_tree_mut(|maybe_tree| match maybe_tree {
+                    None => merge_fns.merge(*merge_fn_id, key, None, operand),
+                    Some(tree) => {
+                        with_value(RefWalker::new(tree, self.source()), key, |existing| {
+                            merge_fns.merge(*merge_fn_id, key, existing, operand)
+                        })
+                    }
+                })?;
+                merged.insert(key, value);
+            }
+        }
+        Ok(ResolvedMerges(RefCell::new(merged)))
+    }
+
     /// Creates a Merkle proof for the list of queried keys. For each key in the
     /// query, if the key is found in the store then the value will be proven to
     /// be in the tree. For each key in the query that does not exist in the
@@ -282,11 +499,10 @@ where
     {
         let mut batch = self.storage.new_batch(transaction)?;
         let mut to_batch = self.use_tree_mut(|maybe_tree| -> UseTreeMutResult {
-            // TODO: concurrent commit
             if let Some(tree) = maybe_tree {
-                // TODO: configurable committer
-                let mut committer = MerkCommitter::new(tree.height(), 100);
-                tree.commit(&mut committer)?;
+                let mut committer = MerkCommitter::<H>::new();
+                tree.commit_parallel(&mut committer, PARALLEL_COMMIT_MIN_WRITES)?;
+                Self::retain(tree, self.retention_policy);
 
                 // update pointer to root node
                 batch.put_root(ROOT_KEY_KEY, tree.key())?;
@@ -299,6 +515,11 @@ where
             }
         })?;
 
+        let record_hasher = !self.hasher_recorded && self.use_tree(|tree| tree.is_some());
+        if record_hasher {
+            batch.put_root(HASHER_KEY, H::NAME)?;
+        }
+
         // TODO: move this to MerkCommitter impl?
         for key in deleted_keys {
             to_batch.push((key, None));
@@ -314,18 +535,76 @@ where
 
         for (key, value) in aux {
             match value {
-                Op::Put(value) => batch.put_aux(key, value)?,
+                Op::Put(value) | Op::PutReference(value, _) | Op::PutLayered(value, _) => {
+                    batch.put_aux(key, value)?
+                }
                 Op::Delete => batch.delete_aux(key)?,
+                Op::DeleteRange(_) | Op::Merge(..) => {
+                    bail!("Aux batch only supports puts and deletes")
+                }
             };
         }
 
         // write to db
         self.storage.commit_batch(batch)?;
+        if record_hasher {
+            self.hasher_recorded = true;
+        }
 
         Ok(())
     }
 
-    pub fn walk<T>(&self, f: impl FnOnce(Option<RefWalker<MerkSource<S>>>) -> T) -> T {
+    /// Returns the policy deciding which nodes are kept in memory
+    pub const fn retention_policy(&self) -> RetentionPolicy {
+        self.retention_policy
+    }
+
+    /// Sets the policy deciding which nodes are kept in memory, it's applied
+    /// right away and then on each commit
+    pub fn set_retention_policy(&mut self, retention_policy: RetentionPolicy) {
+        self.retention_policy = retention_policy;
+        self.prune();
+    }
+
+    /// Returns an approximate number of bytes taken by tree nodes loaded into
+    /// memory
+    pub fn memory_usage(&self) -> usize {
+        self.use_tree(|maybe_tree| {
+            maybe_tree.map_or(0, |tree| tree.levels_memory_usage().iter().sum())
+        })
+    }
+
+    /// Applies the retention policy to nodes loaded into memory, including
+    /// ones loaded by reads since the last commit
+    pub fn prune(&self) {
+        self.use_tree_mut(|maybe_tree| {
+            if let Some(tree) = maybe_tree {
+                Self::retain(tree, self.retention_policy);
+            }
+        })
+    }
+
+    fn retain(tree: &mut Tree, retention_policy: RetentionPolicy) {
+        let levels = match retention_policy {
+            RetentionPolicy::KeepLevels(levels) => levels,
+            RetentionPolicy::KeepNothing => 1,
+            RetentionPolicy::MemoryBudget(budget) => {
+                let mut used = 0;
+                let fitting_levels = tree
+                    .levels_memory_usage()
+                    .into_iter()
+                    .take_while(|level_usage| {
+                        used += level_usage;
+                        used <= budget
+                    })
+                    .count();
+                fitting_levels.clamp(1, u8::MAX as usize) as u8
+            }
+        };
+        tree.prune_levels(levels);
+    }
+
+    pub fn walk<T>(&self, f: impl FnOnce(Option<RefWalker<MerkSource<S, H>>>) -> T) -> T {
         let mut tree = self.tree.take();
         let maybe_walker = tree
             .as_mut()
@@ -342,6 +621,17 @@ where
         self.storage.raw_iter(transaction)
     }
 
+    /// Iterates over key/value pairs within `range` in the given direction.
+    /// Works inside of `transaction` if one is given.
+    pub fn iter<'a>(
+        &'a self,
+        range: QueryItem,
+        left_to_right: bool,
+        transaction: Option<&'a S::DBTransaction<'a>>,
+    ) -> Iter<S::RawIterator<'a>> {
+        Iter::new(self.raw_iter(transaction), range, left_to_right)
+    }
+
     pub fn is_empty_tree<'a>(&'a self, transaction: Option<&'a S::DBTransaction<'a>>) -> bool {
         let mut iter = self.raw_iter(transaction);
         iter.seek_to_first();
@@ -349,9 +639,10 @@ where
         !iter.valid()
     }
 
-    fn source(&self) -> MerkSource<S> {
+    fn source(&self) -> MerkSource<S, H> {
         MerkSource {
             storage: &self.storage,
+            hasher: PhantomData,
         }
     }
 
@@ -382,7 +673,7 @@ where
     }
 }
 
-impl Clone for Merk<PrefixedRocksDbStorage> {
+impl<H: Hasher> Clone for Merk<PrefixedRocksDbStorage, H> {
     fn clone(&self) -> Self {
         let tree_clone = match self.tree.take() {
             None => None,
@@ -395,52 +686,59 @@ impl Clone for Merk<PrefixedRocksDbStorage> {
         Self {
             tree: Cell::new(tree_clone),
             storage: self.storage.clone(),
+            retention_policy: self.retention_policy,
+            hasher_recorded: self.hasher_recorded,
+            hasher: PhantomData,
         }
     }
 }
 
 // TODO: get rid of Fetch/source and use GroveDB storage abstraction
 #[derive(Debug)]
-pub struct MerkSource<'a, S: Storage> {
+pub struct MerkSource<'a, S: Storage, H: Hasher = Blake3Hasher> {
     storage: &'a S,
+    hasher: PhantomData<H>,
 }
 
-impl<'a, S: Storage> Clone for MerkSource<'a, S> {
+impl<'a, S: Storage, H: Hasher> Clone for MerkSource<'a, S, H> {
     fn clone(&self) -> Self {
         MerkSource {
             storage: self.storage,
+            hasher: PhantomData,
         }
     }
 }
 
-impl<'a, S: Storage> Fetch for MerkSource<'a, S>
+impl<'a, S: Storage, H: Hasher> Fetch for MerkSource<'a, S, H>
 where
     //    crate::error::Error: From<<S as
     // Storage>::Error>,
     <S as Storage>::Error: std::error::Error,
 {
+    type Hasher = H;
+
     fn fetch(&self, link: &Link) -> Result<Tree> {
         Tree::get(&self.storage, link.key())?.ok_or(anyhow!("Key not found"))
     }
 }
 
-struct MerkCommitter {
+struct MerkCommitter<H> {
     batch: Vec<(Vec<u8>, Option<Vec<u8>>)>,
-    height: u8,
-    levels: u8,
+    hasher: PhantomData<H>,
 }
 
-impl MerkCommitter {
-    fn new(height: u8, levels: u8) -> Self {
+impl<H: Hasher> MerkCommitter<H> {
+    fn new() -> Self {
         Self {
             batch: Vec::with_capacity(10000),
-            height,
-            levels,
+            hasher: PhantomData,
         }
     }
 }
 
-impl Commit for MerkCommitter {
+impl<H: Hasher> Commit for MerkCommitter<H> {
+    type Hasher = H;
+
     fn write(&mut self, tree: &Tree) -> Result<()> {
         let mut buf = Vec::with_capacity(tree.encoding_length());
         tree.encode_into(&mut buf);
@@ -448,10 +746,61 @@ impl Commit for MerkCommitter {
         Ok(())
     }
 
-    fn prune(&self, tree: &Tree) -> (bool, bool) {
-        // keep N top levels of tree
-        let prune = (self.height - tree.height()) >= self.levels;
-        (prune, prune)
+    fn prune(&self, _tree: &Tree) -> (bool, bool) {
+        // nodes are pruned by the retention policy once the whole tree is committed
+        (false, false)
+    }
+}
+
+impl<H: Hasher> ParallelCommit for MerkCommitter<H> {
+    fn fork(&self) -> Self {
+        Self {
+            batch: Vec::new(),
+            hasher: PhantomData,
+        }
+    }
+
+    fn join(&mut self, other: Self) {
+        self.batch.extend(other.batch);
+    }
+}
+
+/// Values of the merges of a batch resolved before the batch is applied, each
+/// of them is taken by the walk once it reaches the key
+struct ResolvedMerges<'a>(RefCell<BTreeMap<&'a [u8], Vec<u8>>>);
+
+impl MergeResolver for ResolvedMerges<'_> {
+    fn merge(
+        &self,
+        _id: MergeFnId,
+        key: &[u8],
+        _existing: Option<&[u8]>,
+        _operand: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.0
+            .borrow_mut()
+            .remove(key)
+            .ok_or_else(|| anyhow!("Merge of key {} is not resolved", hex::encode(key)))
+    }
+}
+
+/// Walks down to `key` and calls `f` with its value, or with `None` if there
+/// is no such key
+fn with_value<S, T>(
+    mut walker: RefWalker<S>,
+    key: &[u8],
+    f: impl FnOnce(Option<&[u8]>) -> Result<T>,
+) -> Result<T>
+where
+    S: Fetch + Clone,
+{
+    let left = match key.cmp(walker.tree().key()) {
+        Ordering::Equal => return f(Some(walker.tree().value())),
+        ordering => ordering == Ordering::Less,
+    };
+    match walker.walk(left)? {
+        None => f(None),
+        Some(child) => with_value(child, key, f),
     }
 }
 
@@ -465,8 +814,14 @@ mod test {
     };
     use tempdir::TempDir;
 
-    use super::{Merk, MerkSource, RefWalker};
-    use crate::{test_utils::*, Op};
+    use super::{Merk, MerkSource, RefWalker, RetentionPolicy};
+    use crate::{
+        execute_proof, execute_proof_by,
+        proofs::{encode_into, Decoder, Node, Op as ProofOp, Query},
+        test_utils::*,
+        tree::{combine_hash, value_hash, Blake3Hasher, Sha256Hasher, ValueBinding},
+        MergeFns, Op, ADD_U64_MERGE_FN, APPEND_MERGE_FN, FIRST_CUSTOM_MERGE_FN,
+    };
 
     // TODO: Close and then reopen test
 
@@ -495,6 +850,50 @@ mod test {
         );
     }
 
+    #[test]
+    fn height_and_root_key() {
+        let mut merk = TempMerk::new();
+        assert_eq!(merk.height(), 0);
+        assert!(merk.root_key().is_none());
+
+        // A balanced tree of 20 nodes has 5 levels
+        let batch = make_batch_seq(0..20);
+        merk.apply::<_, Vec<_>>(&batch, &[], None)
+            .expect("apply failed");
+        assert_eq!(merk.height(), 5);
+        assert!(merk.root_key().is_some());
+    }
+
+    #[test]
+    fn retention_policy() {
+        let loaded_levels = |merk: &TempMerk| {
+            merk.use_tree(|maybe_tree| maybe_tree.unwrap().levels_memory_usage().len())
+        };
+        let mut merk = TempMerk::new();
+        let batch = make_batch_seq(0..1000);
+        merk.apply::<_, Vec<_>>(&batch, &[], None)
+            .expect("apply failed");
+        assert_eq!(loaded_levels(&merk), merk.height() as usize);
+        let full_usage = merk.memory_usage();
+
+        merk.set_retention_policy(RetentionPolicy::KeepLevels(3));
+        assert_eq!(loaded_levels(&merk), 3);
+        assert!(merk.memory_usage() < full_usage);
+
+        merk.set_retention_policy(RetentionPolicy::KeepNothing);
+        assert_eq!(loaded_levels(&merk), 1);
+
+        // A budget is applied on commits as well
+        merk.set_retention_policy(RetentionPolicy::MemoryBudget(4096));
+        let batch = make_batch_seq(1000..2000);
+        merk.apply::<_, Vec<_>>(&batch, &[], None)
+            .expect("apply failed");
+        assert!(merk.memory_usage() <= 4096);
+        assert!(loaded_levels(&merk) > 1);
+        assert_invariants(&merk);
+        assert_eq!(merk.get(&seq_key(1)).unwrap(), Some(vec![123; 60]));
+    }
+
     #[test]
     fn insert_uncached() {
         let batch_size = 20;
@@ -541,6 +940,126 @@ mod test {
         assert!(value.is_none());
     }
 
+    #[test]
+    fn reference_value_hash() {
+        let mut merk = TempMerk::new();
+        merk.apply::<_, Vec<_>>(
+            &[
+                (vec![1], Op::Put(vec![1])),
+                (vec![2], Op::PutReference(vec![2], [7; 32])),
+            ],
+            &[],
+            None,
+        )
+        .expect("apply failed");
+        let root_hash = merk.root_hash();
+        assert_eq!(
+            merk.get_value_hash(&[2]).unwrap(),
+            Some(combine_hash(&value_hash(&[2]), &[7; 32]))
+        );
+
+        // Changing the referenced value hash changes the root hash
+        merk.apply::<_, Vec<_>>(&[(vec![2], Op::PutReference(vec![2], [8; 32]))], &[], None)
+            .expect("apply failed");
+        assert_ne!(merk.root_hash(), root_hash);
+
+        // Proofs carry the combined value hash
+        let mut query = Query::new();
+        query.insert_key(vec![2]);
+        let proof = merk.prove(query, None, None).unwrap();
+        let (hash, map) = crate::execute_proof(&proof).unwrap();
+        assert_eq!(hash, merk.root_hash());
+        assert_eq!(map.get(&[2]).unwrap(), Some(&[2][..]));
+        assert_eq!(
+            map.get_value_hash(&[2]),
+            Some(combine_hash(&value_hash(&[2]), &[8; 32]))
+        );
+    }
+
+    #[test]
+    fn forged_value_proof() {
+        let mut merk = TempMerk::new();
+        merk.apply::<_, Vec<_>>(&[(vec![1], Op::Put(vec![1]))], &[], None)
+            .expect("apply failed");
+        let mut query = Query::new();
+        query.insert_key(vec![1]);
+        let proof = merk.prove(query, None, None).unwrap();
+
+        // A reference node for a plain item can't carry another value, even with
+        // the item's real value hash
+        let ops: Vec<_> = Decoder::new(&proof)
+            .map(|op| match op.unwrap() {
+                ProofOp::Push(Node::KV(key, value)) => {
+                    ProofOp::Push(Node::KVRefValueHash(key, vec![2], value_hash(&value)))
+                }
+                op => op,
+            })
+            .collect();
+        let mut forged = Vec::new();
+        encode_into(ops.iter(), &mut forged);
+        let (hash, _) = execute_proof(&forged).unwrap();
+        assert_ne!(hash, merk.root_hash());
+    }
+
+    #[test]
+    fn layered_value_hash() {
+        let mut child = TempMerk::new();
+        child
+            .apply::<_, Vec<_>>(&[(vec![1], Op::Put(vec![1]))], &[], None)
+            .expect("apply failed");
+
+        let mut merk = TempMerk::new();
+        merk.apply::<_, Vec<_>>(
+            &[
+                (
+                    vec![1],
+                    Op::PutLayered(child.root_hash().to_vec(), child.root_hash()),
+                ),
+                (vec![2], Op::Put(child.root_hash().to_vec())),
+                (
+                    vec![3],
+                    Op::PutReference(child.root_hash().to_vec(), child.root_hash()),
+                ),
+            ],
+            &[],
+            None,
+        )
+        .expect("apply failed");
+
+        // A subtree commitment differs from a plain value or a reference with
+        // the same bytes
+        let mut query = Query::new();
+        query.insert_key(vec![1]);
+        query.insert_key(vec![2]);
+        query.insert_key(vec![3]);
+        let proof = merk.prove(query, None, None).unwrap();
+        let (hash, map) = crate::execute_proof(&proof).unwrap();
+        assert_eq!(hash, merk.root_hash());
+        assert_eq!(
+            map.get_binding(&[1]),
+            Some(ValueBinding::Layered(child.root_hash()))
+        );
+        assert_eq!(
+            map.get_value_hash(&[1]),
+            Some(crate::tree::layered_value_hash(
+                &value_hash(&child.root_hash()),
+                &child.root_hash()
+            ))
+        );
+        assert_eq!(
+            map.get_value_hash(&[2]),
+            Some(value_hash(&child.root_hash()))
+        );
+        assert_eq!(
+            map.get_value_hash(&[3]),
+            Some(combine_hash(
+                &value_hash(&child.root_hash()),
+                &child.root_hash()
+            ))
+        );
+        assert_ne!(map.get_value_hash(&[1]), map.get_value_hash(&[3]));
+    }
+
     #[test]
     fn aux_data() {
         let mut merk = TempMerk::new();
@@ -597,6 +1116,38 @@ mod test {
         assert!(merk.get(&[3, 3, 3]).unwrap().is_none());
     }
 
+    #[test]
+    fn get_many() {
+        let tmp_dir = TempDir::new("test_get_many").expect("cannot open tempdir");
+        let batch = make_batch_seq(1..1_000);
+        {
+            let db = default_rocksdb(tmp_dir.path());
+            let mut merk =
+                Merk::open(PrefixedRocksDbStorage::new(db, Vec::new()).unwrap()).unwrap();
+            merk.apply::<_, Vec<_>>(batch.as_slice(), &[], None)
+                .unwrap();
+        }
+
+        // Only the root node is loaded after reopening
+        let db = default_rocksdb(tmp_dir.path());
+        let merk = Merk::open(PrefixedRocksDbStorage::new(db, Vec::new()).unwrap()).unwrap();
+        let mut keys: Vec<Vec<u8>> = batch
+            .iter()
+            .step_by(7)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.push(vec![0, 0, 0]);
+        keys.push(batch[500].0.clone());
+
+        let values = merk.get_many(&keys).unwrap();
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(value, merk.get(key).unwrap());
+        }
+        assert!(merk.get_many(&[[0, 0, 0]]).unwrap()[0].is_none());
+        assert!(merk.get_many::<Vec<u8>>(&[]).unwrap().is_empty());
+    }
+
     #[test]
     fn reopen() {
         fn collect(
@@ -673,4 +1224,121 @@ mod test {
 
         assert_eq!(reopen_nodes, original_nodes);
     }
+
+    #[test]
+    fn sha256_hasher() {
+        let tmp_dir = TempDir::new("sha256_hasher").expect("cannot open tempdir");
+        let batch = make_batch_seq(1..100);
+
+        let blake3_hash = {
+            let mut merk = TempMerk::new();
+            merk.apply::<_, Vec<_>>(&batch, &[], None).unwrap();
+            merk.root_hash()
+        };
+
+        let sha256_hash = {
+            let db = default_rocksdb(tmp_dir.path());
+            let mut merk = Merk::open_with_hasher::<Sha256Hasher>(
+                PrefixedRocksDbStorage::new(db, Vec::new()).unwrap(),
+            )
+            .unwrap();
+            merk.apply::<_, Vec<_>>(&batch, &[], None).unwrap();
+            merk.root_hash()
+        };
+        assert_ne!(sha256_hash, blake3_hash);
+
+        let db = default_rocksdb(tmp_dir.path());
+        assert!(Merk::open(PrefixedRocksDbStorage::new(db.clone(), Vec::new()).unwrap()).is_err());
+        assert!(Merk::open_with_hasher::<Blake3Hasher>(
+            PrefixedRocksDbStorage::new(db.clone(), Vec::new()).unwrap()
+        )
+        .is_err());
+        let merk = Merk::open_with_hasher::<Sha256Hasher>(
+            PrefixedRocksDbStorage::new(db, Vec::new()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(merk.root_hash(), sha256_hash);
+
+        let mut query = Query::new();
+        query.insert_key(batch[10].0.clone());
+        let proof = merk.prove(query, None, None).unwrap();
+        let (root_hash, map) = execute_proof_by::<Sha256Hasher>(&proof).unwrap();
+        assert_eq!(root_hash, sha256_hash);
+        assert!(map.get(&batch[10].0).unwrap().is_some());
+        assert_ne!(execute_proof(&proof).unwrap().0, sha256_hash);
+    }
+
+    #[test]
+    fn delete_range_and_merge() {
+        let mut merk = TempMerk::new();
+        merk.apply::<_, Vec<_>>(&make_batch_seq(0..100), &[], None)
+            .unwrap();
+
+        let delete_range = |start: u64, end: u64| {
+            (
+                seq_key(start).to_vec(),
+                Op::DeleteRange(seq_key(end).to_vec()),
+            )
+        };
+        assert!(merk
+            .apply::<_, Vec<_>>(&[delete_range(10, 10)], &[], None)
+            .is_err());
+        assert!(merk
+            .apply::<_, Vec<_>>(&[delete_range(10, 20), put_entry(15)], &[], None)
+            .is_err());
+        assert!(merk
+            .apply::<Vec<u8>, _>(&[], &[delete_range(10, 20)], None)
+            .is_err());
+
+        // a failed merge leaves the tree as it was
+        let root_hash = merk.root_hash();
+        let batch = [
+            (seq_key(5).to_vec(), Op::Merge(vec![1], APPEND_MERGE_FN)),
+            (seq_key(6).to_vec(), Op::Merge(vec![1], ADD_U64_MERGE_FN)),
+        ];
+        assert!(merk.apply::<_, Vec<_>>(&batch, &[], None).is_err());
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_invariants(&merk);
+
+        // custom merge functions are given along with the batch
+        fn replace(_existing: Option<&[u8]>, operand: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(operand.to_vec())
+        }
+        let mut merge_fns = MergeFns::default();
+        merge_fns.register(FIRST_CUSTOM_MERGE_FN, replace).unwrap();
+        let batch = [(
+            seq_key(7).to_vec(),
+            Op::Merge(vec![7], FIRST_CUSTOM_MERGE_FN),
+        )];
+        assert!(merk.apply::<_, Vec<_>>(&batch, &[], None).is_err());
+        merk.apply_with_merge_fns::<_, Vec<_>>(&batch, &[], &merge_fns, None)
+            .unwrap();
+        assert_eq!(merk.get(&seq_key(7)).unwrap(), Some(vec![7]));
+
+        let batch = [
+            (seq_key(5).to_vec(), Op::Merge(vec![1], APPEND_MERGE_FN)),
+            delete_range(10, 90),
+            (seq_key(200).to_vec(), Op::Merge(vec![2], APPEND_MERGE_FN)),
+        ];
+        merk.apply::<_, Vec<_>>(&batch, &[], None).unwrap();
+        assert_invariants(&merk);
+
+        let mut expected = vec![123; 60];
+        expected.push(1);
+        assert_eq!(merk.get(&seq_key(5)).unwrap(), Some(expected));
+        assert_eq!(merk.get(&seq_key(200)).unwrap(), Some(vec![2]));
+        assert!(merk.get(&seq_key(9)).unwrap().is_some());
+        assert!(merk.get(&seq_key(10)).unwrap().is_none());
+        assert!(merk.get(&seq_key(89)).unwrap().is_none());
+        assert!(merk.get(&seq_key(90)).unwrap().is_some());
+
+        let mut keys = 0;
+        let mut iter = merk.raw_iter(None);
+        iter.seek_to_first();
+        while iter.valid() {
+            keys += 1;
+            iter.next();
+        }
+        assert_eq!(keys, 21);
+    }
 }
@@ -9,7 +9,7 @@ use crate::{
         tree::{Child, Tree as ProofTree},
         Decoder, Node,
     },
-    tree::{Link, RefWalker, Tree},
+    tree::{Link, RefWalker, Tree, ValueBinding},
     Hash, Result,
 };
 use failure::bail;
@@ -107,13 +107,20 @@ impl<'a> Restorer<'a> {
         let mut batch = WriteBatch::default();
 
         tree.visit_refs(&mut |proof_node| {
-            let (key, value) = match &proof_node.node {
-                Node::KV(key, value) => (key, value),
+            // TODO: encode tree node without cloning key/value
+            let (key, mut node) = match &proof_node.node {
+                Node::KV(key, value) => (key, Tree::new(key.clone(), value.clone())),
+                Node::KVRefValueHash(key, value, _) | Node::KVLayered(key, value, _) => (
+                    key,
+                    Tree::new_with_binding(
+                        key.clone(),
+                        value.clone(),
+                        proof_node.node.value_binding().expect("node has a value"),
+                    ),
+                ),
                 _ => return,
             };
 
-            // TODO: encode tree node without cloning key/value
-            let mut node = Tree::new(key.clone(), value.clone());
             *node.slot_mut(true) = proof_node.left.as_ref().map(Child::as_link);
             *node.slot_mut(false) = proof_node.right.as_ref().map(Child::as_link);
 
@@ -320,7 +327,9 @@ impl ProofTree {
 impl Child {
     fn as_link(&self) -> Link {
         let key = match &self.tree.node {
-            Node::KV(key, _) => key.as_slice(),
+            Node::KV(key, _) | Node::KVRefValueHash(key, ..) | Node::KVLayered(key, ..) => {
+                key.as_slice()
+            }
             // for the connection between the trunk and leaf chunks, we don't
             // have the child key so we must first write in an empty one. once
             // the leaf gets verified, we can write in this key to its parent
@@ -3,8 +3,7 @@ use storage::RawIterator;
 #[cfg(feature = "full")]
 use {
     super::tree::{execute, Tree as ProofTree},
-    crate::tree::Hash,
-    crate::tree::Tree,
+    crate::tree::{Blake3Hasher, Hash, Tree},
 };
 
 use super::{Node, Op};
@@ -142,7 +141,7 @@ pub(crate) fn get_next_chunk(
         let encoded_node = iter.value().unwrap();
         Tree::decode_into(&mut node, vec![], encoded_node);
 
-        let kv = Node::KV(key.to_vec(), node.value().to_vec());
+        let kv = Node::from_tree_node(key.to_vec(), &node);
         chunk.push(Op::Push(kv));
 
         if node.link(true).is_some() {
@@ -180,8 +179,8 @@ pub(crate) fn verify_leaf<I: Iterator<Item = Result<Op>>>(
     ops: I,
     expected_hash: Hash,
 ) -> Result<ProofTree> {
-    let tree = execute(ops, false, |node| match node {
-        Node::KV(..) => Ok(()),
+    let tree = execute::<Blake3Hasher, _, _>(ops, false, |node| match node {
+        Node::KV(..) | Node::KVRefValueHash(..) | Node::KVLayered(..) => Ok(()),
         _ => bail!("Leaf chunks must contain full subtree"),
     })?;
 
@@ -225,7 +224,7 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(Pr
 
         if remaining_depth > 0 {
             match tree.node {
-                Node::KV(..) => {}
+                Node::KV(..) | Node::KVRefValueHash(..) | Node::KVLayered(..) => {}
                 _ => bail!("Expected trunk inner nodes to contain keys and values"),
             }
             recurse(true, leftmost)?;
@@ -244,8 +243,11 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(Pr
     }
 
     let mut kv_only = true;
-    let tree = execute(ops, false, |node| {
-        kv_only &= matches!(node, Node::KV(_, _));
+    let tree = execute::<Blake3Hasher, _, _>(ops, false, |node| {
+        kv_only &= matches!(
+            node,
+            Node::KV(..) | Node::KVRefValueHash(..) | Node::KVLayered(..)
+        );
         Ok(())
     })?;
 
@@ -287,7 +289,7 @@ mod tests {
             match node {
                 Node::Hash(_) => counts.hash += 1,
                 Node::KVHash(_) => counts.kvhash += 1,
-                Node::KV(..) => counts.kv += 1,
+                Node::KV(..) | Node::KVRefValueHash(..) | Node::KVLayered(..) => counts.kv += 1,
             };
         });
 
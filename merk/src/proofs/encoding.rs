@@ -26,6 +26,26 @@ impl Encode for Op {
                 (value.len() as u16).encode_into(dest)?;
                 dest.write_all(value)?;
             }
+            Op::Push(Node::KVRefValueHash(key, value, referenced_value_hash)) => {
+                debug_assert!(key.len() < 256);
+                debug_assert!(value.len() < 65536);
+
+                dest.write_all(&[0x04, key.len() as u8])?;
+                dest.write_all(key)?;
+                (value.len() as u16).encode_into(dest)?;
+                dest.write_all(value)?;
+                dest.write_all(referenced_value_hash)?;
+            }
+            Op::Push(Node::KVLayered(key, value, child_root_hash)) => {
+                debug_assert!(key.len() < 256);
+                debug_assert!(value.len() < 65536);
+
+                dest.write_all(&[0x05, key.len() as u8])?;
+                dest.write_all(key)?;
+                (value.len() as u16).encode_into(dest)?;
+                dest.write_all(value)?;
+                dest.write_all(child_root_hash)?;
+            }
             Op::Parent => dest.write_all(&[0x10])?,
             Op::Child => dest.write_all(&[0x11])?,
         };
@@ -37,6 +57,8 @@ impl Encode for Op {
             Op::Push(Node::Hash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KVHash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KV(key, value)) => 4 + key.len() + value.len(),
+            Op::Push(Node::KVRefValueHash(key, value, _))
+            | Op::Push(Node::KVLayered(key, value, _)) => 4 + key.len() + value.len() + HASH_LENGTH,
             Op::Parent => 1,
             Op::Child => 1,
         })
@@ -69,6 +91,34 @@ impl Decode for Op {
 
                 Self::Push(Node::KV(key, value))
             }
+            0x04 => {
+                let key_len: u8 = Decode::decode(&mut input)?;
+                let mut key = vec![0; key_len as usize];
+                input.read_exact(key.as_mut_slice())?;
+
+                let value_len: u16 = Decode::decode(&mut input)?;
+                let mut value = vec![0; value_len as usize];
+                input.read_exact(value.as_mut_slice())?;
+
+                let mut referenced_value_hash = [0; HASH_LENGTH];
+                input.read_exact(&mut referenced_value_hash)?;
+
+                Self::Push(Node::KVRefValueHash(key, value, referenced_value_hash))
+            }
+            0x05 => {
+                let key_len: u8 = Decode::decode(&mut input)?;
+                let mut key = vec![0; key_len as usize];
+                input.read_exact(key.as_mut_slice())?;
+
+                let value_len: u16 = Decode::decode(&mut input)?;
+                let mut value = vec![0; value_len as usize];
+                input.read_exact(value.as_mut_slice())?;
+
+                let mut child_root_hash = [0; HASH_LENGTH];
+                input.read_exact(&mut child_root_hash)?;
+
+                Self::Push(Node::KVLayered(key, value, child_root_hash))
+            }
             0x10 => Self::Parent,
             0x11 => Self::Child,
             // TODO: get rid of `failure` with improvements to ed API (or removing dependency on ed)
@@ -181,6 +231,40 @@ mod test {
         assert_eq!(bytes, vec![0x03, 3, 1, 2, 3, 0, 3, 4, 5, 6]);
     }
 
+    #[test]
+    fn encode_push_kvrefvaluehash() {
+        let op = Op::Push(Node::KVRefValueHash(
+            vec![1, 2, 3],
+            vec![4, 5, 6],
+            [123; HASH_LENGTH],
+        ));
+        assert_eq!(op.encoding_length(), 10 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        let mut expected = vec![0x04, 3, 1, 2, 3, 0, 3, 4, 5, 6];
+        expected.extend_from_slice(&[123; HASH_LENGTH]);
+        assert_eq!(bytes, expected);
+        assert_eq!(Op::decode(bytes.as_slice()).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn encode_push_kvlayered() {
+        let op = Op::Push(Node::KVLayered(
+            vec![1, 2, 3],
+            vec![4, 5, 6],
+            [123; HASH_LENGTH],
+        ));
+        assert_eq!(op.encoding_length(), 10 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        let mut expected = vec![0x05, 3, 1, 2, 3, 0, 3, 4, 5, 6];
+        expected.extend_from_slice(&[123; HASH_LENGTH]);
+        assert_eq!(bytes, expected);
+        assert_eq!(Op::decode(bytes.as_slice()).expect("decode failed"), op);
+    }
+
     #[test]
     fn encode_parent() {
         let op = Op::Parent;
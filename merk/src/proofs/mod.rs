@@ -7,7 +7,7 @@ pub use encoding::{encode_into, Decoder};
 pub use query::Query;
 pub use tree::Tree;
 
-use crate::tree::Hash;
+use crate::tree::{Hash, ValueBinding};
 
 /// A proof operator, executed to verify the data in a Merkle proof.
 #[derive(Debug, PartialEq)]
@@ -38,4 +38,44 @@ pub enum Node {
 
     /// Represents the key and value of a tree node.
     KV(Vec<u8>, Vec<u8>),
+
+    /// Represents the key and value of a reference tree node, and the value
+    /// hash of the node it refers to. The node's value hash is computed from
+    /// both of them, so the value is bound by the proof as for `KV`.
+    KVRefValueHash(Vec<u8>, Vec<u8>, Hash),
+
+    /// Represents the key and value of a tree node holding a subtree, and the
+    /// root hash of that subtree. The node's layered value hash is computed
+    /// from both of them, so the subtree root is bound by the proof.
+    KVLayered(Vec<u8>, Vec<u8>, Hash),
+}
+
+impl Node {
+    /// Creates a `Node::KV` for the tree node, a `Node::KVRefValueHash` if its
+    /// value hash is bound to the value hash of a referenced node, or a
+    /// `Node::KVLayered` if it is bound to the root hash of a subtree.
+    pub(crate) fn from_tree_node(key: Vec<u8>, tree: &crate::tree::Tree) -> Self {
+        match tree.value_binding() {
+            ValueBinding::Plain => Node::KV(key, tree.value().to_vec()),
+            ValueBinding::Reference(referenced_value_hash) => {
+                Node::KVRefValueHash(key, tree.value().to_vec(), *referenced_value_hash)
+            }
+            ValueBinding::Layered(child_root_hash) => {
+                Node::KVLayered(key, tree.value().to_vec(), *child_root_hash)
+            }
+        }
+    }
+
+    /// Returns what the value hash of a `KV`, `KVRefValueHash` or `KVLayered`
+    /// node is bound to besides its value, `None` for other nodes.
+    pub fn value_binding(&self) -> Option<ValueBinding> {
+        match self {
+            Node::KV(..) => Some(ValueBinding::Plain),
+            Node::KVRefValueHash(_, _, referenced_value_hash) => {
+                Some(ValueBinding::Reference(*referenced_value_hash))
+            }
+            Node::KVLayered(_, _, child_root_hash) => Some(ValueBinding::Layered(*child_root_hash)),
+            _ => None,
+        }
+    }
 }
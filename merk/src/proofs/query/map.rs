@@ -6,6 +6,7 @@ use std::{
 use anyhow::{anyhow, bail, ensure, Result};
 
 use super::super::Node;
+use crate::tree::{Blake3Hasher, Hash, Hasher, ValueBinding};
 
 /// `MapBuilder` allows a consumer to construct a `Map` by inserting the nodes
 /// contained in a proof, in key-order.
@@ -14,18 +15,27 @@ pub struct MapBuilder(Map);
 impl MapBuilder {
     /// Creates a new `MapBuilder` with an empty internal `Map`.
     pub fn new() -> Self {
+        Self::new_by::<Blake3Hasher>()
+    }
+
+    /// Same as `new`, but for proofs of a tree hashed with `H`.
+    pub fn new_by<H: Hasher>() -> Self {
         Self(Map {
             entries: Default::default(),
+            bindings: Default::default(),
+            value_hasher: ValueBinding::value_hash::<H>,
             right_edge: true,
         })
     }
 
-    /// Adds the node's data to the underlying `Map` (if node is type `KV`), or
-    /// makes a note of non-contiguous data (if node is type `KVHash` or
-    /// `Hash`).
+    /// Adds the node's data to the underlying `Map` (if node is type `KV`,
+    /// `KVRefValueHash` or `KVLayered`), or makes a note of non-contiguous data
+    /// (if node is type `KVHash` or `Hash`).
     pub fn insert(&mut self, node: &Node) -> Result<()> {
         match node {
-            Node::KV(key, value) => {
+            Node::KV(key, value)
+            | Node::KVRefValueHash(key, value, _)
+            | Node::KVLayered(key, value, _) => {
                 if let Some((prev_key, _)) = self.0.entries.last_key_value() {
                     ensure!(
                         key > prev_key,
@@ -35,6 +45,12 @@ impl MapBuilder {
 
                 let value = (self.0.right_edge, value.clone());
                 self.0.entries.insert(key.clone(), value);
+                match node.value_binding() {
+                    Some(ValueBinding::Plain) | None => {}
+                    Some(binding) => {
+                        self.0.bindings.insert(key.clone(), binding);
+                    }
+                }
                 self.0.right_edge = true;
             }
             _ => self.0.right_edge = false,
@@ -56,6 +72,10 @@ impl MapBuilder {
 #[derive(Debug)]
 pub struct Map {
     entries: BTreeMap<Vec<u8>, (bool, Vec<u8>)>,
+    // Bindings of entries whose value hashes are not the plain hashes of their
+    // values
+    bindings: BTreeMap<Vec<u8>, ValueBinding>,
+    value_hasher: fn(&ValueBinding, &[u8]) -> Hash,
     right_edge: bool,
 }
 
@@ -80,6 +100,29 @@ impl Map {
         Ok(entry)
     }
 
+    /// Gets what the value hash of a key included in the proof is bound to
+    /// besides its value, or `None` if the proof doesn't include the key's
+    /// data.
+    pub fn get_binding(&self, key: &[u8]) -> Option<ValueBinding> {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+        Some(
+            self.bindings
+                .get(key)
+                .copied()
+                .unwrap_or(ValueBinding::Plain),
+        )
+    }
+
+    /// Gets the value hash the proof committed to for a key included in the
+    /// proof, or `None` if the proof doesn't include the key's data.
+    pub fn get_value_hash(&self, key: &[u8]) -> Option<Hash> {
+        let binding = self.get_binding(key)?;
+        let (_, value) = &self.entries[key];
+        Some((self.value_hasher)(&binding, value.as_slice()))
+    }
+
     pub fn all(&self) -> Iter<'_, Vec<u8>, (bool, Vec<u8>)> {
         self.entries.iter()
     }
@@ -210,7 +253,10 @@ impl<'a> Iterator for Range<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::HASH_LENGTH;
+    use crate::{
+        tree::{combine_hash, value_hash},
+        HASH_LENGTH,
+    };
 
     #[test]
     #[should_panic(expected = "Expected nodes to be in increasing key order")]
@@ -337,4 +383,26 @@ mod tests {
         range.next().unwrap().unwrap();
         assert_eq!(range.next().unwrap().unwrap(), (&[1][..], &[1][..]));
     }
+
+    #[test]
+    fn map_value_hashes() {
+        let mut builder = MapBuilder::new();
+        builder.insert(&Node::KV(vec![1], vec![1])).unwrap();
+        builder
+            .insert(&Node::KVRefValueHash(vec![2], vec![2], [3; HASH_LENGTH]))
+            .unwrap();
+        let map = builder.build();
+
+        assert_eq!(map.get(&[2]).unwrap(), Some(&[2][..]));
+        assert_eq!(map.get_value_hash(&[1]), Some(value_hash(&[1])));
+        assert_eq!(
+            map.get_value_hash(&[2]),
+            Some(combine_hash(&value_hash(&[2]), &[3; HASH_LENGTH]))
+        );
+        assert_eq!(
+            map.get_binding(&[2]),
+            Some(ValueBinding::Reference([3; HASH_LENGTH]))
+        );
+        assert_eq!(map.get_value_hash(&[3]), None);
+    }
 }
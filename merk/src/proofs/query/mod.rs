@@ -9,12 +9,12 @@ use std::{
 
 use anyhow::{bail, Result};
 pub use map::*;
-use storage::{rocksdb_storage::RawPrefixedTransactionalIterator, RawIterator};
+use storage::RawIterator;
 #[cfg(feature = "full")]
 use {super::Op, std::collections::LinkedList};
 
 use super::{tree::execute, Decoder, Node};
-use crate::tree::{Fetch, Hash, Link, RefWalker};
+use crate::tree::{Blake3Hasher, Fetch, Hash, Hasher, Link, RefWalker};
 
 /// `Query` represents one or more keys or ranges of keys, which can be used to
 /// resolve a proof which will include all of the requested values.
@@ -367,7 +367,7 @@ impl QueryItem {
         !matches!(self, QueryItem::Key(_))
     }
 
-    pub fn seek_for_iter(&self, iter: &mut RawPrefixedTransactionalIterator, left_to_right: bool) {
+    pub fn seek_for_iter<I: RawIterator>(&self, iter: &mut I, left_to_right: bool) {
         match self {
             QueryItem::Key(_) => {}
             QueryItem::Range(Range { start, end }) => {
@@ -471,9 +471,9 @@ impl QueryItem {
         a.len().cmp(&b.len())
     }
 
-    pub fn iter_is_valid_for_type(
+    pub fn iter_is_valid_for_type<I: RawIterator>(
         &self,
-        iter: &RawPrefixedTransactionalIterator,
+        iter: &I,
         limit: Option<u16>,
         left_to_right: bool,
     ) -> bool {
@@ -690,9 +690,11 @@ impl<'a, S> RefWalker<'a, S>
 where
     S: Fetch + Sized + Clone,
 {
-    /// Creates a `Node::KV` from the key/value pair of the root node.
+    /// Creates a `Node::KV` from the key/value pair of the root node, or a
+    /// `Node::KVRefValueHash` or `Node::KVLayered` if its value hash is bound
+    /// to the one of another node or to a subtree root.
     pub(crate) fn to_kv_node(&self) -> Node {
-        Node::KV(self.tree().key().to_vec(), self.tree().value().to_vec())
+        Node::from_tree_node(self.tree().key().to_vec(), self.tree())
     }
 
     /// Creates a `Node::KVHash` from the hash of the key/value pair of the root
@@ -703,7 +705,7 @@ where
 
     /// Creates a `Node::Hash` from the hash of the node.
     pub(crate) fn to_hash_node(&self) -> Node {
-        Node::Hash(self.tree().hash())
+        Node::Hash(self.tree().hash_by::<S::Hasher>())
     }
 
     #[cfg(feature = "full")]
@@ -861,16 +863,21 @@ where
 }
 
 pub fn verify(bytes: &[u8], expected_hash: Hash) -> Result<Map> {
+    verify_by::<Blake3Hasher>(bytes, expected_hash)
+}
+
+/// Same as `verify`, but for a proof of a tree hashed with `H`.
+pub fn verify_by<H: Hasher>(bytes: &[u8], expected_hash: Hash) -> Result<Map> {
     let ops = Decoder::new(bytes);
-    let mut map_builder = MapBuilder::new();
+    let mut map_builder = MapBuilder::new_by::<H>();
 
-    let root = execute(ops, true, |node| map_builder.insert(node))?;
+    let root = execute::<H, _, _>(ops, true, |node| map_builder.insert(node))?;
 
-    if root.hash() != expected_hash {
+    if root.hash_by::<H>() != expected_hash {
         bail!(
             "Proof did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
             expected_hash,
-            root.hash()
+            root.hash_by::<H>()
         );
     }
 
@@ -878,12 +885,17 @@ pub fn verify(bytes: &[u8], expected_hash: Hash) -> Result<Map> {
 }
 
 pub fn execute_proof(bytes: &[u8]) -> Result<(Hash, Map)> {
+    execute_proof_by::<Blake3Hasher>(bytes)
+}
+
+/// Same as `execute_proof`, but for a proof of a tree hashed with `H`.
+pub fn execute_proof_by<H: Hasher>(bytes: &[u8]) -> Result<(Hash, Map)> {
     let ops = Decoder::new(bytes);
-    let mut map_builder = MapBuilder::new();
+    let mut map_builder = MapBuilder::new_by::<H>();
 
-    let root = execute(ops, true, |node| map_builder.insert(node))?;
+    let root = execute::<H, _, _>(ops, true, |node| map_builder.insert(node))?;
 
-    Ok((root.hash(), map_builder.build()))
+    Ok((root.hash_by::<H>(), map_builder.build()))
 }
 
 /// Verifies the encoded proof with the given query and expected hash.
@@ -909,8 +921,11 @@ pub fn verify_query(
 
     let ops = Decoder::new(bytes);
 
-    let root = execute(ops, true, |node| {
-        if let Node::KV(key, value) = node {
+    let root = execute::<Blake3Hasher, _, _>(ops, true, |node| {
+        if let Node::KV(key, value)
+        | Node::KVRefValueHash(key, value, _)
+        | Node::KVLayered(key, value, _) = node
+        {
             while let Some(item) = query.peek() {
                 // get next item in query
                 let query_item = *item;
@@ -933,7 +948,9 @@ pub fn verify_query(
 
                         // lower bound is proven - the preceding tree node
                         // is lower than the bound
-                        Some(Node::KV(..)) => {}
+                        Some(Node::KV(..))
+                        | Some(Node::KVRefValueHash(..))
+                        | Some(Node::KVLayered(..)) => {}
 
                         // cannot verify lower bound - we have an abridged
                         // tree so we cannot tell what the preceding key was
@@ -983,7 +1000,7 @@ pub fn verify_query(
     if query.peek().is_some() {
         match last_push {
             // last node in tree was less than queried item
-            Some(Node::KV(..)) => {}
+            Some(Node::KV(..)) | Some(Node::KVRefValueHash(..)) | Some(Node::KVLayered(..)) => {}
 
             // proof contains abridged data so we cannot verify absence of
             // remaining query items
@@ -1,7 +1,7 @@
 use anyhow::{bail, Result};
 
 use super::{Node, Op};
-use crate::tree::{kv_hash, node_hash, Hash, NULL_HASH};
+use crate::tree::{Blake3Hasher, Hash, Hasher, NULL_HASH};
 
 /// Contains a tree's child node and its hash. The hash can always be assumed to
 /// be up-to-date.
@@ -43,16 +43,25 @@ impl PartialEq for Tree {
 impl Tree {
     /// Gets or computes the hash for this tree node.
     pub fn hash(&self) -> Hash {
-        fn compute_hash(tree: &Tree, kv_hash: Hash) -> Hash {
-            node_hash(&kv_hash, &tree.child_hash(true), &tree.child_hash(false))
-        }
+        self.hash_by::<Blake3Hasher>()
+    }
+
+    /// Same as `hash`, but for a tree hashed with `H`.
+    pub fn hash_by<H: Hasher>(&self) -> Hash {
+        let compute_hash =
+            |kv_hash: Hash| H::node_hash(&kv_hash, &self.child_hash(true), &self.child_hash(false));
 
         match &self.node {
             Node::Hash(hash) => *hash,
-            Node::KVHash(kv_hash) => compute_hash(self, *kv_hash),
-            Node::KV(key, value) => {
-                let kv_hash = kv_hash(key.as_slice(), value.as_slice());
-                compute_hash(self, kv_hash)
+            Node::KVHash(kv_hash) => compute_hash(*kv_hash),
+            Node::KV(key, value) => compute_hash(H::kv_hash(key.as_slice(), value.as_slice())),
+            Node::KVRefValueHash(key, value, _) | Node::KVLayered(key, value, _) => {
+                let binding = self
+                    .node
+                    .value_binding()
+                    .expect("node has a key/value pair");
+                let value_hash = binding.value_hash::<H>(value.as_slice());
+                compute_hash(H::kv_digest_to_kv_hash(key.as_slice(), &value_hash))
             }
         }
     }
@@ -112,14 +121,14 @@ impl Tree {
 
     /// Attaches the child to the `Tree`'s given side. Panics if there is
     /// already a child attached to this side.
-    pub(crate) fn attach(&mut self, left: bool, child: Self) -> Result<()> {
+    pub(crate) fn attach<H: Hasher>(&mut self, left: bool, child: Self) -> Result<()> {
         if self.child(left).is_some() {
             bail!("Tried to attach to left child, but it is already Some");
         }
 
         self.height = self.height.max(child.height + 1);
 
-        let hash = child.hash();
+        let hash = child.hash_by::<H>();
         let tree = Box::new(child);
         *self.child_mut(left) = Some(Child { tree, hash });
 
@@ -139,8 +148,8 @@ impl Tree {
 
     /// Consumes the tree node, calculates its hash, and returns a `Node::Hash`
     /// variant.
-    fn into_hash(self) -> Self {
-        Node::Hash(self.hash()).into()
+    fn into_hash<H: Hasher>(self) -> Self {
+        Node::Hash(self.hash_by::<H>()).into()
     }
 
     // #[cfg(feature = "full")]
@@ -233,8 +242,11 @@ impl<'a> Iterator for LayerIter<'a> {
 /// `visit_node` will be called once for every push operation in the proof, in
 /// key-order. If `visit_node` returns an `Err` result, it will halt the
 /// execution and `execute` will return the error.
-pub(crate) fn execute<I, F>(ops: I, collapse: bool, mut visit_node: F) -> Result<Tree>
+///
+/// Nodes are hashed with `H`.
+pub(crate) fn execute<H, I, F>(ops: I, collapse: bool, mut visit_node: F) -> Result<Tree>
 where
+    H: Hasher,
     I: IntoIterator<Item = Result<Op>>,
     F: FnMut(&Node) -> Result<()>,
 {
@@ -252,12 +264,22 @@ where
         match op? {
             Op::Parent => {
                 let (mut parent, child) = (try_pop(&mut stack)?, try_pop(&mut stack)?);
-                parent.attach(true, if collapse { child.into_hash() } else { child })?;
+                let child = if collapse {
+                    child.into_hash::<H>()
+                } else {
+                    child
+                };
+                parent.attach::<H>(true, child)?;
                 stack.push(parent);
             }
             Op::Child => {
                 let (child, mut parent) = (try_pop(&mut stack)?, try_pop(&mut stack)?);
-                parent.attach(false, if collapse { child.into_hash() } else { child })?;
+                let child = if collapse {
+                    child.into_hash::<H>()
+                } else {
+                    child
+                };
+                parent.attach::<H>(false, child)?;
                 stack.push(parent);
             }
             Op::Push(node) => {
@@ -296,13 +318,13 @@ mod test {
 
         let mut tree = make_node(3);
         let mut left = make_node(1);
-        left.attach(true, make_node(0)).unwrap();
-        left.attach(false, make_node(2)).unwrap();
+        left.attach::<Blake3Hasher>(true, make_node(0)).unwrap();
+        left.attach::<Blake3Hasher>(false, make_node(2)).unwrap();
         let mut right = make_node(5);
-        right.attach(true, make_node(4)).unwrap();
-        right.attach(false, make_node(6)).unwrap();
-        tree.attach(true, left).unwrap();
-        tree.attach(false, right).unwrap();
+        right.attach::<Blake3Hasher>(true, make_node(4)).unwrap();
+        right.attach::<Blake3Hasher>(false, make_node(6)).unwrap();
+        tree.attach::<Blake3Hasher>(true, left).unwrap();
+        tree.attach::<Blake3Hasher>(false, right).unwrap();
 
         tree
     }
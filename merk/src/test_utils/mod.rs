@@ -7,7 +7,7 @@ pub use crash_merk::CrashMerk;
 use rand::prelude::*;
 pub use temp_merk::TempMerk;
 
-use crate::tree::{BatchEntry, MerkBatch, NoopCommit, Op, PanicSource, Tree, Walker};
+use crate::tree::{BatchEntry, MergeFns, MerkBatch, NoopCommit, Op, PanicSource, Tree, Walker};
 
 pub fn assert_tree_invariants(tree: &Tree) {
     assert!(tree.balance_factor().abs() < 2);
@@ -34,10 +34,11 @@ pub fn assert_tree_invariants(tree: &Tree) {
 
 pub fn apply_memonly_unchecked(tree: Tree, batch: &MerkBatch<Vec<u8>>) -> Tree {
     let walker = Walker::<PanicSource>::new(tree, PanicSource {});
-    let mut tree = Walker::<PanicSource>::apply_to(Some(walker), batch, PanicSource {})
-        .expect("apply failed")
-        .0
-        .expect("expected tree");
+    let mut tree =
+        Walker::<PanicSource>::apply_to(Some(walker), batch, PanicSource {}, &MergeFns::default())
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
     tree.commit(&mut NoopCommit {}).expect("commit failed");
     tree
 }
@@ -50,7 +51,7 @@ pub fn apply_memonly(tree: Tree, batch: &MerkBatch<Vec<u8>>) -> Tree {
 
 pub fn apply_to_memonly(maybe_tree: Option<Tree>, batch: &MerkBatch<Vec<u8>>) -> Option<Tree> {
     let maybe_walker = maybe_tree.map(|tree| Walker::<PanicSource>::new(tree, PanicSource {}));
-    Walker::<PanicSource>::apply_to(maybe_walker, batch, PanicSource {})
+    Walker::<PanicSource>::apply_to(maybe_walker, batch, PanicSource {}, &MergeFns::default())
         .expect("apply failed")
         .0
         .map(|mut tree| {
@@ -1,10 +1,13 @@
 use anyhow::Result;
 
-use super::Tree;
+use super::{Blake3Hasher, Hasher, Tree};
 
 /// To be used when committing a tree (writing it to a store after applying the
 /// changes).
 pub trait Commit {
+    /// The hash function hashes of committed nodes are computed with.
+    type Hasher: Hasher;
+
     /// Called once per updated node when a finalized tree is to be written to a
     /// backing store or cache.
     fn write(&mut self, tree: &Tree) -> Result<()>;
@@ -18,10 +21,22 @@ pub trait Commit {
     }
 }
 
+/// A `Commit` which can be split to commit parts of a tree on different
+/// threads, see `Tree::commit_parallel`.
+pub trait ParallelCommit: Commit + Send + Sized {
+    /// Creates an empty `Commit` to write a part of the tree to.
+    fn fork(&self) -> Self;
+
+    /// Takes over writes of a `Commit` created by `fork`.
+    fn join(&mut self, other: Self);
+}
+
 /// A `Commit` implementation which does not write to a store and does not prune
 /// any nodes from the Tree. Useful when only keeping a tree in memory.
 pub struct NoopCommit {}
 impl Commit for NoopCommit {
+    type Hasher = Blake3Hasher;
+
     fn write(&mut self, _tree: &Tree) -> Result<()> {
         Ok(())
     }
@@ -1,8 +1,51 @@
+use std::io::{Read, Write};
+
 use anyhow::{anyhow, Error};
 use ed::{Decode, Encode};
 use storage::{Storage, Store};
 
-use super::Tree;
+use super::{kv::KV, Link, Tree, TreeInner};
+
+/// Version of the node encoding, written as the first byte of every encoded
+/// node. Legacy nodes start with the `Option` tag of the left link instead,
+/// which is always `0` or `1`, so versions start at `2`.
+pub const NODE_ENCODING_VERSION: u8 = 2;
+
+impl Encode for Tree {
+    #[inline]
+    fn encode_into<W: Write>(&self, out: &mut W) -> ed::Result<()> {
+        out.write_all(&[NODE_ENCODING_VERSION])?;
+        self.inner.encode_into(out)
+    }
+
+    #[inline]
+    fn encoding_length(&self) -> ed::Result<usize> {
+        Ok(1 + self.inner.encoding_length()?)
+    }
+}
+
+impl Decode for Tree {
+    #[inline]
+    fn decode<R: Read>(mut input: R) -> ed::Result<Self> {
+        let mut version = [0];
+        input.read_exact(&mut version)?;
+        let inner = match version[0] {
+            NODE_ENCODING_VERSION => TreeInner::decode(input)?,
+            // Legacy node, the byte read is the tag of its left link
+            0 | 1 => {
+                let mut input = (&version[..]).chain(input);
+                let left = Option::<Link>::decode(&mut input)?;
+                let right = Option::<Link>::decode(&mut input)?;
+                let kv = KV::decode_legacy(input)?;
+                TreeInner { left, right, kv }
+            }
+            version => failure::bail!("Unknown node encoding version {}", version),
+        };
+        Ok(Tree {
+            inner: Box::new(inner),
+        })
+    }
+}
 
 impl Store for Tree {
     type Error = Error;
@@ -70,17 +113,22 @@ impl Tree {
 
 #[cfg(test)]
 mod tests {
-    use super::{super::Link, *};
+    use super::{
+        super::{value_hash, ValueBinding},
+        *,
+    };
 
     #[test]
     fn encode_leaf_tree() {
-        let tree = Tree::from_fields(vec![0], vec![1], [55; 32], None, None);
-        assert_eq!(tree.encoding_length(), 35);
+        let tree = Tree::from_fields(vec![0], vec![1], [55; 32], [77; 32], None, None);
+        assert_eq!(tree.encoding_length(), 69);
         assert_eq!(
             tree.encode(),
             vec![
-                0, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 1,
+                2, 0, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+                55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 77, 77, 77, 77, 77, 77, 77,
+                77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77,
+                77, 77, 77, 77, 0, 1,
             ]
         );
     }
@@ -92,6 +140,7 @@ mod tests {
             vec![0],
             vec![1],
             [55; 32],
+            [77; 32],
             Some(Link::Modified {
                 pending_writes: 1,
                 child_heights: (123, 124),
@@ -108,6 +157,7 @@ mod tests {
             vec![0],
             vec![1],
             [55; 32],
+            [77; 32],
             Some(Link::Loaded {
                 hash: [66; 32],
                 child_heights: (123, 124),
@@ -118,10 +168,11 @@ mod tests {
         assert_eq!(
             tree.encode(),
             vec![
-                1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
+                2, 1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
                 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 123, 124, 0, 55, 55, 55,
                 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 55, 55, 55, 55, 55, 55, 55, 1
+                55, 55, 55, 55, 55, 55, 55, 55, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77,
+                77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 0, 1
             ]
         );
     }
@@ -132,6 +183,7 @@ mod tests {
             vec![0],
             vec![1],
             [55; 32],
+            [77; 32],
             Some(Link::Uncommitted {
                 hash: [66; 32],
                 child_heights: (123, 124),
@@ -142,10 +194,11 @@ mod tests {
         assert_eq!(
             tree.encode(),
             vec![
-                1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
+                2, 1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
                 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 123, 124, 0, 55, 55, 55,
                 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 55, 55, 55, 55, 55, 55, 55, 1
+                55, 55, 55, 55, 55, 55, 55, 55, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77,
+                77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 0, 1
             ]
         );
     }
@@ -156,6 +209,7 @@ mod tests {
             vec![0],
             vec![1],
             [55; 32],
+            [77; 32],
             Some(Link::Reference {
                 hash: [66; 32],
                 child_heights: (123, 124),
@@ -163,14 +217,15 @@ mod tests {
             }),
             None,
         );
-        assert_eq!(tree.encoding_length(), 71);
+        assert_eq!(tree.encoding_length(), 105);
         assert_eq!(
             tree.encode(),
             vec![
-                1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
+                2, 1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
                 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 123, 124, 0, 55, 55, 55,
                 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-                55, 55, 55, 55, 55, 55, 55, 55, 1
+                55, 55, 55, 55, 55, 55, 55, 55, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77,
+                77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 0, 1
             ]
         );
     }
@@ -178,8 +233,9 @@ mod tests {
     #[test]
     fn decode_leaf_tree() {
         let bytes = vec![
-            0, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-            1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+            2, 0, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+            55, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77,
+            77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 0, 1,
         ];
         let tree = Tree::decode(vec![0], bytes.as_slice());
         assert_eq!(tree.key(), &[0]);
@@ -189,10 +245,11 @@ mod tests {
     #[test]
     fn decode_reference_tree() {
         let bytes = vec![
-            1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
+            2, 1, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66,
             66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 123, 124, 0, 55, 55, 55, 55, 55,
             55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
-            55, 55, 55, 55, 55, 1,
+            55, 55, 55, 55, 55, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77,
+            77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 77, 0, 1,
         ];
         let tree = Tree::decode(vec![0], bytes.as_slice());
         assert_eq!(tree.key(), &[0]);
@@ -210,4 +267,31 @@ mod tests {
             panic!("Expected Link::Reference");
         }
     }
+
+    #[test]
+    fn decode_legacy_tree() {
+        // A leaf node as written before node encodings were versioned
+        let bytes = vec![
+            0, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
+            55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 1,
+        ];
+        let tree = Tree::decode(vec![0], bytes.as_slice());
+        assert_eq!(tree.key(), &[0]);
+        assert_eq!(tree.value(), &[1]);
+        assert_eq!(tree.kv_hash(), &[55; 32]);
+        assert_eq!(tree.value_hash(), &value_hash(&[1]));
+        assert_eq!(tree.value_binding(), &ValueBinding::Plain);
+        assert!(tree.link(true).is_none());
+
+        // It is written back in the current encoding
+        assert_eq!(tree.encode()[0], NODE_ENCODING_VERSION);
+        let reencoded = Tree::decode(vec![0], tree.encode().as_slice());
+        assert_eq!(reencoded.value_hash(), tree.value_hash());
+    }
+
+    #[test]
+    fn decode_unknown_version_fails() {
+        let bytes = vec![NODE_ENCODING_VERSION + 1, 0, 0];
+        assert!(<Tree as Store>::decode(bytes.as_slice()).is_err());
+    }
 }
@@ -118,12 +118,23 @@ fn make_batch(maybe_tree: Option<&Tree>, size: u64, seed: u64) -> Vec<BatchEntry
 fn apply_to_map(map: &mut Map, batch: &Batch) {
     for entry in batch.iter() {
         match entry {
-            (key, Op::Put(value)) => {
+            (key, Op::Put(value))
+            | (key, Op::PutReference(value, _))
+            | (key, Op::PutLayered(value, _)) => {
                 map.insert(key.to_vec(), value.to_vec());
             }
             (key, Op::Delete) => {
                 map.remove(key);
             }
+            (key, Op::DeleteRange(end)) => {
+                map.retain(|k, _| k < key || k >= end);
+            }
+            (key, Op::Merge(operand, merge_fn_id)) => {
+                let value = MergeFns::default()
+                    .resolve(*merge_fn_id, map.get(key).map(Vec::as_slice), operand)
+                    .unwrap();
+                map.insert(key.to_vec(), value);
+            }
         }
     }
 }
@@ -1,4 +1,5 @@
 use integer_encoding::*;
+use sha2::Digest;
 
 /// The length of a `Hash` (in bytes).
 pub const HASH_LENGTH: usize = 32;
@@ -9,51 +10,161 @@ pub const NULL_HASH: Hash = [0; HASH_LENGTH];
 /// A cryptographic hash digest.
 pub type Hash = [u8; HASH_LENGTH];
 
-pub fn value_hash(value: &[u8]) -> Hash {
-    // TODO: make generic to allow other hashers
-    let mut hasher = blake3::Hasher::new();
+/// Domain separation tag of `Hasher::layered_value_hash`.
+const LAYERED_VALUE_HASH_TAG: u8 = 0xff;
+
+/// Domain separation tag of `Hasher::combine_hash`.
+const REFERENCE_VALUE_HASH_TAG: u8 = 0xfe;
+
+/// A hash function trees are hashed with. Only `digest` has to be implemented,
+/// the way keys, values and nodes are hashed with it is the same for all hash
+/// functions.
+pub trait Hasher: Send + Sync + 'static {
+    /// The name the hash function is recorded under in Merk metadata.
+    const NAME: &'static [u8];
+
+    /// Hashes the concatenation of `parts`.
+    fn digest(parts: &[&[u8]]) -> Hash;
+
+    /// Hashes a value.
+    ///
+    /// The result is Hash(value_len, value)
+    fn value_hash(value: &[u8]) -> Hash {
+        let val_length = value.len().encode_var_vec();
+        Self::digest(&[val_length.as_slice(), value])
+    }
+
+    /// Hashes a key/value pair.
+    ///
+    /// The result is Hash(key_len, key, Hash(value_len, value))
+    fn kv_hash(key: &[u8], value: &[u8]) -> Hash {
+        Self::kv_digest_to_kv_hash(key, &Self::value_hash(value))
+    }
+
+    /// Hashes a key together with an already computed value hash.
+    ///
+    /// The result is Hash(key_len, key, value_hash)
+    fn kv_digest_to_kv_hash(key: &[u8], value_hash: &Hash) -> Hash {
+        let key_length = key.len().encode_var_vec();
+        Self::digest(&[key_length.as_slice(), key, value_hash])
+    }
+
+    /// Combines two hashes into one, used to bind the value hash of a node to
+    /// the value hash of the node it refers to.
+    ///
+    /// The result is Hash(0xfe, hash_one, hash_two), tagged the same way as
+    /// `layered_value_hash` but with its own tag.
+    fn combine_hash(hash_one: &Hash, hash_two: &Hash) -> Hash {
+        Self::digest(&[&[REFERENCE_VALUE_HASH_TAG], hash_one, hash_two])
+    }
+
+    /// Binds the value hash of a node holding a subtree to the root hash of
+    /// that subtree.
+    ///
+    /// The result is Hash(0xff, value_hash, child_root_hash), the leading tag
+    /// can not start an input of the same length to any other hash here, so a
+    /// layered value hash can not pass for a value or reference one. The
+    /// same holds for the tag of `combine_hash`.
+    fn layered_value_hash(value_hash: &Hash, child_root_hash: &Hash) -> Hash {
+        Self::digest(&[&[LAYERED_VALUE_HASH_TAG], value_hash, child_root_hash])
+    }
+
+    /// Hashes a node based on the hash of its key/value pair, the hash of its
+    /// left child (if any), and the hash of its right child (if any).
+    fn node_hash(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
+        Self::digest(&[kv, left, right])
+    }
+}
+
+/// BLAKE3, the default hash function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Blake3Hasher;
+
+impl Hasher for Blake3Hasher {
+    const NAME: &'static [u8] = b"blake3";
 
-    let val_length = value.len().encode_var_vec();
-    hasher.update(val_length.as_slice());
-    hasher.update(value);
+    fn digest(parts: &[&[u8]]) -> Hash {
+        let mut hasher = blake3::Hasher::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        *hasher.finalize().as_bytes()
+    }
+}
+
+/// SHA-256, for verifiers which have no BLAKE3 at hand.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sha256Hasher;
 
-    let res = hasher.finalize();
-    let mut hash: Hash = Default::default();
-    hash.copy_from_slice(res.as_bytes());
-    hash
+impl Hasher for Sha256Hasher {
+    const NAME: &'static [u8] = b"sha256";
+
+    fn digest(parts: &[&[u8]]) -> Hash {
+        let mut hasher = sha2::Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
 }
 
-/// Hashes a key/value pair.
-///
-/// The result is Hash(key_len, key, Hash(value_len, value))
+/// Hashes a value with BLAKE3, see `Hasher::value_hash`.
+pub fn value_hash(value: &[u8]) -> Hash {
+    Blake3Hasher::value_hash(value)
+}
+
+/// Hashes a key/value pair with BLAKE3, see `Hasher::kv_hash`.
 pub fn kv_hash(key: &[u8], value: &[u8]) -> Hash {
-    // TODO: make generic to allow other hashers
-    let mut hasher = blake3::Hasher::new();
+    Blake3Hasher::kv_hash(key, value)
+}
 
-    let key_length = key.len().encode_var_vec();
-    hasher.update(key_length.as_slice());
-    hasher.update(key);
+/// Hashes a key together with an already computed value hash with BLAKE3, see
+/// `Hasher::kv_digest_to_kv_hash`.
+pub fn kv_digest_to_kv_hash(key: &[u8], value_hash: &Hash) -> Hash {
+    Blake3Hasher::kv_digest_to_kv_hash(key, value_hash)
+}
 
-    let value_hash = value_hash(value);
-    hasher.update(value_hash.as_slice());
+/// Combines two hashes into one with BLAKE3, see `Hasher::combine_hash`.
+pub fn combine_hash(hash_one: &Hash, hash_two: &Hash) -> Hash {
+    Blake3Hasher::combine_hash(hash_one, hash_two)
+}
 
-    let res = hasher.finalize();
-    let mut hash: Hash = Default::default();
-    hash.copy_from_slice(res.as_bytes());
-    hash
+/// Binds a value hash to a subtree root hash with BLAKE3, see
+/// `Hasher::layered_value_hash`.
+pub fn layered_value_hash(value_hash: &Hash, child_root_hash: &Hash) -> Hash {
+    Blake3Hasher::layered_value_hash(value_hash, child_root_hash)
 }
 
-/// Hashes a node based on the hash of its key/value pair, the hash of its left
-/// child (if any), and the hash of its right child (if any).
+/// Hashes a node with BLAKE3, see `Hasher::node_hash`.
 pub fn node_hash(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
-    // TODO: make generic to allow other hashers
-    let mut hasher = blake3::Hasher::new();
-    hasher.update(kv);
-    hasher.update(left);
-    hasher.update(right);
-
-    let res = hasher.finalize();
-    let mut hash: Hash = Default::default();
-    hash.copy_from_slice(res.as_bytes());
-    hash
+    Blake3Hasher::node_hash(kv, left, right)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hashers_differ() {
+        assert_ne!(
+            Blake3Hasher::kv_hash(b"key", b"value"),
+            Sha256Hasher::kv_hash(b"key", b"value")
+        );
+        assert_eq!(
+            Sha256Hasher::value_hash(b""),
+            sha2::Sha256::digest([0u8]).as_slice()
+        );
+    }
+
+    #[test]
+    fn bindings_are_domain_separated() {
+        let (one, two) = ([1; HASH_LENGTH], [2; HASH_LENGTH]);
+        let untagged = Blake3Hasher::digest(&[&one, &two]);
+        assert_ne!(combine_hash(&one, &two), untagged);
+        assert_ne!(combine_hash(&one, &two), layered_value_hash(&one, &two));
+        assert_eq!(
+            Sha256Hasher::combine_hash(&one, &two),
+            sha2::Sha256::digest([&[0xfe][..], &one, &two].concat()).as_slice()
+        );
+    }
 }
@@ -2,44 +2,138 @@ use std::io::{Read, Write};
 
 use ed::{Decode, Encode, Result};
 
-use super::hash::{kv_hash, Hash, HASH_LENGTH, NULL_HASH};
+use super::hash::{Blake3Hasher, Hash, Hasher, HASH_LENGTH, NULL_HASH};
 
 // TODO: maybe use something similar to Vec but without capacity field,
 //       (should save 16 bytes per entry). also, maybe a shorter length
 //       field to save even more. also might be possible to combine key
 //       field and value field.
 
-/// Contains a key/value pair, and the hash of the key/value pair.
+/// What the value hash of a node covers besides the node's value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueBinding {
+    /// Nothing, the value hash is the plain hash of the value
+    Plain,
+    /// The value hash of the node a reference points to
+    Reference(Hash),
+    /// The root hash of the subtree the node holds
+    Layered(Hash),
+}
+
+impl ValueBinding {
+    /// Computes the value hash of `value` bound this way.
+    pub fn value_hash<H: Hasher>(&self, value: &[u8]) -> Hash {
+        match self {
+            ValueBinding::Plain => H::value_hash(value),
+            ValueBinding::Reference(referenced_value_hash) => {
+                H::combine_hash(&H::value_hash(value), referenced_value_hash)
+            }
+            ValueBinding::Layered(child_root_hash) => {
+                H::layered_value_hash(&H::value_hash(value), child_root_hash)
+            }
+        }
+    }
+}
+
+/// Contains a key/value pair, the hash of the key/value pair and the hash of
+/// the value.
+///
+/// The value hash is usually the hash of the value alone, for references it
+/// also covers the value hash of the referenced node (see `ValueBinding`).
 #[derive(Clone)]
 pub struct KV {
     pub(super) key: Vec<u8>,
     pub(super) value: Vec<u8>,
     pub(super) hash: Hash,
+    pub(super) value_hash: Hash,
+    pub(super) binding: ValueBinding,
 }
 
 impl KV {
     /// Creates a new `KV` with the given key and value and computes its hash.
     #[inline]
-    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+    pub fn new<H: Hasher>(key: Vec<u8>, value: Vec<u8>) -> Self {
         // TODO: length checks?
-        let hash = kv_hash(key.as_slice(), value.as_slice());
-        Self { key, value, hash }
+        Self::new_with_binding::<H>(key, value, ValueBinding::Plain)
+    }
+
+    /// Creates a new `KV` whose value hash combines the hash of its value with
+    /// the value hash of the node it refers to, so the `KV` hash changes
+    /// whenever the referenced value does.
+    #[inline]
+    pub fn new_with_combined_value_hash<H: Hasher>(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        referenced_value_hash: Hash,
+    ) -> Self {
+        Self::new_with_binding::<H>(key, value, ValueBinding::Reference(referenced_value_hash))
+    }
+
+    /// Creates a new `KV` with the given key and value whose value hash is
+    /// bound as given, and computes its hashes.
+    #[inline]
+    pub fn new_with_binding<H: Hasher>(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        binding: ValueBinding,
+    ) -> Self {
+        let value_hash = binding.value_hash::<H>(value.as_slice());
+        let hash = H::kv_digest_to_kv_hash(key.as_slice(), &value_hash);
+        Self {
+            key,
+            value,
+            hash,
+            value_hash,
+            binding,
+        }
     }
 
-    /// Creates a new `KV` with the given key, value, and hash. The hash is not
-    /// checked to be correct for the given key/value.
+    /// Creates a new `KV` with the given key, value, hash and value hash of a
+    /// plain value. The hashes are not checked to be correct for the given
+    /// key/value.
     #[inline]
-    pub fn from_fields(key: Vec<u8>, value: Vec<u8>, hash: Hash) -> Self {
-        Self { key, value, hash }
+    pub fn from_fields(key: Vec<u8>, value: Vec<u8>, hash: Hash, value_hash: Hash) -> Self {
+        Self {
+            key,
+            value,
+            hash,
+            value_hash,
+            binding: ValueBinding::Plain,
+        }
     }
 
     /// Replaces the `KV`'s value with the given value, updates the hash, and
     /// returns the modified `KV`.
     #[inline]
-    pub fn with_value(mut self, value: Vec<u8>) -> Self {
+    pub fn with_value<H: Hasher>(self, value: Vec<u8>) -> Self {
         // TODO: length check?
+        self.with_value_and_binding::<H>(value, ValueBinding::Plain)
+    }
+
+    /// Replaces the `KV`'s value with the given value, combines its value hash
+    /// with the value hash of the referenced node, updates the hash, and
+    /// returns the modified `KV`.
+    #[inline]
+    pub fn with_value_and_referenced_value_hash<H: Hasher>(
+        self,
+        value: Vec<u8>,
+        referenced_value_hash: Hash,
+    ) -> Self {
+        self.with_value_and_binding::<H>(value, ValueBinding::Reference(referenced_value_hash))
+    }
+
+    /// Replaces the `KV`'s value with the given value whose value hash is
+    /// bound as given, updates the hash, and returns the modified `KV`.
+    #[inline]
+    pub fn with_value_and_binding<H: Hasher>(
+        mut self,
+        value: Vec<u8>,
+        binding: ValueBinding,
+    ) -> Self {
         self.value = value;
-        self.hash = kv_hash(self.key(), self.value());
+        self.binding = binding;
+        self.value_hash = binding.value_hash::<H>(self.value());
+        self.hash = H::kv_digest_to_kv_hash(self.key(), &self.value_hash);
         self
     }
 
@@ -61,6 +155,18 @@ impl KV {
         &self.hash
     }
 
+    /// Returns the value hash.
+    #[inline]
+    pub const fn value_hash(&self) -> &Hash {
+        &self.value_hash
+    }
+
+    /// Returns what the value hash is bound to besides the value.
+    #[inline]
+    pub const fn binding(&self) -> &ValueBinding {
+        &self.binding
+    }
+
     /// Consumes the `KV` and returns its key without allocating or cloning.
     #[inline]
     pub fn take_key(self) -> Vec<u8> {
@@ -68,10 +174,26 @@ impl KV {
     }
 }
 
+const PLAIN_BINDING: u8 = 0;
+const REFERENCE_BINDING: u8 = 1;
+const LAYERED_BINDING: u8 = 2;
+
 impl Encode for KV {
     #[inline]
     fn encode_into<W: Write>(&self, out: &mut W) -> Result<()> {
         out.write_all(&self.hash[..])?;
+        out.write_all(&self.value_hash[..])?;
+        match &self.binding {
+            ValueBinding::Plain => out.write_all(&[PLAIN_BINDING])?,
+            ValueBinding::Reference(referenced_value_hash) => {
+                out.write_all(&[REFERENCE_BINDING])?;
+                out.write_all(&referenced_value_hash[..])?;
+            }
+            ValueBinding::Layered(child_root_hash) => {
+                out.write_all(&[LAYERED_BINDING])?;
+                out.write_all(&child_root_hash[..])?;
+            }
+        }
         out.write_all(self.value.as_slice())?;
         Ok(())
     }
@@ -79,7 +201,11 @@ impl Encode for KV {
     #[inline]
     fn encoding_length(&self) -> Result<usize> {
         debug_assert!(self.key().len() < 256, "Key length must be less than 256");
-        Ok(HASH_LENGTH + self.value.len())
+        let binding_length = match self.binding {
+            ValueBinding::Plain => 1,
+            ValueBinding::Reference(_) | ValueBinding::Layered(_) => 1 + HASH_LENGTH,
+        };
+        Ok(2 * HASH_LENGTH + binding_length + self.value.len())
     }
 }
 
@@ -90,6 +216,8 @@ impl Decode for KV {
             key: Vec::with_capacity(0),
             value: Vec::with_capacity(128),
             hash: NULL_HASH,
+            value_hash: NULL_HASH,
+            binding: ValueBinding::Plain,
         };
         Self::decode_into(&mut kv, input)?;
         Ok(kv)
@@ -100,6 +228,23 @@ impl Decode for KV {
         self.key.clear();
 
         input.read_exact(&mut self.hash[..])?;
+        input.read_exact(&mut self.value_hash[..])?;
+        let mut binding_kind = [0];
+        input.read_exact(&mut binding_kind)?;
+        self.binding = match binding_kind[0] {
+            PLAIN_BINDING => ValueBinding::Plain,
+            REFERENCE_BINDING => {
+                let mut referenced_value_hash = NULL_HASH;
+                input.read_exact(&mut referenced_value_hash)?;
+                ValueBinding::Reference(referenced_value_hash)
+            }
+            LAYERED_BINDING => {
+                let mut child_root_hash = NULL_HASH;
+                input.read_exact(&mut child_root_hash)?;
+                ValueBinding::Layered(child_root_hash)
+            }
+            byte => failure::bail!("Unexpected value binding kind {}", byte),
+        };
 
         self.value.clear();
         input.read_to_end(self.value.as_mut())?;
@@ -108,13 +253,37 @@ impl Decode for KV {
     }
 }
 
+impl KV {
+    /// Decodes a `KV` stored in the legacy node encoding, which has no value
+    /// hash and no binding. Such nodes were always hashed with BLAKE3 and hold
+    /// plain values, so the value hash is recomputed from the value.
+    pub(super) fn decode_legacy<R: Read>(mut input: R) -> Result<Self> {
+        let mut hash = NULL_HASH;
+        input.read_exact(&mut hash[..])?;
+
+        let mut value = Vec::with_capacity(128);
+        input.read_to_end(&mut value)?;
+
+        Ok(Self {
+            key: Vec::with_capacity(0),
+            value_hash: Blake3Hasher::value_hash(&value),
+            value,
+            hash,
+            binding: ValueBinding::Plain,
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::*;
+    use super::{
+        super::hash::{combine_hash, Blake3Hasher},
+        *,
+    };
 
     #[test]
     fn new_kv() {
-        let kv = KV::new(vec![1, 2, 3], vec![4, 5, 6]);
+        let kv = KV::new::<Blake3Hasher>(vec![1, 2, 3], vec![4, 5, 6]);
 
         assert_eq!(kv.key(), &[1, 2, 3]);
         assert_eq!(kv.value(), &[4, 5, 6]);
@@ -123,10 +292,47 @@ mod test {
 
     #[test]
     fn with_value() {
-        let kv = KV::new(vec![1, 2, 3], vec![4, 5, 6]).with_value(vec![7, 8, 9]);
+        let kv = KV::new::<Blake3Hasher>(vec![1, 2, 3], vec![4, 5, 6])
+            .with_value::<Blake3Hasher>(vec![7, 8, 9]);
 
         assert_eq!(kv.key(), &[1, 2, 3]);
         assert_eq!(kv.value(), &[7, 8, 9]);
         assert_ne!(kv.hash(), &super::super::hash::NULL_HASH);
     }
+
+    #[test]
+    fn combined_value_hash() {
+        let plain = KV::new::<Blake3Hasher>(vec![1, 2, 3], vec![4, 5, 6]);
+        let combined =
+            KV::new_with_combined_value_hash::<Blake3Hasher>(vec![1, 2, 3], vec![4, 5, 6], [7; 32]);
+        assert_ne!(plain.hash(), combined.hash());
+        assert_eq!(
+            combined.value_hash(),
+            &combine_hash(plain.value_hash(), &[7; 32])
+        );
+
+        let updated =
+            combined.with_value_and_referenced_value_hash::<Blake3Hasher>(vec![4, 5, 6], [8; 32]);
+        assert_eq!(
+            updated.value_hash(),
+            &combine_hash(plain.value_hash(), &[8; 32])
+        );
+
+        // Plain values drop the combined value hash
+        let plain_again = updated.with_value::<Blake3Hasher>(vec![4, 5, 6]);
+        assert_eq!(plain_again.hash(), plain.hash());
+    }
+
+    #[test]
+    fn encoding_keeps_value_hash() {
+        let kv =
+            KV::new_with_combined_value_hash::<Blake3Hasher>(vec![1, 2, 3], vec![4, 5, 6], [7; 32]);
+        let bytes = kv.encode().unwrap();
+        assert_eq!(bytes.len(), kv.encoding_length().unwrap());
+
+        let decoded = KV::decode(bytes.as_slice()).unwrap();
+        assert_eq!(decoded.value(), kv.value());
+        assert_eq!(decoded.hash(), kv.hash());
+        assert_eq!(decoded.value_hash(), kv.value_hash());
+    }
 }
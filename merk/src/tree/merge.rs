@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result};
+
+/// Identifies the function an `Op::Merge` is resolved with.
+pub type MergeFnId = u16;
+
+/// Computes the new value of a key from its current value (`None` if the key
+/// is not in the tree yet) and a merge operand. Merge functions must be
+/// deterministic, as every node applying a batch has to compute the same
+/// value.
+pub type MergeFn = fn(existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>>;
+
+/// Appends the operand to the current value.
+pub const APPEND_MERGE_FN: MergeFnId = 0;
+
+/// Adds the operand to the current value, both are big-endian `u64`s. A
+/// missing value counts as zero, overflows are errors.
+pub const ADD_U64_MERGE_FN: MergeFnId = 1;
+
+/// Merge functions registered with `MergeFns::register` have ids starting
+/// from this one, lower ids are reserved for built-in merge functions.
+pub const FIRST_CUSTOM_MERGE_FN: MergeFnId = 256;
+
+/// Resolves the `Op::Merge`s of a batch while it's applied.
+pub trait MergeResolver {
+    /// Computes the new value of `key` from its current value (`None` if the
+    /// key is not in the tree yet) and the operand of a merge with the given
+    /// merge function id.
+    fn merge(
+        &self,
+        id: MergeFnId,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operand: &[u8],
+    ) -> Result<Vec<u8>>;
+}
+
+/// The built-in merge functions along with custom ones registered on the set.
+/// Batches are applied with a set of merge functions, so different Merks may
+/// give different meanings to the same custom merge function id.
+#[derive(Debug, Clone, Default)]
+pub struct MergeFns {
+    custom: BTreeMap<MergeFnId, MergeFn>,
+}
+
+impl MergeFns {
+    /// Registers a merge function under the given id, which has to be at
+    /// least `FIRST_CUSTOM_MERGE_FN` and not registered yet.
+    pub fn register(&mut self, id: MergeFnId, merge_fn: MergeFn) -> Result<()> {
+        if id < FIRST_CUSTOM_MERGE_FN {
+            bail!(
+                "Merge function id {} is reserved for built-in functions",
+                id
+            );
+        }
+        if self.custom.contains_key(&id) {
+            bail!("Merge function {} is already registered", id);
+        }
+        self.custom.insert(id, merge_fn);
+        Ok(())
+    }
+
+    /// Merges `operand` into the `existing` value with the merge function of
+    /// the given id.
+    pub fn resolve(
+        &self,
+        id: MergeFnId,
+        existing: Option<&[u8]>,
+        operand: &[u8],
+    ) -> Result<Vec<u8>> {
+        let merge_fn: MergeFn = match id {
+            APPEND_MERGE_FN => append,
+            ADD_U64_MERGE_FN => add_u64,
+            _ => *self
+                .custom
+                .get(&id)
+                .ok_or_else(|| anyhow!("Unknown merge function {}", id))?,
+        };
+        merge_fn(existing, operand)
+    }
+}
+
+impl MergeResolver for MergeFns {
+    fn merge(
+        &self,
+        id: MergeFnId,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operand: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.resolve(id, existing, operand)
+    }
+}
+
+fn append(existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+    let mut value = existing.unwrap_or_default().to_vec();
+    value.extend_from_slice(operand);
+    Ok(value)
+}
+
+fn add_u64(existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+    let decode = |bytes: &[u8]| -> Result<u64> {
+        Ok(u64::from_be_bytes(bytes.try_into().map_err(|_| {
+            anyhow!("Expected an 8 byte counter, got {} bytes", bytes.len())
+        })?))
+    };
+    let current = existing.map(decode).transpose()?.unwrap_or(0);
+    let sum = current
+        .checked_add(decode(operand)?)
+        .ok_or_else(|| anyhow!("Counter overflow"))?;
+    Ok(sum.to_be_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builtin_merge_fns() {
+        let merge_fns = MergeFns::default();
+        let merge =
+            |id, existing: Option<&[u8]>, operand: &[u8]| merge_fns.resolve(id, existing, operand);
+        assert_eq!(merge(APPEND_MERGE_FN, None, b"ab").unwrap(), b"ab");
+        assert_eq!(merge(APPEND_MERGE_FN, Some(b"ab"), b"cd").unwrap(), b"abcd");
+
+        let one = 1u64.to_be_bytes();
+        assert_eq!(merge(ADD_U64_MERGE_FN, None, &one).unwrap(), one);
+        assert_eq!(
+            merge(ADD_U64_MERGE_FN, Some(&one), &41u64.to_be_bytes()).unwrap(),
+            42u64.to_be_bytes()
+        );
+        assert!(merge(ADD_U64_MERGE_FN, Some(&u64::MAX.to_be_bytes()), &one).is_err());
+        assert!(merge(ADD_U64_MERGE_FN, Some(b"x"), &one).is_err());
+    }
+
+    #[test]
+    fn custom_merge_fns() {
+        fn replace(_existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+            Ok(operand.to_vec())
+        }
+
+        let mut merge_fns = MergeFns::default();
+        assert!(merge_fns
+            .resolve(FIRST_CUSTOM_MERGE_FN + 1, None, b"a")
+            .is_err());
+        assert!(merge_fns.register(APPEND_MERGE_FN, replace).is_err());
+        merge_fns
+            .register(FIRST_CUSTOM_MERGE_FN + 1, replace)
+            .unwrap();
+        assert!(merge_fns
+            .register(FIRST_CUSTOM_MERGE_FN + 1, replace)
+            .is_err());
+        assert_eq!(
+            merge_fns
+                .resolve(FIRST_CUSTOM_MERGE_FN + 1, Some(b"a"), b"b")
+                .unwrap(),
+            b"b"
+        );
+
+        // other sets don't know functions registered on this one
+        assert!(MergeFns::default()
+            .resolve(FIRST_CUSTOM_MERGE_FN + 1, None, b"a")
+            .is_err());
+    }
+}
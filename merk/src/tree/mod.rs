@@ -7,17 +7,26 @@ mod hash;
 mod iter;
 mod kv;
 mod link;
+mod merge;
 mod ops;
 mod walk;
 
-use std::cmp::max;
+use std::{cmp::max, mem::size_of};
 
 use anyhow::Result;
-pub use commit::{Commit, NoopCommit};
+pub use commit::{Commit, NoopCommit, ParallelCommit};
 use ed::{Decode, Encode};
-pub use hash::{kv_hash, node_hash, Hash, HASH_LENGTH, NULL_HASH};
+pub use hash::{
+    combine_hash, kv_digest_to_kv_hash, kv_hash, layered_value_hash, node_hash, value_hash,
+    Blake3Hasher, Hash, Hasher, Sha256Hasher, HASH_LENGTH, NULL_HASH,
+};
+pub use kv::ValueBinding;
 use kv::KV;
 pub use link::Link;
+pub use merge::{
+    MergeFn, MergeFnId, MergeFns, MergeResolver, ADD_U64_MERGE_FN, APPEND_MERGE_FN,
+    FIRST_CUSTOM_MERGE_FN,
+};
 pub use ops::{BatchEntry, MerkBatch, Op, PanicSource};
 pub use walk::{Fetch, RefWalker, Walker};
 
@@ -37,7 +46,7 @@ struct TreeInner {
 /// Trees' inner fields are stored on the heap so that nodes can recursively
 /// link to each other, and so we can detach nodes from their parents, then
 /// reattach without allocating or freeing heap memory.
-#[derive(Clone, Encode, Decode)]
+#[derive(Clone)]
 pub struct Tree {
     inner: Box<TreeInner>,
 }
@@ -47,9 +56,61 @@ impl Tree {
     ///
     /// Hashes the key/value pair and initializes the `kv_hash` field.
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        Self::new_by::<Blake3Hasher>(key, value)
+    }
+
+    /// Same as `new`, but hashes with `H`.
+    pub fn new_by<H: Hasher>(key: Vec<u8>, value: Vec<u8>) -> Self {
+        Self {
+            inner: Box::new(TreeInner {
+                kv: KV::new::<H>(key, value),
+                left: None,
+                right: None,
+            }),
+        }
+    }
+
+    /// Creates a new `Tree` with the given key and value, and no children. The
+    /// value hash combines the hash of the value with the value hash of the
+    /// referenced node.
+    pub fn new_with_combined_value_hash(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        referenced_value_hash: Hash,
+    ) -> Self {
+        Self::new_with_combined_value_hash_by::<Blake3Hasher>(key, value, referenced_value_hash)
+    }
+
+    /// Same as `new_with_combined_value_hash`, but hashes with `H`.
+    pub fn new_with_combined_value_hash_by<H: Hasher>(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        referenced_value_hash: Hash,
+    ) -> Self {
+        Self {
+            inner: Box::new(TreeInner {
+                kv: KV::new_with_combined_value_hash::<H>(key, value, referenced_value_hash),
+                left: None,
+                right: None,
+            }),
+        }
+    }
+
+    /// Creates a new `Tree` with the given key and value whose value hash is
+    /// bound as given, and no children.
+    pub fn new_with_binding(key: Vec<u8>, value: Vec<u8>, binding: ValueBinding) -> Self {
+        Self::new_with_binding_by::<Blake3Hasher>(key, value, binding)
+    }
+
+    /// Same as `new_with_binding`, but hashes with `H`.
+    pub fn new_with_binding_by<H: Hasher>(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        binding: ValueBinding,
+    ) -> Self {
         Self {
             inner: Box::new(TreeInner {
-                kv: KV::new(key, value),
+                kv: KV::new_with_binding::<H>(key, value, binding),
                 left: None,
                 right: None,
             }),
@@ -57,17 +118,19 @@ impl Tree {
     }
 
     /// Creates a `Tree` by supplying all the raw struct fields (mainly useful
-    /// for testing). The `kv_hash` and `Link`s are not ensured to be correct.
+    /// for testing), the value is plain. The `kv_hash`, `value_hash` and
+    /// `Link`s are not ensured to be correct.
     pub fn from_fields(
         key: Vec<u8>,
         value: Vec<u8>,
         kv_hash: Hash,
+        value_hash: Hash,
         left: Option<Link>,
         right: Option<Link>,
     ) -> Self {
         Self {
             inner: Box::new(TreeInner {
-                kv: KV::from_fields(key, value, kv_hash),
+                kv: KV::from_fields(key, value, kv_hash, value_hash),
                 left,
                 right,
             }),
@@ -103,6 +166,19 @@ impl Tree {
         self.inner.kv.hash()
     }
 
+    /// Returns the hash of the root node's value, combined with the value hash
+    /// of the referenced node for references.
+    #[inline]
+    pub const fn value_hash(&self) -> &Hash {
+        self.inner.kv.value_hash()
+    }
+
+    /// Returns what the root node's value hash is bound to besides its value.
+    #[inline]
+    pub const fn value_binding(&self) -> &ValueBinding {
+        self.inner.kv.binding()
+    }
+
     /// Returns a reference to the root node's `Link` on the given side, if any.
     /// If there is no child, returns `None`.
     #[inline]
@@ -161,7 +237,13 @@ impl Tree {
     /// Computes and returns the hash of the root node.
     #[inline]
     pub fn hash(&self) -> Hash {
-        node_hash(
+        self.hash_by::<Blake3Hasher>()
+    }
+
+    /// Same as `hash`, but for a tree hashed with `H`.
+    #[inline]
+    pub fn hash_by<H: Hasher>(&self) -> Hash {
+        H::node_hash(
             self.inner.kv.hash(),
             self.child_hash(true),
             self.child_hash(false),
@@ -316,8 +398,58 @@ impl Tree {
     /// Replaces the root node's value with the given value and returns the
     /// modified `Tree`.
     #[inline]
-    pub fn with_value(mut self, value: Vec<u8>) -> Self {
-        self.inner.kv = self.inner.kv.with_value(value);
+    pub fn with_value(self, value: Vec<u8>) -> Self {
+        self.with_value_by::<Blake3Hasher>(value)
+    }
+
+    /// Same as `with_value`, but hashes with `H`.
+    #[inline]
+    pub fn with_value_by<H: Hasher>(mut self, value: Vec<u8>) -> Self {
+        self.inner.kv = self.inner.kv.with_value::<H>(value);
+        self
+    }
+
+    /// Replaces the root node's value with the given value, combining its value
+    /// hash with the value hash of the referenced node, and returns the
+    /// modified `Tree`.
+    #[inline]
+    pub fn with_value_and_referenced_value_hash(
+        self,
+        value: Vec<u8>,
+        referenced_value_hash: Hash,
+    ) -> Self {
+        self.with_value_and_referenced_value_hash_by::<Blake3Hasher>(value, referenced_value_hash)
+    }
+
+    /// Same as `with_value_and_referenced_value_hash`, but hashes with `H`.
+    #[inline]
+    pub fn with_value_and_referenced_value_hash_by<H: Hasher>(
+        mut self,
+        value: Vec<u8>,
+        referenced_value_hash: Hash,
+    ) -> Self {
+        self.inner.kv = self
+            .inner
+            .kv
+            .with_value_and_referenced_value_hash::<H>(value, referenced_value_hash);
+        self
+    }
+
+    /// Replaces the root node's value with the given value whose value hash is
+    /// bound as given, and returns the modified `Tree`.
+    #[inline]
+    pub fn with_value_and_binding(self, value: Vec<u8>, binding: ValueBinding) -> Self {
+        self.with_value_and_binding_by::<Blake3Hasher>(value, binding)
+    }
+
+    /// Same as `with_value_and_binding`, but hashes with `H`.
+    #[inline]
+    pub fn with_value_and_binding_by<H: Hasher>(
+        mut self,
+        value: Vec<u8>,
+        binding: ValueBinding,
+    ) -> Self {
+        self.inner.kv = self.inner.kv.with_value_and_binding::<H>(value, binding);
         self
     }
 
@@ -332,45 +464,83 @@ impl Tree {
     /// method to test whether or not to keep or prune nodes from memory.
     #[inline]
     pub fn commit<C: Commit>(&mut self, c: &mut C) -> Result<()> {
-        // TODO: make this method less ugly
         // TODO: call write in-order for better performance in writing batch to db?
-
-        if let Some(Link::Modified { .. }) = self.inner.left {
-            if let Some(Link::Modified {
-                mut tree,
-                child_heights,
-                ..
-            }) = self.inner.left.take()
-            {
+        for left in [true, false] {
+            if let Some((mut tree, child_heights)) = self.take_modified(left) {
                 tree.commit(c)?;
-                self.inner.left = Some(Link::Loaded {
-                    hash: tree.hash(),
-                    tree,
-                    child_heights,
-                });
-            } else {
-                unreachable!()
+                self.put_committed::<C::Hasher>(left, tree, child_heights);
+            }
+        }
+        self.finish_commit(c)
+    }
+
+    /// Same as `commit`, but modified child subtrees are committed on the
+    /// rayon thread pool in parallel, to a `Commit` forked for one of the
+    /// sides, if both of them have at least `min_parallel_writes` pending
+    /// writes. Hashes and the set of written nodes are the same as with
+    /// `commit`, only the order of writes may differ.
+    #[cfg(feature = "full")]
+    pub fn commit_parallel<C: ParallelCommit>(
+        &mut self,
+        c: &mut C,
+        min_parallel_writes: usize,
+    ) -> Result<()> {
+        let parallel = self.child_pending_writes(true) >= min_parallel_writes
+            && self.child_pending_writes(false) >= min_parallel_writes;
+        if !parallel {
+            for left in [true, false] {
+                if let Some((mut tree, child_heights)) = self.take_modified(left) {
+                    tree.commit_parallel(c, min_parallel_writes)?;
+                    self.put_committed::<C::Hasher>(left, tree, child_heights);
+                }
             }
+            return self.finish_commit(c);
         }
 
-        if let Some(Link::Modified { .. }) = self.inner.right {
+        let (mut left_tree, left_heights) = self.take_modified(true).expect("checked above");
+        let (mut right_tree, right_heights) = self.take_modified(false).expect("checked above");
+        let mut right_c = c.fork();
+        let (left_result, right_result) = rayon::join(
+            || left_tree.commit_parallel(c, min_parallel_writes),
+            || right_tree.commit_parallel(&mut right_c, min_parallel_writes),
+        );
+        left_result?;
+        right_result?;
+        c.join(right_c);
+        self.put_committed::<C::Hasher>(true, left_tree, left_heights);
+        self.put_committed::<C::Hasher>(false, right_tree, right_heights);
+        self.finish_commit(c)
+    }
+
+    /// Takes the child on the given side out of its slot if the link to it
+    /// is `Link::Modified`.
+    fn take_modified(&mut self, left: bool) -> Option<(Self, (u8, u8))> {
+        if let Some(Link::Modified { .. }) = self.link(left) {
             if let Some(Link::Modified {
-                mut tree,
+                tree,
                 child_heights,
                 ..
-            }) = self.inner.right.take()
+            }) = self.slot_mut(left).take()
             {
-                tree.commit(c)?;
-                self.inner.right = Some(Link::Loaded {
-                    hash: tree.hash(),
-                    tree,
-                    child_heights,
-                });
-            } else {
-                unreachable!()
+                return Some((tree, child_heights));
             }
+            unreachable!()
         }
+        None
+    }
 
+    /// Puts a committed child back into its slot as `Link::Loaded`.
+    fn put_committed<H: Hasher>(&mut self, left: bool, tree: Self, child_heights: (u8, u8)) {
+        *self.slot_mut(left) = Some(Link::Loaded {
+            hash: tree.hash_by::<H>(),
+            tree,
+            child_heights,
+        });
+    }
+
+    /// Writes the root node once its children are committed and prunes them
+    /// if asked to.
+    fn finish_commit<C: Commit>(&mut self, c: &mut C) -> Result<()> {
         c.write(self)?;
 
         let (prune_left, prune_right) = c.prune(self);
@@ -410,6 +580,58 @@ impl Tree {
 
         Ok(())
     }
+
+    /// Returns an approximate number of bytes taken in memory by the node
+    /// itself, without its children.
+    pub fn node_memory_usage(&self) -> usize {
+        let reference_keys: usize = [true, false]
+            .into_iter()
+            .map(|left| match self.link(left) {
+                Some(Link::Reference { key, .. }) => key.len(),
+                _ => 0,
+            })
+            .sum();
+        size_of::<Self>()
+            + size_of::<TreeInner>()
+            + self.key().len()
+            + self.value().len()
+            + reference_keys
+    }
+
+    /// Returns approximate numbers of bytes taken in memory by nodes retained
+    /// on each level of the tree, starting from the root node.
+    pub fn levels_memory_usage(&self) -> Vec<usize> {
+        let mut usage = Vec::new();
+        let mut level = vec![self];
+        while !level.is_empty() {
+            usage.push(level.iter().map(|node| node.node_memory_usage()).sum());
+            level = level
+                .iter()
+                .flat_map(|node| [node.child(true), node.child(false)])
+                .flatten()
+                .collect();
+        }
+        usage
+    }
+
+    /// Prunes descendants retained in memory which are `levels` or more
+    /// levels below the root node, replacing them with `Link::Reference`s.
+    /// Modified nodes are kept as they are not written yet.
+    pub fn prune_levels(&mut self, levels: u8) {
+        if levels >= self.height() {
+            return;
+        }
+        for left in [true, false] {
+            if levels <= 1 {
+                if let Some(Link::Loaded { .. }) = self.link(left) {
+                    let slot = self.slot_mut(left);
+                    *slot = slot.take().map(Link::into_reference);
+                }
+            } else if let Some(child) = self.child_mut(left) {
+                child.prune_levels(levels - 1);
+            }
+        }
+    }
 }
 
 pub const fn side_to_str(left: bool) -> &'static str {
@@ -422,7 +644,13 @@ pub const fn side_to_str(left: bool) -> &'static str {
 
 #[cfg(test)]
 mod test {
-    use super::{commit::NoopCommit, hash::NULL_HASH, Tree};
+    use anyhow::Result;
+
+    use super::{
+        commit::{Commit, NoopCommit, ParallelCommit},
+        hash::{Blake3Hasher, NULL_HASH},
+        Tree,
+    };
 
     #[test]
     fn build_tree() {
@@ -569,4 +797,66 @@ mod test {
 
         assert!(tree.link(false).expect("expected link").is_stored());
     }
+
+    #[derive(Default)]
+    struct RecordingCommit {
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl Commit for RecordingCommit {
+        type Hasher = Blake3Hasher;
+
+        fn write(&mut self, tree: &Tree) -> Result<()> {
+            let mut buf = Vec::with_capacity(tree.encoding_length());
+            tree.encode_into(&mut buf);
+            self.writes.push(buf);
+            Ok(())
+        }
+
+        fn prune(&self, _tree: &Tree) -> (bool, bool) {
+            (false, false)
+        }
+    }
+
+    impl ParallelCommit for RecordingCommit {
+        fn fork(&self) -> Self {
+            Self::default()
+        }
+
+        fn join(&mut self, other: Self) {
+            self.writes.extend(other.writes);
+        }
+    }
+
+    fn build_balanced(range: std::ops::Range<u32>) -> Option<Tree> {
+        if range.is_empty() {
+            return None;
+        }
+        let mid = range.start + (range.end - range.start) / 2;
+        let tree = Tree::new(mid.to_be_bytes().to_vec(), vec![1; 40])
+            .attach(true, build_balanced(range.start..mid))
+            .attach(false, build_balanced(mid + 1..range.end));
+        Some(tree)
+    }
+
+    #[test]
+    #[cfg(feature = "full")]
+    fn commit_parallel() {
+        let mut tree = build_balanced(0..5000).unwrap();
+        let mut parallel_tree = tree.clone();
+
+        let mut serial = RecordingCommit::default();
+        tree.commit(&mut serial).expect("commit failed");
+        let mut parallel = RecordingCommit::default();
+        parallel_tree
+            .commit_parallel(&mut parallel, 1)
+            .expect("commit failed");
+
+        assert_eq!(tree.hash(), parallel_tree.hash());
+        assert_eq!(parallel.writes.len(), 5000);
+        serial.writes.sort();
+        parallel.writes.sort();
+        assert_eq!(serial.writes, parallel.writes);
+        assert!(parallel_tree.link(true).expect("expected link").is_stored());
+    }
 }
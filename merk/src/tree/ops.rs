@@ -3,12 +3,29 @@ use std::{collections::LinkedList, fmt};
 use anyhow::Result;
 use Op::*;
 
-use super::{Fetch, Link, Tree, Walker};
+use super::{
+    Blake3Hasher, Fetch, Hash, Link, MergeFnId, MergeResolver, Tree, ValueBinding, Walker,
+};
 
 /// An operation to be applied to a key in the store.
 pub enum Op {
     Put(Vec<u8>),
+    /// Puts a value which refers to another node, binding the node hash to the
+    /// value hash of the referenced node
+    PutReference(Vec<u8>, Hash),
+    /// Puts a value which commits to another Merk, binding the node hash to
+    /// the root hash of that Merk with a layered value hash, so subtree
+    /// commitments can be told apart from plain values and references
+    PutLayered(Vec<u8>, Hash),
     Delete,
+    /// Deletes all keys from the key of the batch entry (inclusive) to the
+    /// given key (exclusive). Other keys of the batch must not be within the
+    /// range
+    DeleteRange(Vec<u8>),
+    /// Sets the value to the result of the merge function with the given id
+    /// applied to the current value and the given operand, merge functions are
+    /// given along with the batch
+    Merge(Vec<u8>, MergeFnId),
 }
 
 impl fmt::Debug for Op {
@@ -18,7 +35,13 @@ impl fmt::Debug for Op {
             "{}",
             match self {
                 Put(value) => format!("Put({:?})", value),
+                PutReference(value, referenced_value_hash) =>
+                    format!("PutReference({:?}, {:?})", value, referenced_value_hash),
+                PutLayered(value, child_root_hash) =>
+                    format!("PutLayered({:?}, {:?})", value, child_root_hash),
                 Delete => "Delete".to_string(),
+                DeleteRange(end) => format!("DeleteRange({:?})", end),
+                Merge(operand, merge_fn_id) => format!("Merge({:?}, {})", operand, merge_fn_id),
             }
         )
     }
@@ -35,6 +58,8 @@ pub type MerkBatch<K> = [BatchEntry<K>];
 #[derive(Clone)]
 pub struct PanicSource {}
 impl Fetch for PanicSource {
+    type Hasher = Blake3Hasher;
+
     fn fetch(&self, _link: &Link) -> Result<Tree> {
         unreachable!("'fetch' should not have been called")
     }
@@ -48,18 +73,25 @@ where
     /// `maybe_tree` is `None`. This is similar to `Walker<S>::apply`, but does
     /// not require a non-empty tree.
     ///
-    /// Keys in batch must be sorted and unique.
+    /// Keys in batch must be sorted and unique, merges are resolved with
+    /// `merge_fns`.
     pub fn apply_to<K: AsRef<[u8]>>(
         maybe_tree: Option<Self>,
         batch: &MerkBatch<K>,
         source: S,
+        merge_fns: &dyn MergeResolver,
     ) -> Result<(Option<Tree>, LinkedList<Vec<u8>>)> {
         let (maybe_walker, deleted_keys) = if batch.is_empty() {
             (maybe_tree, LinkedList::default())
         } else {
             match maybe_tree {
-                None => return Ok((Self::build(batch, source)?, LinkedList::default())),
-                Some(tree) => tree.apply(batch)?,
+                None => {
+                    return Ok((
+                        Self::build(batch, source, merge_fns)?,
+                        LinkedList::default(),
+                    ))
+                }
+                Some(tree) => tree.apply(batch, merge_fns)?,
             }
         };
 
@@ -70,37 +102,58 @@ where
     /// Builds a `Tree` from a batch of operations.
     ///
     /// Keys in batch must be sorted and unique.
-    fn build<K: AsRef<[u8]>>(batch: &MerkBatch<K>, source: S) -> Result<Option<Tree>> {
+    fn build<K: AsRef<[u8]>>(
+        batch: &MerkBatch<K>,
+        source: S,
+        merge_fns: &dyn MergeResolver,
+    ) -> Result<Option<Tree>> {
         if batch.is_empty() {
             return Ok(None);
         }
 
         let mid_index = batch.len() / 2;
         let (mid_key, mid_op) = &batch[mid_index];
-        let mid_value = match mid_op {
-            Delete => {
+        let mid_tree = match mid_op {
+            Delete | DeleteRange(_) => {
                 let left_batch = &batch[..mid_index];
                 let right_batch = &batch[mid_index + 1..];
 
-                let maybe_tree = Self::build(left_batch, source.clone())?
+                let maybe_tree = Self::build(left_batch, source.clone(), merge_fns)?
                     .map(|tree| Self::new(tree, source.clone()));
                 let maybe_tree = match maybe_tree {
-                    Some(tree) => tree.apply(right_batch)?.0,
-                    None => Self::build(right_batch, source.clone())?
+                    Some(tree) => tree.apply(right_batch, merge_fns)?.0,
+                    None => Self::build(right_batch, source.clone(), merge_fns)?
                         .map(|tree| Self::new(tree, source.clone())),
                 };
                 return Ok(maybe_tree.map(|tree| tree.into()));
             }
-            Put(value) => value,
+            // TODO: take from batch so we don't have to clone
+            Put(value) => Tree::new_by::<S::Hasher>(mid_key.as_ref().to_vec(), value.to_vec()),
+            PutReference(value, referenced_value_hash) => {
+                Tree::new_with_combined_value_hash_by::<S::Hasher>(
+                    mid_key.as_ref().to_vec(),
+                    value.to_vec(),
+                    *referenced_value_hash,
+                )
+            }
+            PutLayered(value, child_root_hash) => Tree::new_with_binding_by::<S::Hasher>(
+                mid_key.as_ref().to_vec(),
+                value.to_vec(),
+                ValueBinding::Layered(*child_root_hash),
+            ),
+            Merge(operand, merge_fn_id) => Tree::new_by::<S::Hasher>(
+                mid_key.as_ref().to_vec(),
+                merge_fns.merge(*merge_fn_id, mid_key.as_ref(), None, operand)?,
+            ),
         };
 
-        // TODO: take from batch so we don't have to clone
-        let mid_tree = Tree::new(mid_key.as_ref().to_vec(), mid_value.to_vec());
-        let mid_walker = Walker::new(mid_tree, PanicSource {});
+        // the source is never fetched from as the tree is built from scratch, it
+        // is only used to hash new nodes the same way
+        let mid_walker = Walker::new(mid_tree, source);
 
         // use walker, ignore deleted_keys since it should be empty
         Ok(mid_walker
-            .recurse(batch, mid_index, true)?
+            .recurse(batch, mid_index, true, merge_fns)?
             .0
             .map(|w| w.into_inner()))
     }
@@ -112,6 +165,7 @@ where
     fn apply<K: AsRef<[u8]>>(
         self,
         batch: &MerkBatch<K>,
+        merge_fns: &dyn MergeResolver,
     ) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
         // binary search to see if this node's key is in the batch, and to split
         // into left and right batches
@@ -119,8 +173,27 @@ where
         let tree = if let Ok(index) = search {
             // a key matches this node's key, apply op to this node
             match &batch[index].1 {
+                Merge(operand, merge_fn_id) => {
+                    let value = merge_fns.merge(
+                        *merge_fn_id,
+                        self.tree().key(),
+                        Some(self.tree().value()),
+                        operand,
+                    )?;
+                    self.with_value(value)
+                }
+                // the range starts at this node, so it may cover keys on both sides
+                DeleteRange(_) => {
+                    return self.remove_in_range(&batch[..index], &batch[index..], merge_fns)
+                }
                 // TODO: take vec from batch so we don't need to clone
                 Put(value) => self.with_value(value.to_vec()),
+                PutReference(value, referenced_value_hash) => self
+                    .with_value_and_referenced_value_hash(value.to_vec(), *referenced_value_hash),
+                PutLayered(value, child_root_hash) => self.with_value_and_binding(
+                    value.to_vec(),
+                    ValueBinding::Layered(*child_root_hash),
+                ),
                 Delete => {
                     // TODO: we shouldn't have to do this as 2 different calls to apply
                     let source = self.clone_source();
@@ -131,11 +204,15 @@ where
                     let maybe_tree = self.remove()?;
 
                     let (maybe_tree, mut deleted_keys) =
-                        Self::apply_to(maybe_tree, &batch[..index], source.clone())?;
+                        Self::apply_to(maybe_tree, &batch[..index], source.clone(), merge_fns)?;
                     let maybe_walker = wrap(maybe_tree);
 
-                    let (maybe_tree, mut deleted_keys_right) =
-                        Self::apply_to(maybe_walker, &batch[index + 1..], source.clone())?;
+                    let (maybe_tree, mut deleted_keys_right) = Self::apply_to(
+                        maybe_walker,
+                        &batch[index + 1..],
+                        source.clone(),
+                        merge_fns,
+                    )?;
                     let maybe_walker = wrap(maybe_tree);
 
                     deleted_keys.append(&mut deleted_keys_right);
@@ -145,6 +222,15 @@ where
                 }
             }
         } else {
+            let index = search.unwrap_err();
+            let in_deleted_range = index > 0
+                && match &batch[index - 1] {
+                    (_, DeleteRange(end)) => self.tree().key() < end.as_slice(),
+                    _ => false,
+                };
+            if in_deleted_range {
+                return self.remove_in_range(&batch[..index], &batch[index - 1..], merge_fns);
+            }
             self
         };
 
@@ -153,7 +239,23 @@ where
             Err(index) => (index, false),
         };
 
-        tree.recurse(batch, mid, exclusive)
+        tree.recurse(batch, mid, exclusive, merge_fns)
+    }
+
+    /// Removes the root node, which is within a deleted range, once the
+    /// batches are applied to its children. Both batches contain the range, so
+    /// the whole range is deleted in the same pass.
+    fn remove_in_range<K: AsRef<[u8]>>(
+        self,
+        left_batch: &MerkBatch<K>,
+        right_batch: &MerkBatch<K>,
+        merge_fns: &dyn MergeResolver,
+    ) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
+        let key = self.tree().key().to_vec();
+        let (tree, mut deleted_keys) =
+            self.apply_to_children(left_batch, right_batch, merge_fns)?;
+        deleted_keys.push_back(key);
+        Ok((tree.remove()?, deleted_keys))
     }
 
     /// Recursively applies operations to the tree's children (if there are any
@@ -166,6 +268,7 @@ where
         batch: &MerkBatch<K>,
         mid: usize,
         exclusive: bool,
+        merge_fns: &dyn MergeResolver,
     ) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
         let left_batch = &batch[..mid];
         let right_batch = if exclusive {
@@ -174,13 +277,27 @@ where
             &batch[mid..]
         };
 
+        let (tree, deleted_keys) = self.apply_to_children(left_batch, right_batch, merge_fns)?;
+        let tree = tree.maybe_balance()?;
+
+        Ok((Some(tree), deleted_keys))
+    }
+
+    /// Applies the batches to the tree's children, leaving the tree itself
+    /// unbalanced.
+    fn apply_to_children<K: AsRef<[u8]>>(
+        self,
+        left_batch: &MerkBatch<K>,
+        right_batch: &MerkBatch<K>,
+        merge_fns: &dyn MergeResolver,
+    ) -> Result<(Self, LinkedList<Vec<u8>>)> {
         let mut deleted_keys = LinkedList::default();
 
         let tree = if !left_batch.is_empty() {
             let source = self.clone_source();
             self.walk(true, |maybe_left| {
                 let (maybe_left, mut deleted_keys_left) =
-                    Self::apply_to(maybe_left, left_batch, source)?;
+                    Self::apply_to(maybe_left, left_batch, source, merge_fns)?;
                 deleted_keys.append(&mut deleted_keys_left);
                 Ok(maybe_left)
             })?
@@ -192,7 +309,7 @@ where
             let source = tree.clone_source();
             tree.walk(false, |maybe_right| {
                 let (maybe_right, mut deleted_keys_right) =
-                    Self::apply_to(maybe_right, right_batch, source)?;
+                    Self::apply_to(maybe_right, right_batch, source, merge_fns)?;
                 deleted_keys.append(&mut deleted_keys_right);
                 Ok(maybe_right)
             })?
@@ -200,9 +317,7 @@ where
             tree
         };
 
-        let tree = tree.maybe_balance()?;
-
-        Ok((Some(tree), deleted_keys))
+        Ok((tree, deleted_keys))
     }
 
     /// Gets the wrapped tree's balance factor.
@@ -301,7 +416,10 @@ where
 mod test {
     use super::*;
     use crate::{
-        test_utils::{apply_memonly, assert_tree_invariants, del_entry, make_tree_seq, seq_key},
+        test_utils::{
+            apply_memonly, apply_to_memonly, assert_tree_invariants, del_entry, make_tree_seq,
+            put_entry, seq_key,
+        },
         tree::*,
     };
 
@@ -310,7 +428,7 @@ mod test {
         let batch = [(b"foo2".to_vec(), Op::Put(b"bar2".to_vec()))];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, &MergeFns::default())
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -323,7 +441,7 @@ mod test {
         let batch = [(b"foo".to_vec(), Op::Put(b"bar2".to_vec()))];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, &MergeFns::default())
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -340,6 +458,7 @@ mod test {
             b"foo".to_vec(),
             b"bar".to_vec(),
             [123; 32],
+            [123; 32],
             None,
             Some(Link::Loaded {
                 hash: [123; 32],
@@ -348,7 +467,7 @@ mod test {
             }),
         );
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, &MergeFns::default())
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
@@ -363,7 +482,9 @@ mod test {
     fn delete_non_existent() {
         let batch = [(b"foo2".to_vec(), Op::Delete)];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
-        Walker::new(tree, PanicSource {}).apply(&batch).unwrap();
+        Walker::new(tree, PanicSource {})
+            .apply(&batch, &MergeFns::default())
+            .unwrap();
     }
 
     #[test]
@@ -371,7 +492,7 @@ mod test {
         let batch = [(b"foo".to_vec(), Op::Delete)];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, &MergeFns::default())
             .expect("apply errored");
         assert!(maybe_walker.is_none());
         assert_eq!(deleted_keys.len(), 1);
@@ -383,7 +504,7 @@ mod test {
         let tree = make_tree_seq(50);
         let batch = [del_entry(5)];
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, &MergeFns::default())
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         assert_eq!(deleted_keys.len(), 1);
@@ -395,7 +516,7 @@ mod test {
         let tree = make_tree_seq(50);
         let batch = [del_entry(29), del_entry(34)];
         let (maybe_walker, mut deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, &MergeFns::default())
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         assert_eq!(deleted_keys.len(), 2);
@@ -408,7 +529,7 @@ mod test {
         let tree = make_tree_seq(10);
         let batch = [del_entry(7), del_entry(9)];
         let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&batch, &MergeFns::default())
             .expect("apply errored");
         maybe_walker.expect("should be Some");
         let mut deleted_keys: Vec<&Vec<u8>> = deleted_keys.iter().collect();
@@ -418,9 +539,13 @@ mod test {
 
     #[test]
     fn apply_empty_none() {
-        let (maybe_tree, deleted_keys) =
-            Walker::<PanicSource>::apply_to::<Vec<u8>>(None, &[], PanicSource {})
-                .expect("apply_to failed");
+        let (maybe_tree, deleted_keys) = Walker::<PanicSource>::apply_to::<Vec<u8>>(
+            None,
+            &[],
+            PanicSource {},
+            &MergeFns::default(),
+        )
+        .expect("apply_to failed");
         assert!(maybe_tree.is_none());
         assert!(deleted_keys.is_empty());
     }
@@ -429,7 +554,8 @@ mod test {
     fn insert_empty_single() {
         let batch = vec![(vec![0], Op::Put(vec![1]))];
         let (maybe_tree, deleted_keys) =
-            Walker::<PanicSource>::apply_to(None, &batch, PanicSource {}).expect("apply_to failed");
+            Walker::<PanicSource>::apply_to(None, &batch, PanicSource {}, &MergeFns::default())
+                .expect("apply_to failed");
         let tree = maybe_tree.expect("expected tree");
         assert_eq!(tree.key(), &[0]);
         assert_eq!(tree.value(), &[1]);
@@ -485,4 +611,71 @@ mod test {
         assert_eq!(tree.child(true).expect("expected child").key(), &[31]);
         assert_eq!(tree.child(false).expect("expected child").key(), &[79]);
     }
+
+    #[test]
+    fn delete_range() {
+        for (start, end) in [
+            (0, 1000),
+            (0, 1),
+            (10, 900),
+            (400, 1000),
+            (999, 2000),
+            (5, 6),
+        ] {
+            let tree = make_tree_seq(1000);
+            let (start, end) = (seq_key(start).to_vec(), seq_key(end).to_vec());
+            let (mut expected_keys, expected_deleted): (Vec<_>, Vec<_>) = tree
+                .iter()
+                .map(|(key, _)| key)
+                .partition(|key| *key < start || *key >= end);
+            expected_keys.push(seq_key(2000).to_vec());
+
+            let batch = vec![(start, Op::DeleteRange(end)), put_entry(2000)];
+            let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
+                .apply(&batch, &MergeFns::default())
+                .expect("apply errored");
+            let mut deleted_keys: Vec<Vec<u8>> = deleted_keys.into_iter().collect();
+            deleted_keys.sort();
+            assert_eq!(deleted_keys, expected_deleted);
+
+            let mut tree = maybe_walker.expect("should be Some").into_inner();
+            let keys: Vec<Vec<u8>> = tree.iter().map(|(key, _)| key).collect();
+            assert_eq!(keys, expected_keys);
+            tree.commit(&mut NoopCommit {}).expect("commit failed");
+            assert_tree_invariants(&tree);
+        }
+    }
+
+    #[test]
+    fn delete_range_of_all_keys() {
+        let tree = make_tree_seq(100);
+        let batch = [(vec![], Op::DeleteRange(vec![255; 20]))];
+        let (maybe_walker, deleted_keys) = Walker::new(tree, PanicSource {})
+            .apply(&batch, &MergeFns::default())
+            .expect("apply errored");
+        assert!(maybe_walker.is_none());
+        assert_eq!(deleted_keys.len(), 101);
+    }
+
+    #[test]
+    fn merge() {
+        let tree = Tree::new(vec![5], vec![1]);
+        let batch = vec![
+            (vec![3], Op::Merge(vec![2], APPEND_MERGE_FN)),
+            (vec![5], Op::Merge(vec![2, 3], APPEND_MERGE_FN)),
+        ];
+        let tree = apply_memonly(tree, &batch);
+        let values: Vec<(Vec<u8>, Vec<u8>)> = tree.iter().collect();
+        assert_eq!(values, vec![(vec![3], vec![2]), (vec![5], vec![1, 2, 3])]);
+
+        let tree = apply_to_memonly(None, &[(vec![1], Op::Merge(vec![4], APPEND_MERGE_FN))])
+            .expect("should be Some");
+        assert_eq!(tree.value(), &[4]);
+
+        let tree = Tree::new(vec![5], vec![1]);
+        let batch = [(vec![5], Op::Merge(vec![1], ADD_U64_MERGE_FN))];
+        assert!(Walker::new(tree, PanicSource {})
+            .apply(&batch, &MergeFns::default())
+            .is_err());
+    }
 }
@@ -5,7 +5,7 @@ use anyhow::Result;
 pub use fetch::Fetch;
 pub use ref_walker::RefWalker;
 
-use super::{Link, Tree};
+use super::{Hash, Link, Tree, ValueBinding};
 use crate::owner::Owner;
 
 /// Allows traversal of a `Tree`, fetching from the given source when traversing
@@ -132,7 +132,26 @@ where
 
     /// Similar to `Tree#with_value`.
     pub fn with_value(mut self, value: Vec<u8>) -> Self {
-        self.tree.own(|t| t.with_value(value));
+        self.tree.own(|t| t.with_value_by::<S::Hasher>(value));
+        self
+    }
+
+    /// Similar to `Tree#with_value_and_referenced_value_hash`.
+    pub fn with_value_and_referenced_value_hash(
+        mut self,
+        value: Vec<u8>,
+        referenced_value_hash: Hash,
+    ) -> Self {
+        self.tree.own(|t| {
+            t.with_value_and_referenced_value_hash_by::<S::Hasher>(value, referenced_value_hash)
+        });
+        self
+    }
+
+    /// Similar to `Tree#with_value_and_binding`.
+    pub fn with_value_and_binding(mut self, value: Vec<u8>, binding: ValueBinding) -> Self {
+        self.tree
+            .own(|t| t.with_value_and_binding_by::<S::Hasher>(value, binding));
         self
     }
 }
@@ -149,12 +168,14 @@ where
 #[cfg(test)]
 mod test {
     use super::{super::NoopCommit, *};
-    use crate::tree::Tree;
+    use crate::tree::{Blake3Hasher, Tree};
 
     #[derive(Clone)]
     struct MockSource {}
 
     impl Fetch for MockSource {
+        type Hasher = Blake3Hasher;
+
         fn fetch(&self, link: &Link) -> Result<Tree> {
             Ok(Tree::new(link.key().to_vec(), b"foo".to_vec()))
         }
@@ -201,6 +222,7 @@ mod test {
             b"test".to_vec(),
             b"abc".to_vec(),
             Default::default(),
+            Default::default(),
             Some(Link::Reference {
                 hash: Default::default(),
                 key: b"foo".to_vec(),
@@ -1,4 +1,5 @@
 #![feature(generic_associated_types)]
+pub mod memory;
 pub mod rocksdb_storage;
 
 // Marker trait for underlying DB transactions
@@ -52,6 +53,10 @@ pub trait Storage {
     /// Get entry by `key` from data storage
     fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error>;
 
+    /// Get entries by `keys` from data storage at once, results are in the
+    /// same order as the keys
+    fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, Self::Error>;
+
     /// Get entry by `key` from auxiliary data storage
     fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error>;
 
@@ -76,6 +81,19 @@ pub trait Storage {
     /// Get raw iterator over storage
     fn raw_iter<'a>(&'a self, tx: Option<&'a Self::DBTransaction<'a>>) -> Self::RawIterator<'a>;
 
+    /// Number of bytes of committed keys and values under the prefix in data,
+    /// auxiliary and trees roots storages, as they are written and before
+    /// compression. Takes a scan over the entries.
+    fn logical_size(&self) -> Result<u64, Self::Error>;
+
+    /// Get distinct `prefix_len` bytes long beginnings of committed keys
+    /// under the prefix in data and trees roots storages, sorted
+    fn stored_prefixes(&self, prefix_len: usize) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Deletes all entries under the prefix in data, auxiliary and trees
+    /// roots storages, pending changes of a shared batch included
+    fn clear(&self) -> Result<(), Self::Error>;
+
     /// Starts DB transaction
     fn transaction<'a>(&'a self, tx: &'a Self::DBTransaction<'a>) -> Self::StorageTransaction<'a>;
 }
@@ -135,6 +153,10 @@ impl<'b, S: Storage> Storage for &'b S {
         (*self).get(key)
     }
 
+    fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        (*self).get_many(keys)
+    }
+
     fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
         (*self).get_aux(key)
     }
@@ -166,6 +188,18 @@ impl<'b, S: Storage> Storage for &'b S {
         (*self).raw_iter(tx)
     }
 
+    fn logical_size(&self) -> Result<u64, Self::Error> {
+        (*self).logical_size()
+    }
+
+    fn stored_prefixes(&self, prefix_len: usize) -> Result<Vec<Vec<u8>>, Self::Error> {
+        (*self).stored_prefixes(prefix_len)
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        (*self).clear()
+    }
+
     fn transaction<'a>(
         &'a self,
         transaction: &'a Self::DBTransaction<'a>,
@@ -174,6 +208,48 @@ impl<'b, S: Storage> Storage for &'b S {
     }
 }
 
+/// A `Storage` sharing its underlying DB with other storages which use
+/// different prefixes; it's also responsible for the DB transactions
+/// lifecycle, so GroveDB can be built on top of it
+pub trait PrefixedStorage: Storage + Clone + Sized + 'static {
+    /// Shared handle to the underlying DB, DB transactions are started on it
+    type DB: Clone;
+
+    /// Opens a storage over the same DB with keys prefixed by `prefix`
+    fn with_prefix(&self, prefix: Vec<u8>) -> Result<Self, Self::Error>;
+
+    /// Returns a handle to the underlying DB
+    fn db(&self) -> Self::DB;
+
+    /// Returns the storage with changes gathered into a batch instead of being
+    /// written right away; the batch is shared by all storages opened from it
+    /// with `with_prefix` and its changes are visible through them
+    fn with_shared_batch(&self) -> Self;
+
+    /// Writes all changes gathered by the shared batch at once, does nothing
+    /// if there is no shared batch
+    fn commit_shared_batch(&self) -> Result<(), Self::Error>;
+
+    /// Checks if changes are gathered into a shared batch
+    fn has_shared_batch(&self) -> bool;
+
+    /// Starts a transaction of the underlying DB
+    fn start_db_transaction(db: &Self::DB) -> Self::DBTransaction<'_>;
+
+    /// Commits a transaction of the underlying DB
+    fn commit_db_transaction(transaction: Self::DBTransaction<'_>) -> Result<(), Self::Error>;
+
+    /// Rollbacks a transaction of the underlying DB to its initial state
+    fn rollback_db_transaction(transaction: &Self::DBTransaction<'_>) -> Result<(), Self::Error>;
+
+    /// Shortens the lifetime of a DB transaction. Transactions are covariant
+    /// over their lifetime, but that's not visible through the associated
+    /// type.
+    fn shorten_db_transaction<'a: 'b, 'b>(
+        transaction: &'b Self::DBTransaction<'a>,
+    ) -> &'b Self::DBTransaction<'b>;
+}
+
 pub trait Batch {
     type Error: std::error::Error + Send + Sync + 'static;
 
@@ -183,11 +259,18 @@ pub trait Batch {
 
     fn put_root<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error>;
 
+    /// Put `value` into GroveDB metadata storage with `key`; metadata keys are
+    /// not prefixed, so the entry is shared by all storages of the DB
+    fn put_meta<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error>;
+
     fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error>;
 
     fn delete_aux<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error>;
 
     fn delete_root<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error>;
+
+    /// Delete entry with `key` from GroveDB metadata storage
+    fn delete_meta<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error>;
 }
 
 pub trait RawIterator {
@@ -0,0 +1,69 @@
+use std::convert::Infallible;
+
+use super::{make_prefixed_key, Changes, MemoryDbTransaction, Namespace};
+use crate::Batch;
+
+/// Batch of changes to the in-memory database, applied at once on commit
+/// either to the database or to a transaction
+pub struct MemoryBatch<'a> {
+    pub(super) prefix: Vec<u8>,
+    pub(super) changes: Changes,
+    pub(super) transaction: Option<&'a MemoryDbTransaction<'a>>,
+}
+
+impl MemoryBatch<'_> {
+    fn put_into(&mut self, namespace: Namespace, key: &[u8], value: &[u8]) {
+        self.changes[namespace as usize].insert(
+            make_prefixed_key(self.prefix.clone(), key),
+            Some(value.to_vec()),
+        );
+    }
+
+    fn delete_from(&mut self, namespace: Namespace, key: &[u8]) {
+        self.changes[namespace as usize].insert(make_prefixed_key(self.prefix.clone(), key), None);
+    }
+}
+
+impl Batch for MemoryBatch<'_> {
+    type Error = Infallible;
+
+    fn put<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.put_into(Namespace::Data, key.as_ref(), value);
+        Ok(())
+    }
+
+    fn put_aux<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.put_into(Namespace::Aux, key.as_ref(), value);
+        Ok(())
+    }
+
+    fn put_root<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.put_into(Namespace::Roots, key.as_ref(), value);
+        Ok(())
+    }
+
+    fn put_meta<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.changes[Namespace::Meta as usize].insert(key.as_ref().to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.delete_from(Namespace::Data, key.as_ref());
+        Ok(())
+    }
+
+    fn delete_aux<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.delete_from(Namespace::Aux, key.as_ref());
+        Ok(())
+    }
+
+    fn delete_root<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.delete_from(Namespace::Roots, key.as_ref());
+        Ok(())
+    }
+
+    fn delete_meta<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.changes[Namespace::Meta as usize].insert(key.as_ref().to_vec(), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+//! Storage implementation keeping everything in memory, useful for tests and
+//! short-lived GroveDBs
+use std::{cell::RefCell, collections::BTreeMap, ops::Bound};
+
+use crate::{rocksdb_storage::prefix_upper_bound, DBTransaction, RawIterator};
+
+mod batch;
+mod storage;
+mod transaction;
+
+pub use batch::MemoryBatch;
+pub use transaction::{MemoryDbTransaction, MemoryTransaction};
+
+pub use self::storage::MemoryStorage;
+
+/// Logical namespaces of the storage, the same as RocksDB column families
+#[derive(Clone, Copy)]
+enum Namespace {
+    Data = 0,
+    Aux = 1,
+    Roots = 2,
+    Meta = 3,
+}
+
+const NAMESPACES_COUNT: usize = 4;
+
+type Namespaces = [BTreeMap<Vec<u8>, Vec<u8>>; NAMESPACES_COUNT];
+
+/// Changes not yet applied to the database, `None` marks a deleted key
+type Changes = [BTreeMap<Vec<u8>, Option<Vec<u8>>>; NAMESPACES_COUNT];
+
+/// In-memory database shared by storages with different prefixes
+#[derive(Default)]
+pub struct MemoryDb {
+    namespaces: RefCell<Namespaces>,
+}
+
+impl MemoryDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a transaction; its changes are visible only through it until
+    /// the transaction is committed
+    pub fn transaction(&self) -> MemoryDbTransaction<'_> {
+        MemoryDbTransaction::new(self)
+    }
+
+    fn get(&self, namespace: Namespace, key: &[u8]) -> Option<Vec<u8>> {
+        self.namespaces.borrow()[namespace as usize]
+            .get(key)
+            .cloned()
+    }
+
+    fn put(&self, namespace: Namespace, key: Vec<u8>, value: &[u8]) {
+        self.namespaces.borrow_mut()[namespace as usize].insert(key, value.to_vec());
+    }
+
+    fn delete(&self, namespace: Namespace, key: &[u8]) {
+        self.namespaces.borrow_mut()[namespace as usize].remove(key);
+    }
+
+    fn apply(&self, changes: Changes) {
+        let mut namespaces = self.namespaces.borrow_mut();
+        for (namespace, changes) in namespaces.iter_mut().zip(changes) {
+            for (key, value) in changes {
+                match value {
+                    Some(value) => namespace.insert(key, value),
+                    None => namespace.remove(&key),
+                };
+            }
+        }
+    }
+
+    /// Collects keys under `prefix` in `namespace` with the prefix stripped
+    fn keys(&self, namespace: Namespace, prefix: &[u8]) -> Vec<Vec<u8>> {
+        self.namespaces.borrow()[namespace as usize]
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key[prefix.len()..].to_vec())
+            .collect()
+    }
+
+    /// Sums lengths of keys and values under `prefix` in `namespace`
+    fn size(&self, namespace: Namespace, prefix: &[u8]) -> u64 {
+        self.namespaces.borrow()[namespace as usize]
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum()
+    }
+}
+
+/// Changes kept aside from the database until they are applied to it at
+/// once; reads through the overlay see them on top of the database data
+#[derive(Default)]
+struct Overlay {
+    changes: RefCell<Changes>,
+}
+
+impl Overlay {
+    fn get(&self, db: &MemoryDb, namespace: Namespace, key: &[u8]) -> Option<Vec<u8>> {
+        match self.changes.borrow()[namespace as usize].get(key) {
+            Some(value) => value.clone(),
+            None => db.get(namespace, key),
+        }
+    }
+
+    fn put(&self, namespace: Namespace, key: Vec<u8>, value: &[u8]) {
+        self.changes.borrow_mut()[namespace as usize].insert(key, Some(value.to_vec()));
+    }
+
+    fn delete(&self, namespace: Namespace, key: Vec<u8>) {
+        self.changes.borrow_mut()[namespace as usize].insert(key, None);
+    }
+
+    /// Turns pending changes of keys under `prefix` into deletions
+    fn delete_pending(&self, namespace: Namespace, prefix: &[u8]) {
+        for (_, value) in self.changes.borrow_mut()[namespace as usize]
+            .range_mut(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+        {
+            *value = None;
+        }
+    }
+
+    fn extend(&self, changes: Changes) {
+        let mut own_changes = self.changes.borrow_mut();
+        for (own_changes, changes) in own_changes.iter_mut().zip(changes) {
+            own_changes.extend(changes);
+        }
+    }
+
+    fn take(&self) -> Changes {
+        self.changes.take()
+    }
+}
+
+impl<'a> DBTransaction<'a> for MemoryDbTransaction<'a> {}
+
+fn make_prefixed_key<K: AsRef<[u8]>>(mut prefix: Vec<u8>, key: K) -> Vec<u8> {
+    prefix.extend_from_slice(key.as_ref());
+    prefix
+}
+
+/// Raw iterator over a prefixed storage. Entries are looked up in the
+/// ordered maps on every move and only the current one is kept, so unlike
+/// RocksDB iterators it sees changes made after its creation.
+pub struct MemoryRawIterator<'a> {
+    db: &'a MemoryDb,
+    // Changes of a transaction or a shared batch seen on top of the data
+    overlay: Option<&'a Overlay>,
+    prefix: Vec<u8>,
+    // Current entry with the prefix stripped from its key, `None` if the
+    // iterator is not valid
+    entry: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> MemoryRawIterator<'a> {
+    fn new(db: &'a MemoryDb, overlay: Option<&'a Overlay>, prefix: Vec<u8>) -> Self {
+        Self {
+            db,
+            overlay,
+            prefix,
+            entry: None,
+        }
+    }
+
+    fn prefix_end(&self) -> Bound<Vec<u8>> {
+        match prefix_upper_bound(&self.prefix) {
+            Some(upper_bound) => Bound::Excluded(upper_bound),
+            None => Bound::Unbounded,
+        }
+    }
+
+    /// Finds the first entry after `from` going forward or the last one
+    /// before it going backward, skipping the ones deleted by the overlay
+    fn find(&self, mut from: Bound<Vec<u8>>, forward: bool) -> Option<(Vec<u8>, Vec<u8>)> {
+        let namespaces = self.db.namespaces.borrow();
+        let changes = self.overlay.map(|overlay| overlay.changes.borrow());
+        loop {
+            let range = if forward {
+                (from.clone(), self.prefix_end())
+            } else {
+                (Bound::Included(self.prefix.clone()), from.clone())
+            };
+            let mut stored = namespaces[Namespace::Data as usize].range(range.clone());
+            let stored = if forward {
+                stored.next()
+            } else {
+                stored.next_back()
+            };
+            let changed = changes.as_ref().and_then(|changes| {
+                let mut changed = changes[Namespace::Data as usize].range(range);
+                if forward {
+                    changed.next()
+                } else {
+                    changed.next_back()
+                }
+            });
+            // The closest entry wins, changes override the data under the same key
+            let (key, value) = match (stored, changed) {
+                (None, None) => return None,
+                (Some((key, value)), None) => (key, Some(value)),
+                (None, Some((key, change))) => (key, change.as_ref()),
+                (Some((stored_key, value)), Some((changed_key, change))) => {
+                    let changed_first = if forward {
+                        changed_key <= stored_key
+                    } else {
+                        changed_key >= stored_key
+                    };
+                    if changed_first {
+                        (changed_key, change.as_ref())
+                    } else {
+                        (stored_key, Some(value))
+                    }
+                }
+            };
+            match value {
+                Some(value) => return Some((key[self.prefix.len()..].to_vec(), value.clone())),
+                None => from = Bound::Excluded(key.clone()),
+            }
+        }
+    }
+}
+
+impl RawIterator for MemoryRawIterator<'_> {
+    fn seek_to_first(&mut self) {
+        self.entry = self.find(Bound::Included(self.prefix.clone()), true);
+    }
+
+    fn seek_to_last(&mut self) {
+        self.entry = self.find(self.prefix_end(), false);
+    }
+
+    fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        self.entry = self.find(Bound::Included(key), true);
+    }
+
+    fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+        let key = make_prefixed_key(self.prefix.clone(), key);
+        self.entry = self.find(Bound::Included(key), false);
+    }
+
+    fn next(&mut self) {
+        if let Some((key, _)) = self.entry.take() {
+            let key = make_prefixed_key(self.prefix.clone(), key);
+            self.entry = self.find(Bound::Excluded(key), true);
+        }
+    }
+
+    fn prev(&mut self) {
+        if let Some((key, _)) = self.entry.take() {
+            let key = make_prefixed_key(self.prefix.clone(), key);
+            self.entry = self.find(Bound::Excluded(key), false);
+        }
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        self.entry.as_ref().map(|(_, value)| value.as_slice())
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.entry.as_ref().map(|(key, _)| key.as_slice())
+    }
+
+    fn valid(&self) -> bool {
+        self.entry.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{Batch, PrefixedStorage, Storage, Transaction};
+
+    fn collect_iter(mut iter: impl RawIterator) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut result = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            result.push((iter.key().unwrap().to_vec(), iter.value().unwrap().to_vec()));
+            iter.next();
+        }
+        result
+    }
+
+    #[test]
+    fn test_namespaces_are_separated() {
+        let db = Rc::new(MemoryDb::new());
+        let storage = MemoryStorage::new(db.clone(), b"test".to_vec());
+        storage.put(b"key", b"data").expect("cannot put data");
+        storage.put_aux(b"key", b"aux").expect("cannot put aux");
+        storage.put_root(b"key", b"root").expect("cannot put root");
+        storage.put_meta(b"key", b"meta").expect("cannot put meta");
+
+        assert_eq!(storage.get(b"key").unwrap().unwrap(), b"data");
+        assert_eq!(storage.get_aux(b"key").unwrap().unwrap(), b"aux");
+        assert_eq!(storage.get_root(b"key").unwrap().unwrap(), b"root");
+        assert_eq!(storage.get_meta(b"key").unwrap().unwrap(), b"meta");
+
+        // Metadata storage requires no prefixes
+        assert_eq!(db.get(Namespace::Data, b"testkey").unwrap(), b"data");
+        assert_eq!(db.get(Namespace::Meta, b"key").unwrap(), b"meta");
+        assert!(db.get(Namespace::Meta, b"testkey").is_none());
+
+        storage.delete(b"key").expect("cannot delete data");
+        storage.delete_aux(b"key").expect("cannot delete aux");
+        assert!(storage.get(b"key").unwrap().is_none());
+        assert!(storage.get_aux(b"key").unwrap().is_none());
+        assert_eq!(storage.get_root(b"key").unwrap().unwrap(), b"root");
+    }
+
+    #[test]
+    fn test_logical_size() {
+        let db = Rc::new(MemoryDb::new());
+        let storage = MemoryStorage::new(db.clone(), b"test".to_vec());
+        let other_storage = MemoryStorage::new(db, b"other".to_vec());
+        storage.put(b"key", b"data").expect("cannot put data");
+        storage.put_aux(b"key", b"aux").expect("cannot put aux");
+        storage.put_root(b"key", b"root").expect("cannot put root");
+        storage.put_meta(b"key", b"meta").expect("cannot put meta");
+        other_storage
+            .put(b"key", b"other")
+            .expect("cannot put data");
+
+        assert_eq!(
+            storage.logical_size().unwrap(),
+            3 * b"testkey".len() as u64 + (b"data".len() + b"aux".len() + b"root".len()) as u64
+        );
+    }
+
+    #[test]
+    fn test_stored_prefixes_and_clear() {
+        let db = Rc::new(MemoryDb::new());
+        let storage = MemoryStorage::new(db.clone(), Vec::new());
+        let first = MemoryStorage::new(db.clone(), b"first".to_vec());
+        let second = MemoryStorage::new(db, b"secnd".to_vec());
+        first.put(b"key", b"value").expect("cannot put data");
+        first.put_aux(b"key", b"value").expect("cannot put aux");
+        second.put_root(b"key", b"value").expect("cannot put root");
+        storage.put_aux(b"aux", b"value").expect("cannot put aux");
+
+        assert_eq!(
+            storage.stored_prefixes(5).unwrap(),
+            vec![b"first".to_vec(), b"secnd".to_vec()]
+        );
+        first.clear().unwrap();
+        assert!(first.get(b"key").unwrap().is_none());
+        assert!(first.get_aux(b"key").unwrap().is_none());
+        assert_eq!(storage.stored_prefixes(5).unwrap(), vec![b"secnd".to_vec()]);
+    }
+
+    #[test]
+    fn test_batch() {
+        let storage = MemoryStorage::default();
+        storage.put(b"key0", b"value0").expect("cannot put");
+        let mut batch = storage.new_batch(None).expect("cannot create batch");
+        batch
+            .put(b"key1", b"value1")
+            .expect("cannot put into batch");
+        batch
+            .put_root(b"root", b"yeet")
+            .expect("cannot put into batch");
+        batch
+            .put_meta(b"meta", b"value")
+            .expect("cannot put into batch");
+        batch.delete(b"key0").expect("cannot delete in batch");
+        assert!(storage.get(b"key1").unwrap().is_none());
+
+        storage.commit_batch(batch).expect("cannot commit batch");
+        assert_eq!(storage.get(b"key1").unwrap().unwrap(), b"value1");
+        assert_eq!(storage.get_root(b"root").unwrap().unwrap(), b"yeet");
+        assert!(storage.get(b"key0").unwrap().is_none());
+        // Metadata isn't prefixed
+        let other = storage
+            .with_prefix(b"prefix".to_vec())
+            .expect("cannot create storage");
+        assert_eq!(other.get_meta(b"meta").unwrap().unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_raw_iterator() {
+        let storage = MemoryStorage::default();
+        for key in [b"key1", b"key0", b"key3", b"key2"] {
+            storage.put(key, &[key[3]]).expect("cannot put");
+        }
+        // Neighbouring prefixes must not leak into the iterator
+        for prefix in [b"prefiw".to_vec(), b"prefix".to_vec(), b"prefiy".to_vec()] {
+            let other = storage.with_prefix(prefix).expect("cannot create storage");
+            other.put(b"key5", b"value5").expect("cannot put");
+        }
+        let storage = storage
+            .with_prefix(b"prefix".to_vec())
+            .expect("cannot create storage");
+        storage.put(b"key1", b"value1").expect("cannot put");
+        storage.put(b"key0", b"value0").expect("cannot put");
+
+        assert_eq!(
+            collect_iter(storage.raw_iter(None)),
+            vec![
+                (b"key0".to_vec(), b"value0".to_vec()),
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (b"key5".to_vec(), b"value5".to_vec()),
+            ]
+        );
+
+        let mut iter = storage.raw_iter(None);
+        iter.seek_to_last();
+        assert_eq!(iter.key(), Some(b"key5".as_ref()));
+        iter.next();
+        assert!(!iter.valid());
+
+        iter.seek(b"key05");
+        assert_eq!(iter.key(), Some(b"key1".as_ref()));
+        iter.seek_for_prev(b"key05");
+        assert_eq!(iter.key(), Some(b"key0".as_ref()));
+        iter.prev();
+        assert!(!iter.valid());
+
+        let empty_storage = storage
+            .with_prefix(b"notexist".to_vec())
+            .expect("cannot create storage");
+        let mut iter = empty_storage.raw_iter(None);
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        iter.next();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_raw_iterator_is_lazy() {
+        let storage = MemoryStorage::default();
+        storage.put(b"key0", b"value0").expect("cannot put");
+        storage.put(b"key2", b"value2").expect("cannot put");
+
+        let mut iter = storage.raw_iter(None);
+        iter.seek_to_first();
+        assert_eq!(iter.key(), Some(b"key0".as_ref()));
+        // Entries are looked up on moves, so changes made meanwhile are seen
+        storage.put(b"key1", b"value1").expect("cannot put");
+        storage.delete(b"key2").expect("cannot delete");
+        iter.next();
+        assert_eq!(iter.value(), Some(b"value1".as_ref()));
+        iter.next();
+        assert!(!iter.valid());
+
+        // Changes of a transaction are merged in both directions
+        let db = storage.db();
+        let db_transaction = db.transaction();
+        let transaction = storage.transaction(&db_transaction);
+        transaction.delete(b"key1").expect("cannot delete");
+        transaction.put(b"key3", b"value3").expect("cannot put");
+        transaction.put(b"key0", b"changed").expect("cannot put");
+        let mut iter = storage.raw_iter(Some(&db_transaction));
+        iter.seek_to_last();
+        assert_eq!(iter.key(), Some(b"key3".as_ref()));
+        iter.prev();
+        assert_eq!(iter.value(), Some(b"changed".as_ref()));
+        iter.prev();
+        assert!(!iter.valid());
+        iter.seek(b"key1");
+        assert_eq!(iter.key(), Some(b"key3".as_ref()));
+    }
+
+    #[test]
+    fn test_transaction_isolation() {
+        let storage = MemoryStorage::default();
+        storage.put(b"key0", b"value0").expect("cannot put");
+        storage.put(b"key1", b"value1").expect("cannot put");
+
+        let db = storage.db();
+        let db_transaction = db.transaction();
+        let transaction = storage.transaction(&db_transaction);
+        transaction.put(b"key2", b"value2").expect("cannot put");
+        transaction.delete(b"key0").expect("cannot delete");
+        transaction.put_meta(b"meta", b"value").expect("cannot put");
+        let mut batch = storage
+            .new_batch(Some(&db_transaction))
+            .expect("cannot create batch");
+        batch
+            .put_aux(b"aux", b"value")
+            .expect("cannot put into batch");
+        storage.commit_batch(batch).expect("cannot commit batch");
+
+        assert_eq!(transaction.get(b"key2").unwrap().unwrap(), b"value2");
+        assert!(transaction.get(b"key0").unwrap().is_none());
+        assert_eq!(transaction.get_aux(b"aux").unwrap().unwrap(), b"value");
+        assert!(storage.get(b"key2").unwrap().is_none());
+        assert!(storage.get_aux(b"aux").unwrap().is_none());
+        assert!(storage.get_meta(b"meta").unwrap().is_none());
+        assert_eq!(
+            collect_iter(storage.raw_iter(Some(&db_transaction))),
+            vec![
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (b"key2".to_vec(), b"value2".to_vec()),
+            ]
+        );
+        assert_eq!(collect_iter(storage.raw_iter(None)).len(), 2);
+
+        MemoryStorage::commit_db_transaction(db_transaction).expect("cannot commit");
+        assert_eq!(storage.get(b"key2").unwrap().unwrap(), b"value2");
+        assert!(storage.get(b"key0").unwrap().is_none());
+        assert_eq!(storage.get_aux(b"aux").unwrap().unwrap(), b"value");
+        assert_eq!(storage.get_meta(b"meta").unwrap().unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let storage = MemoryStorage::default();
+        let db = storage.db();
+        let db_transaction = db.transaction();
+        let transaction = storage.transaction(&db_transaction);
+        transaction.put(b"key", b"value").expect("cannot put");
+
+        MemoryStorage::rollback_db_transaction(&db_transaction).expect("cannot rollback");
+        assert!(transaction.get(b"key").unwrap().is_none());
+        transaction.put(b"key2", b"value2").expect("cannot put");
+        MemoryStorage::commit_db_transaction(db_transaction).expect("cannot commit");
+        assert!(storage.get(b"key").unwrap().is_none());
+        assert_eq!(storage.get(b"key2").unwrap().unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_shared_batch() {
+        let storage = MemoryStorage::default();
+        storage.put(b"key0", b"value0").expect("cannot put");
+        storage.put(b"key1", b"value1").expect("cannot put");
+
+        let batched_storage = storage.with_shared_batch();
+        let another_batched_storage = batched_storage
+            .with_prefix(b"prefix".to_vec())
+            .expect("cannot create storage");
+        batched_storage.delete(b"key0").expect("cannot delete");
+        another_batched_storage
+            .put_aux(b"aux", b"value")
+            .expect("cannot put");
+
+        assert!(batched_storage.get(b"key0").unwrap().is_none());
+        assert_eq!(collect_iter(batched_storage.raw_iter(None)).len(), 1);
+        assert_eq!(
+            another_batched_storage.get_aux(b"aux").unwrap().unwrap(),
+            b"value"
+        );
+        assert_eq!(storage.get(b"key0").unwrap().unwrap(), b"value0");
+
+        batched_storage
+            .commit_shared_batch()
+            .expect("cannot commit shared batch");
+        assert!(storage.get(b"key0").unwrap().is_none());
+        assert_eq!(
+            storage
+                .with_prefix(b"prefix".to_vec())
+                .unwrap()
+                .get_aux(b"aux")
+                .unwrap()
+                .unwrap(),
+            b"value"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::BTreeSet, convert::Infallible, rc::Rc};
+
+use super::{
+    make_prefixed_key, MemoryBatch, MemoryDb, MemoryDbTransaction, MemoryRawIterator,
+    MemoryTransaction, Namespace, Overlay,
+};
+use crate::{Batch, PrefixedStorage, Storage};
+
+/// In-memory database wrapper to store items with prefixes
+#[derive(Clone)]
+pub struct MemoryStorage {
+    db: Rc<MemoryDb>,
+    prefix: Vec<u8>,
+    shared_batch: Option<Rc<Overlay>>,
+}
+
+impl MemoryStorage {
+    /// Wraps in-memory database to prepend prefixes to each operation
+    pub fn new(db: Rc<MemoryDb>, prefix: Vec<u8>) -> Self {
+        Self {
+            db,
+            prefix,
+            shared_batch: None,
+        }
+    }
+
+    fn read(&self, namespace: Namespace, key: &[u8]) -> Option<Vec<u8>> {
+        match &self.shared_batch {
+            Some(shared_batch) => shared_batch.get(&self.db, namespace, key),
+            None => self.db.get(namespace, key),
+        }
+    }
+
+    fn write(&self, namespace: Namespace, key: Vec<u8>, value: &[u8]) {
+        match &self.shared_batch {
+            Some(shared_batch) => shared_batch.put(namespace, key, value),
+            None => self.db.put(namespace, key, value),
+        }
+    }
+
+    fn remove(&self, namespace: Namespace, key: Vec<u8>) {
+        match &self.shared_batch {
+            Some(shared_batch) => shared_batch.delete(namespace, key),
+            None => self.db.delete(namespace, &key),
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    /// Creates a storage on top of a new empty database with no prefix
+    fn default() -> Self {
+        Self::new(Rc::new(MemoryDb::new()), Vec::new())
+    }
+}
+
+impl Storage for MemoryStorage {
+    type Batch<'a> = MemoryBatch<'a>;
+    type DBTransaction<'a> = MemoryDbTransaction<'a>;
+    type Error = Infallible;
+    type RawIterator<'a> = MemoryRawIterator<'a>;
+    type StorageTransaction<'a> = MemoryTransaction<'a>;
+
+    fn put<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.write(
+            Namespace::Data,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_aux<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.write(
+            Namespace::Aux,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_root<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.write(
+            Namespace::Roots,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_meta<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.write(Namespace::Meta, key.as_ref().to_vec(), value);
+        Ok(())
+    }
+
+    fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.remove(Namespace::Data, make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.remove(Namespace::Aux, make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_root<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.remove(
+            Namespace::Roots,
+            make_prefixed_key(self.prefix.clone(), key),
+        );
+        Ok(())
+    }
+
+    fn delete_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.remove(Namespace::Meta, key.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.read(
+            Namespace::Data,
+            &make_prefixed_key(self.prefix.clone(), key),
+        ))
+    }
+
+    fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        Ok(keys
+            .iter()
+            .map(|key| {
+                self.read(
+                    Namespace::Data,
+                    &make_prefixed_key(self.prefix.clone(), key),
+                )
+            })
+            .collect())
+    }
+
+    fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.read(Namespace::Aux, &make_prefixed_key(self.prefix.clone(), key)))
+    }
+
+    fn get_root<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.read(
+            Namespace::Roots,
+            &make_prefixed_key(self.prefix.clone(), key),
+        ))
+    }
+
+    fn get_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.read(Namespace::Meta, key.as_ref()))
+    }
+
+    fn new_batch<'a: 'b, 'b>(
+        &'a self,
+        transaction: Option<&'b MemoryDbTransaction<'b>>,
+    ) -> Result<Self::Batch<'b>, Self::Error> {
+        Ok(MemoryBatch {
+            prefix: self.prefix.clone(),
+            changes: Default::default(),
+            transaction,
+        })
+    }
+
+    fn commit_batch<'a>(&'a self, batch: Self::Batch<'a>) -> Result<(), Self::Error> {
+        // Changes of a transactional batch are a part of the transaction and must be
+        // committed with it by its creator
+        match (batch.transaction, &self.shared_batch) {
+            (Some(tx), _) => tx.apply(batch.changes),
+            (None, Some(shared_batch)) => shared_batch.extend(batch.changes),
+            (None, None) => self.db.apply(batch.changes),
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn raw_iter<'a>(
+        &'a self,
+        db_transaction: Option<&'a MemoryDbTransaction<'a>>,
+    ) -> Self::RawIterator<'a> {
+        match db_transaction {
+            Some(tx) => tx.raw_iter(self.prefix.clone()),
+            None => {
+                MemoryRawIterator::new(&self.db, self.shared_batch.as_deref(), self.prefix.clone())
+            }
+        }
+    }
+
+    fn logical_size(&self) -> Result<u64, Self::Error> {
+        Ok([Namespace::Data, Namespace::Aux, Namespace::Roots]
+            .into_iter()
+            .map(|namespace| self.db.size(namespace, &self.prefix))
+            .sum())
+    }
+
+    fn stored_prefixes(&self, prefix_len: usize) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let prefixes: BTreeSet<Vec<u8>> = [Namespace::Data, Namespace::Roots]
+            .into_iter()
+            .flat_map(|namespace| self.db.keys(namespace, &self.prefix))
+            .filter(|key| key.len() >= prefix_len)
+            .map(|key| key[..prefix_len].to_vec())
+            .collect();
+        Ok(prefixes.into_iter().collect())
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            for namespace in [Namespace::Data, Namespace::Aux, Namespace::Roots] {
+                shared_batch.delete_pending(namespace, &self.prefix);
+            }
+        }
+        let mut batch = self.new_batch(None)?;
+        for key in self.db.keys(Namespace::Data, &self.prefix) {
+            batch.delete(key)?;
+        }
+        for key in self.db.keys(Namespace::Aux, &self.prefix) {
+            batch.delete_aux(key)?;
+        }
+        for key in self.db.keys(Namespace::Roots, &self.prefix) {
+            batch.delete_root(key)?;
+        }
+        self.commit_batch(batch)
+    }
+
+    fn transaction<'a>(
+        &'a self,
+        db_transaction: &'a MemoryDbTransaction<'a>,
+    ) -> Self::StorageTransaction<'a> {
+        MemoryTransaction::new(db_transaction, self.prefix.clone())
+    }
+}
+
+impl PrefixedStorage for MemoryStorage {
+    type DB = Rc<MemoryDb>;
+
+    fn with_prefix(&self, prefix: Vec<u8>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            db: self.db.clone(),
+            prefix,
+            shared_batch: self.shared_batch.clone(),
+        })
+    }
+
+    fn with_shared_batch(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            prefix: self.prefix.clone(),
+            shared_batch: Some(Default::default()),
+        }
+    }
+
+    fn commit_shared_batch(&self) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            self.db.apply(shared_batch.take());
+        }
+        Ok(())
+    }
+
+    fn has_shared_batch(&self) -> bool {
+        self.shared_batch.is_some()
+    }
+
+    fn db(&self) -> Self::DB {
+        self.db.clone()
+    }
+
+    fn start_db_transaction(db: &Self::DB) -> Self::DBTransaction<'_> {
+        db.transaction()
+    }
+
+    fn commit_db_transaction(transaction: Self::DBTransaction<'_>) -> Result<(), Self::Error> {
+        transaction.commit();
+        Ok(())
+    }
+
+    fn rollback_db_transaction(transaction: &Self::DBTransaction<'_>) -> Result<(), Self::Error> {
+        transaction.rollback();
+        Ok(())
+    }
+
+    fn shorten_db_transaction<'a: 'b, 'b>(
+        transaction: &'b Self::DBTransaction<'a>,
+    ) -> &'b Self::DBTransaction<'b> {
+        transaction
+    }
+}
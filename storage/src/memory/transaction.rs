@@ -0,0 +1,153 @@
+use std::convert::Infallible;
+
+use super::{make_prefixed_key, Changes, MemoryDb, MemoryRawIterator, Namespace, Overlay};
+use crate::Transaction;
+
+/// Transaction of the in-memory database. Changes are kept aside until
+/// commit, so the database itself sees none of them.
+pub struct MemoryDbTransaction<'a> {
+    db: &'a MemoryDb,
+    overlay: Overlay,
+}
+
+impl<'a> MemoryDbTransaction<'a> {
+    pub(super) fn new(db: &'a MemoryDb) -> Self {
+        Self {
+            db,
+            overlay: Default::default(),
+        }
+    }
+
+    /// Applies the transaction changes to the database
+    pub fn commit(self) {
+        self.db.apply(self.overlay.take());
+    }
+
+    /// Drops all changes made in the transaction so far
+    pub fn rollback(&self) {
+        self.overlay.take();
+    }
+
+    pub(super) fn get(&self, namespace: Namespace, key: &[u8]) -> Option<Vec<u8>> {
+        self.overlay.get(self.db, namespace, key)
+    }
+
+    pub(super) fn put(&self, namespace: Namespace, key: Vec<u8>, value: &[u8]) {
+        self.overlay.put(namespace, key, value);
+    }
+
+    pub(super) fn delete(&self, namespace: Namespace, key: Vec<u8>) {
+        self.overlay.delete(namespace, key);
+    }
+
+    pub(super) fn apply(&self, changes: Changes) {
+        self.overlay.extend(changes);
+    }
+
+    /// Iterates over data entries under `prefix` as seen by the transaction
+    pub(super) fn raw_iter(&self, prefix: Vec<u8>) -> MemoryRawIterator<'_> {
+        MemoryRawIterator::new(self.db, Some(&self.overlay), prefix)
+    }
+}
+
+/// Prefixed view on a transaction of the in-memory database
+pub struct MemoryTransaction<'a> {
+    transaction: &'a MemoryDbTransaction<'a>,
+    prefix: Vec<u8>,
+}
+
+impl<'a> MemoryTransaction<'a> {
+    pub fn new(transaction: &'a MemoryDbTransaction<'a>, prefix: Vec<u8>) -> Self {
+        Self {
+            transaction,
+            prefix,
+        }
+    }
+}
+
+impl Transaction for MemoryTransaction<'_> {
+    type Error = Infallible;
+
+    fn put<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.transaction.put(
+            Namespace::Data,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_aux<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.transaction.put(
+            Namespace::Aux,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_root<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.transaction.put(
+            Namespace::Roots,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_meta<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.transaction
+            .put(Namespace::Meta, key.as_ref().to_vec(), value);
+        Ok(())
+    }
+
+    fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.transaction
+            .delete(Namespace::Data, make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.transaction
+            .delete(Namespace::Aux, make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_root<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.transaction.delete(
+            Namespace::Roots,
+            make_prefixed_key(self.prefix.clone(), key),
+        );
+        Ok(())
+    }
+
+    fn delete_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        self.transaction
+            .delete(Namespace::Meta, key.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.transaction.get(
+            Namespace::Data,
+            &make_prefixed_key(self.prefix.clone(), key),
+        ))
+    }
+
+    fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .transaction
+            .get(Namespace::Aux, &make_prefixed_key(self.prefix.clone(), key)))
+    }
+
+    fn get_root<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.transaction.get(
+            Namespace::Roots,
+            &make_prefixed_key(self.prefix.clone(), key),
+        ))
+    }
+
+    fn get_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.transaction.get(Namespace::Meta, key.as_ref()))
+    }
+}
@@ -1,8 +1,8 @@
-use std::convert::Infallible;
+use std::{cell::RefCell, collections::BTreeMap, convert::Infallible};
 
 use rocksdb::{ColumnFamily, OptimisticTransactionDB};
 
-use super::{make_prefixed_key, PrefixedRocksDbStorageError};
+use super::{make_prefixed_key, Column, PrefixedRocksDbStorageError, COLUMNS};
 use crate::Batch;
 
 /// Wrapper to RocksDB batch
@@ -11,6 +11,7 @@ pub struct PrefixedRocksDbBatch<'a> {
     pub batch: rocksdb::WriteBatchWithTransaction<true>,
     pub cf_aux: &'a ColumnFamily,
     pub cf_roots: &'a ColumnFamily,
+    pub cf_meta: &'a ColumnFamily,
 }
 
 impl<'a> Batch for PrefixedRocksDbBatch<'a> {
@@ -40,6 +41,11 @@ impl<'a> Batch for PrefixedRocksDbBatch<'a> {
         Ok(())
     }
 
+    fn put_meta<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.batch.put_cf(self.cf_meta, key, value);
+        Ok(())
+    }
+
     fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
         self.batch
             .delete(make_prefixed_key(self.prefix.clone(), key));
@@ -57,6 +63,11 @@ impl<'a> Batch for PrefixedRocksDbBatch<'a> {
             .delete_cf(self.cf_roots, make_prefixed_key(self.prefix.clone(), key));
         Ok(())
     }
+
+    fn delete_meta<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.batch.delete_cf(self.cf_meta, key);
+        Ok(())
+    }
 }
 
 /// Wrapper to RocksDB batch
@@ -64,6 +75,7 @@ pub struct PrefixedTransactionalRocksDbBatch<'a> {
     pub prefix: Vec<u8>,
     pub cf_aux: &'a ColumnFamily,
     pub cf_roots: &'a ColumnFamily,
+    pub cf_meta: &'a ColumnFamily,
     pub transaction: &'a rocksdb::Transaction<'a, OptimisticTransactionDB>,
 }
 
@@ -95,6 +107,11 @@ impl<'a> Batch for PrefixedTransactionalRocksDbBatch<'a> {
         Ok(())
     }
 
+    fn put_meta<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.transaction.put_cf(self.cf_meta, key, value)?;
+        Ok(())
+    }
+
     fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
         self.transaction
             .delete(make_prefixed_key(self.prefix.clone(), key))?;
@@ -112,11 +129,159 @@ impl<'a> Batch for PrefixedTransactionalRocksDbBatch<'a> {
             .delete_cf(self.cf_roots, make_prefixed_key(self.prefix.clone(), key))?;
         Ok(())
     }
+
+    fn delete_meta<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.transaction.delete_cf(self.cf_meta, key)?;
+        Ok(())
+    }
+}
+
+/// Pending changes of one column family, `None` marks a deleted key
+type ColumnChanges = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Changes of many prefixed storages gathered to be written to RocksDB with
+/// one write batch, so they are applied all or nothing. Unlike RocksDB write
+/// batch it allows to read the changes before they are written.
+#[derive(Default)]
+pub struct SharedRocksDbBatch {
+    changes: RefCell<[ColumnChanges; 4]>,
+}
+
+impl SharedRocksDbBatch {
+    pub(crate) fn put(&self, column: Column, key: Vec<u8>, value: &[u8]) {
+        self.changes.borrow_mut()[column as usize].insert(key, Some(value.to_vec()));
+    }
+
+    pub(crate) fn delete(&self, column: Column, key: Vec<u8>) {
+        self.changes.borrow_mut()[column as usize].insert(key, None);
+    }
+
+    /// Turns pending changes of keys under `prefix` into deletions
+    pub(crate) fn delete_pending(&self, column: Column, prefix: &[u8]) {
+        for (_, value) in self.changes.borrow_mut()[column as usize]
+            .range_mut(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+        {
+            *value = None;
+        }
+    }
+
+    /// Returns a pending change of the `key`: `Some(None)` if it's deleted
+    /// and `None` if the batch doesn't touch it
+    pub(crate) fn get(&self, column: Column, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.changes.borrow()[column as usize].get(key).cloned()
+    }
+
+    /// Pending changes of data under `prefix`, the prefix is stripped from
+    /// their keys
+    pub(crate) fn pending_data(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.changes.borrow()[Column::Default as usize]
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key[prefix.len()..].to_vec(), value.clone()))
+            .collect()
+    }
+
+    /// Writes all gathered changes at once and empties the batch
+    pub(crate) fn write(
+        &self,
+        db: &OptimisticTransactionDB,
+    ) -> Result<(), PrefixedRocksDbStorageError> {
+        let changes = self.changes.take();
+        let mut batch = rocksdb::WriteBatchWithTransaction::<true>::default();
+        for (column, changes) in COLUMNS.into_iter().zip(changes) {
+            let cf = column
+                .name()
+                .map(|name| {
+                    db.cf_handle(name)
+                        .ok_or(PrefixedRocksDbStorageError::ColumnFamilyNotFound(name))
+                })
+                .transpose()?;
+            for (key, value) in changes {
+                match (cf, value) {
+                    (Some(cf), Some(value)) => batch.put_cf(cf, key, value),
+                    (Some(cf), None) => batch.delete_cf(cf, key),
+                    (None, Some(value)) => batch.put(key, value),
+                    (None, None) => batch.delete(key),
+                }
+            }
+        }
+        db.write(batch)?;
+        Ok(())
+    }
+}
+
+/// Wrapper to a batch shared by many storages
+pub struct PrefixedSharedRocksDbBatch<'a> {
+    pub prefix: Vec<u8>,
+    pub shared_batch: &'a SharedRocksDbBatch,
+}
+
+impl<'a> Batch for PrefixedSharedRocksDbBatch<'a> {
+    type Error = Infallible;
+
+    fn put<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.shared_batch.put(
+            Column::Default,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_aux<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.shared_batch.put(
+            Column::Aux,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_root<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.shared_batch.put(
+            Column::Roots,
+            make_prefixed_key(self.prefix.clone(), key),
+            value,
+        );
+        Ok(())
+    }
+
+    fn put_meta<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        self.shared_batch
+            .put(Column::Meta, key.as_ref().to_vec(), value);
+        Ok(())
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.shared_batch
+            .delete(Column::Default, make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_aux<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.shared_batch
+            .delete(Column::Aux, make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_root<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.shared_batch
+            .delete(Column::Roots, make_prefixed_key(self.prefix.clone(), key));
+        Ok(())
+    }
+
+    fn delete_meta<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        self.shared_batch
+            .delete(Column::Meta, key.as_ref().to_vec());
+        Ok(())
+    }
 }
 
 pub enum OrBatch<'a> {
     Batch(PrefixedRocksDbBatch<'a>),
     TransactionalBatch(PrefixedTransactionalRocksDbBatch<'a>),
+    SharedBatch(PrefixedSharedRocksDbBatch<'a>),
 }
 
 impl<'a> Batch for OrBatch<'a> {
@@ -126,6 +291,7 @@ impl<'a> Batch for OrBatch<'a> {
         match self {
             Self::TransactionalBatch(batch) => batch.put(key, value)?,
             Self::Batch(batch) => batch.put(key, value).unwrap_or_default(),
+            Self::SharedBatch(batch) => batch.put(key, value).unwrap_or_default(),
         }
         Ok(())
     }
@@ -134,6 +300,7 @@ impl<'a> Batch for OrBatch<'a> {
         match self {
             Self::TransactionalBatch(batch) => batch.put_aux(key, value)?,
             Self::Batch(batch) => batch.put_aux(key, value).unwrap_or_default(),
+            Self::SharedBatch(batch) => batch.put_aux(key, value).unwrap_or_default(),
         }
         Ok(())
     }
@@ -142,6 +309,16 @@ impl<'a> Batch for OrBatch<'a> {
         match self {
             Self::TransactionalBatch(batch) => batch.put_root(key, value)?,
             Self::Batch(batch) => batch.put_root(key, value).unwrap_or_default(),
+            Self::SharedBatch(batch) => batch.put_root(key, value).unwrap_or_default(),
+        }
+        Ok(())
+    }
+
+    fn put_meta<K: AsRef<[u8]>>(&mut self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            Self::TransactionalBatch(batch) => batch.put_meta(key, value)?,
+            Self::Batch(batch) => batch.put_meta(key, value).unwrap_or_default(),
+            Self::SharedBatch(batch) => batch.put_meta(key, value).unwrap_or_default(),
         }
         Ok(())
     }
@@ -150,6 +327,7 @@ impl<'a> Batch for OrBatch<'a> {
         match self {
             Self::TransactionalBatch(batch) => batch.delete(key)?,
             Self::Batch(batch) => batch.delete(key).unwrap_or_default(),
+            Self::SharedBatch(batch) => batch.delete(key).unwrap_or_default(),
         }
         Ok(())
     }
@@ -158,6 +336,7 @@ impl<'a> Batch for OrBatch<'a> {
         match self {
             Self::TransactionalBatch(batch) => batch.delete_aux(key)?,
             Self::Batch(batch) => batch.delete_aux(key).unwrap_or_default(),
+            Self::SharedBatch(batch) => batch.delete_aux(key).unwrap_or_default(),
         }
         Ok(())
     }
@@ -166,6 +345,16 @@ impl<'a> Batch for OrBatch<'a> {
         match self {
             Self::TransactionalBatch(batch) => batch.delete_root(key)?,
             Self::Batch(batch) => batch.delete_root(key).unwrap_or_default(),
+            Self::SharedBatch(batch) => batch.delete_root(key).unwrap_or_default(),
+        }
+        Ok(())
+    }
+
+    fn delete_meta<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), Self::Error> {
+        match self {
+            Self::TransactionalBatch(batch) => batch.delete_meta(key)?,
+            Self::Batch(batch) => batch.delete_meta(key).unwrap_or_default(),
+            Self::SharedBatch(batch) => batch.delete_meta(key).unwrap_or_default(),
         }
         Ok(())
     }
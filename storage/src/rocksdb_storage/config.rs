@@ -0,0 +1,241 @@
+use std::path::{Path, PathBuf};
+
+use rocksdb::{
+    BlockBasedOptions, Cache, ColumnFamilyDescriptor, DBCompressionType, DBRecoveryMode, Error,
+    OptimisticTransactionDB, Options, SliceTransform,
+};
+
+use super::{Column, COLUMNS, PREFIX_LEN};
+
+/// Tuning of a single column family
+#[derive(Debug, Clone, Default)]
+struct ColumnConfig {
+    compression: Option<DBCompressionType>,
+    write_buffer_size: Option<usize>,
+}
+
+/// RocksDB settings the storage is opened with. Options not set explicitly
+/// are left to RocksDB defaults, except ones the storage relied on before
+/// they became configurable: mmap reads and writes, atomic flush and
+/// parallelism by the number of CPUs, and the prefix extractor with bloom
+/// filters of the data column family.
+///
+/// ```
+/// # use storage::rocksdb_storage::{Column, DBCompressionType, RocksDbConfig};
+/// # let tmp_dir = tempdir::TempDir::new("db").unwrap();
+/// let db = RocksDbConfig::default()
+///     .block_cache_size(64 << 20)
+///     .compression(Column::Aux, DBCompressionType::Lz4)
+///     .max_open_files(1024)
+///     .open(tmp_dir.path())?;
+/// # Ok::<(), storage::rocksdb_storage::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct RocksDbConfig {
+    parallelism: i32,
+    allow_mmap_reads: bool,
+    allow_mmap_writes: bool,
+    atomic_flush: bool,
+    max_open_files: Option<i32>,
+    block_cache_size: Option<usize>,
+    db_write_buffer_size: Option<usize>,
+    prefix_extractor: bool,
+    bloom_filter_bits_per_key: Option<f64>,
+    wal_dir: Option<PathBuf>,
+    max_total_wal_size: Option<u64>,
+    wal_recovery_mode: Option<DBRecoveryMode>,
+    columns: [ColumnConfig; 4],
+}
+
+impl Default for RocksDbConfig {
+    fn default() -> Self {
+        Self {
+            parallelism: num_cpus::get() as i32,
+            allow_mmap_reads: true,
+            allow_mmap_writes: true,
+            atomic_flush: true,
+            max_open_files: None,
+            block_cache_size: None,
+            db_write_buffer_size: None,
+            prefix_extractor: true,
+            bloom_filter_bits_per_key: Some(10.0),
+            wal_dir: None,
+            max_total_wal_size: None,
+            wal_recovery_mode: None,
+            columns: Default::default(),
+        }
+    }
+}
+
+impl RocksDbConfig {
+    /// Number of background threads for flushes and compactions
+    pub fn parallelism(mut self, threads: i32) -> Self {
+        self.parallelism = threads;
+        self
+    }
+
+    /// Read SST files with mmap
+    pub fn allow_mmap_reads(mut self, allow: bool) -> Self {
+        self.allow_mmap_reads = allow;
+        self
+    }
+
+    /// Write SST files with mmap
+    pub fn allow_mmap_writes(mut self, allow: bool) -> Self {
+        self.allow_mmap_writes = allow;
+        self
+    }
+
+    /// Flush memtables of all column families at once, so they stay
+    /// consistent with each other without WAL
+    pub fn atomic_flush(mut self, atomic_flush: bool) -> Self {
+        self.atomic_flush = atomic_flush;
+        self
+    }
+
+    /// Limit of files kept open by RocksDB, `-1` keeps all of them open
+    pub fn max_open_files(mut self, max_open_files: i32) -> Self {
+        self.max_open_files = Some(max_open_files);
+        self
+    }
+
+    /// Size in bytes of the LRU block cache shared by all column families
+    pub fn block_cache_size(mut self, size: usize) -> Self {
+        self.block_cache_size = Some(size);
+        self
+    }
+
+    /// Compression of the column family data
+    pub fn compression(mut self, column: Column, compression: DBCompressionType) -> Self {
+        self.columns[column as usize].compression = Some(compression);
+        self
+    }
+
+    /// Size in bytes of a single memtable of the column family
+    pub fn write_buffer_size(mut self, column: Column, size: usize) -> Self {
+        self.columns[column as usize].write_buffer_size = Some(size);
+        self
+    }
+
+    /// Total size in bytes of memtables of all column families
+    pub fn db_write_buffer_size(mut self, size: usize) -> Self {
+        self.db_write_buffer_size = Some(size);
+        self
+    }
+
+    /// Extracts subtree prefixes of data keys, so bloom filters are built for
+    /// prefixes as well and scans of a subtree skip files without it
+    pub fn prefix_extractor(mut self, prefix_extractor: bool) -> Self {
+        self.prefix_extractor = prefix_extractor;
+        self
+    }
+
+    /// Bits per key of bloom filters of the data column family, `None`
+    /// disables them
+    pub fn bloom_filter_bits_per_key(mut self, bits: Option<f64>) -> Self {
+        self.bloom_filter_bits_per_key = bits;
+        self
+    }
+
+    /// Directory for write-ahead log files, the DB directory by default
+    pub fn wal_dir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.wal_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Size in bytes of write-ahead log files which forces flushes of the
+    /// oldest memtables once exceeded
+    pub fn max_total_wal_size(mut self, size: u64) -> Self {
+        self.max_total_wal_size = Some(size);
+        self
+    }
+
+    /// How to treat corrupted records of write-ahead log on recovery
+    pub fn wal_recovery_mode(mut self, mode: DBRecoveryMode) -> Self {
+        self.wal_recovery_mode = Some(mode);
+        self
+    }
+
+    /// Creates a block cache to be shared by all column families if its size
+    /// is set
+    pub fn block_cache(&self) -> Result<Option<Cache>, Error> {
+        self.block_cache_size.map(Cache::new_lru_cache).transpose()
+    }
+
+    /// Options of the DB, they also apply to the default column family
+    pub fn db_opts(&self, block_cache: Option<&Cache>) -> Options {
+        let mut opts = self.column_opts(Column::Default, block_cache);
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        opts.increase_parallelism(self.parallelism);
+        opts.set_allow_mmap_writes(self.allow_mmap_writes);
+        opts.set_allow_mmap_reads(self.allow_mmap_reads);
+        opts.set_atomic_flush(self.atomic_flush);
+        if let Some(max_open_files) = self.max_open_files {
+            opts.set_max_open_files(max_open_files);
+        }
+        if let Some(size) = self.db_write_buffer_size {
+            opts.set_db_write_buffer_size(size);
+        }
+        if let Some(wal_dir) = &self.wal_dir {
+            opts.set_wal_dir(wal_dir);
+        }
+        if let Some(size) = self.max_total_wal_size {
+            opts.set_max_total_wal_size(size);
+        }
+        if let Some(mode) = self.wal_recovery_mode {
+            opts.set_wal_recovery_mode(mode);
+        }
+        opts
+    }
+
+    /// Descriptors of non-default column families
+    pub fn column_families(&self, block_cache: Option<&Cache>) -> Vec<ColumnFamilyDescriptor> {
+        COLUMNS
+            .into_iter()
+            .filter_map(|column| {
+                column.name().map(|name| {
+                    ColumnFamilyDescriptor::new(name, self.column_opts(column, block_cache))
+                })
+            })
+            .collect()
+    }
+
+    /// Opens RocksDB at `path` with these settings
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<OptimisticTransactionDB, Error> {
+        let block_cache = self.block_cache()?;
+        OptimisticTransactionDB::open_cf_descriptors(
+            &self.db_opts(block_cache.as_ref()),
+            path,
+            self.column_families(block_cache.as_ref()),
+        )
+    }
+
+    fn column_opts(&self, column: Column, block_cache: Option<&Cache>) -> Options {
+        let mut opts = Options::default();
+        let column_config = &self.columns[column as usize];
+        if let Some(compression) = column_config.compression {
+            opts.set_compression_type(compression);
+        }
+        if let Some(size) = column_config.write_buffer_size {
+            opts.set_write_buffer_size(size);
+        }
+        let bloom_filter_bits_per_key = self
+            .bloom_filter_bits_per_key
+            .filter(|_| column == Column::Default);
+        if block_cache.is_some() || bloom_filter_bits_per_key.is_some() {
+            let mut table_opts = BlockBasedOptions::default();
+            if let Some(cache) = block_cache {
+                table_opts.set_block_cache(cache);
+            }
+            if let Some(bits) = bloom_filter_bits_per_key {
+                table_opts.set_bloom_filter(bits, false);
+            }
+            opts.set_block_based_table_factory(&table_opts);
+        }
+        if self.prefix_extractor && column == Column::Default {
+            opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(PREFIX_LEN));
+        }
+        opts
+    }
+}
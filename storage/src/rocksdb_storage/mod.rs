@@ -1,16 +1,20 @@
 //! Storage implementation using RocksDB
 use std::{path::Path, rc::Rc};
 
-pub use rocksdb::{checkpoint::Checkpoint, Error, OptimisticTransactionDB};
-use rocksdb::{ColumnFamilyDescriptor, DBRawIteratorWithThreadMode};
+pub use rocksdb::{
+    checkpoint::Checkpoint, DBCompressionType, DBRecoveryMode, Error, OptimisticTransactionDB,
+};
+use rocksdb::{ColumnFamilyDescriptor, DBRawIteratorWithThreadMode, ReadOptions};
 
 use crate::{DBTransaction, RawIterator};
 
 mod batch;
+mod config;
 mod storage;
 mod transaction;
 
-pub use batch::PrefixedRocksDbBatch;
+pub use batch::{PrefixedRocksDbBatch, PrefixedSharedRocksDbBatch, SharedRocksDbBatch};
+pub use config::RocksDbConfig;
 pub use transaction::PrefixedRocksDbTransaction;
 
 pub use self::storage::{PrefixedRocksDbStorage, PrefixedRocksDbStorageError};
@@ -19,16 +23,40 @@ const AUX_CF_NAME: &str = "aux";
 const ROOTS_CF_NAME: &str = "roots";
 const META_CF_NAME: &str = "meta";
 
+/// Length of subtree prefixes the data column family prefix extractor is
+/// built for, GroveDB derives them with 32 bytes hashes
+pub const PREFIX_LEN: usize = 32;
+
+/// RocksDB column families used by the storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// Data, stored in the default column family
+    Default = 0,
+    /// Auxiliary data
+    Aux = 1,
+    /// Trees roots
+    Roots = 2,
+    /// GroveDB metadata, its keys are not prefixed
+    Meta = 3,
+}
+
+impl Column {
+    /// Name of the column family, `None` for the default one
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Column::Default => None,
+            Column::Aux => Some(AUX_CF_NAME),
+            Column::Roots => Some(ROOTS_CF_NAME),
+            Column::Meta => Some(META_CF_NAME),
+        }
+    }
+}
+
+const COLUMNS: [Column; 4] = [Column::Default, Column::Aux, Column::Roots, Column::Meta];
+
 /// RocksDB options
 pub fn default_db_opts() -> rocksdb::Options {
-    let mut opts = rocksdb::Options::default();
-    opts.create_if_missing(true);
-    opts.increase_parallelism(num_cpus::get() as i32);
-    opts.set_allow_mmap_writes(true);
-    opts.set_allow_mmap_reads(true);
-    opts.create_missing_column_families(true);
-    opts.set_atomic_flush(true);
-    opts
+    RocksDbConfig::default().db_opts(None)
 }
 
 pub type OptimisticTransactionDBTransaction<'a> = rocksdb::Transaction<'a, OptimisticTransactionDB>;
@@ -37,22 +65,15 @@ impl<'a> DBTransaction<'a> for OptimisticTransactionDBTransaction<'a> {}
 
 /// RocksDB column families
 pub fn column_families() -> Vec<ColumnFamilyDescriptor> {
-    vec![
-        ColumnFamilyDescriptor::new(AUX_CF_NAME, default_db_opts()),
-        ColumnFamilyDescriptor::new(ROOTS_CF_NAME, default_db_opts()),
-        ColumnFamilyDescriptor::new(META_CF_NAME, default_db_opts()),
-    ]
+    RocksDbConfig::default().column_families(None)
 }
 
 /// Create RocksDB with default settings
 pub fn default_rocksdb(path: &Path) -> Rc<rocksdb::OptimisticTransactionDB> {
     Rc::new(
-        rocksdb::OptimisticTransactionDB::open_cf_descriptors(
-            &default_db_opts(),
-            &path,
-            column_families(),
-        )
-        .expect("cannot create rocksdb"),
+        RocksDbConfig::default()
+            .open(path)
+            .expect("cannot create rocksdb"),
     )
 }
 
@@ -61,6 +82,32 @@ fn make_prefixed_key<K: AsRef<[u8]>>(mut prefix: Vec<u8>, key: K) -> Vec<u8> {
     prefix
 }
 
+/// The smallest key greater than all keys starting with `prefix`, `None` if
+/// there is no such key
+pub(crate) fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper_bound = prefix.to_vec();
+    while let Some(last) = upper_bound.pop() {
+        if last < u8::MAX {
+            upper_bound.push(last + 1);
+            return Some(upper_bound);
+        }
+    }
+    None
+}
+
+/// Read options of an iterator which never leaves `prefix`
+fn prefix_read_opts(prefix: &[u8]) -> ReadOptions {
+    let mut opts = ReadOptions::default();
+    opts.set_iterate_lower_bound(prefix);
+    if let Some(upper_bound) = prefix_upper_bound(prefix) {
+        opts.set_iterate_upper_bound(upper_bound);
+    }
+    // Prefix bloom filters are usable only if the extractor takes exactly the
+    // storage prefix, otherwise it would cut prefixes of different subtrees
+    opts.set_total_order_seek(prefix.len() != PREFIX_LEN);
+    opts
+}
+
 // There is no public API to abstract over raw iterators yet
 enum RawIteratorVariant<'a> {
     StorageIterator(DBRawIteratorWithThreadMode<'a, OptimisticTransactionDB>),
@@ -72,6 +119,51 @@ enum RawIteratorVariant<'a> {
 pub struct RawPrefixedTransactionalIterator<'a> {
     rocksdb_iterator: RawIteratorVariant<'a>,
     prefix: &'a [u8],
+    // Changes of a shared batch not written yet, they're merged into the
+    // iteration
+    pending: Option<PendingChanges>,
+}
+
+struct PendingChanges {
+    // Sorted by keys with the prefix stripped, `None` marks a deleted key
+    changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    // Indices of changes which put values, in the same order
+    values: Vec<usize>,
+    current: Option<(Vec<u8>, Vec<u8>)>,
+    // Whether the current entry is a pending one rather than a stored one
+    current_pending: bool,
+    // Number of pending values with keys before the current entry, so
+    // stepping from it doesn't search them again
+    cursor: usize,
+}
+
+impl PendingChanges {
+    fn new(changes: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Self {
+        let values = changes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, v))| v.as_ref().map(|_| i))
+            .collect();
+        PendingChanges {
+            changes,
+            values,
+            current: None,
+            current_pending: false,
+            cursor: 0,
+        }
+    }
+
+    /// Number of pending values with keys for which `before` holds, the
+    /// keys are expected to be partitioned by it
+    fn values_before(&self, before: impl Fn(&[u8]) -> bool) -> usize {
+        self.values
+            .partition_point(|&i| before(self.changes[i].0.as_slice()))
+    }
+
+    fn value(&self, position: usize) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (key, value) = &self.changes[*self.values.get(position)?];
+        Some((key.clone(), value.clone()?))
+    }
 }
 
 macro_rules! iterator_call {
@@ -91,61 +183,261 @@ macro_rules! iterator_call {
     }
 }
 
-impl RawIterator for RawPrefixedTransactionalIterator<'_> {
-    fn seek_to_first(&mut self) {
+impl<'a> RawPrefixedTransactionalIterator<'a> {
+    fn new(
+        rocksdb_iterator: RawIteratorVariant<'a>,
+        prefix: &'a [u8],
+        pending_changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Self {
+        RawPrefixedTransactionalIterator {
+            rocksdb_iterator,
+            prefix,
+            pending: (!pending_changes.is_empty()).then(|| PendingChanges::new(pending_changes)),
+        }
+    }
+
+    // The underlying iterator is bounded by the prefix, but uncommitted changes
+    // of a transaction are merged into it ignoring the lower bound and
+    // checking the upper one only once stored entries are exhausted, so
+    // positioning is done with explicit seeks and validity is checked against
+    // the prefix as well
+
+    fn stored_seek_to_first(&mut self) {
         iterator_call!(mut self, seek(self.prefix));
     }
 
-    fn seek_to_last(&mut self) {
-        let mut prefix_vec = self.prefix.to_vec();
-        for i in (0..prefix_vec.len()).rev() {
-            prefix_vec[i] += 1;
-            if prefix_vec[i] != 0 {
-                // if it is == 0 then we need to go to next bit
-                break;
+    fn stored_seek_to_last(&mut self) {
+        match prefix_upper_bound(self.prefix) {
+            Some(upper_bound) => {
+                iterator_call!(mut self, seek_for_prev(&upper_bound));
+                if iterator_call!(self, key()) == Some(upper_bound.as_slice()) {
+                    iterator_call!(mut self, prev());
+                }
             }
+            None => iterator_call!(mut self, seek_to_last()),
         }
-        iterator_call!(mut self, seek_for_prev(prefix_vec));
     }
 
-    fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+    fn stored_seek<K: AsRef<[u8]>>(&mut self, key: K) {
         iterator_call!(mut self, seek(make_prefixed_key(self.prefix.to_vec(), key)));
     }
 
-    fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+    fn stored_seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
         iterator_call!(mut self, seek_for_prev(make_prefixed_key(self.prefix.to_vec(), key)));
     }
 
-    fn next(&mut self) {
-        iterator_call!(mut self, next());
-    }
-
-    fn prev(&mut self) {
-        iterator_call!(mut self, prev());
-    }
-
-    fn value(&self) -> Option<&[u8]> {
-        if self.valid() {
+    fn stored_value(&self) -> Option<&[u8]> {
+        if self.stored_valid() {
             iterator_call!(self, value())
         } else {
             None
         }
     }
 
-    fn key(&self) -> Option<&[u8]> {
-        if self.valid() {
+    fn stored_key(&self) -> Option<&[u8]> {
+        if self.stored_valid() {
             iterator_call!(self, key().map(|k| k.split_at(self.prefix.len()).1))
         } else {
             None
         }
     }
 
-    fn valid(&self) -> bool {
+    fn stored_valid(&self) -> bool {
         iterator_call!(
             self,
             key().map(|k| k.starts_with(self.prefix)).unwrap_or(false)
         )
     }
+
+    /// Returns the first stored entry after the iterator position going
+    /// forward or backward, skipping entries overridden by pending changes and
+    /// the `excluded` key
+    fn stored_entry(
+        &mut self,
+        changes: &[(Vec<u8>, Option<Vec<u8>>)],
+        excluded: Option<&[u8]>,
+        forward: bool,
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        loop {
+            let key = self.stored_key()?;
+            if Some(key) != excluded
+                && changes
+                    .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+                    .is_err()
+            {
+                return Some((key.to_vec(), self.stored_value()?.to_vec()));
+            }
+            if forward {
+                iterator_call!(mut self, next());
+            } else {
+                iterator_call!(mut self, prev());
+            }
+        }
+    }
+
+    /// Positions the iterator on the first entry of the merged view which is
+    /// after (or equal to, if `inclusive`) `bound` going forward
+    fn merged_seek(&mut self, bound: Option<&[u8]>, inclusive: bool) {
+        let pending = self
+            .pending
+            .as_ref()
+            .expect("merging requires pending changes");
+        let first = match bound {
+            Some(bound) if inclusive => pending.values_before(|k| k < bound),
+            Some(bound) => pending.values_before(|k| k <= bound),
+            None => 0,
+        };
+        self.merged_seek_from(bound, inclusive, first);
+    }
+
+    /// Does `merged_seek` with the first pending value after `bound` known
+    /// to be at `first`
+    fn merged_seek_from(&mut self, bound: Option<&[u8]>, inclusive: bool, first: usize) {
+        let mut pending = self
+            .pending
+            .take()
+            .expect("merging requires pending changes");
+        match bound {
+            Some(key) => self.stored_seek(key),
+            None => self.stored_seek_to_first(),
+        }
+        let excluded = bound.filter(|_| !inclusive);
+        let stored = self.stored_entry(&pending.changes, excluded, true);
+        let (current, current_pending) = match (stored, pending.value(first)) {
+            (Some(s), Some(p)) if s.0 > p.0 => (Some(p), true),
+            (None, Some(p)) => (Some(p), true),
+            (s, _) => (s, false),
+        };
+        pending.current = current;
+        pending.current_pending = current_pending;
+        pending.cursor = first;
+        self.pending = Some(pending);
+    }
+
+    /// Positions the iterator on the first entry of the merged view which is
+    /// before (or equal to, if `inclusive`) `bound` going backward
+    fn merged_seek_for_prev(&mut self, bound: Option<&[u8]>, inclusive: bool) {
+        let pending = self
+            .pending
+            .as_ref()
+            .expect("merging requires pending changes");
+        let after = match bound {
+            Some(bound) if inclusive => pending.values_before(|k| k <= bound),
+            Some(bound) => pending.values_before(|k| k < bound),
+            None => pending.values.len(),
+        };
+        self.merged_seek_for_prev_from(bound, inclusive, after);
+    }
+
+    /// Does `merged_seek_for_prev` with pending values before `bound` known
+    /// to end at `after`
+    fn merged_seek_for_prev_from(&mut self, bound: Option<&[u8]>, inclusive: bool, after: usize) {
+        let mut pending = self
+            .pending
+            .take()
+            .expect("merging requires pending changes");
+        match bound {
+            Some(key) => self.stored_seek_for_prev(key),
+            None => self.stored_seek_to_last(),
+        }
+        let excluded = bound.filter(|_| !inclusive);
+        let stored = self.stored_entry(&pending.changes, excluded, false);
+        let last = after.checked_sub(1).and_then(|last| pending.value(last));
+        let (current, current_pending) = match (stored, last) {
+            (Some(s), Some(p)) if s.0 < p.0 => (Some(p), true),
+            (None, Some(p)) => (Some(p), true),
+            (s, _) => (s, false),
+        };
+        pending.cursor = if current_pending { after - 1 } else { after };
+        pending.current = current;
+        pending.current_pending = current_pending;
+        self.pending = Some(pending);
+    }
+
+    fn current_key(&self) -> Option<Vec<u8>> {
+        self.pending
+            .as_ref()
+            .and_then(|p| p.current.as_ref())
+            .map(|(k, _)| k.clone())
+    }
+}
+
+impl RawIterator for RawPrefixedTransactionalIterator<'_> {
+    fn seek_to_first(&mut self) {
+        if self.pending.is_some() {
+            self.merged_seek(None, true);
+        } else {
+            self.stored_seek_to_first();
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        if self.pending.is_some() {
+            self.merged_seek_for_prev(None, true);
+        } else {
+            self.stored_seek_to_last();
+        }
+    }
+
+    fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        if self.pending.is_some() {
+            self.merged_seek(Some(key.as_ref()), true);
+        } else {
+            self.stored_seek(key);
+        }
+    }
+
+    fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+        if self.pending.is_some() {
+            self.merged_seek_for_prev(Some(key.as_ref()), true);
+        } else {
+            self.stored_seek_for_prev(key);
+        }
+    }
+
+    fn next(&mut self) {
+        if self.pending.is_some() {
+            if let Some(key) = self.current_key() {
+                let pending = self.pending.as_ref().expect("checked above");
+                let first = pending.cursor + usize::from(pending.current_pending);
+                self.merged_seek_from(Some(&key), false, first);
+            }
+        } else {
+            iterator_call!(mut self, next());
+        }
+    }
+
+    fn prev(&mut self) {
+        if self.pending.is_some() {
+            if let Some(key) = self.current_key() {
+                let after = self.pending.as_ref().expect("checked above").cursor;
+                self.merged_seek_for_prev_from(Some(&key), false, after);
+            }
+        } else {
+            iterator_call!(mut self, prev());
+        }
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        match &self.pending {
+            Some(pending) => pending.current.as_ref().map(|(_, v)| v.as_slice()),
+            None => self.stored_value(),
+        }
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        match &self.pending {
+            Some(pending) => pending.current.as_ref().map(|(k, _)| k.as_slice()),
+            None => self.stored_key(),
+        }
+    }
+
+    fn valid(&self) -> bool {
+        match &self.pending {
+            Some(pending) => pending.current.is_some(),
+            None => self.stored_valid(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -155,7 +447,7 @@ mod tests {
     use tempdir::TempDir;
 
     use super::*;
-    use crate::{Batch, Storage, Transaction};
+    use crate::{Batch, PrefixedStorage, Storage, Transaction};
 
     struct TempPrefixedStorage {
         storage: PrefixedRocksDbStorage,
@@ -184,6 +476,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_config() {
+        let tmp_dir = TempDir::new("test_config").expect("unable to open a tempdir");
+        let config = RocksDbConfig::default()
+            .parallelism(2)
+            .allow_mmap_writes(false)
+            .max_open_files(64)
+            .block_cache_size(1 << 20)
+            .compression(Column::Default, DBCompressionType::Lz4)
+            .compression(Column::Meta, DBCompressionType::None)
+            .write_buffer_size(Column::Aux, 1 << 20)
+            .db_write_buffer_size(8 << 20)
+            .prefix_extractor(false)
+            .bloom_filter_bits_per_key(Some(16.0))
+            .max_total_wal_size(16 << 20)
+            .wal_recovery_mode(DBRecoveryMode::PointInTime);
+        {
+            let db = config.open(tmp_dir.path()).expect("cannot open rocksdb");
+            let storage = PrefixedRocksDbStorage::new(Rc::new(db), b"pref".to_vec())
+                .expect("cannot create a prefixed storage");
+            storage.put(b"key", b"value").expect("cannot put");
+            storage.put_aux(b"key", b"aux").expect("cannot put");
+            storage.put_meta(b"key", b"meta").expect("cannot put");
+        }
+
+        // Data is readable with other settings
+        let storage =
+            PrefixedRocksDbStorage::new(default_rocksdb(tmp_dir.path()), b"pref".to_vec())
+                .expect("cannot create a prefixed storage");
+        assert_eq!(storage.get(b"key").unwrap().unwrap(), b"value");
+        assert_eq!(storage.get_aux(b"key").unwrap().unwrap(), b"aux");
+        assert_eq!(storage.get_meta(b"key").unwrap().unwrap(), b"meta");
+    }
+
     #[test]
     fn test_get_put() {
         let storage = TempPrefixedStorage::new();
@@ -340,6 +666,160 @@ mod tests {
             .is_none());
     }
 
+    #[test]
+    fn test_get_many() {
+        let storage = TempPrefixedStorage::new();
+        storage.put(b"key0", b"value0").expect("cannot put");
+        storage.put(b"key1", b"value1").expect("cannot put");
+        let other_storage = storage
+            .with_prefix(b"other".to_vec())
+            .expect("cannot create a prefixed storage");
+        other_storage.put(b"key2", b"other").expect("cannot put");
+
+        let keys = [b"key0", b"key1", b"key2", b"key3"];
+        assert_eq!(
+            storage.get_many(&keys).expect("cannot get many"),
+            vec![
+                Some(b"value0".to_vec()),
+                Some(b"value1".to_vec()),
+                None,
+                None
+            ]
+        );
+
+        let batched_storage = storage.with_shared_batch();
+        batched_storage.delete(b"key0").expect("cannot delete");
+        batched_storage.put(b"key2", b"value2").expect("cannot put");
+        assert_eq!(
+            batched_storage.get_many(&keys).expect("cannot get many"),
+            vec![
+                None,
+                Some(b"value1".to_vec()),
+                Some(b"value2".to_vec()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_logical_size() {
+        let storage = TempPrefixedStorage::new();
+        let first = storage
+            .with_prefix(b"first".to_vec())
+            .expect("cannot create a prefixed storage");
+        let second = storage
+            .with_prefix(b"second".to_vec())
+            .expect("cannot create a prefixed storage");
+        assert_eq!(first.logical_size().expect("cannot get size"), 0);
+
+        first.put(b"key", b"data").expect("cannot put");
+        first.put_aux(b"key", b"aux").expect("cannot put aux");
+        first.put_root(b"key", b"root").expect("cannot put root");
+        first.put_meta(b"key", b"meta").expect("cannot put meta");
+        second.put(b"key", b"other").expect("cannot put");
+
+        // Prefixed keys and values of data, aux and roots, metadata is not
+        // prefixed and is not accounted
+        assert_eq!(
+            first.logical_size().expect("cannot get size"),
+            3 * b"firstkey".len() as u64 + (b"data".len() + b"aux".len() + b"root".len()) as u64
+        );
+        assert_eq!(
+            second.logical_size().expect("cannot get size"),
+            (b"secondkey".len() + b"other".len()) as u64
+        );
+    }
+
+    #[test]
+    fn test_stored_prefixes_and_clear() {
+        let temp_storage = TempPrefixedStorage::new();
+        let storage = temp_storage
+            .with_prefix(Vec::new())
+            .expect("cannot create a prefixed storage");
+        let first = storage
+            .with_prefix(b"first".to_vec())
+            .expect("cannot create a prefixed storage");
+        let second = storage
+            .with_prefix(b"secnd".to_vec())
+            .expect("cannot create a prefixed storage");
+        first.put(b"key1", b"value").expect("cannot put");
+        first.put(b"key2", b"value").expect("cannot put");
+        first.put_aux(b"key", b"value").expect("cannot put aux");
+        second.put_root(b"key", b"value").expect("cannot put root");
+        second.put_meta(b"key", b"value").expect("cannot put meta");
+        storage.put_aux(b"aux", b"value").expect("cannot put aux");
+        storage.put(b"key", b"short").expect("cannot put");
+
+        // Auxiliary data and keys shorter than the prefix length are skipped
+        assert_eq!(
+            storage.stored_prefixes(5).expect("cannot get prefixes"),
+            vec![b"first".to_vec(), b"secnd".to_vec()]
+        );
+        assert_eq!(
+            first.stored_prefixes(3).expect("cannot get prefixes"),
+            vec![b"key".to_vec()]
+        );
+
+        first.clear().expect("cannot clear");
+        assert!(first.get(b"key1").expect("cannot get").is_none());
+        assert!(first.get_aux(b"key").expect("cannot get aux").is_none());
+        assert_eq!(
+            storage.stored_prefixes(5).expect("cannot get prefixes"),
+            vec![b"secnd".to_vec()]
+        );
+        second.clear().expect("cannot clear");
+        assert!(second.get_root(b"key").expect("cannot get root").is_none());
+        // Metadata is not prefixed and stays
+        assert!(second.get_meta(b"key").expect("cannot get meta").is_some());
+        assert_eq!(
+            storage.get(b"key").expect("cannot get"),
+            Some(b"short".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_clear_with_shared_batch() {
+        let storage = TempPrefixedStorage::new();
+        storage.put(b"committed", b"value").expect("cannot put");
+        let batched_storage = storage.with_shared_batch();
+        batched_storage
+            .put(b"pending", b"value")
+            .expect("cannot put");
+        batched_storage
+            .put_root(b"root", b"value")
+            .expect("cannot put root");
+        let other = batched_storage
+            .with_prefix(b"other".to_vec())
+            .expect("cannot create a prefixed storage");
+        other.put(b"key", b"value").expect("cannot put");
+
+        batched_storage.clear().expect("cannot clear");
+        assert!(batched_storage
+            .get(b"pending")
+            .expect("cannot get")
+            .is_none());
+        assert!(batched_storage
+            .get(b"committed")
+            .expect("cannot get")
+            .is_none());
+        // Nothing is deleted until the batch is written
+        assert!(storage.get(b"committed").expect("cannot get").is_some());
+
+        batched_storage
+            .commit_shared_batch()
+            .expect("cannot commit shared batch");
+        assert!(storage.get(b"committed").expect("cannot get").is_none());
+        assert!(storage.get(b"pending").expect("cannot get").is_none());
+        assert!(storage
+            .get_root(b"root")
+            .expect("cannot get root")
+            .is_none());
+        assert_eq!(
+            other.get(b"key").expect("cannot get"),
+            Some(b"value".to_vec())
+        );
+    }
+
     #[test]
     fn test_batch() {
         let storage = TempPrefixedStorage::new();
@@ -377,6 +857,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_batch_meta() {
+        let storage = TempPrefixedStorage::new();
+        storage.put_meta(b"old", b"value").expect("cannot put meta");
+        let other_storage = storage
+            .with_prefix(b"other".to_vec())
+            .expect("cannot create a prefixed storage");
+
+        let mut batch = storage.new_batch(None).expect("cannot create batch");
+        batch
+            .put(b"key1", b"value1")
+            .expect("cannot put into batch");
+        batch
+            .put_meta(b"meta", b"yeet")
+            .expect("cannot put into batch");
+        batch.delete_meta(b"old").expect("cannot delete in batch");
+        assert!(storage.get_meta(b"meta").unwrap().is_none());
+        storage.commit_batch(batch).expect("cannot commit batch");
+
+        // Metadata is not prefixed, so it's the same for all storages
+        assert_eq!(other_storage.get_meta(b"meta").unwrap().unwrap(), b"yeet");
+        assert!(other_storage.get_meta(b"old").unwrap().is_none());
+        assert!(other_storage.get(b"key1").unwrap().is_none());
+
+        let db = storage.db();
+        let db_transaction = db.transaction();
+        let mut batch = other_storage
+            .new_batch(Some(&db_transaction))
+            .expect("cannot create batch");
+        batch
+            .put_meta(b"meta", b"transactional")
+            .expect("cannot put into batch");
+        other_storage
+            .commit_batch(batch)
+            .expect("cannot commit batch");
+        assert_eq!(storage.get_meta(b"meta").unwrap().unwrap(), b"yeet");
+        db_transaction.commit().expect("cannot commit transaction");
+        assert_eq!(
+            storage.get_meta(b"meta").unwrap().unwrap(),
+            b"transactional"
+        );
+    }
+
     #[test]
     fn test_raw_iterator() {
         let tmp_dir = TempDir::new("test_raw_iterator").expect("unable to open a tempdir");
@@ -460,6 +983,105 @@ mod tests {
         assert!(!iter.valid());
     }
 
+    #[test]
+    fn test_raw_iterator_prefix_bounds() {
+        let tmp_dir = TempDir::new("test_raw_iterator").expect("unable to open a tempdir");
+        let db = default_rocksdb(tmp_dir.path());
+
+        // Subtree prefixes which need a carry to get the next one
+        let mut prefix = vec![7; PREFIX_LEN];
+        prefix[PREFIX_LEN - 1] = u8::MAX;
+        let mut previous_prefix = prefix.clone();
+        previous_prefix[PREFIX_LEN - 1] -= 1;
+        let mut next_prefix = vec![7; PREFIX_LEN];
+        next_prefix[PREFIX_LEN - 2] = 8;
+        next_prefix[PREFIX_LEN - 1] = 0;
+        let last_prefix = vec![u8::MAX; PREFIX_LEN];
+
+        let storage = |prefix: &[u8]| {
+            PrefixedRocksDbStorage::new(db.clone(), prefix.to_vec())
+                .expect("cannot create a prefixed storage")
+        };
+        for neighbour in [&previous_prefix, &next_prefix] {
+            let neighbour = storage(neighbour);
+            neighbour.put(b"key0", b"neighbour").expect("cannot put");
+            neighbour.put(b"key3", b"neighbour").expect("cannot put");
+        }
+        for prefix in [&prefix, &last_prefix] {
+            let storage = storage(prefix);
+            storage.put(b"key1", b"value1").expect("cannot put");
+            storage.put(b"key2", b"value2").expect("cannot put");
+        }
+        // Bloom filters are used for data in SST files only
+        storage(&prefix).flush().expect("cannot flush");
+
+        for prefix in [&prefix, &last_prefix] {
+            let storage = storage(prefix);
+            let mut iter = storage.raw_iter(None);
+            iter.seek_to_first();
+            assert_eq!(iter.key(), Some(b"key1".as_ref()));
+            iter.next();
+            assert_eq!(iter.key(), Some(b"key2".as_ref()));
+            iter.next();
+            assert!(!iter.valid());
+
+            iter.seek_to_last();
+            assert_eq!(iter.key(), Some(b"key2".as_ref()));
+            iter.prev();
+            assert_eq!(iter.key(), Some(b"key1".as_ref()));
+            iter.prev();
+            assert!(!iter.valid());
+
+            iter.seek(b"key3");
+            assert!(!iter.valid());
+            iter.seek_for_prev(b"key0");
+            assert!(!iter.valid());
+            iter.seek_for_prev(b"key9");
+            assert_eq!(iter.key(), Some(b"key2".as_ref()));
+        }
+
+        // Uncommitted changes of neighbours must not leak either
+        let db_transaction = db.transaction();
+        for neighbour in [&previous_prefix, &next_prefix] {
+            let neighbour = storage(neighbour);
+            let transaction = neighbour.transaction(&db_transaction);
+            transaction.put(b"key1", b"neighbour").expect("cannot put");
+            transaction.put(b"key5", b"neighbour").expect("cannot put");
+        }
+        let prefixed_storage = storage(&prefix);
+        prefixed_storage
+            .transaction(&db_transaction)
+            .put(b"key4", b"value4")
+            .expect("cannot put");
+        let mut iter = prefixed_storage.raw_iter(Some(&db_transaction));
+        iter.seek_to_first();
+        assert_eq!(iter.key(), Some(b"key1".as_ref()));
+        iter.seek_to_last();
+        assert_eq!(iter.key(), Some(b"key4".as_ref()));
+        iter.next();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        iter.prev();
+        iter.prev();
+        assert_eq!(iter.key(), Some(b"key1".as_ref()));
+        iter.prev();
+        assert!(!iter.valid());
+        drop(iter);
+        drop(prefixed_storage);
+        drop(db_transaction);
+
+        // Storage without a prefix sees everything
+        let storage = storage(&[]);
+        let mut iter = storage.raw_iter(None);
+        let mut count = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            count += 1;
+            iter.next();
+        }
+        assert_eq!(count, 8);
+    }
+
     #[test]
     fn test_raw_iterator_with_transaction() {
         let tmp_dir = TempDir::new("test_raw_iterator").expect("unable to open a tempdir");
@@ -548,4 +1170,158 @@ mod tests {
         }
         assert!(expected_iter.next().is_none());
     }
+
+    #[test]
+    fn test_shared_batch() {
+        let tmp_dir = TempDir::new("test_shared_batch").expect("unable to open a tempdir");
+        let db = default_rocksdb(tmp_dir.path());
+
+        let storage = PrefixedRocksDbStorage::new(db.clone(), b"someprefix".to_vec())
+            .expect("cannot create a prefixed storage");
+        storage
+            .put(b"key0", b"value0")
+            .expect("expected successful insertion");
+        storage
+            .put(b"key2", b"value2")
+            .expect("expected successful insertion");
+        storage
+            .put(b"key4", b"value4")
+            .expect("expected successful insertion");
+
+        let batched_storage = storage.with_shared_batch();
+        let another_batched_storage = batched_storage
+            .with_prefix(b"anotherprefix".to_vec())
+            .expect("cannot create a prefixed storage");
+        batched_storage
+            .put(b"key1", b"value1")
+            .expect("expected successful insertion");
+        batched_storage
+            .delete(b"key2")
+            .expect("expected successful deletion");
+        batched_storage
+            .put(b"key4", b"value4new")
+            .expect("expected successful insertion");
+        batched_storage
+            .put_meta(b"meta", b"value")
+            .expect("expected successful insertion");
+        let mut batch = another_batched_storage
+            .new_batch(None)
+            .expect("cannot create batch");
+        batch
+            .put_root(b"root", b"yeet")
+            .expect("cannot put into batch");
+        another_batched_storage
+            .commit_batch(batch)
+            .expect("cannot commit batch");
+
+        // Changes are visible through the batched storages only
+        assert_eq!(batched_storage.get(b"key1").unwrap().unwrap(), b"value1");
+        assert!(batched_storage.get(b"key2").unwrap().is_none());
+        assert_eq!(
+            another_batched_storage.get_root(b"root").unwrap().unwrap(),
+            b"yeet"
+        );
+        assert!(storage.get(b"key1").unwrap().is_none());
+        assert!(storage.get_meta(b"meta").unwrap().is_none());
+
+        let expected: [(&'static [u8], &'static [u8]); 3] = [
+            (b"key0", b"value0"),
+            (b"key1", b"value1"),
+            (b"key4", b"value4new"),
+        ];
+        let mut expected_iter = expected.into_iter();
+        let mut iter = batched_storage.raw_iter(None);
+        iter.seek_to_first();
+        while iter.valid() {
+            assert_eq!(
+                (iter.key().unwrap(), iter.value().unwrap()),
+                expected_iter.next().unwrap()
+            );
+            iter.next();
+        }
+        assert!(expected_iter.next().is_none());
+
+        iter.seek_to_last();
+        assert_eq!(iter.key().unwrap(), b"key4");
+        iter.prev();
+        assert_eq!(iter.key().unwrap(), b"key1");
+        iter.seek_for_prev(b"key3");
+        assert_eq!(iter.key().unwrap(), b"key1");
+        iter.seek(b"key2");
+        assert_eq!(iter.key().unwrap(), b"key4");
+        iter.prev();
+        iter.prev();
+        iter.prev();
+        assert!(!iter.valid());
+        drop(iter);
+
+        another_batched_storage
+            .commit_shared_batch()
+            .expect("cannot commit shared batch");
+        assert_eq!(storage.get(b"key1").unwrap().unwrap(), b"value1");
+        assert!(storage.get(b"key2").unwrap().is_none());
+        assert_eq!(storage.get(b"key4").unwrap().unwrap(), b"value4new");
+        assert_eq!(storage.get_meta(b"meta").unwrap().unwrap(), b"value");
+        assert_eq!(
+            PrefixedRocksDbStorage::new(db, b"anotherprefix".to_vec())
+                .unwrap()
+                .get_root(b"root")
+                .unwrap()
+                .unwrap(),
+            b"yeet"
+        );
+    }
+
+    #[test]
+    fn test_shared_batch_iteration_steps() {
+        let storage = TempPrefixedStorage::new();
+        let mut expected = std::collections::BTreeMap::new();
+        for i in (0u8..40).step_by(2) {
+            storage.put([i], &[i]).expect("cannot put");
+            expected.insert(vec![i], vec![i]);
+        }
+        let batched_storage = storage.with_shared_batch();
+        for i in 0u8..40 {
+            if i % 5 == 0 {
+                batched_storage.delete([i]).expect("cannot delete");
+                expected.remove(&vec![i]);
+            } else if i % 3 == 0 {
+                batched_storage.put([i], &[i, i]).expect("cannot put");
+                expected.insert(vec![i], vec![i, i]);
+            }
+        }
+        let expected: Vec<_> = expected.into_iter().collect();
+        let entry = |iter: &RawPrefixedTransactionalIterator| {
+            (iter.key().unwrap().to_vec(), iter.value().unwrap().to_vec())
+        };
+
+        let mut iter = batched_storage.raw_iter(None);
+        iter.seek_to_first();
+        for expected_entry in &expected {
+            assert_eq!(&entry(&iter), expected_entry);
+            iter.next();
+        }
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        for expected_entry in expected.iter().rev() {
+            assert_eq!(&entry(&iter), expected_entry);
+            iter.prev();
+        }
+        assert!(!iter.valid());
+
+        // Changing directions in the middle
+        iter.seek([7]);
+        let mut position = expected.iter().position(|(k, _)| k[0] >= 7).unwrap();
+        for forward in [true, true, false, true, false, false, false, true] {
+            assert_eq!(entry(&iter), expected[position]);
+            if forward {
+                iter.next();
+                position += 1;
+            } else {
+                iter.prev();
+                position -= 1;
+            }
+        }
+        assert_eq!(entry(&iter), expected[position]);
+    }
 }
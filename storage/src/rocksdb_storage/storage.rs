@@ -1,17 +1,18 @@
-use std::rc::Rc;
+use std::{collections::BTreeSet, rc::Rc};
 
-use rocksdb::WriteBatchWithTransaction;
+use rocksdb::{DBRawIteratorWithThreadMode, WriteBatchWithTransaction};
 
 use super::{
-    make_prefixed_key, PrefixedRocksDbBatch, PrefixedRocksDbTransaction, RawIteratorVariant,
-    RawPrefixedTransactionalIterator, AUX_CF_NAME, META_CF_NAME, ROOTS_CF_NAME,
+    make_prefixed_key, prefix_read_opts, prefix_upper_bound, Column, PrefixedRocksDbBatch,
+    PrefixedRocksDbTransaction, PrefixedSharedRocksDbBatch, RawIteratorVariant,
+    RawPrefixedTransactionalIterator, SharedRocksDbBatch, AUX_CF_NAME, META_CF_NAME, ROOTS_CF_NAME,
 };
 use crate::{
     rocksdb_storage::{
         batch::{OrBatch, PrefixedTransactionalRocksDbBatch},
         OptimisticTransactionDBTransaction,
     },
-    Storage,
+    Batch, PrefixedStorage, Storage,
 };
 
 /// RocksDB wrapper to store items with prefixes
@@ -19,6 +20,7 @@ use crate::{
 pub struct PrefixedRocksDbStorage {
     pub(crate) db: Rc<rocksdb::OptimisticTransactionDB>,
     prefix: Vec<u8>,
+    shared_batch: Option<Rc<SharedRocksDbBatch>>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -35,7 +37,26 @@ impl PrefixedRocksDbStorage {
         db: Rc<rocksdb::OptimisticTransactionDB>,
         prefix: Vec<u8>,
     ) -> Result<Self, PrefixedRocksDbStorageError> {
-        Ok(Self { prefix, db })
+        Ok(Self {
+            prefix,
+            db,
+            shared_batch: None,
+        })
+    }
+
+    /// Get iterator over committed entries under the prefix of a column
+    /// family, `None` stands for the data column family
+    fn committed_iter(
+        &self,
+        cf: Option<&rocksdb::ColumnFamily>,
+    ) -> DBRawIteratorWithThreadMode<'_, rocksdb::OptimisticTransactionDB> {
+        let opts = prefix_read_opts(&self.prefix);
+        let mut iter = match cf {
+            Some(cf) => self.db.raw_iterator_cf_opt(cf, opts),
+            None => self.db.raw_iterator_opt(opts),
+        };
+        iter.seek(&self.prefix);
+        iter
     }
 
     /// Get auxiliary data column family
@@ -74,12 +95,28 @@ impl Storage for PrefixedRocksDbStorage {
     type StorageTransaction<'a> = PrefixedRocksDbTransaction<'a>;
 
     fn put<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            shared_batch.put(
+                Column::Default,
+                make_prefixed_key(self.prefix.clone(), &key),
+                value,
+            );
+            return Ok(());
+        }
         self.db
             .put(make_prefixed_key(self.prefix.clone(), key), value)?;
         Ok(())
     }
 
     fn put_aux<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            shared_batch.put(
+                Column::Aux,
+                make_prefixed_key(self.prefix.clone(), &key),
+                value,
+            );
+            return Ok(());
+        }
         self.db.put_cf(
             self.cf_aux()?,
             make_prefixed_key(self.prefix.clone(), key),
@@ -89,6 +126,14 @@ impl Storage for PrefixedRocksDbStorage {
     }
 
     fn put_root<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            shared_batch.put(
+                Column::Roots,
+                make_prefixed_key(self.prefix.clone(), &key),
+                value,
+            );
+            return Ok(());
+        }
         self.db.put_cf(
             self.cf_roots()?,
             make_prefixed_key(self.prefix.clone(), key),
@@ -98,18 +143,33 @@ impl Storage for PrefixedRocksDbStorage {
     }
 
     fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            shared_batch.delete(
+                Column::Default,
+                make_prefixed_key(self.prefix.clone(), &key),
+            );
+            return Ok(());
+        }
         self.db
             .delete(make_prefixed_key(self.prefix.clone(), key))?;
         Ok(())
     }
 
     fn delete_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            shared_batch.delete(Column::Aux, make_prefixed_key(self.prefix.clone(), &key));
+            return Ok(());
+        }
         self.db
             .delete_cf(self.cf_aux()?, make_prefixed_key(self.prefix.clone(), key))?;
         Ok(())
     }
 
     fn delete_root<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            shared_batch.delete(Column::Roots, make_prefixed_key(self.prefix.clone(), &key));
+            return Ok(());
+        }
         self.db.delete_cf(
             self.cf_roots()?,
             make_prefixed_key(self.prefix.clone(), key),
@@ -118,16 +178,68 @@ impl Storage for PrefixedRocksDbStorage {
     }
 
     fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(change) = self.shared_batch.as_ref().and_then(|b| {
+            b.get(
+                Column::Default,
+                &make_prefixed_key(self.prefix.clone(), &key),
+            )
+        }) {
+            return Ok(change);
+        }
         Ok(self.db.get(make_prefixed_key(self.prefix.clone(), key))?)
     }
 
+    fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let prefixed_keys: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|key| make_prefixed_key(self.prefix.clone(), key))
+            .collect();
+        // Pending changes of a shared batch take precedence, the rest is read
+        // from RocksDB at once
+        let mut values: Vec<Option<Option<Vec<u8>>>> = prefixed_keys
+            .iter()
+            .map(|key| {
+                self.shared_batch
+                    .as_ref()
+                    .and_then(|b| b.get(Column::Default, key))
+            })
+            .collect();
+        let stored_keys = prefixed_keys
+            .iter()
+            .zip(values.iter())
+            .filter_map(|(key, value)| value.is_none().then_some(key));
+        let mut stored_values = self.db.multi_get(stored_keys).into_iter();
+        for value in values.iter_mut().filter(|value| value.is_none()) {
+            *value = Some(
+                stored_values
+                    .next()
+                    .expect("a value is returned for each key")?,
+            );
+        }
+        Ok(values.into_iter().flatten().collect())
+    }
+
     fn get_aux<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(change) = self
+            .shared_batch
+            .as_ref()
+            .and_then(|b| b.get(Column::Aux, &make_prefixed_key(self.prefix.clone(), &key)))
+        {
+            return Ok(change);
+        }
         Ok(self
             .db
             .get_cf(self.cf_aux()?, make_prefixed_key(self.prefix.clone(), key))?)
     }
 
     fn get_root<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(change) = self
+            .shared_batch
+            .as_ref()
+            .and_then(|b| b.get(Column::Roots, &make_prefixed_key(self.prefix.clone(), &key)))
+        {
+            return Ok(change);
+        }
         Ok(self.db.get_cf(
             self.cf_roots()?,
             make_prefixed_key(self.prefix.clone(), key),
@@ -135,14 +247,29 @@ impl Storage for PrefixedRocksDbStorage {
     }
 
     fn put_meta<K: AsRef<[u8]>>(&self, key: K, value: &[u8]) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            shared_batch.put(Column::Meta, key.as_ref().to_vec(), value);
+            return Ok(());
+        }
         Ok(self.db.put_cf(self.cf_meta()?, key, value)?)
     }
 
     fn delete_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Self::Error> {
+        if let Some(shared_batch) = &self.shared_batch {
+            shared_batch.delete(Column::Meta, key.as_ref().to_vec());
+            return Ok(());
+        }
         Ok(self.db.delete_cf(self.cf_meta()?, key)?)
     }
 
     fn get_meta<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(change) = self
+            .shared_batch
+            .as_ref()
+            .and_then(|b| b.get(Column::Meta, key.as_ref()))
+        {
+            return Ok(change);
+        }
         Ok(self.db.get_cf(self.cf_meta()?, key)?)
     }
 
@@ -157,22 +284,30 @@ impl Storage for PrefixedRocksDbStorage {
                     transaction: tx,
                     cf_aux: self.cf_aux()?,
                     cf_roots: self.cf_roots()?,
+                    cf_meta: self.cf_meta()?,
                 },
             )),
-            None => Ok(OrBatch::Batch(PrefixedRocksDbBatch {
-                prefix: self.prefix.clone(),
-                batch: WriteBatchWithTransaction::<true>::default(),
-                cf_aux: self.cf_aux()?,
-                cf_roots: self.cf_roots()?,
-            })),
+            None => Ok(match &self.shared_batch {
+                Some(shared_batch) => OrBatch::SharedBatch(PrefixedSharedRocksDbBatch {
+                    prefix: self.prefix.clone(),
+                    shared_batch,
+                }),
+                None => OrBatch::Batch(PrefixedRocksDbBatch {
+                    prefix: self.prefix.clone(),
+                    batch: WriteBatchWithTransaction::<true>::default(),
+                    cf_aux: self.cf_aux()?,
+                    cf_roots: self.cf_roots()?,
+                    cf_meta: self.cf_meta()?,
+                }),
+            }),
         }
     }
 
     fn commit_batch<'a>(&'a self, batch: Self::Batch<'a>) -> Result<(), Self::Error> {
         // Do nothing if transaction exists, as the transaction must be explicitly
-        // committed by its creator
+        // committed by its creator, the same goes for a shared batch
         match batch {
-            OrBatch::TransactionalBatch(_) => {}
+            OrBatch::TransactionalBatch(_) | OrBatch::SharedBatch(_) => {}
             OrBatch::Batch(batch) => self.db.write(batch.batch)?,
         }
         Ok(())
@@ -187,13 +322,117 @@ impl Storage for PrefixedRocksDbStorage {
         &'a self,
         db_transaction: Option<&'a OptimisticTransactionDBTransaction>,
     ) -> Self::RawIterator<'a> {
-        let rocksdb_iterator = db_transaction
-            .map(|tx| RawIteratorVariant::TransactionIterator(tx.raw_iterator()))
-            .unwrap_or_else(|| RawIteratorVariant::StorageIterator(self.db.raw_iterator()));
-        RawPrefixedTransactionalIterator {
-            rocksdb_iterator,
-            prefix: &self.prefix,
+        match db_transaction {
+            Some(tx) => RawPrefixedTransactionalIterator::new(
+                RawIteratorVariant::TransactionIterator(
+                    tx.raw_iterator_opt(prefix_read_opts(&self.prefix)),
+                ),
+                &self.prefix,
+                Vec::new(),
+            ),
+            None => RawPrefixedTransactionalIterator::new(
+                RawIteratorVariant::StorageIterator(
+                    self.db.raw_iterator_opt(prefix_read_opts(&self.prefix)),
+                ),
+                &self.prefix,
+                self.shared_batch
+                    .as_ref()
+                    .map(|b| b.pending_data(&self.prefix))
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+
+    fn logical_size(&self) -> Result<u64, Self::Error> {
+        // TODO: use approximate sizes of the `[prefix, prefix + 1)` range of each
+        // column family once the bindings have them. The ones in use have no
+        // `get_approximate_sizes_cf` and don't expose the base DB of the optimistic
+        // transaction DB to call the C API on, so entries are measured one by one
+        let mut size = 0;
+        for cf in [None, Some(self.cf_aux()?), Some(self.cf_roots()?)] {
+            let mut iter = self.committed_iter(cf);
+            while let Some((key, value)) = iter.key().zip(iter.value()) {
+                if !key.starts_with(&self.prefix) {
+                    break;
+                }
+                size += (key.len() + value.len()) as u64;
+                iter.next();
+            }
+            iter.status()?;
+        }
+        Ok(size)
+    }
+
+    fn stored_prefixes(&self, prefix_len: usize) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut prefixes = BTreeSet::new();
+        for cf in [None, Some(self.cf_roots()?)] {
+            let mut iter = self.committed_iter(cf);
+            while let Some(key) = iter.key() {
+                if !key.starts_with(&self.prefix) {
+                    break;
+                }
+                let key = &key[self.prefix.len()..];
+                if key.len() < prefix_len {
+                    iter.next();
+                    continue;
+                }
+                let prefix = key[..prefix_len].to_vec();
+                let upper_bound = prefix_upper_bound(&prefix);
+                prefixes.insert(prefix);
+                // Skip the rest of entries under the found prefix at once
+                match upper_bound {
+                    Some(upper_bound) => {
+                        iter.seek(make_prefixed_key(self.prefix.clone(), upper_bound))
+                    }
+                    None => break,
+                }
+            }
+            iter.status()?;
         }
+        Ok(prefixes.into_iter().collect())
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        let upper_bound = prefix_upper_bound(&self.prefix);
+        let mut batch = self.new_batch(None)?;
+        for column in [Column::Default, Column::Aux, Column::Roots] {
+            let cf = match column {
+                Column::Aux => Some(self.cf_aux()?),
+                Column::Roots => Some(self.cf_roots()?),
+                _ => None,
+            };
+            match (&self.shared_batch, &upper_bound) {
+                // Changes gathered in a shared batch must stay all or nothing, so entries
+                // are deleted one by one there
+                (Some(shared_batch), _) => shared_batch.delete_pending(column, &self.prefix),
+                // Optimistic transaction DB refuses range deletions in any API, written
+                // batches included, and its base DB isn't exposed; files with entries of
+                // the prefix only can still be dropped at once, the rest is deleted below
+                // by keys
+                (None, Some(upper_bound)) => match cf {
+                    Some(cf) => self
+                        .db
+                        .delete_file_in_range_cf(cf, &self.prefix, upper_bound)?,
+                    None => self.db.delete_file_in_range(&self.prefix, upper_bound)?,
+                },
+                (None, None) => {}
+            }
+            let mut iter = self.committed_iter(cf);
+            while let Some(key) = iter.key() {
+                if !key.starts_with(&self.prefix) {
+                    break;
+                }
+                let key = &key[self.prefix.len()..];
+                match column {
+                    Column::Aux => batch.delete_aux(key)?,
+                    Column::Roots => batch.delete_root(key)?,
+                    _ => batch.delete(key)?,
+                }
+                iter.next();
+            }
+            iter.status()?;
+        }
+        self.commit_batch(batch)
     }
 
     fn transaction<'a>(
@@ -203,3 +442,56 @@ impl Storage for PrefixedRocksDbStorage {
         PrefixedRocksDbTransaction::new(db_transaction, self.prefix.clone(), &self.db)
     }
 }
+
+impl PrefixedStorage for PrefixedRocksDbStorage {
+    type DB = Rc<rocksdb::OptimisticTransactionDB>;
+
+    fn with_prefix(&self, prefix: Vec<u8>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            db: self.db.clone(),
+            prefix,
+            shared_batch: self.shared_batch.clone(),
+        })
+    }
+
+    fn with_shared_batch(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            prefix: self.prefix.clone(),
+            shared_batch: Some(Default::default()),
+        }
+    }
+
+    fn commit_shared_batch(&self) -> Result<(), Self::Error> {
+        match &self.shared_batch {
+            Some(shared_batch) => shared_batch.write(&self.db),
+            None => Ok(()),
+        }
+    }
+
+    fn has_shared_batch(&self) -> bool {
+        self.shared_batch.is_some()
+    }
+
+    fn db(&self) -> Self::DB {
+        self.db.clone()
+    }
+
+    fn start_db_transaction(db: &Self::DB) -> Self::DBTransaction<'_> {
+        db.transaction()
+    }
+
+    fn commit_db_transaction(transaction: Self::DBTransaction<'_>) -> Result<(), Self::Error> {
+        Ok(transaction.commit()?)
+    }
+
+    fn rollback_db_transaction(transaction: &Self::DBTransaction<'_>) -> Result<(), Self::Error> {
+        Ok(transaction.rollback()?)
+    }
+
+    fn shorten_db_transaction<'a: 'b, 'b>(
+        transaction: &'b Self::DBTransaction<'a>,
+    ) -> &'b Self::DBTransaction<'b> {
+        transaction
+    }
+}